@@ -0,0 +1,287 @@
+//! Heap usage charged to accounts, so one user of the heap can be limited without the others.
+//!
+//! Every allocation starts with a header naming the account it was charged to, the one
+//! [`entered`](Account::enter) when it was made, so it's credited back to it when freed.
+//!
+//! Allocations made by code that may be aborted, outside of [`critical`](watchdog::critical)
+//! sections, are also linked in a list while [`tracking`](track), so that what the aborted code
+//! still held can be [reclaimed](reclaim) instead of leaking.
+
+use core::alloc::Layout;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::watchdog;
+
+pub const MAX_ACCOUNTS: usize = 64;
+
+/// Slot of allocations made outside of any account, never limited.
+const KERNEL: usize = 0;
+
+struct Slot {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+    /// Whether an allocation went past the limit since the account was last entered.
+    exceeded: AtomicBool,
+    in_use: AtomicBool,
+}
+
+static SLOTS: [Slot; MAX_ACCOUNTS] = [const {
+    Slot {
+        used: AtomicUsize::new(0),
+        limit: AtomicUsize::new(usize::MAX),
+        exceeded: AtomicBool::new(false),
+        in_use: AtomicBool::new(false),
+    }
+}; MAX_ACCOUNTS];
+
+/// Slot of the account allocations are charged to.
+static CURRENT: AtomicUsize = AtomicUsize::new(KERNEL);
+
+/// Whether allocations made outside of critical sections are tracked.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+/// Most recently tracked allocation.
+static TRACKED: AtomicPtr<Links> = AtomicPtr::new(ptr::null_mut());
+
+/// Bit of the word before an allocation telling it has [`Links`], the others naming its
+/// account.
+const WITH_LINKS: usize = 1 << (usize::BITS - 1);
+
+/// Start of the header of allocations made while tracking.
+#[repr(C)]
+struct Links {
+    /// Layout the caller asked for, to free the allocation when it's reclaimed.
+    size: usize,
+    align: usize,
+    /// Whether the allocation is still tracked.
+    linked: bool,
+    previous: *mut Links,
+    next: *mut Links,
+}
+
+/// Heap usage of one user, limited to a number of bytes.
+pub struct Account {
+    slot: usize,
+}
+
+impl Account {
+    /// Opens an account limited to `limit` bytes.
+    ///
+    /// Once [`MAX_ACCOUNTS`] are open, returns the kernel's account, which is never limited.
+    pub fn new(limit: usize) -> Account {
+        let slot = (1..MAX_ACCOUNTS)
+            .find(|slot| {
+                SLOTS[*slot]
+                    .in_use
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            })
+            .unwrap_or(KERNEL);
+        let account = Account { slot };
+        if slot != KERNEL {
+            SLOTS[slot].used.store(0, Ordering::SeqCst);
+            SLOTS[slot].exceeded.store(false, Ordering::SeqCst);
+            account.set_limit(limit);
+        }
+        account
+    }
+
+    /// Bytes of heap currently charged to the account, headers included.
+    pub fn used(&self) -> usize {
+        SLOTS[self.slot].used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        SLOTS[self.slot].limit.load(Ordering::SeqCst)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        if self.slot != KERNEL {
+            SLOTS[self.slot].limit.store(limit, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether an allocation went past the limit since the account was entered.
+    pub fn exceeded(&self) -> bool {
+        SLOTS[self.slot].exceeded.load(Ordering::SeqCst)
+    }
+
+    /// Runs `f`, charging its allocations to this account.
+    ///
+    /// An allocation past the limit aborts the code running under [`watchdog::run`] if it can,
+    /// and is made anyway otherwise: check [`Account::exceeded`] afterwards. What the aborted code
+    /// allocated is credited back once [reclaimed](reclaim).
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.swap(self.slot, Ordering::SeqCst);
+        if previous != self.slot {
+            SLOTS[self.slot].exceeded.store(false, Ordering::SeqCst);
+        }
+        let result = f();
+        CURRENT.store(previous, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        if self.slot != KERNEL {
+            SLOTS[self.slot].in_use.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether the current account went past its limit since it was entered.
+pub fn exceeded() -> bool {
+    SLOTS[CURRENT.load(Ordering::SeqCst)]
+        .exceeded
+        .load(Ordering::SeqCst)
+}
+
+/// Runs `f`, charging its allocations to the kernel's account, which is never limited.
+pub fn kernel<R>(f: impl FnOnce() -> R) -> R {
+    Account { slot: KERNEL }.enter(f)
+}
+
+/// Layout of an allocation with its header, and the offset of the caller's part.
+fn with_header(layout: Layout, links: bool) -> Option<(Layout, usize)> {
+    let header = if links {
+        size_of::<Links>() + size_of::<usize>()
+    } else {
+        size_of::<usize>()
+    };
+    let offset = header.next_multiple_of(layout.align());
+    let size = layout.size().checked_add(offset)?;
+    let align = layout.align().max(align_of::<usize>());
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+/// Starts or stops tracking the allocations made outside of critical sections.
+///
+/// Stopping keeps the allocations tracked so far, see [`forget`] and [`reclaim`].
+pub fn track(tracking: bool) {
+    TRACKING.store(tracking, Ordering::SeqCst);
+}
+
+/// Stops tracking the allocations tracked so far, leaving them to their owners.
+pub fn forget() {
+    watchdog::critical(|| {
+        let mut links = TRACKED.swap(ptr::null_mut(), Ordering::SeqCst);
+        while !links.is_null() {
+            unsafe {
+                (*links).linked = false;
+                links = (*links).next;
+            }
+        }
+    })
+}
+
+/// Frees the allocations still tracked, crediting them back to their accounts.
+///
+/// Only for allocations nothing refers to anymore: those of aborted code, once what kept its
+/// work was dropped.
+pub fn reclaim() {
+    loop {
+        let links = TRACKED.load(Ordering::SeqCst);
+        if links.is_null() {
+            return;
+        }
+        unsafe {
+            let layout = Layout::from_size_align_unchecked((*links).size, (*links).align);
+            let (_, offset) = with_header(layout, true).expect("layout was allocated");
+            // Unlinks the allocation
+            alloc::alloc::dealloc((links as *mut u8).add(offset), layout);
+        }
+    }
+}
+
+/// Adds `links` to the tracked allocations.
+unsafe fn link(links: *mut Links) {
+    let next = TRACKED.load(Ordering::SeqCst);
+    unsafe {
+        (*links).linked = true;
+        (*links).next = next;
+        if !next.is_null() {
+            (*next).previous = links;
+        }
+    }
+    TRACKED.store(links, Ordering::SeqCst);
+}
+
+/// Removes `links` from the tracked allocations.
+unsafe fn unlink(links: *mut Links) {
+    unsafe {
+        let Links { previous, next, .. } = *links;
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+        if previous.is_null() {
+            TRACKED.store(next, Ordering::SeqCst);
+        } else {
+            (*previous).next = next;
+        }
+    }
+}
+
+/// Allocates `layout` with `alloc`, charged to the current account.
+pub(super) unsafe fn alloc(layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    // Allocations made in critical sections belong to state that outlives aborted code
+    let tracked = TRACKING.load(Ordering::SeqCst) && !watchdog::is_critical();
+    let Some((block, offset)) = with_header(layout, tracked) else {
+        return core::ptr::null_mut();
+    };
+    let account = CURRENT.load(Ordering::SeqCst);
+    let slot = &SLOTS[account];
+    if account != KERNEL
+        && slot.used.load(Ordering::SeqCst) + block.size() > slot.limit.load(Ordering::SeqCst)
+    {
+        slot.exceeded.store(true, Ordering::SeqCst);
+        watchdog::abort();
+    }
+    watchdog::critical(|| {
+        let ptr = alloc(block);
+        if ptr.is_null() {
+            return ptr;
+        }
+        slot.used.fetch_add(block.size(), Ordering::SeqCst);
+        unsafe {
+            let caller = ptr.add(offset);
+            let mut tag = account;
+            if tracked {
+                let links = ptr as *mut Links;
+                links.write(Links {
+                    size: layout.size(),
+                    align: layout.align(),
+                    linked: false,
+                    previous: ptr::null_mut(),
+                    next: ptr::null_mut(),
+                });
+                link(links);
+                tag |= WITH_LINKS;
+            }
+            (caller as *mut usize).sub(1).write(tag);
+            caller
+        }
+    })
+}
+
+/// Frees an allocation made by [`alloc`] with `dealloc`, credited to its account.
+pub(super) unsafe fn dealloc(ptr: *mut u8, layout: Layout, dealloc: impl FnOnce(*mut u8, Layout)) {
+    let tag = unsafe { (ptr as *const usize).sub(1).read() };
+    let account = tag & !WITH_LINKS;
+    let with_links = tag & WITH_LINKS != 0;
+    let (block, offset) = with_header(layout, with_links).expect("layout was allocated");
+    let ptr = unsafe { ptr.sub(offset) };
+    // The account may have been reopened since, don't wrap around
+    let _ = SLOTS[account]
+        .used
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            Some(used.saturating_sub(block.size()))
+        });
+    watchdog::critical(|| {
+        let links = ptr as *mut Links;
+        if with_links && unsafe { (*links).linked } {
+            unsafe { unlink(links) };
+        }
+        dealloc(ptr, block)
+    });
+}
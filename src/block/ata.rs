@@ -0,0 +1,149 @@
+//! ATA disks on the primary IDE bus, driven by polling in PIO mode with 28-bit LBA addresses.
+
+use x86_64::instructions::port::Port;
+
+use super::{BlockDevice, BlockError, check_range};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const DATA: u16 = 0x1F0;
+const SECTOR_COUNT: u16 = 0x1F2;
+const LBA_LOW: u16 = 0x1F3;
+const LBA_MID: u16 = 0x1F4;
+const LBA_HIGH: u16 = 0x1F5;
+const DRIVE: u16 = 0x1F6;
+const STATUS_COMMAND: u16 = 0x1F7;
+
+const READ_SECTORS: u8 = 0x20;
+const WRITE_SECTORS: u8 = 0x30;
+const CACHE_FLUSH: u8 = 0xE7;
+const IDENTIFY: u8 = 0xEC;
+
+const ERR: u8 = 1 << 0;
+const DRQ: u8 = 1 << 3;
+const DF: u8 = 1 << 5;
+const BSY: u8 = 1 << 7;
+
+/// Status polls before giving up on the disk.
+const POLL_LIMIT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// A disk on the primary bus.
+pub struct AtaDisk {
+    drive: Drive,
+    sectors: u64,
+}
+
+impl AtaDisk {
+    /// The disk at `drive`, or `None` if there is no ATA disk there.
+    pub fn identify(drive: Drive) -> Option<AtaDisk> {
+        let mut disk = AtaDisk { drive, sectors: 0 };
+        unsafe {
+            Port::<u8>::new(DRIVE).write(match drive {
+                Drive::Master => 0xA0,
+                Drive::Slave => 0xB0,
+            });
+            for port in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+                Port::<u8>::new(port).write(0);
+            }
+            Port::<u8>::new(STATUS_COMMAND).write(IDENTIFY);
+            if Port::<u8>::new(STATUS_COMMAND).read() == 0 {
+                return None;
+            }
+            disk.wait(0).ok()?;
+            // ATAPI and SATA devices set these instead of answering
+            if Port::<u8>::new(LBA_MID).read() != 0 || Port::<u8>::new(LBA_HIGH).read() != 0 {
+                return None;
+            }
+            disk.wait(DRQ).ok()?;
+            let mut identity = [0u16; 256];
+            for word in &mut identity {
+                *word = Port::<u16>::new(DATA).read();
+            }
+            disk.sectors = identity[60] as u64 | (identity[61] as u64) << 16;
+        }
+        Some(disk)
+    }
+
+    /// Polls the status until the disk isn't busy and has `flags` set.
+    fn wait(&self, flags: u8) -> Result<(), BlockError> {
+        let mut status = Port::<u8>::new(STATUS_COMMAND);
+        for _ in 0..POLL_LIMIT {
+            let value = unsafe { status.read() };
+            if value & (ERR | DF) != 0 {
+                return Err(BlockError::Io);
+            }
+            if value & BSY == 0 && value & flags == flags {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Io)
+    }
+
+    /// Starts `command` on `count` sectors from `lba`, at most 255.
+    fn command(&self, command: u8, lba: u64, count: u8) -> Result<(), BlockError> {
+        self.wait(0)?;
+        let drive = match self.drive {
+            Drive::Master => 0xE0,
+            Drive::Slave => 0xF0,
+        };
+        unsafe {
+            Port::<u8>::new(DRIVE).write(drive | ((lba >> 24) & 0x0F) as u8);
+            Port::<u8>::new(SECTOR_COUNT).write(count);
+            Port::<u8>::new(LBA_LOW).write(lba as u8);
+            Port::<u8>::new(LBA_MID).write((lba >> 8) as u8);
+            Port::<u8>::new(LBA_HIGH).write((lba >> 16) as u8);
+            Port::<u8>::new(STATUS_COMMAND).write(command);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, block, buf.len())?;
+        let mut data = Port::<u16>::new(DATA);
+        for (lba, chunk) in (block..)
+            .step_by(255)
+            .zip(buf.chunks_mut(255 * SECTOR_SIZE))
+        {
+            self.command(READ_SECTORS, lba, (chunk.len() / SECTOR_SIZE) as u8)?;
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                self.wait(DRQ)?;
+                for word in sector.chunks_mut(2) {
+                    word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, block, buf.len())?;
+        let mut data = Port::<u16>::new(DATA);
+        for (lba, chunk) in (block..).step_by(255).zip(buf.chunks(255 * SECTOR_SIZE)) {
+            self.command(WRITE_SECTORS, lba, (chunk.len() / SECTOR_SIZE) as u8)?;
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                self.wait(DRQ)?;
+                for word in sector.chunks(2) {
+                    unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+                }
+            }
+        }
+        unsafe { Port::<u8>::new(STATUS_COMMAND).write(CACHE_FLUSH) };
+        self.wait(0)
+    }
+}
@@ -0,0 +1,78 @@
+//! Block devices: storage read and written in fixed-size blocks, such as disks.
+
+pub mod ata;
+
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// The block is past the end of the device.
+    OutOfRange(u64),
+    /// The buffer isn't a whole number of blocks.
+    InvalidLength(usize),
+    /// The device reported an error.
+    Io,
+}
+
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks from `block` on into `buf`, a whole number of blocks.
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, a whole number of blocks, to the blocks from `block` on.
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Number of blocks in `len` bytes, checking they fit on `device` from `block` on.
+pub fn check_range(device: &dyn BlockDevice, block: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::InvalidLength(len));
+    }
+    let count = (len / device.block_size()) as u64;
+    match block.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange(block)),
+    }
+}
+
+/// A block device kept in memory, allocated up front.
+pub struct RamDisk {
+    block_size: usize,
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, block_count: u64) -> RamDisk {
+        RamDisk {
+            block_size,
+            data: alloc::vec![0; block_size * block_count as usize],
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, block, buf.len())?;
+        let start = block as usize * self.block_size;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, block, buf.len())?;
+        let start = block as usize * self.block_size;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
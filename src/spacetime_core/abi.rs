@@ -0,0 +1,617 @@
+//! Host functions imported by WebAssembly guests, see [`wasm_host`](super::wasm_host).
+//!
+//! Functions are imported from a versioned namespace, `spacetime_<major>.<minor>`. Within a
+//! major version, functions keep their signature and behaviour and minor versions only add new
+//! ones, so the kernel runs every module built against a minor version up to [`VERSION`].
+//! Introducing a new major version keeps the [`FUNCTIONS`] of the previous ones.
+//!
+//! Strings are passed as a pointer and a length into guest memory, rows, index keys and reducer
+//! arguments in the binary format. Functions return [`OK`] or one of the error codes below, and
+//! hand back variable-length data as buffers, copied into guest memory with `buffer_consume`.
+//!
+//! | Function | Signature |
+//! |---|---|
+//! | `log` | `(message, message_len)` |
+//! | `caller` | `() -> i64` |
+//! | `iter_start` | `(table, table_len, out_iter) -> i32` |
+//! | `index_seek` | `(table, table_len, index, index_len, key, key_len, out_iter) -> i32` |
+//! | `index_within` | `(table, table_len, index, index_len, min, max, out_iter) -> i32` |
+//! | `index_nearest` | `(table, table_len, index, index_len, point, k, out_iter) -> i32` |
+//! | `index_search` | `(table, table_len, index, index_len, search, search_len, limit, out_iter) -> i32` |
+//! | `iter_next` | `(iter, out_row_id, out_buffer) -> i32` |
+//! | `insert` | `(table, table_len, row, row_len, out_row_id) -> i32` |
+//! | `delete` | `(table, table_len, row_id: i64) -> i32` |
+//! | `update` | `(table, table_len, row_id: i64, row, row_len) -> i32` |
+//! | `schedule` | `(reducer, reducer_len, args, args_len, delay: i64) -> i32` |
+//! | `buffer_len` | `(buffer, out_len) -> i32` |
+//! | `buffer_consume` | `(buffer, destination) -> i32` |
+//!
+//! `insert` writes the row back with its sequence values filled in. `iter_next` returns
+//! [`EXHAUSTED`] once every row was returned, freeing the iterator.
+//!
+//! `index_within` and `index_nearest`, since 1.1, search a spatial index for the rows between
+//! two corners or the `k` nearest to a point, each given as one little-endian `f64` per
+//! column of the index. `index_search`, since 1.2, iterates the `limit` rows best matching a
+//! search of a text index, best first.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::binary::{self, DecodeError};
+use super::buffer_pool::StorageError;
+use super::table::{RowId, TableError};
+use super::value::ProductValue;
+use super::{ROW_READ_ENERGY, ReducerCallError, ReducerContext};
+use crate::allocator::account;
+use crate::wasm::ValType::{self, I32, I64};
+use crate::wasm::parser::Import;
+use crate::wasm::{FuncType, Host, Memory, Trap, Value};
+use crate::watchdog;
+
+/// Most recent version of the ABI, as `(major, minor)`.
+pub const VERSION: (u16, u16) = (1, 2);
+
+pub const OK: i32 = 0;
+pub const NO_SUCH_TABLE: i32 = 1;
+pub const NO_SUCH_INDEX: i32 = 2;
+pub const NO_SUCH_ROW: i32 = 3;
+pub const NO_SUCH_REDUCER: i32 = 4;
+/// The iterator or buffer handle is not in use.
+pub const INVALID_HANDLE: i32 = 5;
+/// A string or value could not be decoded.
+pub const DECODE: i32 = 6;
+/// The row or reducer arguments have the wrong type.
+pub const INVALID_ROW: i32 = 7;
+pub const UNIQUE_VIOLATION: i32 = 8;
+pub const SEQUENCE_EXHAUSTED: i32 = 9;
+pub const EXHAUSTED: i32 = 10;
+pub const FOREIGN_KEY_VIOLATION: i32 = 11;
+pub const CHECK_VIOLATION: i32 = 12;
+/// The point or box doesn't have as many coordinates as the spatial index has columns.
+pub const INVALID_COORDINATES: i32 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Log,
+    Caller,
+    IterStart,
+    IndexSeek,
+    IndexWithin,
+    IndexNearest,
+    IndexSearch,
+    IterNext,
+    Insert,
+    Delete,
+    Update,
+    Schedule,
+    BufferLen,
+    BufferConsume,
+}
+
+/// A host function, as imported by guests.
+pub struct Signature {
+    pub name: &'static str,
+    /// Version which introduced the function.
+    pub since: (u16, u16),
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    function: Function,
+}
+
+pub const FUNCTIONS: &[Signature] = &[
+    Signature {
+        name: "log",
+        since: (1, 0),
+        params: &[I32, I32],
+        results: &[],
+        function: Function::Log,
+    },
+    Signature {
+        name: "caller",
+        since: (1, 0),
+        params: &[],
+        results: &[I64],
+        function: Function::Caller,
+    },
+    Signature {
+        name: "iter_start",
+        since: (1, 0),
+        params: &[I32, I32, I32],
+        results: &[I32],
+        function: Function::IterStart,
+    },
+    Signature {
+        name: "index_seek",
+        since: (1, 0),
+        params: &[I32, I32, I32, I32, I32, I32, I32],
+        results: &[I32],
+        function: Function::IndexSeek,
+    },
+    Signature {
+        name: "index_within",
+        since: (1, 1),
+        params: &[I32, I32, I32, I32, I32, I32, I32],
+        results: &[I32],
+        function: Function::IndexWithin,
+    },
+    Signature {
+        name: "index_nearest",
+        since: (1, 1),
+        params: &[I32, I32, I32, I32, I32, I32, I32],
+        results: &[I32],
+        function: Function::IndexNearest,
+    },
+    Signature {
+        name: "index_search",
+        since: (1, 2),
+        params: &[I32, I32, I32, I32, I32, I32, I32, I32],
+        results: &[I32],
+        function: Function::IndexSearch,
+    },
+    Signature {
+        name: "iter_next",
+        since: (1, 0),
+        params: &[I32, I32, I32],
+        results: &[I32],
+        function: Function::IterNext,
+    },
+    Signature {
+        name: "insert",
+        since: (1, 0),
+        params: &[I32, I32, I32, I32, I32],
+        results: &[I32],
+        function: Function::Insert,
+    },
+    Signature {
+        name: "delete",
+        since: (1, 0),
+        params: &[I32, I32, I64],
+        results: &[I32],
+        function: Function::Delete,
+    },
+    Signature {
+        name: "update",
+        since: (1, 0),
+        params: &[I32, I32, I64, I32, I32],
+        results: &[I32],
+        function: Function::Update,
+    },
+    Signature {
+        name: "schedule",
+        since: (1, 0),
+        params: &[I32, I32, I32, I32, I64],
+        results: &[I32],
+        function: Function::Schedule,
+    },
+    Signature {
+        name: "buffer_len",
+        since: (1, 0),
+        params: &[I32, I32],
+        results: &[I32],
+        function: Function::BufferLen,
+    },
+    Signature {
+        name: "buffer_consume",
+        since: (1, 0),
+        params: &[I32, I32],
+        results: &[I32],
+        function: Function::BufferConsume,
+    },
+];
+
+/// Parses an import namespace such as `spacetime_1.0`.
+fn parse_namespace(namespace: &str) -> Option<(u16, u16)> {
+    let (major, minor) = namespace.strip_prefix("spacetime_")?.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Index into [`FUNCTIONS`] of the function a guest imports, if this kernel provides it.
+pub fn resolve(import: &Import, ty: &FuncType) -> Option<u32> {
+    let (major, minor) = parse_namespace(&import.module)?;
+    if major != VERSION.0 || minor > VERSION.1 {
+        return None;
+    }
+    FUNCTIONS
+        .iter()
+        .position(|signature| {
+            signature.name == import.name
+                && signature.since.0 == major
+                && signature.since.1 <= minor
+                && signature.params == ty.params.as_slice()
+                && signature.results == ty.results.as_slice()
+        })
+        .map(|index| index as u32)
+}
+
+enum Error {
+    Code(i32),
+    Trap(Trap),
+}
+
+impl From<Trap> for Error {
+    fn from(trap: Trap) -> Error {
+        Error::Trap(trap)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(_: DecodeError) -> Error {
+        Error::Code(DECODE)
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(error: StorageError) -> Error {
+        Error::from(TableError::Storage(error))
+    }
+}
+
+impl From<TableError> for Error {
+    fn from(error: TableError) -> Error {
+        Error::Code(match error {
+            TableError::NoSuchTable(_) => NO_SUCH_TABLE,
+            TableError::NoSuchIndex(_) => NO_SUCH_INDEX,
+            TableError::InvalidRow => INVALID_ROW,
+            TableError::UniqueViolation(_) => UNIQUE_VIOLATION,
+            TableError::SequenceExhausted(_) => SEQUENCE_EXHAUSTED,
+            TableError::ForeignKeyViolation(_) => FOREIGN_KEY_VIOLATION,
+            TableError::CheckViolation(_) => CHECK_VIOLATION,
+            TableError::InvalidCoordinates(_) => INVALID_COORDINATES,
+            TableError::OutOfEnergy => return Error::Trap(Trap::OutOfFuel),
+            TableError::OutOfQuota => return Error::Trap(Trap::Interrupted),
+            TableError::Storage(error) => {
+                return Error::Trap(Trap::Host(format!("table storage failed: {:?}", error)));
+            }
+        })
+    }
+}
+
+/// Rows left to return from an `iter_start` or `index_seek`, last first.
+struct RowIter {
+    table: String,
+    row_ids: Vec<RowId>,
+}
+
+/// Host functions for a guest running a reducer.
+pub struct AbiHost<'a, 'b> {
+    ctx: &'a mut ReducerContext<'b>,
+    iters: BTreeMap<u32, RowIter>,
+    buffers: BTreeMap<u32, Vec<u8>>,
+    next_handle: u32,
+}
+
+impl<'a, 'b> AbiHost<'a, 'b> {
+    pub fn new(ctx: &'a mut ReducerContext<'b>) -> AbiHost<'a, 'b> {
+        AbiHost {
+            ctx,
+            iters: BTreeMap::new(),
+            buffers: BTreeMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    pub fn ctx(&mut self) -> &mut ReducerContext<'b> {
+        self.ctx
+    }
+
+    fn handle(&mut self) -> u32 {
+        self.next_handle += 1;
+        self.next_handle
+    }
+
+    fn start_iter(&mut self, table: String, mut row_ids: Vec<RowId>) -> u32 {
+        row_ids.reverse();
+        let handle = self.handle();
+        self.iters.insert(handle, RowIter { table, row_ids });
+        handle
+    }
+
+    fn dispatch(
+        &mut self,
+        function: Function,
+        memory: &mut Memory,
+        args: &[Value],
+    ) -> Result<(), Error> {
+        match function {
+            Function::Log | Function::Caller => unreachable!("function returns no error code"),
+            Function::IterStart => {
+                let table = read_str(memory, args, 0)?;
+                let row_ids = self.ctx.table(&table)?.row_ids().collect();
+                let handle = self.start_iter(table, row_ids);
+                memory.write(arg(args, 2)?, &handle.to_le_bytes())?;
+            }
+            Function::IndexSeek => {
+                let table = read_str(memory, args, 0)?;
+                let index = read_str(memory, args, 2)?;
+                let (key_address, key_len) = (arg(args, 4)?, arg(args, 5)?);
+                let found = self.ctx.table(&table)?;
+                let key_type = found.index_key_type(&index)?;
+                let key = binary::from_bytes(&key_type, memory.read(key_address, key_len)?)?;
+                let row_ids = found.seek(&index, &key)?;
+                let handle = self.start_iter(table, row_ids);
+                memory.write(arg(args, 6)?, &handle.to_le_bytes())?;
+            }
+            Function::IndexWithin => {
+                let table = read_str(memory, args, 0)?;
+                let index = read_str(memory, args, 2)?;
+                let found = self.ctx.table(&table)?;
+                let dims = found.spatial_dims(&index)?;
+                let min = read_coordinates(memory, arg(args, 4)?, dims)?;
+                let max = read_coordinates(memory, arg(args, 5)?, dims)?;
+                let row_ids = found.within(&index, &min, &max)?;
+                let handle = self.start_iter(table, row_ids);
+                memory.write(arg(args, 6)?, &handle.to_le_bytes())?;
+            }
+            Function::IndexNearest => {
+                let table = read_str(memory, args, 0)?;
+                let index = read_str(memory, args, 2)?;
+                let found = self.ctx.table(&table)?;
+                let dims = found.spatial_dims(&index)?;
+                let point = read_coordinates(memory, arg(args, 4)?, dims)?;
+                let row_ids = found.nearest(&index, &point, arg(args, 5)? as usize)?;
+                let handle = self.start_iter(table, row_ids);
+                memory.write(arg(args, 6)?, &handle.to_le_bytes())?;
+            }
+            Function::IndexSearch => {
+                let table = read_str(memory, args, 0)?;
+                let index = read_str(memory, args, 2)?;
+                let search = read_str(memory, args, 4)?;
+                let limit = arg(args, 6)? as usize;
+                let row_ids = self.ctx.table(&table)?.search(&index, &search, limit)?;
+                let handle = self.start_iter(table, row_ids);
+                memory.write(arg(args, 7)?, &handle.to_le_bytes())?;
+            }
+            Function::IterNext => {
+                let handle = arg(args, 0)?;
+                let iter = self
+                    .iters
+                    .get_mut(&handle)
+                    .ok_or(Error::Code(INVALID_HANDLE))?;
+                let table = self.ctx.table(&iter.table)?;
+                let next = core::iter::from_fn(|| iter.row_ids.pop())
+                    .find_map(|row_id| {
+                        table
+                            .get(row_id)
+                            .map(|row| Some((row_id, row?)))
+                            .transpose()
+                    })
+                    .transpose()?;
+                let Some((row_id, row)) = next else {
+                    self.iters.remove(&handle);
+                    return Err(Error::Code(EXHAUSTED));
+                };
+                let row = binary::to_bytes(&row);
+                self.ctx.charge(ROW_READ_ENERGY)?;
+                memory.write(arg(args, 1)?, &row_id.0.to_le_bytes())?;
+                let buffer = self.handle();
+                self.buffers.insert(buffer, row);
+                memory.write(arg(args, 2)?, &buffer.to_le_bytes())?;
+            }
+            Function::Insert => {
+                let table = read_str(memory, args, 0)?;
+                let (row_address, row_len) = (arg(args, 2)?, arg(args, 3)?);
+                let row = self.read_row(memory, &table, row_address, row_len)?;
+                let row_id = self.ctx.insert(&table, row)?;
+                let row = self
+                    .ctx
+                    .table(&table)?
+                    .get(row_id)?
+                    .expect("row was inserted");
+                memory.write(row_address, &binary::to_bytes(&row))?;
+                memory.write(arg(args, 4)?, &row_id.0.to_le_bytes())?;
+            }
+            Function::Delete => {
+                let table = read_str(memory, args, 0)?;
+                let row_id = RowId(arg_u64(args, 2)?);
+                if self.ctx.delete(&table, row_id)?.is_none() {
+                    return Err(Error::Code(NO_SUCH_ROW));
+                }
+            }
+            Function::Update => {
+                let table = read_str(memory, args, 0)?;
+                let row_id = RowId(arg_u64(args, 2)?);
+                let row = self.read_row(memory, &table, arg(args, 3)?, arg(args, 4)?)?;
+                if self.ctx.update(&table, row_id, row)?.is_none() {
+                    return Err(Error::Code(NO_SUCH_ROW));
+                }
+            }
+            Function::Schedule => {
+                let reducer = read_str(memory, args, 0)?;
+                let params = self
+                    .ctx
+                    .reducer(&reducer)
+                    .ok_or(Error::Code(NO_SUCH_REDUCER))?
+                    .params();
+                let args_bytes = memory.read(arg(args, 2)?, arg(args, 3)?)?;
+                let call_args = binary::from_bytes(params, args_bytes)?;
+                self.ctx
+                    .schedule(&reducer, call_args, arg_u64(args, 4)?)
+                    .map_err(|error| match error {
+                        ReducerCallError::NoSuchReducer(_) => Error::Code(NO_SUCH_REDUCER),
+                        _ => Error::Code(INVALID_ROW),
+                    })?;
+            }
+            Function::BufferLen => {
+                let buffer = self
+                    .buffers
+                    .get(&arg(args, 0)?)
+                    .ok_or(Error::Code(INVALID_HANDLE))?;
+                let len = buffer.len() as u32;
+                memory.write(arg(args, 1)?, &len.to_le_bytes())?;
+            }
+            Function::BufferConsume => {
+                let handle = arg(args, 0)?;
+                let buffer = self
+                    .buffers
+                    .get(&handle)
+                    .ok_or(Error::Code(INVALID_HANDLE))?;
+                memory.write(arg(args, 1)?, buffer)?;
+                self.buffers.remove(&handle);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_row(
+        &self,
+        memory: &Memory,
+        table: &str,
+        address: u32,
+        len: u32,
+    ) -> Result<ProductValue, Error> {
+        let columns = self.ctx.table(table)?.columns();
+        Ok(binary::from_bytes(columns, memory.read(address, len)?)?)
+    }
+}
+
+impl Host for AbiHost<'_, '_> {
+    fn call(
+        &mut self,
+        function: u32,
+        memory: &mut Memory,
+        args: &[Value],
+    ) -> Result<Option<Value>, Trap> {
+        let function = FUNCTIONS
+            .get(function as usize)
+            .ok_or(Trap::Host(format!("unknown host function {}", function)))?
+            .function;
+        match function {
+            Function::Log => {
+                self.ctx.log(
+                    &read_str(memory, args, 0).unwrap_or_else(|_| String::from("<invalid utf-8>")),
+                );
+                Ok(None)
+            }
+            Function::Caller => Ok(Some(Value::I64(self.ctx.caller() as i64))),
+            function => match self.dispatch(function, memory, args) {
+                Ok(()) => Ok(Some(Value::I32(OK))),
+                Err(Error::Code(code)) => Ok(Some(Value::I32(code))),
+                Err(Error::Trap(trap)) => Err(trap),
+            },
+        }
+    }
+
+    fn interrupted(&self) -> bool {
+        watchdog::expired() || account::exceeded()
+    }
+}
+
+fn arg(args: &[Value], index: usize) -> Result<u32, Trap> {
+    match args.get(index) {
+        Some(Value::I32(value)) => Ok(*value as u32),
+        _ => Err(Trap::TypeMismatch),
+    }
+}
+
+fn arg_u64(args: &[Value], index: usize) -> Result<u64, Trap> {
+    match args.get(index) {
+        Some(Value::I64(value)) => Ok(*value as u64),
+        _ => Err(Trap::TypeMismatch),
+    }
+}
+
+/// String whose address and length are the arguments at `index` and `index + 1`.
+fn read_str(memory: &Memory, args: &[Value], index: usize) -> Result<String, Error> {
+    let bytes = memory.read(arg(args, index)?, arg(args, index + 1)?)?;
+    core::str::from_utf8(bytes)
+        .map(ToString::to_string)
+        .map_err(|_| Error::Code(DECODE))
+}
+
+/// Reads `dims` little-endian `f64` coordinates at `address`.
+fn read_coordinates(memory: &Memory, address: u32, dims: usize) -> Result<Vec<f64>, Error> {
+    let bytes = memory.read(address, (dims * 8) as u32)?;
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|chunk| f64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes")))
+        .collect())
+}
+
+// Tests
+
+/// Guest with a table `counter(n: u32)`, a reducer `add(n: u32)` inserting a row and a reducer
+/// `count(expected: u32)` iterating the table, failing unless it has `expected` rows.
+#[cfg(test)]
+#[rustfmt::skip]
+const COUNTER_GUEST: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x20, 0x05, 0x60,
+    0x05, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x03, 0x7f, 0x7f,
+    0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+    0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x02, 0x4d, 0x03, 0x0d, 0x73, 0x70,
+    0x61, 0x63, 0x65, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x31, 0x2e, 0x30, 0x06,
+    0x69, 0x6e, 0x73, 0x65, 0x72, 0x74, 0x00, 0x00, 0x0d, 0x73, 0x70, 0x61,
+    0x63, 0x65, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x31, 0x2e, 0x30, 0x0a, 0x69,
+    0x74, 0x65, 0x72, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x01, 0x0d,
+    0x73, 0x70, 0x61, 0x63, 0x65, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x31, 0x2e,
+    0x30, 0x09, 0x69, 0x74, 0x65, 0x72, 0x5f, 0x6e, 0x65, 0x78, 0x74, 0x00,
+    0x01, 0x03, 0x05, 0x04, 0x02, 0x03, 0x04, 0x04, 0x05, 0x03, 0x01, 0x00,
+    0x01, 0x07, 0x50, 0x05, 0x13, 0x5f, 0x5f, 0x64, 0x65, 0x73, 0x63, 0x72,
+    0x69, 0x62, 0x65, 0x5f, 0x6d, 0x6f, 0x64, 0x75, 0x6c, 0x65, 0x5f, 0x5f,
+    0x00, 0x03, 0x09, 0x5f, 0x5f, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x5f, 0x5f,
+    0x00, 0x04, 0x0e, 0x5f, 0x5f, 0x72, 0x65, 0x64, 0x75, 0x63, 0x65, 0x72,
+    0x5f, 0x5f, 0x61, 0x64, 0x64, 0x00, 0x05, 0x10, 0x5f, 0x5f, 0x72, 0x65,
+    0x64, 0x75, 0x63, 0x65, 0x72, 0x5f, 0x5f, 0x63, 0x6f, 0x75, 0x6e, 0x74,
+    0x00, 0x06, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x0a,
+    0x71, 0x04, 0x0a, 0x00, 0x42, 0xe1, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01,
+    0x0b, 0x04, 0x00, 0x41, 0x10, 0x0b, 0x10, 0x00, 0x41, 0x80, 0x04, 0x41,
+    0x07, 0x20, 0x00, 0x20, 0x01, 0x41, 0xc8, 0x00, 0x10, 0x00, 0x0b, 0x4e,
+    0x01, 0x02, 0x7f, 0x41, 0x80, 0x04, 0x41, 0x07, 0x41, 0xc0, 0x00, 0x10,
+    0x01, 0x21, 0x03, 0x20, 0x03, 0x04, 0x40, 0x20, 0x03, 0x0f, 0x0b, 0x02,
+    0x40, 0x03, 0x40, 0x41, 0xc0, 0x00, 0x28, 0x02, 0x00, 0x41, 0xc8, 0x00,
+    0x41, 0xd0, 0x00, 0x10, 0x02, 0x21, 0x03, 0x20, 0x03, 0x41, 0x0a, 0x46,
+    0x0d, 0x01, 0x20, 0x03, 0x04, 0x40, 0x20, 0x03, 0x0f, 0x0b, 0x20, 0x02,
+    0x41, 0x01, 0x6a, 0x21, 0x02, 0x0c, 0x00, 0x0b, 0x0b, 0x20, 0x02, 0x20,
+    0x00, 0x28, 0x02, 0x00, 0x47, 0x0b, 0x0b, 0x75, 0x02, 0x00, 0x41, 0x80,
+    0x08, 0x0b, 0x61, 0x07, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x75, 0x6e, 0x74,
+    0x65, 0x72, 0x01, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x63, 0x6f,
+    0x75, 0x6e, 0x74, 0x65, 0x72, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+    0x00, 0x6e, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x61,
+    0x64, 0x64, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x6e, 0x06,
+    0x00, 0x05, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x75, 0x6e, 0x74, 0x01, 0x00,
+    0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x65, 0x78, 0x70, 0x65, 0x63, 0x74,
+    0x65, 0x64, 0x06, 0x00, 0x00, 0x41, 0x80, 0x04, 0x0b, 0x07, 0x63, 0x6f,
+    0x75, 0x6e, 0x74, 0x65, 0x72,
+];
+
+/// Guest importing `log` from a newer major version of the ABI.
+#[cfg(test)]
+#[rustfmt::skip]
+const NEWER_GUEST: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60,
+    0x02, 0x7f, 0x7f, 0x00, 0x02, 0x15, 0x01, 0x0d, 0x73, 0x70, 0x61, 0x63,
+    0x65, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x32, 0x2e, 0x30, 0x03, 0x6c, 0x6f,
+    0x67, 0x00, 0x00, 0x03, 0x01, 0x00, 0x07, 0x01, 0x00, 0x0a, 0x01, 0x00,
+];
+
+#[test_case]
+fn test_abi_table_access() {
+    use super::test_util::row;
+    use super::value::AlgebraicValue;
+    use super::wasm_host;
+
+    let mut module = wasm_host::load(COUNTER_GUEST).expect("valid guest");
+    let args = |n| row([AlgebraicValue::U32(n)]);
+    module.call_reducer("add", 1, args(3)).unwrap();
+    module.call_reducer("add", 1, args(4)).unwrap();
+    assert_eq!(module.table("counter").unwrap().len(), 2);
+    assert_eq!(module.call_reducer("count", 1, args(2)), Ok(()));
+    assert!(module.call_reducer("count", 1, args(3)).is_err());
+}
+
+#[test_case]
+fn test_abi_newer_major_version() {
+    use super::wasm_host::{self, WasmModuleError};
+    use crate::wasm::InstantiateError;
+
+    assert!(matches!(
+        wasm_host::load(NEWER_GUEST),
+        Err(WasmModuleError::Instantiate(
+            InstantiateError::UnresolvedImport { .. }
+        ))
+    ));
+}
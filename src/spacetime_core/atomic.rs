@@ -0,0 +1,250 @@
+//! Atomic transactions: reducer calls in several modules of a core, committed together or not
+//! at all.
+//!
+//! The calls run in order, each leaving its writes in its module's tables without committing
+//! them. Once they all succeeded, each module commits its call; if one fails, the calls that
+//! ran before it are rolled back and recorded as [`ReducerCallError::Aborted`]. Nothing else
+//! runs in between, so no other call sees writes that may be rolled back: the calls reducers
+//! make to other modules are only queued as their calls commit, and dropped if they're aborted.
+//! A module takes part in one call of a transaction at most, its commits holding the writes of
+//! one reducer each.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+
+use super::value::ProductValue;
+use super::{Module, ReducerCallError};
+
+/// A reducer call of an atomic transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtomicCall {
+    pub module_id: u64,
+    pub reducer: String,
+    pub args: ProductValue,
+}
+
+impl AtomicCall {
+    pub fn new(module_id: u64, reducer: &str, args: ProductValue) -> AtomicCall {
+        AtomicCall {
+            module_id,
+            reducer: String::from(reducer),
+            args,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AtomicError {
+    /// The module has more than one call in the transaction, none were run.
+    DuplicateModule(u64),
+    /// The call at `index` failed, none were committed.
+    Failed {
+        index: usize,
+        error: ReducerCallError,
+    },
+}
+
+/// Runs `calls` as `caller` in one transaction across `modules`.
+pub(super) fn run(
+    modules: &mut BTreeMap<u64, Module>,
+    calls: Vec<AtomicCall>,
+    caller: u64,
+) -> Result<(), AtomicError> {
+    let mut module_ids = BTreeSet::new();
+    for (index, call) in calls.iter().enumerate() {
+        if !module_ids.insert(call.module_id) {
+            return Err(AtomicError::DuplicateModule(call.module_id));
+        }
+        if !modules.contains_key(&call.module_id) {
+            let error = ReducerCallError::NoSuchModule(call.module_id);
+            return Err(AtomicError::Failed { index, error });
+        }
+    }
+
+    let mut prepared = Vec::new();
+    for (index, call) in calls.into_iter().enumerate() {
+        let module = modules
+            .get_mut(&call.module_id)
+            .expect("module was checked");
+        match module.prepare(&call.reducer, caller, call.args) {
+            Ok(ran) => prepared.push((call.module_id, ran)),
+            Err(error) => {
+                for (module_id, ran) in prepared.into_iter().rev() {
+                    let module = modules.get_mut(&module_id).expect("module was checked");
+                    module.abort(ran, ReducerCallError::Aborted);
+                }
+                return Err(AtomicError::Failed { index, error });
+            }
+        }
+    }
+    for (module_id, ran) in prepared {
+        let module = modules.get_mut(&module_id).expect("module was checked");
+        module.commit(ran);
+    }
+    Ok(())
+}
+
+// Tests
+
+#[cfg(test)]
+fn amount(amount: u64) -> ProductValue {
+    super::test_util::row([super::value::AlgebraicValue::U64(amount)])
+}
+
+/// Core of an economy with 10 gold, whose reducer `spend(amount)` takes from it, and an
+/// inventory, whose reducer `give(name)` adds an item, and their ids.
+#[cfg(test)]
+fn market() -> (super::SpacetimeCore, u64, u64) {
+    use super::test_util::{add_reducer, columns, failed, inserting, module};
+    use super::value::{AlgebraicType, AlgebraicValue};
+
+    let gold = columns([("amount", AlgebraicType::U64)]);
+    let mut economy = module("economy", [("gold", gold.clone())]);
+    add_reducer(&mut economy, "spend", gold, |ctx, args| {
+        let (row_id, row) = ctx
+            .table("gold")
+            .map_err(failed)?
+            .iter()
+            .next()
+            .unwrap()
+            .unwrap();
+        let (AlgebraicValue::U64(have), AlgebraicValue::U64(spent)) =
+            (&row.elements[0], &args.elements[0])
+        else {
+            unreachable!()
+        };
+        let left = have.checked_sub(*spent).ok_or("not enough gold")?;
+        ctx.update("gold", row_id, amount(left))
+            .map(|_| ())
+            .map_err(failed)
+    });
+    economy.insert_rows("gold", 0, [amount(10)]).unwrap();
+    let item = columns([("name", AlgebraicType::String)]);
+    let mut inventory = module("inventory", [("item", item.clone())]);
+    add_reducer(&mut inventory, "give", item, inserting("item"));
+    let economy_id = economy.id();
+    let inventory_id = inventory.id();
+    let mut core = super::SpacetimeCore::new();
+    core.publish_module(economy, 0).unwrap();
+    core.publish_module(inventory, 0).unwrap();
+    (core, economy_id, inventory_id)
+}
+
+/// Calls giving the item `name` for `price` gold.
+#[cfg(test)]
+fn buy(economy_id: u64, inventory_id: u64, name: &str, price: u64) -> Vec<AtomicCall> {
+    alloc::vec![
+        AtomicCall::new(inventory_id, "give", super::test_util::named(name)),
+        AtomicCall::new(economy_id, "spend", amount(price)),
+    ]
+}
+
+#[cfg(test)]
+fn module_rows(core: &super::SpacetimeCore, module_id: u64, table: &str) -> Vec<ProductValue> {
+    super::test_util::rows(core.module(&module_id).unwrap(), table)
+}
+
+#[test_case]
+fn test_atomic_transactions() {
+    use super::test_util::named;
+
+    let (mut core, economy_id, inventory_id) = market();
+    core.call_atomic(buy(economy_id, inventory_id, "sword", 4), 7)
+        .unwrap();
+    assert_eq!(module_rows(&core, inventory_id, "item"), [named("sword")]);
+    assert_eq!(module_rows(&core, economy_id, "gold"), [amount(6)]);
+}
+
+/// Both sides of a purchase commit, or neither does.
+#[test_case]
+fn test_atomic_abort() {
+    let (mut core, economy_id, inventory_id) = market();
+    assert_eq!(
+        core.call_atomic(buy(economy_id, inventory_id, "shield", 20), 7),
+        Err(AtomicError::Failed {
+            index: 1,
+            error: ReducerCallError::Failed(String::from("not enough gold")),
+        })
+    );
+    assert!(module_rows(&core, inventory_id, "item").is_empty());
+    assert_eq!(module_rows(&core, economy_id, "gold"), [amount(10)]);
+    let inventory = core.module(&inventory_id).unwrap();
+    let aborted = inventory.audit_log().iter().last().unwrap();
+    assert_eq!(aborted.outcome, Err(ReducerCallError::Aborted));
+    let aborted = inventory.commit_log().commits().last().unwrap();
+    assert!(!aborted.committed && aborted.aborted);
+}
+
+#[test_case]
+fn test_atomic_duplicate_module() {
+    let (mut core, economy_id, inventory_id) = market();
+    let mut twice = buy(economy_id, inventory_id, "bow", 1);
+    twice.push(AtomicCall::new(economy_id, "spend", amount(1)));
+    assert_eq!(
+        core.call_atomic(twice, 7),
+        Err(AtomicError::DuplicateModule(economy_id))
+    );
+    assert_eq!(module_rows(&core, economy_id, "gold"), [amount(10)]);
+}
+
+/// Core of a log, whose reducer `record(text)` adds an entry, a shop granted `record`, whose
+/// reducer `sell(item)` records the item, and a bank whose reducer `refuse()` always fails, and
+/// their ids.
+#[cfg(test)]
+fn log_shop_and_bank() -> (super::SpacetimeCore, u64, u64, u64) {
+    use super::test_util::{add_reducer, columns, failed, inserting, module};
+    use super::value::AlgebraicType;
+
+    let entry = columns([("text", AlgebraicType::String)]);
+    let mut log = module("log", [("entry", entry.clone())]);
+    add_reducer(&mut log, "record", entry.clone(), inserting("entry"));
+    let log_id = log.id();
+    let mut shop = module("shop", []);
+    add_reducer(&mut shop, "sell", entry, move |ctx, args| {
+        ctx.call_module(log_id, "record", args, None)
+            .map(|_| ())
+            .map_err(failed)
+    });
+    let shop_id = shop.id();
+    let mut bank = module("bank", []);
+    add_reducer(&mut bank, "refuse", columns([]), |_, _| {
+        Err(String::from("refused"))
+    });
+    let bank_id = bank.id();
+    let mut core = super::SpacetimeCore::new();
+    for module in [log, shop, bank] {
+        core.publish_module(module, 0).unwrap();
+    }
+    core.grant(&log_id, shop_id, "record").unwrap();
+    (core, log_id, shop_id, bank_id)
+}
+
+/// The call made by a prepared reducer is dropped with it.
+#[test_case]
+fn test_atomic_module_calls_dropped() {
+    use super::test_util::{named, row};
+
+    let (mut core, log_id, shop_id, bank_id) = log_shop_and_bank();
+    let sell = AtomicCall::new(shop_id, "sell", named("sword"));
+    let refuse = AtomicCall::new(bank_id, "refuse", row([]));
+    assert!(matches!(
+        core.call_atomic(alloc::vec![sell, refuse], 7),
+        Err(AtomicError::Failed { index: 1, .. })
+    ));
+    assert!(core.module(&shop_id).unwrap().pending_calls().is_empty());
+    assert!(module_rows(&core, log_id, "entry").is_empty());
+}
+
+/// The call made by a prepared reducer is made once all of them commit.
+#[test_case]
+fn test_atomic_module_calls() {
+    use super::test_util::named;
+
+    let (mut core, log_id, shop_id, _) = log_shop_and_bank();
+    let sell = AtomicCall::new(shop_id, "sell", named("shield"));
+    core.call_atomic(alloc::vec![sell], 7).unwrap();
+    assert_eq!(module_rows(&core, log_id, "entry"), [named("shield")]);
+}
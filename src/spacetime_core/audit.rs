@@ -0,0 +1,154 @@
+//! Record of a module's latest reducer calls, queryable as the `st_audit` system table.
+
+use alloc::{collections::VecDeque, string::String};
+
+use super::ReducerCallError;
+use super::binary;
+use super::value::ProductValue;
+
+/// Calls kept by a module's audit log, unless it sets another capacity.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Position of the call among all calls to the module, counting the ones dropped.
+    pub call_id: u64,
+    pub reducer: String,
+    pub caller: u64,
+    /// Tick at which the call started.
+    pub timestamp: u64,
+    /// Hash of the arguments in the binary format, see [`hash_args`].
+    pub args_hash: u64,
+    pub outcome: Result<(), ReducerCallError>,
+    /// Timer ticks the call ran for.
+    pub duration: u64,
+    /// Rows inserted, deleted or updated, including writes that were rolled back.
+    pub rows_touched: u64,
+}
+
+/// The latest calls, dropping the oldest once `capacity` are kept.
+#[derive(Debug)]
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+    next_call_id: u64,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> AuditLog {
+        AuditLog {
+            entries: VecDeque::new(),
+            capacity,
+            next_call_id: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate();
+    }
+
+    /// Records a call, filling in its id.
+    pub fn record(&mut self, mut entry: AuditEntry) {
+        entry.call_id = self.next_call_id;
+        self.next_call_id += 1;
+        self.entries.push_back(entry);
+        self.truncate();
+    }
+
+    /// Entries from the oldest to the latest call.
+    pub fn iter(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter()
+    }
+
+    fn truncate(&mut self) {
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// FNV-1a hash of `args` in the binary format.
+pub fn hash_args(args: &ProductValue) -> u64 {
+    binary::to_bytes(args)
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Short description of an outcome, as shown in `st_audit`.
+pub fn describe(outcome: &Result<(), ReducerCallError>) -> String {
+    match outcome {
+        Ok(()) => String::from("ok"),
+        Err(ReducerCallError::Failed(error)) => alloc::format!("failed: {}", error),
+        Err(error) => alloc::format!("{:?}", error),
+    }
+}
+
+// Tests
+
+/// Module whose audit log keeps 2 entries, after callers 1, 2 and 3 added 1, 2 and 0 to its
+/// counter, adding 0 failing.
+#[cfg(test)]
+fn audited_module() -> super::Module {
+    use super::test_util::{add_reducer, columns, failed, module};
+    use super::value::{AlgebraicType, AlgebraicValue};
+
+    let columns = columns([("n", AlgebraicType::U64)]);
+    let mut module = module("audited", [("counter", columns.clone())]);
+    add_reducer(&mut module, "add", columns, |ctx, args| {
+        ctx.insert("counter", args.clone()).map_err(failed)?;
+        match args.elements[0] {
+            AlgebraicValue::U64(0) => Err(String::from("zero")),
+            _ => Ok(()),
+        }
+    });
+    module.set_audit_capacity(2);
+    for (caller, n) in [(1, 1), (2, 2), (3, 0)] {
+        let _ = module.call_reducer("add", caller, test_counter(n));
+    }
+    module
+}
+
+#[cfg(test)]
+fn test_counter(n: u64) -> ProductValue {
+    super::test_util::row([super::value::AlgebraicValue::U64(n)])
+}
+
+#[test_case]
+fn test_audit_log() {
+    use alloc::vec::Vec;
+
+    let module = audited_module();
+    let entries: Vec<_> = module.audit_log().iter().collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].call_id, entries[0].caller), (1, 2));
+    assert_eq!(entries[0].args_hash, hash_args(&test_counter(2)));
+    assert_ne!(entries[0].args_hash, entries[1].args_hash);
+    assert_eq!(entries[1].rows_touched, 1);
+    assert_eq!(
+        entries[1].outcome,
+        Err(ReducerCallError::Failed(String::from("zero")))
+    );
+}
+
+#[test_case]
+fn test_audit_system_table() {
+    use super::query::Query;
+    use super::value::AlgebraicValue;
+
+    let module = audited_module();
+    let failed = module
+        .query(&Query::table("st_audit").filter_eq("caller", AlgebraicValue::U64(3)))
+        .unwrap();
+    assert_eq!(failed.rows.len(), 1);
+    assert_eq!(
+        failed.rows[0].elements[6],
+        AlgebraicValue::String(String::from("failed: zero"))
+    );
+}
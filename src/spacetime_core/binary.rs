@@ -0,0 +1,297 @@
+//! Compact binary encoding of values.
+//!
+//! Integers and floats are little-endian, strings, bytes and arrays are prefixed by their
+//! length as a `u32`, sums by their tag as a `u8`, and products are their elements in order.
+//! The encoding is not self-describing: decoding needs the [`AlgebraicType`].
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use super::value::{
+    AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue, SumType,
+    SumTypeVariant, SumValue,
+};
+
+/// Arrays, products and sums a decoded type can nest, bounding the decoder's recursion.
+pub const MAX_TYPE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    InvalidBool(u8),
+    InvalidTag(u8),
+    InvalidUtf8,
+    TrailingBytes(usize),
+    /// A type nests deeper than [`MAX_TYPE_DEPTH`].
+    TooDeep,
+}
+
+pub fn encode(value: &AlgebraicValue, buf: &mut Vec<u8>) {
+    match value {
+        AlgebraicValue::Bool(value) => buf.push(*value as u8),
+        AlgebraicValue::I8(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::U8(value) => buf.push(*value),
+        AlgebraicValue::I16(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::U16(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::I32(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::U32(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::I64(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::U64(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::F32(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::F64(value) => buf.extend_from_slice(&value.to_le_bytes()),
+        AlgebraicValue::String(value) => encode_bytes(value.as_bytes(), buf),
+        AlgebraicValue::Bytes(value) => encode_bytes(value, buf),
+        AlgebraicValue::Array(values) => {
+            encode_len(values.len(), buf);
+            for value in values {
+                encode(value, buf);
+            }
+        }
+        AlgebraicValue::Product(value) => encode_product(value, buf),
+        AlgebraicValue::Sum(value) => {
+            buf.push(value.tag);
+            encode(&value.value, buf);
+        }
+    }
+}
+
+pub fn encode_product(value: &ProductValue, buf: &mut Vec<u8>) {
+    for element in &value.elements {
+        encode(element, buf);
+    }
+}
+
+pub fn to_bytes(value: &ProductValue) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_product(value, &mut buf);
+    buf
+}
+
+pub fn encode_type(ty: &AlgebraicType, buf: &mut Vec<u8>) {
+    match ty {
+        AlgebraicType::Bool => buf.push(0),
+        AlgebraicType::I8 => buf.push(1),
+        AlgebraicType::U8 => buf.push(2),
+        AlgebraicType::I16 => buf.push(3),
+        AlgebraicType::U16 => buf.push(4),
+        AlgebraicType::I32 => buf.push(5),
+        AlgebraicType::U32 => buf.push(6),
+        AlgebraicType::I64 => buf.push(7),
+        AlgebraicType::U64 => buf.push(8),
+        AlgebraicType::F32 => buf.push(9),
+        AlgebraicType::F64 => buf.push(10),
+        AlgebraicType::String => buf.push(11),
+        AlgebraicType::Bytes => buf.push(12),
+        AlgebraicType::Array(element_type) => {
+            buf.push(13);
+            encode_type(element_type, buf);
+        }
+        AlgebraicType::Product(ty) => {
+            buf.push(14);
+            encode_product_type(ty, buf);
+        }
+        AlgebraicType::Sum(ty) => {
+            buf.push(15);
+            encode_len(ty.variants.len(), buf);
+            for variant in &ty.variants {
+                encode_str(&variant.name, buf);
+                encode_type(&variant.algebraic_type, buf);
+            }
+        }
+    }
+}
+
+pub fn encode_product_type(ty: &ProductType, buf: &mut Vec<u8>) {
+    encode_len(ty.elements.len(), buf);
+    for element in &ty.elements {
+        encode_str(&element.name, buf);
+        encode_type(&element.algebraic_type, buf);
+    }
+}
+
+pub(crate) fn encode_len(len: usize, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    encode_len(bytes.len(), buf);
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn encode_str(value: &str, buf: &mut Vec<u8>) {
+    encode_bytes(value.as_bytes(), buf);
+}
+
+/// Decodes a value of type `ty` from the front of `bytes`, advancing it.
+pub fn decode(ty: &AlgebraicType, bytes: &mut &[u8]) -> Result<AlgebraicValue, DecodeError> {
+    Ok(match ty {
+        AlgebraicType::Bool => match decode_u8(bytes)? {
+            0 => AlgebraicValue::Bool(false),
+            1 => AlgebraicValue::Bool(true),
+            other => return Err(DecodeError::InvalidBool(other)),
+        },
+        AlgebraicType::I8 => AlgebraicValue::I8(i8::from_le_bytes(take(bytes)?)),
+        AlgebraicType::U8 => AlgebraicValue::U8(u8::from_le_bytes(take(bytes)?)),
+        AlgebraicType::I16 => AlgebraicValue::I16(i16::from_le_bytes(take(bytes)?)),
+        AlgebraicType::U16 => AlgebraicValue::U16(u16::from_le_bytes(take(bytes)?)),
+        AlgebraicType::I32 => AlgebraicValue::I32(i32::from_le_bytes(take(bytes)?)),
+        AlgebraicType::U32 => AlgebraicValue::U32(u32::from_le_bytes(take(bytes)?)),
+        AlgebraicType::I64 => AlgebraicValue::I64(i64::from_le_bytes(take(bytes)?)),
+        AlgebraicType::U64 => AlgebraicValue::U64(u64::from_le_bytes(take(bytes)?)),
+        AlgebraicType::F32 => AlgebraicValue::F32(f32::from_le_bytes(take(bytes)?)),
+        AlgebraicType::F64 => AlgebraicValue::F64(f64::from_le_bytes(take(bytes)?)),
+        AlgebraicType::String => AlgebraicValue::String(decode_string(bytes)?),
+        AlgebraicType::Bytes => AlgebraicValue::Bytes(decode_bytes(bytes)?),
+        AlgebraicType::Array(element_type) => {
+            let len = decode_len(bytes)?;
+            let mut values = Vec::new();
+            for _ in 0..len {
+                values.push(decode(element_type, bytes)?);
+            }
+            AlgebraicValue::Array(values)
+        }
+        AlgebraicType::Product(ty) => AlgebraicValue::Product(decode_product(ty, bytes)?),
+        AlgebraicType::Sum(ty) => {
+            let tag = decode_u8(bytes)?;
+            let variant = ty
+                .variants
+                .get(tag as usize)
+                .ok_or(DecodeError::InvalidTag(tag))?;
+            let value = decode(&variant.algebraic_type, bytes)?;
+            AlgebraicValue::Sum(SumValue::new(tag, value))
+        }
+    })
+}
+
+pub fn decode_product(ty: &ProductType, bytes: &mut &[u8]) -> Result<ProductValue, DecodeError> {
+    let mut elements = Vec::with_capacity(ty.elements.len());
+    for element in &ty.elements {
+        elements.push(decode(&element.algebraic_type, bytes)?);
+    }
+    Ok(ProductValue::new(elements))
+}
+
+/// Decodes a whole buffer as a single product, e.g. a row or reducer arguments.
+pub fn from_bytes(ty: &ProductType, mut bytes: &[u8]) -> Result<ProductValue, DecodeError> {
+    let value = decode_product(ty, &mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes(bytes.len()));
+    }
+    Ok(value)
+}
+
+/// Decodes rows of type `ty` prefixed by their count as a `u32`, as encoded by
+/// [`QueryResult::to_bytes`](super::query::QueryResult::to_bytes).
+pub fn rows_from_bytes(
+    ty: &ProductType,
+    mut bytes: &[u8],
+) -> Result<Vec<ProductValue>, DecodeError> {
+    let bytes = &mut bytes;
+    let len = decode_len(bytes)?;
+    let mut rows = Vec::new();
+    for _ in 0..len {
+        rows.push(decode_product(ty, bytes)?);
+    }
+    if !bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes(bytes.len()));
+    }
+    Ok(rows)
+}
+
+pub fn decode_type(bytes: &mut &[u8]) -> Result<AlgebraicType, DecodeError> {
+    decode_type_in(bytes, 0)
+}
+
+pub fn decode_product_type(bytes: &mut &[u8]) -> Result<ProductType, DecodeError> {
+    decode_product_type_in(bytes, 0)
+}
+
+/// Decodes a type nested in `depth` others.
+fn decode_type_in(bytes: &mut &[u8], depth: usize) -> Result<AlgebraicType, DecodeError> {
+    if depth == MAX_TYPE_DEPTH {
+        return Err(DecodeError::TooDeep);
+    }
+    Ok(match decode_u8(bytes)? {
+        0 => AlgebraicType::Bool,
+        1 => AlgebraicType::I8,
+        2 => AlgebraicType::U8,
+        3 => AlgebraicType::I16,
+        4 => AlgebraicType::U16,
+        5 => AlgebraicType::I32,
+        6 => AlgebraicType::U32,
+        7 => AlgebraicType::I64,
+        8 => AlgebraicType::U64,
+        9 => AlgebraicType::F32,
+        10 => AlgebraicType::F64,
+        11 => AlgebraicType::String,
+        12 => AlgebraicType::Bytes,
+        13 => AlgebraicType::Array(Box::new(decode_type_in(bytes, depth + 1)?)),
+        14 => AlgebraicType::Product(decode_product_type_in(bytes, depth + 1)?),
+        15 => {
+            let len = decode_len(bytes)?;
+            let mut variants = Vec::new();
+            for _ in 0..len {
+                let name = decode_string(bytes)?;
+                let algebraic_type = decode_type_in(bytes, depth + 1)?;
+                variants.push(SumTypeVariant {
+                    name,
+                    algebraic_type,
+                });
+            }
+            AlgebraicType::Sum(SumType { variants })
+        }
+        tag => return Err(DecodeError::InvalidTag(tag)),
+    })
+}
+
+fn decode_product_type_in(bytes: &mut &[u8], depth: usize) -> Result<ProductType, DecodeError> {
+    let len = decode_len(bytes)?;
+    let mut elements = Vec::new();
+    for _ in 0..len {
+        let name = decode_string(bytes)?;
+        let algebraic_type = decode_type_in(bytes, depth)?;
+        elements.push(ProductTypeElement {
+            name,
+            algebraic_type,
+        });
+    }
+    Ok(ProductType::new(elements))
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    if bytes.len() < N {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (head, tail) = bytes.split_at(N);
+    *bytes = tail;
+    Ok(head.try_into().unwrap())
+}
+
+pub(crate) fn decode_u8(bytes: &mut &[u8]) -> Result<u8, DecodeError> {
+    Ok(take::<1>(bytes)?[0])
+}
+
+pub(crate) fn decode_u16(bytes: &mut &[u8]) -> Result<u16, DecodeError> {
+    Ok(u16::from_le_bytes(take(bytes)?))
+}
+
+pub(crate) fn decode_u64(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+    Ok(u64::from_le_bytes(take(bytes)?))
+}
+
+pub(crate) fn decode_len(bytes: &mut &[u8]) -> Result<usize, DecodeError> {
+    Ok(u32::from_le_bytes(take(bytes)?) as usize)
+}
+
+pub(crate) fn decode_string(bytes: &mut &[u8]) -> Result<String, DecodeError> {
+    String::from_utf8(decode_bytes(bytes)?).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let len = decode_len(bytes)?;
+    if bytes.len() < len {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head.to_vec())
+}
@@ -0,0 +1,363 @@
+//! Pages of a module's tables kept in memory, the least recently used ones written to a block
+//! device to make room once there are more than the pool's capacity.
+//!
+//! Without a device every page stays in memory, whatever the capacity. Only pages are written
+//! out: values kept apart as blobs, those over [`MAX_INLINE`](super::page::MAX_INLINE) bytes,
+//! and all of a table's indexes stay in memory.
+//!
+//! Pages written since the pool was last [unpinned](BufferPool::unpin) stay in memory too, so
+//! that undoing or committing the writes of a reducer never has to read a page back.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use super::page::{PAGE_SIZE, Page};
+use crate::block::{BlockDevice, BlockError};
+
+/// Pages a module keeps in memory, unless it sets another capacity.
+pub const DEFAULT_CAPACITY: usize = 16;
+
+/// Id of a page in its pool.
+pub type PageId = u64;
+
+/// An evicted page couldn't be read back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    /// The device failed to read the page.
+    Device(BlockError),
+    /// The device gave back bytes that aren't a page.
+    BadPage,
+}
+
+struct Frame {
+    page: Page,
+    /// Whether the page changed since it was read from the device.
+    dirty: bool,
+    /// Whether the page was written since the pool was last unpinned.
+    pinned: bool,
+    last_used: u64,
+}
+
+pub struct BufferPool {
+    device: Option<Box<dyn BlockDevice>>,
+    capacity: usize,
+    frames: BTreeMap<PageId, Frame>,
+    /// First block of the pages on the device, whether or not they're also in memory.
+    blocks: BTreeMap<PageId, u64>,
+    /// First blocks of the runs of blocks freed by dropped pages.
+    free_blocks: Vec<u64>,
+    next_block: u64,
+    next_page: PageId,
+    clock: u64,
+    faults: u64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> BufferPool {
+        BufferPool {
+            device: None,
+            capacity,
+            frames: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            free_blocks: Vec::new(),
+            next_block: 0,
+            next_page: 0,
+            clock: 0,
+            faults: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets how many pages are kept in memory, at least one, evicting pages past it.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict_to(self.capacity);
+    }
+
+    /// Starts writing evicted pages to `device`, whose blocks must divide a page.
+    ///
+    /// Gives `device` back if the pool already has one, as its pages would be lost, or if its
+    /// blocks are larger than a page or don't divide it.
+    pub fn attach(&mut self, device: Box<dyn BlockDevice>) -> Result<(), Box<dyn BlockDevice>> {
+        let block_size = device.block_size();
+        if self.device.is_some()
+            || !(1..=PAGE_SIZE).contains(&block_size)
+            || !PAGE_SIZE.is_multiple_of(block_size)
+        {
+            return Err(device);
+        }
+        self.device = Some(device);
+        self.evict_to(self.capacity);
+        Ok(())
+    }
+
+    /// Pages in memory.
+    pub fn resident(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Pages read back from the device so far.
+    pub fn faults(&self) -> u64 {
+        self.faults
+    }
+
+    /// Adds an empty page.
+    pub fn allocate(&mut self) -> PageId {
+        let id = self.next_page;
+        self.next_page += 1;
+        self.evict_to(self.capacity.saturating_sub(1));
+        let page = Page::new();
+        self.insert(id, page, true);
+        id
+    }
+
+    /// Drops a page, freeing its memory and blocks.
+    pub fn release(&mut self, id: PageId) {
+        self.frames.remove(&id);
+        if let Some(block) = self.blocks.remove(&id) {
+            self.free_blocks.push(block);
+        }
+    }
+
+    /// Runs `f` on a page, reading it back from the device if it was evicted.
+    ///
+    /// Fails if the page can't be read back, or `check` finds the bytes read aren't a page of
+    /// its table, leaving it on the device to be tried again.
+    pub fn read<T>(
+        &mut self,
+        id: PageId,
+        check: impl FnOnce(&Page) -> bool,
+        f: impl FnOnce(&Page) -> T,
+    ) -> Result<T, StorageError> {
+        Ok(f(&self.frame(id, check)?.page))
+    }
+
+    /// Runs `f` on a page, as [`BufferPool::read`], and marks it to be written out again.
+    pub fn write<T>(
+        &mut self,
+        id: PageId,
+        check: impl FnOnce(&Page) -> bool,
+        f: impl FnOnce(&mut Page) -> T,
+    ) -> Result<T, StorageError> {
+        let frame = self.frame(id, check)?;
+        frame.dirty = true;
+        frame.pinned = true;
+        Ok(f(&mut frame.page))
+    }
+
+    /// Lets the pages written so far be evicted again, evicting pages past the capacity.
+    pub fn unpin(&mut self) {
+        for frame in self.frames.values_mut() {
+            frame.pinned = false;
+        }
+        self.evict_to(self.capacity);
+    }
+
+    fn frame(
+        &mut self,
+        id: PageId,
+        check: impl FnOnce(&Page) -> bool,
+    ) -> Result<&mut Frame, StorageError> {
+        self.clock += 1;
+        if !self.frames.contains_key(&id) {
+            self.faults += 1;
+            let block = self.blocks[&id];
+            let mut bytes = alloc::vec![0; PAGE_SIZE];
+            self.device
+                .as_mut()
+                .expect("page evicted without a device")
+                .read(block, &mut bytes)
+                .map_err(StorageError::Device)?;
+            let page = Page::from_bytes(&bytes)
+                .filter(|page| check(page))
+                .ok_or(StorageError::BadPage)?;
+            self.evict_to(self.capacity.saturating_sub(1));
+            self.insert(id, page, false);
+        }
+        let frame = self.frames.get_mut(&id).expect("page is in memory");
+        frame.last_used = self.clock;
+        Ok(frame)
+    }
+
+    fn insert(&mut self, id: PageId, page: Page, dirty: bool) {
+        let frame = Frame {
+            page,
+            dirty,
+            pinned: false,
+            last_used: self.clock,
+        };
+        self.frames.insert(id, frame);
+    }
+
+    /// Evicts the least recently used pages until at most `len` are in memory.
+    ///
+    /// Pinned pages and pages that fail to be written stay in memory, over the capacity.
+    fn evict_to(&mut self, len: usize) {
+        if self.frames.len() <= len {
+            return;
+        }
+        let Some(device) = self.device.as_mut() else {
+            return;
+        };
+        let mut by_use: Vec<(u64, PageId)> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| !frame.pinned)
+            .map(|(id, frame)| (frame.last_used, *id))
+            .collect();
+        by_use.sort_unstable();
+        let excess = self.frames.len().saturating_sub(len);
+        for (_, id) in by_use.into_iter().take(excess) {
+            let frame = &self.frames[&id];
+            if frame.dirty {
+                let block = match self.blocks.get(&id) {
+                    Some(block) => *block,
+                    None => match self.free_blocks.pop() {
+                        Some(block) => block,
+                        None => {
+                            let block = self.next_block;
+                            self.next_block += (PAGE_SIZE / device.block_size()) as u64;
+                            block
+                        }
+                    },
+                };
+                let written = device.write(block, frame.page.as_bytes());
+                if written.is_err() {
+                    if !self.blocks.contains_key(&id) {
+                        self.free_blocks.push(block);
+                    }
+                    continue;
+                }
+                self.blocks.insert(id, block);
+            }
+            self.frames.remove(&id);
+        }
+    }
+}
+
+// Tests
+
+/// A disk whose reads fail while `failing` is set.
+#[cfg(test)]
+struct FlakyDisk {
+    disk: crate::block::RamDisk,
+    failing: alloc::rc::Rc<core::cell::Cell<bool>>,
+}
+
+#[cfg(test)]
+impl BlockDevice for FlakyDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        match self.failing.get() {
+            true => Err(BlockError::Io),
+            false => self.disk.read(block, buf),
+        }
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.disk.write(block, buf)
+    }
+}
+
+#[cfg(test)]
+fn paged_row(id: u64) -> super::ProductValue {
+    use super::value::AlgebraicValue;
+
+    super::test_util::row([
+        AlgebraicValue::U64(id),
+        AlgebraicValue::String(alloc::format!("row {}", id)),
+    ])
+}
+
+/// Module keeping 2 pages of its 100 rows in memory and the others on a [`FlakyDisk`], whose
+/// reducer `drop_first()` deletes the first row. Reads from the disk fail while the returned
+/// flag is set.
+#[cfg(test)]
+fn paged_module() -> (super::Module, alloc::rc::Rc<core::cell::Cell<bool>>) {
+    use super::table::RowId;
+    use super::test_util::{add_reducer, columns, failed, module};
+    use super::value::AlgebraicType;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let row = columns([("id", AlgebraicType::U64), ("name", AlgebraicType::String)]);
+    let mut module = module("paged", [("row", row)]);
+    add_reducer(&mut module, "drop_first", columns([]), |ctx, _| {
+        ctx.delete("row", RowId(0)).map(|_| ()).map_err(failed)
+    });
+    let failing = Rc::new(Cell::new(false));
+    let disk = FlakyDisk {
+        disk: crate::block::RamDisk::new(512, 16),
+        failing: failing.clone(),
+    };
+    assert!(module.attach_block_device(Box::new(disk)).is_ok());
+    module.set_buffer_pool_capacity(2);
+    module.set_memory_quota(64 * 1024);
+    let rejected = module
+        .insert_rows("row", 0, (0..100).map(paged_row))
+        .unwrap();
+    assert!(rejected.is_empty());
+    (module, failing)
+}
+
+#[test_case]
+fn test_buffer_pool() {
+    let (module, _) = paged_module();
+    let table = module.table("row").unwrap();
+    assert!(table.store().page_count() > 2);
+    assert!(module.buffer_pool().resident() <= 2);
+    let rows: Vec<_> = table.iter().map(|row| row.unwrap().1).collect();
+    assert_eq!(rows, (0..100).map(paged_row).collect::<Vec<_>>());
+    assert!(module.buffer_pool().faults() > 0);
+    assert!(module.buffer_pool().resident() <= 2);
+}
+
+/// Pages that can't be read back fail the reads and writes needing them, not the kernel.
+#[test_case]
+fn test_buffer_pool_read_failure() {
+    use super::ReducerCallError;
+    use super::query::{Query, QueryError};
+    use super::table::TableError;
+    use super::test_util::{failed, row, rows};
+
+    let (mut module, failing) = paged_module();
+    failing.set(true);
+    let error = StorageError::Device(BlockError::Io);
+    assert_eq!(
+        module.query(&Query::table("row")),
+        Err(QueryError::Storage(error.clone()))
+    );
+    assert_eq!(
+        module.call_reducer("drop_first", 0, row([])),
+        Err(ReducerCallError::Failed(failed(TableError::Storage(error))))
+    );
+    failing.set(false);
+    assert_eq!(rows(&module, "row").len(), 100);
+}
+
+#[test_case]
+fn test_buffer_pool_attach() {
+    use crate::block::RamDisk;
+
+    let (mut module, _) = paged_module();
+    assert!(
+        module
+            .attach_block_device(Box::new(RamDisk::new(512, 1)))
+            .is_err()
+    );
+    let mut pool = BufferPool::new(1);
+    assert!(
+        pool.attach(Box::new(RamDisk::new(PAGE_SIZE * 2, 1)))
+            .is_err()
+    );
+    assert!(pool.attach(Box::new(RamDisk::new(0, 1))).is_err());
+}
@@ -0,0 +1,181 @@
+//! Calls from a reducer of one module to a reducer of another module of the same core.
+//!
+//! A reducer queues calls with [`ReducerContext::call_module`], dropped if it fails. Once it
+//! committed, the core runs each call in its own transaction in the called module, which must
+//! have granted the reducer to the calling module with [`Module::grant`]. The outcome is passed
+//! back to a reply reducer of the calling module, if the call names one, in a transaction of
+//! its own. Calls queued by called and reply reducers are run in turn, up to
+//! [`MAX_CALL_DEPTH`] calls deep.
+//!
+//! [`ReducerContext::call_module`]: super::ReducerContext::call_module
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use super::audit;
+use super::value::{
+    AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue, SumValue,
+};
+use super::{Module, ReducerCallError};
+
+/// Calls a module call can lead to, through the calls made by the reducers it runs.
+pub const MAX_CALL_DEPTH: u32 = 8;
+
+/// Call queued by [`ReducerContext::call_module`](super::ReducerContext::call_module).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleCall {
+    /// Id of the call among those of the calling module, passed to the reply reducer.
+    pub id: u64,
+    /// Id of the calling module.
+    pub from: u64,
+    /// Id of the called module.
+    pub module: u64,
+    pub reducer: String,
+    pub args: ProductValue,
+    /// Id of the user who called the reducer making the call, the caller of the called
+    /// reducer too.
+    pub caller: u64,
+    /// Reducer of the calling module passed the outcome.
+    pub reply: Option<String>,
+    /// Module calls that led to this one.
+    pub depth: u32,
+}
+
+/// Parameters of reply reducers: the call's id, and a description of its error, `none` if it
+/// succeeded.
+pub fn reply_params() -> ProductType {
+    ProductType::new(alloc::vec![
+        ProductTypeElement::new("call_id", AlgebraicType::U64),
+        ProductTypeElement::new("error", AlgebraicType::option(AlgebraicType::String)),
+    ])
+}
+
+/// Runs the calls queued by the reducers of `modules` and their replies, until none are left.
+pub(super) fn deliver(modules: &mut BTreeMap<u64, Module>) {
+    loop {
+        let calls: Vec<ModuleCall> = modules.values_mut().flat_map(Module::take_calls).collect();
+        if calls.is_empty() {
+            return;
+        }
+        for call in calls {
+            let outcome = run(modules, &call);
+            let (Some(reply), Some(module)) = (&call.reply, modules.get_mut(&call.from)) else {
+                continue;
+            };
+            let error = match &outcome {
+                Ok(()) => SumValue::new(1, AlgebraicValue::unit()),
+                Err(_) => SumValue::new(0, AlgebraicValue::String(audit::describe(&outcome))),
+            };
+            let args = ProductValue::new(alloc::vec![
+                AlgebraicValue::U64(call.id),
+                AlgebraicValue::Sum(error),
+            ]);
+            // A failed reply is only recorded in the audit log, as scheduled calls are
+            let _ = module.call_from(call.module, call.depth + 1, reply, call.caller, args);
+        }
+    }
+}
+
+fn run(modules: &mut BTreeMap<u64, Module>, call: &ModuleCall) -> Result<(), ReducerCallError> {
+    let module = modules
+        .get_mut(&call.module)
+        .ok_or(ReducerCallError::NoSuchModule(call.module))?;
+    if !module.is_granted(call.from, &call.reducer) {
+        return Err(ReducerCallError::NotGranted(call.reducer.clone()));
+    }
+    if call.depth >= MAX_CALL_DEPTH {
+        return Err(ReducerCallError::CallTooDeep);
+    }
+    module.call_from(
+        call.from,
+        call.depth,
+        &call.reducer,
+        call.caller,
+        call.args.clone(),
+    )
+}
+
+// Tests
+
+#[cfg(test)]
+fn amount(amount: u64) -> ProductValue {
+    super::test_util::row([AlgebraicValue::U64(amount)])
+}
+
+/// Core of a bank, whose reducer `deposit(amount)` adds to its balances, and a shop, whose
+/// reducer `sell(amount)` deposits the amount at the bank and keeps the replies, and their ids.
+/// The bank hasn't granted `deposit` to the shop.
+#[cfg(test)]
+fn bank_and_shop() -> (super::SpacetimeCore, u64, u64) {
+    use super::test_util::{add_reducer, columns, failed, inserting, module};
+
+    let amount = || columns([("amount", AlgebraicType::U64)]);
+    let mut bank = module("bank", [("balance", amount())]);
+    add_reducer(&mut bank, "deposit", amount(), |ctx, args| {
+        if args.elements[0] == AlgebraicValue::U64(0) {
+            return Err(String::from("nothing to deposit"));
+        }
+        ctx.insert("balance", args).map(|_| ()).map_err(failed)
+    });
+    let bank_id = bank.id();
+    let mut shop = module("shop", [("reply", reply_params())]);
+    add_reducer(&mut shop, "sell", amount(), move |ctx, args| {
+        ctx.call_module(bank_id, "deposit", args, Some("deposited"))
+            .map(|_| ())
+            .map_err(failed)
+    });
+    add_reducer(&mut shop, "deposited", reply_params(), inserting("reply"));
+    let shop_id = shop.id();
+    let mut core = super::SpacetimeCore::new();
+    core.publish_module(bank, 0).unwrap();
+    core.publish_module(shop, 0).unwrap();
+    (core, bank_id, shop_id)
+}
+
+/// Reply to the call `id`, failed with `error` if any.
+#[cfg(test)]
+fn reply(id: u64, error: Option<&str>) -> ProductValue {
+    let error = match error {
+        Some(error) => SumValue::new(0, AlgebraicValue::String(String::from(error))),
+        None => SumValue::new(1, AlgebraicValue::unit()),
+    };
+    super::test_util::row([AlgebraicValue::U64(id), AlgebraicValue::Sum(error)])
+}
+
+#[cfg(test)]
+fn replies(core: &super::SpacetimeCore, shop_id: u64) -> Vec<ProductValue> {
+    let replies = core.query(&shop_id, &super::query::Query::table("reply"));
+    replies.unwrap().rows
+}
+
+/// Calls need a grant.
+#[test_case]
+fn test_module_call_not_granted() {
+    let (mut core, bank_id, shop_id) = bank_and_shop();
+    core.call_reducer(&shop_id, "sell", 7, amount(5)).unwrap();
+    let not_granted = ReducerCallError::NotGranted(String::from("deposit"));
+    let not_granted = audit::describe(&Err(not_granted));
+    assert_eq!(replies(&core, shop_id), [reply(0, Some(&not_granted))]);
+    assert_eq!(
+        core.grant(&bank_id, shop_id, "withdraw"),
+        Err(ReducerCallError::NoSuchReducer(String::from("withdraw")))
+    );
+}
+
+/// Each side of a call commits on its own.
+#[test_case]
+fn test_module_calls() {
+    use super::query::Query;
+
+    let (mut core, bank_id, shop_id) = bank_and_shop();
+    core.grant(&bank_id, shop_id, "deposit").unwrap();
+    core.call_reducer(&shop_id, "sell", 7, amount(5)).unwrap();
+    core.call_reducer(&shop_id, "sell", 7, amount(0)).unwrap();
+    let balance = core.query(&bank_id, &Query::table("balance")).unwrap();
+    assert_eq!(balance.rows, [amount(5)]);
+    assert_eq!(
+        replies(&core, shop_id),
+        [reply(0, None), reply(1, Some("failed: nothing to deposit"))]
+    );
+    let audit = core.module(&bank_id).unwrap().audit_log();
+    assert_eq!(audit.iter().last().unwrap().caller, 7);
+}
@@ -0,0 +1,380 @@
+//! CSV encoding of rows, to move table data to and from spreadsheets and other databases.
+//!
+//! The first record is a header naming the columns. Scalars are written as text, bytes as
+//! lowercase hex, and arrays, products and sums as JSON. Options are written as their value,
+//! or an empty field for `none`.
+//!
+//! Decoding coerces each field to its column's type: numbers may be surrounded by spaces, bools
+//! may be `true`, `false`, `1` or `0` in any case, and bytes may start with `0x`.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::json::{self, JsonError};
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductValue, SumValue};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvError {
+    /// A quoted field starting at the given line is never closed.
+    UnterminatedQuote(usize),
+    UnknownColumn(String),
+    MissingColumn(String),
+    /// A record doesn't have as many fields as the header.
+    FieldCount {
+        expected: usize,
+        found: usize,
+    },
+    /// A field can't be converted to its column's type.
+    InvalidField {
+        column: String,
+        message: String,
+    },
+}
+
+/// A decoded record, with the line it starts at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvRecord {
+    pub line: usize,
+    pub row: Result<ProductValue, CsvError>,
+}
+
+/// Encodes rows sharing the schema `ty` as CSV, with a header, failing on a row that doesn't
+/// match it.
+pub fn rows_to_string<'a>(
+    ty: &ProductType,
+    rows: impl IntoIterator<Item = &'a ProductValue>,
+) -> Result<String, JsonError> {
+    let mut out = String::new();
+    for (i, element) in ty.elements.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_field(&element.name, &mut out);
+    }
+    out.push_str("\r\n");
+    for row in rows {
+        if row.elements.len() != ty.elements.len() {
+            return Err(JsonError::InvalidValue(format!(
+                "{:?} does not match type {:?}",
+                row, ty
+            )));
+        }
+        for (i, (element, value)) in ty.elements.iter().zip(&row.elements).enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            encode_field(&to_field(&element.algebraic_type, value)?, &mut out);
+        }
+        out.push_str("\r\n");
+    }
+    Ok(out)
+}
+
+fn to_field(ty: &AlgebraicType, value: &AlgebraicValue) -> Result<String, JsonError> {
+    Ok(match (ty, value) {
+        (AlgebraicType::Sum(sum), AlgebraicValue::Sum(value)) if sum.is_option() => {
+            match value.tag {
+                0 => to_field(&sum.variants[0].algebraic_type, &value.value)?,
+                _ => String::new(),
+            }
+        }
+        (_, AlgebraicValue::Bool(value)) => value.to_string(),
+        (_, AlgebraicValue::I8(value)) => value.to_string(),
+        (_, AlgebraicValue::U8(value)) => value.to_string(),
+        (_, AlgebraicValue::I16(value)) => value.to_string(),
+        (_, AlgebraicValue::U16(value)) => value.to_string(),
+        (_, AlgebraicValue::I32(value)) => value.to_string(),
+        (_, AlgebraicValue::U32(value)) => value.to_string(),
+        (_, AlgebraicValue::I64(value)) => value.to_string(),
+        (_, AlgebraicValue::U64(value)) => value.to_string(),
+        (_, AlgebraicValue::F32(value)) => value.to_string(),
+        (_, AlgebraicValue::F64(value)) => value.to_string(),
+        (_, AlgebraicValue::String(value)) => value.clone(),
+        (_, AlgebraicValue::Bytes(value)) => {
+            value.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+        (ty, value) => {
+            let mut out = String::new();
+            json::encode(ty, value, &mut out)?;
+            out
+        }
+    })
+}
+
+fn encode_field(field: &str, out: &mut String) {
+    if !field.contains([',', '"', '\r', '\n']) {
+        out.push_str(field);
+        return;
+    }
+    out.push('"');
+    for c in field.chars() {
+        if c == '"' {
+            out.push('"');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+/// Parses CSV with a header into rows of type `ty`.
+///
+/// Fails as a whole if the header doesn't name the columns of `ty` or the text isn't valid
+/// CSV, and record by record if a record doesn't fit `ty`.
+pub fn from_str(ty: &ProductType, text: &str) -> Result<Vec<CsvRecord>, CsvError> {
+    let mut records = parse(text)?.into_iter();
+    let Some((_, header)) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let mut positions = Vec::new();
+    for name in &header {
+        let position = ty
+            .index_of(name.trim())
+            .ok_or_else(|| CsvError::UnknownColumn(name.clone()))?;
+        positions.push(position);
+    }
+    if let Some(element) = ty
+        .elements
+        .iter()
+        .enumerate()
+        .find(|(i, _)| !positions.contains(i))
+        .map(|(_, element)| element)
+    {
+        return Err(CsvError::MissingColumn(element.name.clone()));
+    }
+    Ok(records
+        .map(|(line, fields)| CsvRecord {
+            line,
+            row: decode_record(ty, &positions, &fields),
+        })
+        .collect())
+}
+
+/// Decodes the fields of a record, `positions` giving the column of each field.
+fn decode_record(
+    ty: &ProductType,
+    positions: &[usize],
+    fields: &[String],
+) -> Result<ProductValue, CsvError> {
+    if fields.len() != positions.len() {
+        return Err(CsvError::FieldCount {
+            expected: positions.len(),
+            found: fields.len(),
+        });
+    }
+    let mut elements = alloc::vec![AlgebraicValue::unit(); ty.elements.len()];
+    for (field, position) in fields.iter().zip(positions) {
+        let element = &ty.elements[*position];
+        elements[*position] = from_field(&element.algebraic_type, field).map_err(|message| {
+            CsvError::InvalidField {
+                column: element.name.clone(),
+                message,
+            }
+        })?;
+    }
+    Ok(ProductValue::new(elements))
+}
+
+fn from_field(ty: &AlgebraicType, field: &str) -> Result<AlgebraicValue, String> {
+    let number = field.trim();
+    let invalid = || format!("\"{}\" is not a valid {:?}", field, ty);
+    Ok(match ty {
+        AlgebraicType::Bool => match number.to_ascii_lowercase().as_str() {
+            "true" | "1" => AlgebraicValue::Bool(true),
+            "false" | "0" => AlgebraicValue::Bool(false),
+            _ => return Err(invalid()),
+        },
+        AlgebraicType::I8 => AlgebraicValue::I8(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::U8 => AlgebraicValue::U8(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::I16 => AlgebraicValue::I16(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::U16 => AlgebraicValue::U16(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::I32 => AlgebraicValue::I32(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::U32 => AlgebraicValue::U32(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::I64 => AlgebraicValue::I64(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::U64 => AlgebraicValue::U64(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::F32 => AlgebraicValue::F32(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::F64 => AlgebraicValue::F64(number.parse().map_err(|_| invalid())?),
+        AlgebraicType::String => AlgebraicValue::String(String::from(field)),
+        AlgebraicType::Bytes => {
+            let hex = number.strip_prefix("0x").unwrap_or(number);
+            let json = json::JsonValue::String(String::from(hex));
+            json::decode(ty, &json).map_err(|_| invalid())?
+        }
+        AlgebraicType::Sum(sum) if sum.is_option() => match field {
+            "" => AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit())),
+            field => {
+                let value = from_field(&sum.variants[0].algebraic_type, field)?;
+                AlgebraicValue::Sum(SumValue::new(0, value))
+            }
+        },
+        ty => {
+            let json = json::parse(field).map_err(|error| format!("{:?}", error))?;
+            json::decode(ty, &json).map_err(|error| format!("{:?}", error))?
+        }
+    })
+}
+
+/// Splits CSV into records of fields, each with the line it starts at.
+fn parse(text: &str) -> Result<Vec<(usize, Vec<String>)>, CsvError> {
+    let mut records = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('"') if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                Some('"') if quoted => quoted = false,
+                Some('"') if field.is_empty() => quoted = true,
+                Some(c) if quoted => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+                None if quoted => return Err(CsvError::UnterminatedQuote(start)),
+                Some(',') => fields.push(core::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') | None => {
+                    line += 1;
+                    fields.push(field);
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+        // Blank lines separate nothing
+        if fields.len() > 1 || !fields[0].is_empty() {
+            records.push((start, fields));
+        }
+    }
+    Ok(records)
+}
+
+// Tests
+
+/// Columns of an id, a name, an optional nickname and a list of tags.
+#[cfg(test)]
+fn test_type() -> ProductType {
+    use alloc::boxed::Box;
+
+    super::test_util::columns([
+        ("id", AlgebraicType::U32),
+        ("name", AlgebraicType::String),
+        ("nickname", AlgebraicType::option(AlgebraicType::String)),
+        ("tags", AlgebraicType::Array(Box::new(AlgebraicType::U8))),
+    ])
+}
+
+#[test_case]
+fn test_csv_round_trip() {
+    use super::test_util::row;
+
+    let ty = test_type();
+    let none = AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit()));
+    let rows = alloc::vec![
+        row([
+            AlgebraicValue::U32(1),
+            AlgebraicValue::String(String::from("Smith, \"Jo\"\nJr")),
+            none,
+            AlgebraicValue::Array(alloc::vec![AlgebraicValue::U8(1), AlgebraicValue::U8(2)]),
+        ]),
+        row([
+            AlgebraicValue::U32(2),
+            AlgebraicValue::String(String::new()),
+            AlgebraicValue::Sum(SumValue::new(0, AlgebraicValue::String(String::from("Al")))),
+            AlgebraicValue::Array(Vec::new()),
+        ]),
+    ];
+    let text = rows_to_string(&ty, &rows).unwrap();
+    let decoded: Vec<_> = from_str(&ty, &text)
+        .unwrap()
+        .into_iter()
+        .map(|record| record.row.unwrap())
+        .collect();
+    assert_eq!(decoded, rows);
+}
+
+#[test_case]
+fn test_csv_invalid_records() {
+    let ty = test_type();
+    let text = "tags,nickname,name,id\n[],,x, 7 \n[],,y,-1\n[],,z\n";
+    let decoded = from_str(&ty, text).unwrap();
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(
+        decoded[0].row.as_ref().unwrap().elements[0],
+        AlgebraicValue::U32(7)
+    );
+    assert_eq!(decoded[1].line, 3);
+    assert!(matches!(
+        &decoded[1].row,
+        Err(CsvError::InvalidField { column, .. }) if column == "id"
+    ));
+    assert_eq!(
+        decoded[2],
+        CsvRecord {
+            line: 4,
+            row: Err(CsvError::FieldCount {
+                expected: 4,
+                found: 3
+            }),
+        }
+    );
+}
+
+#[test_case]
+fn test_csv_missing_column() {
+    assert_eq!(
+        from_str(&test_type(), "id,name\n"),
+        Err(CsvError::MissingColumn(String::from("nickname")))
+    );
+}
+
+#[test_case]
+fn test_csv_import() {
+    use super::def::{ConstraintDef, TableDef};
+    use super::table::TableError;
+    use super::test_util::columns;
+    use super::{ImportError, Module, SpacetimeCore};
+
+    let columns = columns([("id", AlgebraicType::U32), ("name", AlgebraicType::String)]);
+    let mut module = Module::new(String::from("people"));
+    let person = TableDef::new("person", columns).with_constraint(ConstraintDef::PrimaryKey {
+        name: String::from("person_id"),
+        column: 0,
+    });
+    module.add_table(person).unwrap();
+    let module_id = module.id();
+    let mut core = SpacetimeCore::new();
+    core.publish_module(module, 0).unwrap();
+
+    let import = core
+        .import_csv(
+            &module_id,
+            "person",
+            0,
+            "name,id\nAda,1\nBob,x\nCy,1\nDee,2\n",
+        )
+        .unwrap();
+    assert_eq!(import.inserted, 2);
+    assert_eq!(import.rejected.len(), 2);
+    assert!(matches!(import.rejected[0], (3, ImportError::Csv(_))));
+    assert!(matches!(
+        import.rejected[1],
+        (4, ImportError::Table(TableError::UniqueViolation(_)))
+    ));
+    assert_eq!(
+        core.export_csv(&module_id, "person").unwrap(),
+        "id,name\r\n1,Ada\r\n2,Dee\r\n"
+    );
+    let log = core.module(&module_id).unwrap().commit_log();
+    assert_eq!(log.commits()[0].writes.len(), 2);
+}
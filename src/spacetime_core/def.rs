@@ -0,0 +1,736 @@
+//! Description of a module as data: its tables and reducers, without reducer code.
+//!
+//! A [`ModuleDef`] round-trips through the binary format so modules can be published from a
+//! byte blob, see [`super::Module::from_def`].
+
+use alloc::{string::String, vec::Vec};
+
+use super::binary::{self, DecodeError};
+use super::page;
+use super::system;
+use super::value::{AlgebraicType, AlgebraicValue, ProductType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModuleDefError {
+    DuplicateTable(String),
+    DuplicateReducer(String),
+    /// A table definition refers to a column position past the end of its columns.
+    NoSuchColumn {
+        table: String,
+        column: u16,
+    },
+    MultiplePrimaryKeys(String),
+    /// The named sequence is on a column that isn't an integer.
+    InvalidSequence(String),
+    DuplicateLifecycle(Lifecycle),
+    /// Lifecycle reducers are called by the core and can't take arguments.
+    LifecycleParams(String),
+    /// No implementation was provided for the named reducer.
+    UnboundReducer(String),
+    /// The table name starts with the prefix reserved for [system tables](super::system).
+    ReservedName(String),
+    /// The named foreign key refers to no table, to columns that aren't unique, or to columns
+    /// of other types.
+    InvalidForeignKey(String),
+    /// The named check compares values of other types than its columns', or checks a column
+    /// that can't be empty for emptiness.
+    InvalidCheck(String),
+    /// The table has too many columns for one of its rows to fit in a [page](super::page).
+    RowTooWide(String),
+    /// The named spatial index isn't on two or three numeric columns.
+    InvalidSpatialIndex(String),
+    /// The named text index isn't on one string column.
+    InvalidTextIndex(String),
+    DuplicateView(String),
+    /// The named view's query doesn't run over the module's tables, or its caller column
+    /// isn't a `u64` column of the view.
+    InvalidView(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleDef {
+    pub name: String,
+    pub tables: Vec<TableDef>,
+    pub reducers: Vec<ReducerDef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: ProductType,
+    pub indexes: Vec<IndexDef>,
+    pub constraints: Vec<ConstraintDef>,
+    pub sequences: Vec<SequenceDef>,
+}
+
+impl TableDef {
+    pub fn new(name: &str, columns: ProductType) -> TableDef {
+        TableDef {
+            name: String::from(name),
+            columns,
+            indexes: Vec::new(),
+            constraints: Vec::new(),
+            sequences: Vec::new(),
+        }
+    }
+
+    pub fn with_index(mut self, name: &str, columns: &[u16]) -> TableDef {
+        self.indexes.push(IndexDef {
+            name: String::from(name),
+            columns: columns.to_vec(),
+            kind: IndexKind::BTree,
+        });
+        self
+    }
+
+    /// Adds an index of the points whose coordinates are in `columns`, two or three numeric
+    /// columns.
+    pub fn with_spatial_index(mut self, name: &str, columns: &[u16]) -> TableDef {
+        self.indexes.push(IndexDef {
+            name: String::from(name),
+            columns: columns.to_vec(),
+            kind: IndexKind::Spatial,
+        });
+        self
+    }
+
+    /// Adds a full-text index of the words in `column`, a string column.
+    pub fn with_text_index(mut self, name: &str, column: u16) -> TableDef {
+        self.indexes.push(IndexDef {
+            name: String::from(name),
+            columns: alloc::vec![column],
+            kind: IndexKind::Text,
+        });
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: ConstraintDef) -> TableDef {
+        self.constraints.push(constraint);
+        self
+    }
+
+    pub fn with_sequence(mut self, name: &str, column: u16) -> TableDef {
+        self.sequences.push(SequenceDef {
+            name: String::from(name),
+            column,
+        });
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ModuleDefError> {
+        if system::is_system_table(&self.name) {
+            return Err(ModuleDefError::ReservedName(self.name.clone()));
+        }
+        if page::slot_size(&self.columns) > page::MAX_SLOT_SIZE {
+            return Err(ModuleDefError::RowTooWide(self.name.clone()));
+        }
+        let check_column = |column: &u16| {
+            if (*column as usize) < self.columns.elements.len() {
+                Ok(())
+            } else {
+                Err(ModuleDefError::NoSuchColumn {
+                    table: self.name.clone(),
+                    column: *column,
+                })
+            }
+        };
+        for index in &self.indexes {
+            index.columns.iter().try_for_each(check_column)?;
+            if index.kind == IndexKind::Spatial
+                && !(matches!(index.columns.len(), 2 | 3)
+                    && index.columns.iter().all(|column| {
+                        let ty = &self.columns.elements[*column as usize].algebraic_type;
+                        matches!(
+                            ty,
+                            AlgebraicType::I8
+                                | AlgebraicType::U8
+                                | AlgebraicType::I16
+                                | AlgebraicType::U16
+                                | AlgebraicType::I32
+                                | AlgebraicType::U32
+                                | AlgebraicType::I64
+                                | AlgebraicType::U64
+                                | AlgebraicType::F32
+                                | AlgebraicType::F64
+                        )
+                    }))
+            {
+                return Err(ModuleDefError::InvalidSpatialIndex(index.name.clone()));
+            }
+            if index.kind == IndexKind::Text
+                && !matches!(
+                    index.columns[..],
+                    [column] if self.columns.elements[column as usize].algebraic_type
+                        == AlgebraicType::String
+                )
+            {
+                return Err(ModuleDefError::InvalidTextIndex(index.name.clone()));
+            }
+        }
+        for constraint in &self.constraints {
+            constraint.columns().iter().try_for_each(check_column)?;
+            if let ConstraintDef::Check { name, check } = constraint
+                && !self.is_valid_check(check)
+            {
+                return Err(ModuleDefError::InvalidCheck(name.clone()));
+            }
+        }
+        let primary_keys = self
+            .constraints
+            .iter()
+            .filter(|constraint| matches!(constraint, ConstraintDef::PrimaryKey { .. }))
+            .count();
+        if primary_keys > 1 {
+            return Err(ModuleDefError::MultiplePrimaryKeys(self.name.clone()));
+        }
+        for sequence in &self.sequences {
+            check_column(&sequence.column)?;
+            let ty = &self.columns.elements[sequence.column as usize].algebraic_type;
+            if !matches!(
+                ty,
+                AlgebraicType::I8
+                    | AlgebraicType::U8
+                    | AlgebraicType::I16
+                    | AlgebraicType::U16
+                    | AlgebraicType::I32
+                    | AlgebraicType::U32
+                    | AlgebraicType::I64
+                    | AlgebraicType::U64
+            ) {
+                return Err(ModuleDefError::InvalidSequence(sequence.name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_valid_check(&self, check: &Check) -> bool {
+        let ty = |column: &u16| &self.columns.elements[*column as usize].algebraic_type;
+        match check {
+            Check::Range { column, min, max } => min
+                .iter()
+                .chain(max)
+                .all(|bound| bound.has_type(ty(column))),
+            Check::NonEmpty { column } => matches!(
+                ty(column),
+                AlgebraicType::String | AlgebraicType::Bytes | AlgebraicType::Array(_)
+            ),
+            Check::Compare { columns, .. } => ty(&columns[0]) == ty(&columns[1]),
+        }
+    }
+}
+
+/// Index over one or more columns, given by position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDef {
+    pub name: String,
+    pub columns: Vec<u16>,
+    pub kind: IndexKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Rows sorted by the values of the columns, sought by [`Table::seek`].
+    ///
+    /// [`Table::seek`]: super::table::Table::seek
+    BTree,
+    /// R-tree of the points the columns are the coordinates of, sought by
+    /// [`Table::within`] and [`Table::nearest`].
+    ///
+    /// [`Table::within`]: super::table::Table::within
+    /// [`Table::nearest`]: super::table::Table::nearest
+    Spatial,
+    /// Inverted index of the words of a string column, searched by [`Table::search`].
+    ///
+    /// [`Table::search`]: super::table::Table::search
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstraintDef {
+    /// No two rows share the same values in `columns`.
+    Unique { name: String, columns: Vec<u16> },
+    /// Unique column used to identify rows.
+    PrimaryKey { name: String, column: u16 },
+    /// Values in `columns` match the values in `references` of a row of `table`, whose
+    /// `references` are a unique constraint or primary key.
+    ///
+    /// Each column either has the type of the column it references or is an option of it, a
+    /// `none` making the row refer to nothing.
+    ForeignKey {
+        name: String,
+        columns: Vec<u16>,
+        table: String,
+        references: Vec<u16>,
+        on_delete: OnDelete,
+    },
+    /// Every row passes `check`.
+    Check { name: String, check: Check },
+}
+
+/// Condition on the columns of a row, see [`ConstraintDef::Check`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    /// The value of `column` is at least `min` and at most `max`, where given.
+    Range {
+        column: u16,
+        min: Option<AlgebraicValue>,
+        max: Option<AlgebraicValue>,
+    },
+    /// The string, bytes or array in `column` isn't empty.
+    NonEmpty { column: u16 },
+    /// `columns[0] op columns[1]`, for two columns of the same type.
+    Compare { columns: [u16; 2], op: CompareOp },
+}
+
+impl Check {
+    pub fn columns(&self) -> &[u16] {
+        match self {
+            Check::Range { column, .. } | Check::NonEmpty { column } => {
+                core::slice::from_ref(column)
+            }
+            Check::Compare { columns, .. } => columns,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// What deleting a row does to the rows referring to it by foreign key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDelete {
+    /// The delete fails.
+    Restrict,
+    /// They're deleted too.
+    Cascade,
+    /// Their referencing columns, which must all be options, are set to `none`.
+    SetNull,
+}
+
+impl ConstraintDef {
+    pub fn name(&self) -> &str {
+        match self {
+            ConstraintDef::Unique { name, .. }
+            | ConstraintDef::PrimaryKey { name, .. }
+            | ConstraintDef::ForeignKey { name, .. }
+            | ConstraintDef::Check { name, .. } => name,
+        }
+    }
+
+    pub fn columns(&self) -> &[u16] {
+        match self {
+            ConstraintDef::Unique { columns, .. } | ConstraintDef::ForeignKey { columns, .. } => {
+                columns
+            }
+            ConstraintDef::PrimaryKey { column, .. } => core::slice::from_ref(column),
+            ConstraintDef::Check { check, .. } => check.columns(),
+        }
+    }
+}
+
+/// Auto-increment: inserting `0` in `column` stores the next value of the sequence instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceDef {
+    pub name: String,
+    pub column: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Called once when the module is published.
+    Init,
+    ClientConnected,
+    ClientDisconnected,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReducerDef {
+    pub name: String,
+    pub params: ProductType,
+    pub lifecycle: Option<Lifecycle>,
+}
+
+impl ReducerDef {
+    pub fn new(name: &str, params: ProductType) -> ReducerDef {
+        ReducerDef {
+            name: String::from(name),
+            params,
+            lifecycle: None,
+        }
+    }
+
+    pub fn lifecycle(name: &str, lifecycle: Lifecycle) -> ReducerDef {
+        ReducerDef {
+            name: String::from(name),
+            params: ProductType::new(Vec::new()),
+            lifecycle: Some(lifecycle),
+        }
+    }
+}
+
+impl ModuleDef {
+    pub fn new(name: &str) -> ModuleDef {
+        ModuleDef {
+            name: String::from(name),
+            tables: Vec::new(),
+            reducers: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        binary::encode_str(&self.name, &mut buf);
+        binary::encode_len(self.tables.len(), &mut buf);
+        for table in &self.tables {
+            encode_table(table, &mut buf);
+        }
+        binary::encode_len(self.reducers.len(), &mut buf);
+        for reducer in &self.reducers {
+            binary::encode_str(&reducer.name, &mut buf);
+            binary::encode_product_type(&reducer.params, &mut buf);
+            buf.push(match reducer.lifecycle {
+                None => 0,
+                Some(Lifecycle::Init) => 1,
+                Some(Lifecycle::ClientConnected) => 2,
+                Some(Lifecycle::ClientDisconnected) => 3,
+            });
+        }
+        buf
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<ModuleDef, DecodeError> {
+        let bytes = &mut bytes;
+        let name = binary::decode_string(bytes)?;
+        let tables = decode_vec(bytes, decode_table)?;
+        let reducers = decode_vec(bytes, |bytes| {
+            Ok(ReducerDef {
+                name: binary::decode_string(bytes)?,
+                params: binary::decode_product_type(bytes)?,
+                lifecycle: match binary::decode_u8(bytes)? {
+                    0 => None,
+                    1 => Some(Lifecycle::Init),
+                    2 => Some(Lifecycle::ClientConnected),
+                    3 => Some(Lifecycle::ClientDisconnected),
+                    tag => return Err(DecodeError::InvalidTag(tag)),
+                },
+            })
+        })?;
+        if !bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes(bytes.len()));
+        }
+        Ok(ModuleDef {
+            name,
+            tables,
+            reducers,
+        })
+    }
+}
+
+fn encode_columns(columns: &[u16], buf: &mut Vec<u8>) {
+    binary::encode_len(columns.len(), buf);
+    for column in columns {
+        buf.extend_from_slice(&column.to_le_bytes());
+    }
+}
+
+fn encode_table(table: &TableDef, buf: &mut Vec<u8>) {
+    binary::encode_str(&table.name, buf);
+    binary::encode_product_type(&table.columns, buf);
+    binary::encode_len(table.indexes.len(), buf);
+    for index in &table.indexes {
+        binary::encode_str(&index.name, buf);
+        encode_columns(&index.columns, buf);
+        buf.push(match index.kind {
+            IndexKind::BTree => 0,
+            IndexKind::Spatial => 1,
+            IndexKind::Text => 2,
+        });
+    }
+    binary::encode_len(table.constraints.len(), buf);
+    for constraint in &table.constraints {
+        match constraint {
+            ConstraintDef::Unique { name, columns } => {
+                buf.push(0);
+                binary::encode_str(name, buf);
+                encode_columns(columns, buf);
+            }
+            ConstraintDef::PrimaryKey { name, column } => {
+                buf.push(1);
+                binary::encode_str(name, buf);
+                buf.extend_from_slice(&column.to_le_bytes());
+            }
+            ConstraintDef::ForeignKey {
+                name,
+                columns,
+                table,
+                references,
+                on_delete,
+            } => {
+                buf.push(2);
+                binary::encode_str(name, buf);
+                encode_columns(columns, buf);
+                binary::encode_str(table, buf);
+                encode_columns(references, buf);
+                buf.push(match on_delete {
+                    OnDelete::Restrict => 0,
+                    OnDelete::Cascade => 1,
+                    OnDelete::SetNull => 2,
+                });
+            }
+            ConstraintDef::Check { name, check } => {
+                buf.push(3);
+                binary::encode_str(name, buf);
+                encode_check(check, &table.columns, buf);
+            }
+        }
+    }
+    binary::encode_len(table.sequences.len(), buf);
+    for sequence in &table.sequences {
+        binary::encode_str(&sequence.name, buf);
+        buf.extend_from_slice(&sequence.column.to_le_bytes());
+    }
+}
+
+fn encode_check(check: &Check, columns: &ProductType, buf: &mut Vec<u8>) {
+    match check {
+        Check::Range { column, min, max } => {
+            buf.push(0);
+            buf.extend_from_slice(&column.to_le_bytes());
+            // Bounds carry their column's type so they decode on their own. A column past the
+            // end of the table drops them, the def being invalid anyway.
+            let ty = columns.elements.get(*column as usize);
+            for bound in [min, max] {
+                match (bound, ty) {
+                    (Some(bound), Some(ty)) => {
+                        buf.push(1);
+                        binary::encode_type(&ty.algebraic_type, buf);
+                        binary::encode(bound, buf);
+                    }
+                    _ => buf.push(0),
+                }
+            }
+        }
+        Check::NonEmpty { column } => {
+            buf.push(1);
+            buf.extend_from_slice(&column.to_le_bytes());
+        }
+        Check::Compare { columns, op } => {
+            buf.push(2);
+            buf.extend_from_slice(&columns[0].to_le_bytes());
+            buf.extend_from_slice(&columns[1].to_le_bytes());
+            buf.push(match op {
+                CompareOp::Eq => 0,
+                CompareOp::Ne => 1,
+                CompareOp::Lt => 2,
+                CompareOp::Le => 3,
+                CompareOp::Gt => 4,
+                CompareOp::Ge => 5,
+            });
+        }
+    }
+}
+
+fn decode_vec<T>(
+    bytes: &mut &[u8],
+    decode: impl Fn(&mut &[u8]) -> Result<T, DecodeError>,
+) -> Result<Vec<T>, DecodeError> {
+    let len = binary::decode_len(bytes)?;
+    let mut values = Vec::new();
+    for _ in 0..len {
+        values.push(decode(bytes)?);
+    }
+    Ok(values)
+}
+
+fn decode_table(bytes: &mut &[u8]) -> Result<TableDef, DecodeError> {
+    Ok(TableDef {
+        name: binary::decode_string(bytes)?,
+        columns: binary::decode_product_type(bytes)?,
+        indexes: decode_vec(bytes, |bytes| {
+            Ok(IndexDef {
+                name: binary::decode_string(bytes)?,
+                columns: decode_vec(bytes, binary::decode_u16)?,
+                kind: match binary::decode_u8(bytes)? {
+                    0 => IndexKind::BTree,
+                    1 => IndexKind::Spatial,
+                    2 => IndexKind::Text,
+                    tag => return Err(DecodeError::InvalidTag(tag)),
+                },
+            })
+        })?,
+        constraints: decode_vec(bytes, |bytes| {
+            Ok(match binary::decode_u8(bytes)? {
+                0 => ConstraintDef::Unique {
+                    name: binary::decode_string(bytes)?,
+                    columns: decode_vec(bytes, binary::decode_u16)?,
+                },
+                1 => ConstraintDef::PrimaryKey {
+                    name: binary::decode_string(bytes)?,
+                    column: binary::decode_u16(bytes)?,
+                },
+                2 => ConstraintDef::ForeignKey {
+                    name: binary::decode_string(bytes)?,
+                    columns: decode_vec(bytes, binary::decode_u16)?,
+                    table: binary::decode_string(bytes)?,
+                    references: decode_vec(bytes, binary::decode_u16)?,
+                    on_delete: match binary::decode_u8(bytes)? {
+                        0 => OnDelete::Restrict,
+                        1 => OnDelete::Cascade,
+                        2 => OnDelete::SetNull,
+                        tag => return Err(DecodeError::InvalidTag(tag)),
+                    },
+                },
+                3 => ConstraintDef::Check {
+                    name: binary::decode_string(bytes)?,
+                    check: decode_check(bytes)?,
+                },
+                tag => return Err(DecodeError::InvalidTag(tag)),
+            })
+        })?,
+        sequences: decode_vec(bytes, |bytes| {
+            Ok(SequenceDef {
+                name: binary::decode_string(bytes)?,
+                column: binary::decode_u16(bytes)?,
+            })
+        })?,
+    })
+}
+
+fn decode_check(bytes: &mut &[u8]) -> Result<Check, DecodeError> {
+    let decode_bound = |bytes: &mut &[u8]| -> Result<Option<AlgebraicValue>, DecodeError> {
+        match binary::decode_u8(bytes)? {
+            0 => Ok(None),
+            1 => {
+                let ty = binary::decode_type(bytes)?;
+                Ok(Some(binary::decode(&ty, bytes)?))
+            }
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    };
+    Ok(match binary::decode_u8(bytes)? {
+        0 => Check::Range {
+            column: binary::decode_u16(bytes)?,
+            min: decode_bound(bytes)?,
+            max: decode_bound(bytes)?,
+        },
+        1 => Check::NonEmpty {
+            column: binary::decode_u16(bytes)?,
+        },
+        2 => Check::Compare {
+            columns: [binary::decode_u16(bytes)?, binary::decode_u16(bytes)?],
+            op: match binary::decode_u8(bytes)? {
+                0 => CompareOp::Eq,
+                1 => CompareOp::Ne,
+                2 => CompareOp::Lt,
+                3 => CompareOp::Le,
+                4 => CompareOp::Gt,
+                5 => CompareOp::Ge,
+                tag => return Err(DecodeError::InvalidTag(tag)),
+            },
+        },
+        tag => return Err(DecodeError::InvalidTag(tag)),
+    })
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_module_def() -> ModuleDef {
+    use super::test_util::columns;
+
+    let mut def = ModuleDef::new("inventory");
+    def.tables.push(
+        TableDef::new(
+            "item",
+            columns([
+                ("id", AlgebraicType::U64),
+                ("owner", AlgebraicType::U64),
+                ("name", AlgebraicType::String),
+            ]),
+        )
+        .with_index("item_owner", &[1])
+        .with_constraint(ConstraintDef::PrimaryKey {
+            name: String::from("item_id"),
+            column: 0,
+        })
+        .with_sequence("item_id_seq", 0),
+    );
+    def.reducers.push(ReducerDef::new(
+        "add_item",
+        columns([("name", AlgebraicType::String)]),
+    ));
+    def.reducers
+        .push(ReducerDef::lifecycle("init", Lifecycle::Init));
+    def
+}
+
+#[test_case]
+fn test_module_def_round_trip() {
+    let def = test_module_def();
+    let bytes = def.to_bytes();
+    assert_eq!(ModuleDef::from_bytes(&bytes), Ok(def));
+    assert_eq!(
+        ModuleDef::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(DecodeError::UnexpectedEof)
+    );
+}
+
+#[test_case]
+fn test_module_def_too_deep() {
+    // A reducer taking an array of arrays of arrays...
+    let mut bytes = Vec::new();
+    binary::encode_str("deep", &mut bytes);
+    binary::encode_len(0, &mut bytes);
+    binary::encode_len(1, &mut bytes);
+    binary::encode_str("nest", &mut bytes);
+    binary::encode_len(1, &mut bytes);
+    binary::encode_str("arg", &mut bytes);
+    bytes.extend(core::iter::repeat_n(13, 10_000));
+    assert_eq!(ModuleDef::from_bytes(&bytes), Err(DecodeError::TooDeep));
+}
+
+#[test_case]
+fn test_module_from_def() {
+    use super::test_util::{failed, named, row};
+    use super::value::AlgebraicValue;
+    use super::{Module, ReducerFn};
+    use alloc::boxed::Box;
+
+    let def = test_module_def();
+    let unbound = Module::from_def(def.clone(), |_| None);
+    assert!(matches!(unbound, Err(ModuleDefError::UnboundReducer(_))));
+
+    let mut module = Module::from_def(def, |reducer| -> Option<ReducerFn> {
+        match reducer.name.as_str() {
+            "add_item" => Some(Box::new(|ctx, args| {
+                let owner = AlgebraicValue::U64(ctx.caller());
+                let item = row([AlgebraicValue::U64(0), owner, args.elements[0].clone()]);
+                ctx.insert("item", item).map_err(failed)?;
+                Ok(())
+            })),
+            "init" => Some(Box::new(|_, _| Ok(()))),
+            _ => None,
+        }
+    })
+    .expect("valid module def");
+
+    module.call_reducer("add_item", 7, named("sword")).unwrap();
+    module.call_reducer("add_item", 7, named("sword")).unwrap();
+    let items = module.table("item").unwrap();
+    let owned = items
+        .seek("item_owner", &row([AlgebraicValue::U64(7)]))
+        .unwrap();
+    assert_eq!(owned.len(), 2);
+    assert_eq!(
+        items.get(owned[1]).unwrap().unwrap().elements[0],
+        AlgebraicValue::U64(2)
+    );
+    assert_eq!(module.lifecycle_reducer(Lifecycle::Init), Some("init"));
+}
@@ -0,0 +1,325 @@
+//! Foreign keys: columns of a row referring to a row of another table, see
+//! [`ConstraintDef::ForeignKey`].
+//!
+//! A reference with `none` in one of its columns refers to no row and is never checked.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use super::def::{ConstraintDef, ModuleDefError, OnDelete, TableDef};
+use super::table::{RowId, Table, TableError};
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductValue, SumValue};
+
+/// Rows referring through a foreign key to a row being deleted.
+pub(super) struct Referencing {
+    pub table_id: u64,
+    pub name: String,
+    pub columns: Vec<u16>,
+    pub on_delete: OnDelete,
+    pub row_ids: Vec<RowId>,
+}
+
+/// Checks the foreign keys of `def` against the tables of its module.
+///
+/// The referenced table must already be in `tables`, unless it's `def` itself, and its
+/// referenced columns must be those of a unique constraint or primary key.
+pub(super) fn validate(
+    tables: &BTreeMap<u64, Table>,
+    def: &TableDef,
+) -> Result<(), ModuleDefError> {
+    for constraint in &def.constraints {
+        let ConstraintDef::ForeignKey {
+            name,
+            columns,
+            table,
+            references,
+            on_delete,
+        } = constraint
+        else {
+            continue;
+        };
+        let invalid = || ModuleDefError::InvalidForeignKey(name.clone());
+        let referenced = if *table == def.name {
+            def
+        } else {
+            tables
+                .values()
+                .find(|referenced| referenced.name() == table)
+                .ok_or_else(invalid)?
+                .def()
+        };
+        if let Some(column) = references
+            .iter()
+            .find(|column| **column as usize >= referenced.columns.elements.len())
+        {
+            return Err(ModuleDefError::NoSuchColumn {
+                table: referenced.name.clone(),
+                column: *column,
+            });
+        }
+        if columns.is_empty()
+            || columns.len() != references.len()
+            || unique_constraint(referenced, references).is_none()
+        {
+            return Err(invalid());
+        }
+        for (column, reference) in columns.iter().zip(references) {
+            let ty = &def.columns.elements[*column as usize].algebraic_type;
+            let referenced_ty = &referenced.columns.elements[*reference as usize].algebraic_type;
+            let matches = match option_of(ty) {
+                Some(ty) => ty == referenced_ty,
+                None => ty == referenced_ty && *on_delete != OnDelete::SetNull,
+            };
+            if !matches {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every reference of `row`, a row of `table`, refers to a row.
+pub(super) fn check(
+    tables: &BTreeMap<u64, Table>,
+    table: &Table,
+    row: &ProductValue,
+) -> Result<(), TableError> {
+    for constraint in &table.def().constraints {
+        let ConstraintDef::ForeignKey {
+            name,
+            columns,
+            table: referenced,
+            references,
+            ..
+        } = constraint
+        else {
+            continue;
+        };
+        let Some(key) = referenced_key(table.columns(), columns, row) else {
+            continue;
+        };
+        let referenced = tables
+            .values()
+            .find(|table| table.name() == referenced)
+            .ok_or_else(|| TableError::NoSuchTable(referenced.clone()))?;
+        let index = unique_constraint(referenced.def(), references)
+            .expect("foreign key references no unique constraint");
+        if referenced.seek(index, &key)?.is_empty() {
+            return Err(TableError::ForeignKeyViolation(name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Rows of every table referring to `row`, a row of the table `table_id`, by foreign key.
+pub(super) fn referencing(
+    tables: &BTreeMap<u64, Table>,
+    table_id: u64,
+    row: &ProductValue,
+) -> Vec<Referencing> {
+    let referenced = &tables[&table_id];
+    let mut referencing = Vec::new();
+    for table in tables.values() {
+        for constraint in &table.def().constraints {
+            let ConstraintDef::ForeignKey {
+                name,
+                columns,
+                table: referenced_name,
+                references,
+                on_delete,
+            } = constraint
+            else {
+                continue;
+            };
+            if referenced_name != referenced.name() {
+                continue;
+            }
+            let key = ProductValue::new(
+                columns
+                    .iter()
+                    .zip(references)
+                    .map(|(column, reference)| {
+                        let value = row.elements[*reference as usize].clone();
+                        let ty = &table.columns().elements[*column as usize].algebraic_type;
+                        match option_of(ty) {
+                            Some(_) => AlgebraicValue::Sum(SumValue::new(0, value)),
+                            None => value,
+                        }
+                    })
+                    .collect(),
+            );
+            let row_ids = table.seek(name, &key).expect("foreign key has no index");
+            if !row_ids.is_empty() {
+                referencing.push(Referencing {
+                    table_id: table.id(),
+                    name: name.clone(),
+                    columns: columns.clone(),
+                    on_delete: *on_delete,
+                    row_ids,
+                });
+            }
+        }
+    }
+    referencing
+}
+
+/// Values of `columns` in `row`, unwrapped from their options, or `None` if one is `none`.
+fn referenced_key(ty: &ProductType, columns: &[u16], row: &ProductValue) -> Option<ProductValue> {
+    let mut key = Vec::new();
+    for column in columns {
+        let value = &row.elements[*column as usize];
+        match (
+            option_of(&ty.elements[*column as usize].algebraic_type),
+            value,
+        ) {
+            (Some(_), AlgebraicValue::Sum(SumValue { tag: 0, value })) => {
+                key.push((**value).clone())
+            }
+            (Some(_), _) => return None,
+            (None, value) => key.push(value.clone()),
+        }
+    }
+    Some(ProductValue::new(key))
+}
+
+/// Name of the unique constraint or primary key of `def` on exactly `columns`.
+fn unique_constraint<'a>(def: &'a TableDef, columns: &[u16]) -> Option<&'a str> {
+    def.constraints
+        .iter()
+        .find(|constraint| {
+            matches!(
+                constraint,
+                ConstraintDef::Unique { .. } | ConstraintDef::PrimaryKey { .. }
+            ) && constraint.columns() == columns
+        })
+        .map(|constraint| constraint.name())
+}
+
+fn option_of(ty: &AlgebraicType) -> Option<&AlgebraicType> {
+    match ty {
+        AlgebraicType::Sum(sum) if sum.is_option() => Some(&sum.variants[0].algebraic_type),
+        _ => None,
+    }
+}
+
+// Tests
+
+/// Foreign key from the `owner` column to the id of a player.
+#[cfg(test)]
+fn owner_key(name: &str, on_delete: OnDelete) -> ConstraintDef {
+    ConstraintDef::ForeignKey {
+        name: String::from(name),
+        columns: alloc::vec![1],
+        table: String::from("player"),
+        references: alloc::vec![0],
+        on_delete,
+    }
+}
+
+/// Module of players 1 and 2 and the items, guilds and bans they own, whose reducer
+/// `delete_player(id)` deletes a player.
+///
+/// Deleting a player deletes their items, leaves their guilds without an owner and is rejected
+/// while they're banned.
+#[cfg(test)]
+fn inventory_module() -> super::Module {
+    use super::test_util::{add_reducer, columns, failed};
+
+    let primary_key = |name: &str| ConstraintDef::PrimaryKey {
+        name: String::from(name),
+        column: 0,
+    };
+    let owned = |owner| columns([("id", AlgebraicType::U64), ("owner", owner)]);
+    let mut module = super::Module::new(String::from("inventory"));
+    let player = TableDef::new("player", columns([("id", AlgebraicType::U64)]))
+        .with_constraint(primary_key("player_id"));
+    module.add_table(player).unwrap();
+    let item = TableDef::new("item", owned(AlgebraicType::U64))
+        .with_constraint(primary_key("item_id"))
+        .with_constraint(owner_key("item_owner", OnDelete::Cascade));
+    module.add_table(item).unwrap();
+    let guild = TableDef::new("guild", owned(AlgebraicType::option(AlgebraicType::U64)))
+        .with_constraint(owner_key("guild_owner", OnDelete::SetNull));
+    module.add_table(guild).unwrap();
+    let ban = TableDef::new("ban", owned(AlgebraicType::U64))
+        .with_constraint(owner_key("ban_player", OnDelete::Restrict));
+    module.add_table(ban).unwrap();
+    let params = columns([("id", AlgebraicType::U64)]);
+    add_reducer(&mut module, "delete_player", params, |ctx, args| {
+        let player = ctx.table("player").unwrap();
+        let row_id = player.seek("player_id", &args).unwrap()[0];
+        ctx.delete("player", row_id).map(|_| ()).map_err(failed)
+    });
+    let rejected = module.insert_rows("player", 0, [u64s(&[1]), u64s(&[2])]);
+    assert_eq!(rejected, Ok(Vec::new()));
+    module
+}
+
+#[cfg(test)]
+fn u64s(values: &[u64]) -> ProductValue {
+    super::test_util::row(values.iter().map(|value| AlgebraicValue::U64(*value)))
+}
+
+#[test_case]
+fn test_foreign_key_def() {
+    let mut module = inventory_module();
+    let loot =
+        super::test_util::columns([("id", AlgebraicType::U64), ("owner", AlgebraicType::U64)]);
+    let loot =
+        TableDef::new("loot", loot).with_constraint(owner_key("loot_owner", OnDelete::SetNull));
+    assert_eq!(
+        module.add_table(loot),
+        Err(ModuleDefError::InvalidForeignKey(String::from(
+            "loot_owner"
+        )))
+    );
+}
+
+#[test_case]
+fn test_foreign_key_insert() {
+    let mut module = inventory_module();
+    let rejected = module.insert_rows("item", 0, [u64s(&[1, 1]), u64s(&[2, 3])]);
+    assert_eq!(
+        rejected,
+        Ok(alloc::vec![(
+            1,
+            TableError::ForeignKeyViolation(String::from("item_owner"))
+        )])
+    );
+}
+
+#[test_case]
+fn test_foreign_key_on_delete() {
+    let mut module = inventory_module();
+    let some = AlgebraicValue::Sum(SumValue::new(0, AlgebraicValue::U64(1)));
+    let none = AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit()));
+    module.insert_rows("item", 0, [u64s(&[1, 1])]).unwrap();
+    let guilds = [
+        ProductValue::new(alloc::vec![AlgebraicValue::U64(1), some]),
+        ProductValue::new(alloc::vec![AlgebraicValue::U64(2), none.clone()]),
+    ];
+    assert_eq!(module.insert_rows("guild", 0, guilds), Ok(Vec::new()));
+    module.call_reducer("delete_player", 0, u64s(&[1])).unwrap();
+    assert!(module.table("item").unwrap().is_empty());
+    let guild = module.table("guild").unwrap();
+    let owners: Vec<_> = guild
+        .iter()
+        .map(|row| row.unwrap().1.elements[1].clone())
+        .collect();
+    assert_eq!(owners, alloc::vec![none.clone(), none]);
+}
+
+#[test_case]
+fn test_foreign_key_restrict() {
+    use super::ReducerCallError;
+
+    let mut module = inventory_module();
+    module.insert_rows("ban", 0, [u64s(&[1, 2])]).unwrap();
+    let restricted = module.call_reducer("delete_player", 0, u64s(&[2]));
+    assert_eq!(
+        restricted,
+        Err(ReducerCallError::Failed(String::from(
+            "ForeignKeyViolation(\"ban_player\")"
+        )))
+    );
+    assert_eq!(module.table("player").unwrap().len(), 2);
+}
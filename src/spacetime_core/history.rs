@@ -0,0 +1,475 @@
+//! A module's history: snapshots of its tables, and the log of reducer calls since.
+//!
+//! Both round-trip through the binary format, decoded against the module they were taken
+//! from, so the history of one machine can be replayed on another, see [`super::replay`].
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+
+use super::Module;
+use super::binary::{self, DecodeError};
+use super::buffer_pool::StorageError;
+use super::table::{RowId, Table, TableError};
+use super::transaction::Transaction;
+use super::value::{ProductType, ProductValue};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HistoryError {
+    NoSuchTable(u64),
+    NoSuchReducer(String),
+    /// A row of the snapshot doesn't fit its table.
+    Table(TableError),
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for HistoryError {
+    fn from(error: DecodeError) -> HistoryError {
+        HistoryError::Decode(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreError {
+    NoSuchModule(u64),
+    /// The offset is past the latest call.
+    NoSuchOffset(u64),
+    /// No kept snapshot reaches the point through the calls still logged.
+    Unavailable,
+    History(HistoryError),
+}
+
+/// Point in a module's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Before the call at this offset.
+    Offset(u64),
+    /// After the calls made up to this tick.
+    Timestamp(u64),
+}
+
+/// State of one row after a commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowWrite {
+    pub table_id: u64,
+    pub row_id: RowId,
+    /// The row, or `None` if it was deleted.
+    pub row: Option<ProductValue>,
+}
+
+/// A reducer call, with the inputs it was called with and the rows it wrote.
+///
+/// Calls that failed are logged too, without writes: they still use up row ids and sequence
+/// values, so replaying them keeps the later calls identical.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub offset: u64,
+    /// Tick the reducer was called at, as seen by [`ReducerContext::now`].
+    ///
+    /// [`ReducerContext::now`]: super::ReducerContext::now
+    pub timestamp: u64,
+    /// Empty for rows written outside of reducers, see [`super::Module::insert_rows`].
+    pub reducer: String,
+    pub caller: u64,
+    pub args: ProductValue,
+    pub committed: bool,
+    /// Whether the call succeeded but was rolled back with the other calls of its
+    /// [atomic transaction](super::atomic).
+    pub aborted: bool,
+    pub writes: Vec<RowWrite>,
+}
+
+/// Calls kept by a module's commit log, unless it sets another capacity.
+pub const DEFAULT_LOG_CAPACITY: usize = 64;
+/// Snapshots kept by a module, see [`super::Module::take_snapshot`].
+pub const SNAPSHOT_CAPACITY: usize = 4;
+
+/// The latest calls to a module, dropping the oldest once `capacity` are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitLog {
+    commits: Vec<Commit>,
+    capacity: usize,
+    next_offset: u64,
+}
+
+impl CommitLog {
+    pub fn new(capacity: usize) -> CommitLog {
+        CommitLog {
+            commits: Vec::new(),
+            capacity,
+            next_offset: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.drop_oldest();
+    }
+
+    /// Offset the next call will be logged at.
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    pub fn commits(&self) -> &[Commit] {
+        &self.commits
+    }
+
+    /// Logs `commit`, filling in its offset.
+    pub fn append(&mut self, mut commit: Commit) {
+        commit.offset = self.next_offset;
+        self.next_offset += 1;
+        self.commits.push(commit);
+        self.drop_oldest();
+    }
+
+    /// Drops the commits from `offset` on, the next call being logged at `offset`.
+    pub fn truncate(&mut self, offset: u64) {
+        self.commits.retain(|commit| commit.offset < offset);
+        self.next_offset = offset;
+    }
+
+    fn drop_oldest(&mut self) {
+        let excess = self.commits.len().saturating_sub(self.capacity);
+        self.commits.drain(..excess);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.next_offset.to_le_bytes());
+        binary::encode_len(self.commits.len(), &mut buf);
+        for commit in &self.commits {
+            encode_commit(commit, &mut buf);
+        }
+        buf
+    }
+
+    /// Decodes a log of calls to `module`, or to a module with the same definition.
+    ///
+    /// The log's capacity is the number of calls it holds.
+    pub fn from_bytes(module: &Module, mut bytes: &[u8]) -> Result<CommitLog, HistoryError> {
+        let bytes = &mut bytes;
+        let next_offset = binary::decode_u64(bytes)?;
+        let len = binary::decode_len(bytes)?;
+        let mut commits = Vec::new();
+        for _ in 0..len {
+            commits.push(decode_commit(module, bytes)?);
+        }
+        end(bytes)?;
+        Ok(CommitLog {
+            capacity: commits.len(),
+            commits,
+            next_offset,
+        })
+    }
+}
+
+/// Final state of each row written by `tx`, in the order they were first written.
+pub(super) fn row_writes(
+    tx: &Transaction,
+    tables: &BTreeMap<u64, Table>,
+) -> Result<Vec<RowWrite>, StorageError> {
+    let mut seen = BTreeSet::new();
+    let mut writes = Vec::new();
+    for write in tx.writes() {
+        let (table_id, row_id) = write.row();
+        if seen.insert((table_id, row_id)) {
+            let row = match tables.get(&table_id) {
+                Some(table) => table.get(row_id)?,
+                None => None,
+            };
+            writes.push(RowWrite {
+                table_id,
+                row_id,
+                row,
+            });
+        }
+    }
+    Ok(writes)
+}
+
+/// Applies the writes of a commit to `tables`, as [`Table::redo`] does.
+pub(super) fn redo_writes(
+    tables: &mut BTreeMap<u64, Table>,
+    writes: &[RowWrite],
+) -> Result<(), HistoryError> {
+    // Rows are cleared first, the commit may have moved unique keys between them
+    for clear in [true, false] {
+        for write in writes {
+            let table = tables
+                .get_mut(&write.table_id)
+                .ok_or(HistoryError::NoSuchTable(write.table_id))?;
+            let row = if clear { None } else { write.row.clone() };
+            table.redo(write.row_id, row).map_err(HistoryError::Table)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rows and counters of one table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSnapshot {
+    pub table_id: u64,
+    pub next_row_id: u64,
+    /// Next value of each sequence, in the order of the table's definition.
+    pub sequences: Vec<u64>,
+    pub rows: Vec<(RowId, ProductValue)>,
+}
+
+/// State of a module's tables before the call at `offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub offset: u64,
+    /// Tick the snapshot was taken at.
+    pub timestamp: u64,
+    pub tables: Vec<TableSnapshot>,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        binary::encode_len(self.tables.len(), &mut buf);
+        for table in &self.tables {
+            buf.extend_from_slice(&table.table_id.to_le_bytes());
+            buf.extend_from_slice(&table.next_row_id.to_le_bytes());
+            binary::encode_len(table.sequences.len(), &mut buf);
+            for next in &table.sequences {
+                buf.extend_from_slice(&next.to_le_bytes());
+            }
+            binary::encode_len(table.rows.len(), &mut buf);
+            for (row_id, row) in &table.rows {
+                buf.extend_from_slice(&row_id.0.to_le_bytes());
+                binary::encode_product(row, &mut buf);
+            }
+        }
+        buf
+    }
+
+    /// Decodes a snapshot of `module`, or of a module with the same definition.
+    pub fn from_bytes(module: &Module, mut bytes: &[u8]) -> Result<Snapshot, HistoryError> {
+        let bytes = &mut bytes;
+        let offset = binary::decode_u64(bytes)?;
+        let timestamp = binary::decode_u64(bytes)?;
+        let mut tables = Vec::new();
+        for _ in 0..binary::decode_len(bytes)? {
+            let table_id = binary::decode_u64(bytes)?;
+            let columns = columns(module, table_id)?;
+            let next_row_id = binary::decode_u64(bytes)?;
+            let mut sequences = Vec::new();
+            for _ in 0..binary::decode_len(bytes)? {
+                sequences.push(binary::decode_u64(bytes)?);
+            }
+            let mut rows = Vec::new();
+            for _ in 0..binary::decode_len(bytes)? {
+                let row_id = RowId(binary::decode_u64(bytes)?);
+                let row = binary::decode_product(columns, bytes)?;
+                rows.push((row_id, row));
+            }
+            tables.push(TableSnapshot {
+                table_id,
+                next_row_id,
+                sequences,
+                rows,
+            });
+        }
+        end(bytes)?;
+        Ok(Snapshot {
+            offset,
+            timestamp,
+            tables,
+        })
+    }
+}
+
+fn columns(module: &Module, table_id: u64) -> Result<&ProductType, HistoryError> {
+    module
+        .tables
+        .get(&table_id)
+        .map(|table| table.columns())
+        .ok_or(HistoryError::NoSuchTable(table_id))
+}
+
+fn end(bytes: &[u8]) -> Result<(), HistoryError> {
+    match bytes.len() {
+        0 => Ok(()),
+        len => Err(DecodeError::TrailingBytes(len).into()),
+    }
+}
+
+fn encode_commit(commit: &Commit, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&commit.offset.to_le_bytes());
+    buf.extend_from_slice(&commit.timestamp.to_le_bytes());
+    binary::encode_str(&commit.reducer, buf);
+    buf.extend_from_slice(&commit.caller.to_le_bytes());
+    binary::encode_product(&commit.args, buf);
+    buf.push(commit.committed as u8);
+    buf.push(commit.aborted as u8);
+    binary::encode_len(commit.writes.len(), buf);
+    for write in &commit.writes {
+        buf.extend_from_slice(&write.table_id.to_le_bytes());
+        buf.extend_from_slice(&write.row_id.0.to_le_bytes());
+        match &write.row {
+            Some(row) => {
+                buf.push(1);
+                binary::encode_product(row, buf);
+            }
+            None => buf.push(0),
+        }
+    }
+}
+
+fn decode_commit(module: &Module, bytes: &mut &[u8]) -> Result<Commit, HistoryError> {
+    let offset = binary::decode_u64(bytes)?;
+    let timestamp = binary::decode_u64(bytes)?;
+    let reducer = binary::decode_string(bytes)?;
+    let no_params = ProductType::new(Vec::new());
+    let params = match reducer.as_str() {
+        "" => &no_params,
+        name => module
+            .reducer(name)
+            .ok_or_else(|| HistoryError::NoSuchReducer(reducer.clone()))?
+            .params(),
+    };
+    let caller = binary::decode_u64(bytes)?;
+    let args = binary::decode_product(params, bytes)?;
+    let committed = decode_bool(bytes)?;
+    let aborted = decode_bool(bytes)?;
+    let len = binary::decode_len(bytes)?;
+    let mut writes = Vec::new();
+    for _ in 0..len {
+        let table_id = binary::decode_u64(bytes)?;
+        let row_id = RowId(binary::decode_u64(bytes)?);
+        let row = match binary::decode_u8(bytes)? {
+            0 => None,
+            1 => Some(binary::decode_product(columns(module, table_id)?, bytes)?),
+            tag => return Err(DecodeError::InvalidTag(tag).into()),
+        };
+        writes.push(RowWrite {
+            table_id,
+            row_id,
+            row,
+        });
+    }
+    Ok(Commit {
+        offset,
+        timestamp,
+        reducer,
+        caller,
+        args,
+        committed,
+        aborted,
+        writes,
+    })
+}
+
+fn decode_bool(bytes: &mut &[u8]) -> Result<bool, HistoryError> {
+    match binary::decode_u8(bytes)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(DecodeError::InvalidBool(other).into()),
+    }
+}
+
+// Tests
+
+/// Module whose reducer `add(name)` inserts an item with the next id, after adding `a`, `b`
+/// and `c`.
+#[cfg(test)]
+fn items_module() -> Module {
+    use super::def::{ConstraintDef, TableDef};
+    use super::test_util::{add_reducer, columns, failed};
+    use super::value::{AlgebraicType, AlgebraicValue};
+
+    let mut module = Module::new(String::from("items"));
+    let item = columns([("id", AlgebraicType::U64), ("name", AlgebraicType::String)]);
+    let item = TableDef::new("item", item)
+        .with_constraint(ConstraintDef::PrimaryKey {
+            name: String::from("item_id"),
+            column: 0,
+        })
+        .with_sequence("item_id_seq", 0);
+    module.add_table(item).unwrap();
+    let params = columns([("name", AlgebraicType::String)]);
+    add_reducer(&mut module, "add", params, |ctx, mut row| {
+        row.elements.insert(0, AlgebraicValue::U64(0));
+        ctx.insert("item", row).map(|_| ()).map_err(failed)
+    });
+    for name in ["a", "b", "c"] {
+        add_item(&mut module, name);
+    }
+    module
+}
+
+#[cfg(test)]
+fn add_item(module: &mut Module, name: &str) {
+    let args = super::test_util::named(name);
+    module.call_reducer("add", 0, args).unwrap();
+}
+
+/// Items of `module` as `(id, name)` pairs.
+#[cfg(test)]
+fn items(module: &Module) -> Vec<(u64, String)> {
+    use super::value::AlgebraicValue;
+
+    let table = module.table("item").unwrap();
+    table
+        .iter()
+        .map(|row| match &row.unwrap().1.elements[..] {
+            [AlgebraicValue::U64(id), AlgebraicValue::String(name)] => (*id, name.clone()),
+            elements => panic!("not an item: {:?}", elements),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn item(id: u64, name: &str) -> (u64, String) {
+    (id, String::from(name))
+}
+
+#[test_case]
+fn test_restored_copy() {
+    let module = items_module();
+    let mut copy = module
+        .restored(RestorePoint::Offset(2), String::from("copy"))
+        .unwrap();
+    assert_eq!(copy.name(), "copy");
+    add_item(&mut copy, "d");
+    assert_eq!(items(&copy), [item(1, "a"), item(2, "b"), item(3, "d")]);
+    assert_eq!(items(&module).len(), 3);
+}
+
+#[test_case]
+fn test_restore_in_place() {
+    let mut module = items_module();
+    assert_eq!(
+        module.restore(RestorePoint::Offset(5)),
+        Err(RestoreError::NoSuchOffset(5))
+    );
+    module.restore(RestorePoint::Offset(1)).unwrap();
+    assert_eq!(items(&module), [item(1, "a")]);
+    assert_eq!(module.commit_log().next_offset(), 1);
+}
+
+#[test_case]
+fn test_restore_needs_logged_calls() {
+    let mut module = items_module();
+    module.set_commit_log_capacity(1);
+    add_item(&mut module, "e");
+    add_item(&mut module, "f");
+    assert_eq!(
+        module.restore(RestorePoint::Offset(4)),
+        Err(RestoreError::Unavailable)
+    );
+    module.take_snapshot().unwrap();
+    add_item(&mut module, "g");
+    module.restore(RestorePoint::Offset(5)).unwrap();
+    assert_eq!(items(&module).len(), 5);
+}
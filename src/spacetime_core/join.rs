@@ -0,0 +1,259 @@
+//! Joins of two tables on a column of each having equal values, for queries and
+//! subscriptions.
+//!
+//! A joined row is the row of the query's table followed by the row of the joined table, its
+//! columns named after their table, as in `player.id`.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+
+use super::buffer_pool::StorageError;
+use super::query::{Query, QueryError};
+use super::subscription::{Deltas, TableDelta};
+use super::table::Table;
+use super::value::{AlgebraicValue, ProductType, ProductTypeElement, ProductValue};
+
+/// Rows of `table` whose `right` column equals the `left` column of a query's rows, see
+/// [`Query::join`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub table: String,
+    pub left: String,
+    pub right: String,
+}
+
+/// A join resolved against a module's tables.
+pub(super) struct JoinPlan {
+    left: u64,
+    right: u64,
+    left_column: usize,
+    right_column: usize,
+}
+
+impl JoinPlan {
+    /// The join of `query` over `tables`, or `None` if it has none.
+    pub(super) fn new(
+        query: &Query,
+        tables: &BTreeMap<u64, Table>,
+    ) -> Result<Option<JoinPlan>, QueryError> {
+        let Some(join) = &query.join else {
+            return Ok(None);
+        };
+        let table = |name: &str| {
+            tables
+                .values()
+                .find(|table| table.name() == name)
+                .ok_or_else(|| QueryError::NoSuchTable(String::from(name)))
+        };
+        let column = |table: &Table, name: &str| {
+            table
+                .columns()
+                .index_of(name)
+                .ok_or_else(|| QueryError::NoSuchColumn(format!("{}.{}", table.name(), name)))
+        };
+        let left = table(&query.table)?;
+        let right = table(&join.table)?;
+        let left_column = column(left, &join.left)?;
+        let right_column = column(right, &join.right)?;
+        let left_ty = &left.columns().elements[left_column].algebraic_type;
+        if *left_ty != right.columns().elements[right_column].algebraic_type {
+            return Err(QueryError::InvalidJoin(join.table.clone()));
+        }
+        Ok(Some(JoinPlan {
+            left: left.id(),
+            right: right.id(),
+            left_column,
+            right_column,
+        }))
+    }
+
+    /// Type of the joined rows.
+    pub(super) fn schema(&self, tables: &BTreeMap<u64, Table>) -> ProductType {
+        let columns = |table: &Table| {
+            table
+                .columns()
+                .elements
+                .iter()
+                .map(|column| {
+                    let name = format!("{}.{}", table.name(), column.name);
+                    ProductTypeElement::new(&name, column.algebraic_type.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut elements = columns(&tables[&self.left]);
+        elements.extend(columns(&tables[&self.right]));
+        ProductType::new(elements)
+    }
+
+    /// The joined rows of `tables`.
+    pub(super) fn rows(
+        &self,
+        tables: &BTreeMap<u64, Table>,
+    ) -> Result<Vec<ProductValue>, StorageError> {
+        let right = &tables[&self.right];
+        let mut rows = Vec::new();
+        for left in tables[&self.left].iter() {
+            let (_, left) = left?;
+            for right in right.find(self.right_column, &left.elements[self.left_column])? {
+                rows.push(joined(&left, &right));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Joined rows removed and added by the changes `deltas` made to `tables`, found from the
+    /// changed rows alone.
+    pub(super) fn delta(
+        &self,
+        deltas: &Deltas,
+        tables: &BTreeMap<u64, Table>,
+    ) -> Result<TableDelta, StorageError> {
+        // The old join is the new one less the changed rows of each table joined with the
+        // new rows of the other, plus the changed rows of both joined together
+        let signed = |table_id| {
+            let delta = deltas.get(&table_id);
+            let deletes = delta.into_iter().flat_map(|delta| &delta.deletes);
+            let inserts = delta.into_iter().flat_map(|delta| &delta.inserts);
+            deletes
+                .map(|row| (row, -1))
+                .chain(inserts.map(|row| (row, 1)))
+                .collect::<Vec<_>>()
+        };
+        let left_delta = signed(self.left);
+        let right_delta = signed(self.right);
+        let mut counts: BTreeMap<ProductValue, i64> = BTreeMap::new();
+        for (left, sign) in &left_delta {
+            let key = &left.elements[self.left_column];
+            for right in tables[&self.right].find(self.right_column, key)? {
+                *counts.entry(joined(left, &right)).or_default() += sign;
+            }
+        }
+        let mut right_by_key: BTreeMap<&AlgebraicValue, Vec<(&ProductValue, i64)>> =
+            BTreeMap::new();
+        for (right, sign) in &right_delta {
+            let key = &right.elements[self.right_column];
+            right_by_key.entry(key).or_default().push((right, *sign));
+            for left in tables[&self.left].find(self.left_column, key)? {
+                *counts.entry(joined(&left, right)).or_default() += sign;
+            }
+        }
+        for (left, left_sign) in &left_delta {
+            let key = &left.elements[self.left_column];
+            for (right, right_sign) in right_by_key.get(key).into_iter().flatten() {
+                *counts.entry(joined(left, right)).or_default() -= left_sign * right_sign;
+            }
+        }
+
+        let mut delta = TableDelta::default();
+        for (row, count) in counts {
+            let rows = match count < 0 {
+                true => &mut delta.deletes,
+                false => &mut delta.inserts,
+            };
+            for _ in 0..count.unsigned_abs() {
+                rows.push(row.clone());
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Whether `deltas` change either table.
+    pub(super) fn is_changed(&self, deltas: &Deltas) -> bool {
+        deltas.contains_key(&self.left) || deltas.contains_key(&self.right)
+    }
+}
+
+fn joined(left: &ProductValue, right: &ProductValue) -> ProductValue {
+    let mut elements = left.elements.clone();
+    elements.extend(right.elements.iter().cloned());
+    ProductValue::new(elements)
+}
+
+// Tests
+
+/// Module of player 1 in chunk 0 and entities 10, 11 and 12 in chunks 0, 1 and 1, whose reducer
+/// `move(id, chunk)` moves a player or an entity to a chunk.
+#[cfg(test)]
+fn world_module() -> super::Module {
+    use super::def::TableDef;
+    use super::test_util::{add_reducer, columns, failed};
+    use super::value::AlgebraicType;
+
+    let columns = || columns([("id", AlgebraicType::U64), ("chunk", AlgebraicType::U32)]);
+    let mut module = super::Module::new(String::from("world"));
+    let player = TableDef::new("player", columns()).with_index("player_id", &[0]);
+    module.add_table(player).unwrap();
+    let entity = TableDef::new("entity", columns()).with_index("entity_chunk", &[1]);
+    module.add_table(entity).unwrap();
+    add_reducer(&mut module, "move", columns(), |ctx, args| {
+        let name = match args.elements[0] {
+            AlgebraicValue::U64(1) => "player",
+            _ => "entity",
+        };
+        let table = ctx.table(name).unwrap();
+        let (row_id, _) = table
+            .iter()
+            .map(Result::unwrap)
+            .find(|(_, row)| row.elements[0] == args.elements[0])
+            .unwrap();
+        ctx.update(name, row_id, args).map(|_| ()).map_err(failed)
+    });
+    module.insert_rows("player", 0, [at(1, 0)]).unwrap();
+    module
+        .insert_rows("entity", 0, [at(10, 0), at(11, 1), at(12, 1)])
+        .unwrap();
+    module
+}
+
+/// Row of a player or entity `id` in `chunk`.
+#[cfg(test)]
+fn at(id: u64, chunk: u32) -> ProductValue {
+    super::test_util::row([AlgebraicValue::U64(id), AlgebraicValue::U32(chunk)])
+}
+
+/// Entities in the same chunk as player 1.
+#[cfg(test)]
+fn near_me() -> Query {
+    Query::table("player")
+        .join("entity", "chunk", "chunk")
+        .filter_eq("player.id", AlgebraicValue::U64(1))
+}
+
+#[test_case]
+fn test_join() {
+    let module = world_module();
+    let result = module.query(&near_me()).unwrap();
+    assert_eq!(result.rows, [joined(&at(1, 0), &at(10, 0))]);
+    assert_eq!(result.schema.elements[3].name, "entity.chunk");
+}
+
+/// Moving the player swaps every entity it sees, moving an entity only that one.
+#[test_case]
+fn test_join_subscriptions() {
+    use super::subscription::SubscriptionUpdate;
+
+    let mut module = world_module();
+    let (near, _) = module.subscribe(&near_me()).unwrap();
+    module.call_reducer("move", 0, at(1, 1)).unwrap();
+    module.call_reducer("move", 0, at(12, 0)).unwrap();
+    let updates = alloc::vec![
+        SubscriptionUpdate {
+            deletes: alloc::vec![joined(&at(1, 0), &at(10, 0))],
+            inserts: alloc::vec![joined(&at(1, 1), &at(11, 1)), joined(&at(1, 1), &at(12, 1))],
+        },
+        SubscriptionUpdate {
+            deletes: alloc::vec![joined(&at(1, 1), &at(12, 1))],
+            inserts: Vec::new(),
+        },
+    ];
+    assert_eq!(module.poll_subscription(near), Ok(updates));
+}
+
+#[test_case]
+fn test_join_invalid() {
+    let module = world_module();
+    let invalid = Query::table("player").join("entity", "id", "chunk");
+    assert_eq!(
+        module.query(&invalid),
+        Err(QueryError::InvalidJoin(String::from("entity")))
+    );
+}
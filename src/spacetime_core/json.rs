@@ -0,0 +1,559 @@
+//! JSON encoding of values, for tooling that doesn't want to decode the binary format.
+//!
+//! Products are objects keyed by element name (arrays are also accepted when decoding), sums
+//! are objects with a single key naming the variant, e.g. `{"some": 5}`, and bytes are
+//! lowercase hex strings. JSON has no NaN or infinities, so they are encoded as `null`, which
+//! decodes to NaN.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductValue, SumType, SumValue};
+
+/// Arrays and objects a document can nest, bounding the parser's recursion.
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    /// Malformed JSON at the given byte offset.
+    Syntax(usize),
+    /// The JSON value doesn't match the expected type.
+    Mismatch(String),
+    MissingField(String),
+    UnknownField(String),
+    UnknownVariant(String),
+    /// Arrays and objects nested deeper than [`MAX_DEPTH`], at the given byte offset.
+    TooDeep(usize),
+    /// A value to encode doesn't match its type.
+    InvalidValue(String),
+}
+
+/// Untyped JSON document, converted to a value once the expected type is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    /// Numbers keep their text so integers are parsed exactly into their target type.
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+pub fn encode(
+    ty: &AlgebraicType,
+    value: &AlgebraicValue,
+    out: &mut String,
+) -> Result<(), JsonError> {
+    match (ty, value) {
+        (_, AlgebraicValue::Bool(value)) => out.push_str(if *value { "true" } else { "false" }),
+        (_, AlgebraicValue::I8(value)) => write_display(out, value),
+        (_, AlgebraicValue::U8(value)) => write_display(out, value),
+        (_, AlgebraicValue::I16(value)) => write_display(out, value),
+        (_, AlgebraicValue::U16(value)) => write_display(out, value),
+        (_, AlgebraicValue::I32(value)) => write_display(out, value),
+        (_, AlgebraicValue::U32(value)) => write_display(out, value),
+        (_, AlgebraicValue::I64(value)) => write_display(out, value),
+        (_, AlgebraicValue::U64(value)) => write_display(out, value),
+        (_, AlgebraicValue::F32(value)) if value.is_finite() => write_display(out, value),
+        (_, AlgebraicValue::F64(value)) if value.is_finite() => write_display(out, value),
+        (_, AlgebraicValue::F32(_)) | (_, AlgebraicValue::F64(_)) => out.push_str("null"),
+        (_, AlgebraicValue::String(value)) => encode_string(value, out),
+        (_, AlgebraicValue::Bytes(value)) => {
+            out.push('"');
+            for byte in value {
+                let _ = write!(out, "{:02x}", byte);
+            }
+            out.push('"');
+        }
+        (AlgebraicType::Array(element_type), AlgebraicValue::Array(values)) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                encode(element_type, value, out)?;
+            }
+            out.push(']');
+        }
+        (AlgebraicType::Product(ty), AlgebraicValue::Product(value)) => {
+            encode_product(ty, value, out)?
+        }
+        (AlgebraicType::Sum(ty), AlgebraicValue::Sum(value)) => {
+            let variant = ty
+                .variants
+                .get(value.tag as usize)
+                .ok_or_else(|| invalid_value(ty, value))?;
+            out.push('{');
+            encode_string(&variant.name, out);
+            out.push(':');
+            encode(&variant.algebraic_type, &value.value, out)?;
+            out.push('}');
+        }
+        _ => return Err(invalid_value(ty, value)),
+    }
+    Ok(())
+}
+
+pub fn encode_product(
+    ty: &ProductType,
+    value: &ProductValue,
+    out: &mut String,
+) -> Result<(), JsonError> {
+    if ty.elements.len() != value.elements.len() {
+        return Err(invalid_value(ty, value));
+    }
+    out.push('{');
+    for (i, (element, value)) in ty.elements.iter().zip(&value.elements).enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_string(&element.name, out);
+        out.push(':');
+        encode(&element.algebraic_type, value, out)?;
+    }
+    out.push('}');
+    Ok(())
+}
+
+fn invalid_value(ty: &impl core::fmt::Debug, value: &impl core::fmt::Debug) -> JsonError {
+    JsonError::InvalidValue(format!("{:?} does not match type {:?}", value, ty))
+}
+
+pub fn to_string(ty: &ProductType, value: &ProductValue) -> Result<String, JsonError> {
+    let mut out = String::new();
+    encode_product(ty, value, &mut out)?;
+    Ok(out)
+}
+
+/// Encodes rows sharing the schema `ty` as a JSON array of objects, failing on a row that
+/// doesn't match it.
+pub fn rows_to_string<'a>(
+    ty: &ProductType,
+    rows: impl IntoIterator<Item = &'a ProductValue>,
+) -> Result<String, JsonError> {
+    let mut out = String::from("[");
+    for (i, row) in rows.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_product(ty, row, &mut out)?;
+    }
+    out.push(']');
+    Ok(out)
+}
+
+fn write_display(out: &mut String, value: &impl core::fmt::Display) {
+    let _ = write!(out, "{}", value);
+}
+
+fn encode_string(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Parses `text` as a product of type `ty`, e.g. reducer arguments.
+pub fn from_str(ty: &ProductType, text: &str) -> Result<ProductValue, JsonError> {
+    decode_product(ty, &parse(text)?)
+}
+
+pub fn decode(ty: &AlgebraicType, json: &JsonValue) -> Result<AlgebraicValue, JsonError> {
+    Ok(match (ty, json) {
+        (AlgebraicType::Bool, JsonValue::Bool(value)) => AlgebraicValue::Bool(*value),
+        (AlgebraicType::I8, JsonValue::Number(n)) => AlgebraicValue::I8(parse_number(ty, n)?),
+        (AlgebraicType::U8, JsonValue::Number(n)) => AlgebraicValue::U8(parse_number(ty, n)?),
+        (AlgebraicType::I16, JsonValue::Number(n)) => AlgebraicValue::I16(parse_number(ty, n)?),
+        (AlgebraicType::U16, JsonValue::Number(n)) => AlgebraicValue::U16(parse_number(ty, n)?),
+        (AlgebraicType::I32, JsonValue::Number(n)) => AlgebraicValue::I32(parse_number(ty, n)?),
+        (AlgebraicType::U32, JsonValue::Number(n)) => AlgebraicValue::U32(parse_number(ty, n)?),
+        (AlgebraicType::I64, JsonValue::Number(n)) => AlgebraicValue::I64(parse_number(ty, n)?),
+        (AlgebraicType::U64, JsonValue::Number(n)) => AlgebraicValue::U64(parse_number(ty, n)?),
+        (AlgebraicType::F32, JsonValue::Number(n)) => AlgebraicValue::F32(parse_number(ty, n)?),
+        (AlgebraicType::F64, JsonValue::Number(n)) => AlgebraicValue::F64(parse_number(ty, n)?),
+        (AlgebraicType::F32, JsonValue::Null) => AlgebraicValue::F32(f32::NAN),
+        (AlgebraicType::F64, JsonValue::Null) => AlgebraicValue::F64(f64::NAN),
+        (AlgebraicType::String, JsonValue::String(value)) => AlgebraicValue::String(value.clone()),
+        (AlgebraicType::Bytes, JsonValue::String(hex)) => AlgebraicValue::Bytes(decode_hex(hex)?),
+        (AlgebraicType::Array(element_type), JsonValue::Array(values)) => AlgebraicValue::Array(
+            values
+                .iter()
+                .map(|value| decode(element_type, value))
+                .collect::<Result<_, _>>()?,
+        ),
+        (AlgebraicType::Product(ty), json) => AlgebraicValue::Product(decode_product(ty, json)?),
+        (AlgebraicType::Sum(ty), json) => AlgebraicValue::Sum(decode_sum(ty, json)?),
+        _ => return Err(mismatch(ty, json)),
+    })
+}
+
+pub fn decode_product(ty: &ProductType, json: &JsonValue) -> Result<ProductValue, JsonError> {
+    let elements = match json {
+        JsonValue::Array(values) if values.len() == ty.elements.len() => values
+            .iter()
+            .zip(&ty.elements)
+            .map(|(value, element)| decode(&element.algebraic_type, value))
+            .collect::<Result<_, _>>()?,
+        JsonValue::Object(fields) => {
+            if let Some((name, _)) = fields.iter().find(|(name, _)| ty.index_of(name).is_none()) {
+                return Err(JsonError::UnknownField(name.clone()));
+            }
+            ty.elements
+                .iter()
+                .map(|element| {
+                    let (_, value) = fields
+                        .iter()
+                        .find(|(name, _)| *name == element.name)
+                        .ok_or_else(|| JsonError::MissingField(element.name.clone()))?;
+                    decode(&element.algebraic_type, value)
+                })
+                .collect::<Result<_, _>>()?
+        }
+        _ => return Err(mismatch(&AlgebraicType::Product(ty.clone()), json)),
+    };
+    Ok(ProductValue::new(elements))
+}
+
+fn decode_sum(ty: &SumType, json: &JsonValue) -> Result<SumValue, JsonError> {
+    match json {
+        JsonValue::Object(fields) if fields.len() == 1 => {
+            let (name, value) = &fields[0];
+            let tag = ty
+                .tag_of(name)
+                .ok_or_else(|| JsonError::UnknownVariant(name.clone()))?;
+            let variant = &ty.variants[tag as usize];
+            Ok(SumValue::new(tag, decode(&variant.algebraic_type, value)?))
+        }
+        _ => Err(mismatch(&AlgebraicType::Sum(ty.clone()), json)),
+    }
+}
+
+fn parse_number<T: core::str::FromStr>(ty: &AlgebraicType, text: &str) -> Result<T, JsonError> {
+    text.parse()
+        .map_err(|_| JsonError::Mismatch(format!("{} is not a valid {:?}", text, ty)))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, JsonError> {
+    let invalid = || JsonError::Mismatch(format!("\"{}\" is not a hex string", hex));
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn mismatch(ty: &AlgebraicType, json: &JsonValue) -> JsonError {
+    JsonError::Mismatch(format!("expected {:?}, found {:?}", ty, json))
+}
+
+/// Parses a JSON document.
+pub fn parse(text: &str) -> Result<JsonValue, JsonError> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position != parser.bytes.len() {
+        return Err(JsonError::Syntax(parser.position));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// Arrays and objects the value being parsed is in.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax(self.position)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        if !self.bytes[self.position..].starts_with(literal.as_bytes()) {
+            return Err(self.error());
+        }
+        self.position += literal.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<JsonValue, JsonError> {
+        self.skip_whitespace();
+        if let Some(b'[' | b'{') = self.peek() {
+            if self.depth == MAX_DEPTH {
+                return Err(JsonError::TooDeep(self.position));
+            }
+            self.depth += 1;
+            let value = self.container();
+            self.depth -= 1;
+            return value;
+        }
+        match self.peek().ok_or_else(|| self.error())? {
+            b'n' => self.literal("null", JsonValue::Null),
+            b't' => self.literal("true", JsonValue::Bool(true)),
+            b'f' => self.literal("false", JsonValue::Bool(false)),
+            b'"' => Ok(JsonValue::String(self.string()?)),
+            b'-' | b'0'..=b'9' => {
+                let start = self.position;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+                    self.position += 1;
+                }
+                // Only ASCII was consumed, so this can't split a character
+                let text = core::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+                Ok(JsonValue::Number(text.to_string()))
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Parses an array or an object.
+    fn container(&mut self) -> Result<JsonValue, JsonError> {
+        match self.peek().ok_or_else(|| self.error())? {
+            b'[' => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(JsonValue::Array(values));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            b'{' => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(JsonValue::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(JsonValue::Object(fields));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Parses the 4 hex digits of a `\u` escape.
+    fn hex_escape(&mut self) -> Result<u32, JsonError> {
+        let hex = self
+            .bytes
+            .get(self.position..self.position + 4)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .ok_or_else(|| self.error())?;
+        // Hex digits are ASCII
+        let hex = core::str::from_utf8(hex).unwrap();
+        self.position += 4;
+        Ok(u32::from_str_radix(hex, 16).unwrap())
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error());
+        }
+        self.position += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.position;
+            while let Some(byte) = self.peek() {
+                if byte == b'"' || byte == b'\\' {
+                    break;
+                }
+                self.position += 1;
+            }
+            // Stopped on an ASCII byte or the end of the input, both are char boundaries
+            out.push_str(
+                core::str::from_utf8(&self.bytes[start..self.position])
+                    .map_err(|_| self.error())?,
+            );
+            match self.peek() {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escaped = self.peek().ok_or_else(|| self.error())?;
+                    self.position += 1;
+                    out.push(match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex_escape()?;
+                            // Characters outside the BMP are escaped as a surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                if !self.bytes[self.position..].starts_with(b"\\u") {
+                                    return Err(self.error());
+                                }
+                                self.position += 2;
+                                let low = self.hex_escape()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error());
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or_else(|| self.error())?
+                        }
+                        _ => return Err(self.error()),
+                    });
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_schema() -> ProductType {
+    use super::value::ProductTypeElement;
+
+    ProductType::new(alloc::vec![
+        ProductTypeElement::new("name", AlgebraicType::String),
+        ProductTypeElement::new("hash", AlgebraicType::Bytes),
+        ProductTypeElement::new("level", AlgebraicType::option(AlgebraicType::U32)),
+    ])
+}
+
+#[test_case]
+fn test_json_round_trip() {
+    let schema = test_schema();
+    let row = ProductValue::new(alloc::vec![
+        AlgebraicValue::String(String::from("a \"quoted\" name")),
+        AlgebraicValue::Bytes(alloc::vec![0x00, 0xab, 0xff]),
+        AlgebraicValue::Sum(SumValue::new(0, AlgebraicValue::U32(7))),
+    ]);
+    let text = to_string(&schema, &row).unwrap();
+    assert_eq!(
+        text,
+        r#"{"name":"a \"quoted\" name","hash":"00abff","level":{"some":7}}"#
+    );
+    assert_eq!(from_str(&schema, &text), Ok(row));
+
+    let ty = AlgebraicType::F64;
+    let mut text = String::new();
+    encode(&ty, &AlgebraicValue::F64(f64::INFINITY), &mut text).unwrap();
+    assert_eq!(text, "null");
+    let decoded = decode(&ty, &parse(&text).unwrap()).unwrap();
+    assert_eq!(decoded, AlgebraicValue::F64(f64::NAN));
+
+    let invalid =
+        |row: ProductValue| matches!(to_string(&schema, &row), Err(JsonError::InvalidValue(_)));
+    assert!(invalid(ProductValue::new(alloc::vec![
+        AlgebraicValue::U32(7)
+    ])));
+    let mut row = ProductValue::new(alloc::vec![
+        AlgebraicValue::String(String::new()),
+        AlgebraicValue::Bytes(Vec::new()),
+        AlgebraicValue::Sum(SumValue::new(2, AlgebraicValue::U32(7))),
+    ]);
+    assert!(invalid(row.clone()));
+    row.elements[2] = AlgebraicValue::Array(Vec::new());
+    assert!(invalid(row));
+}
+
+#[test_case]
+fn test_json_decode_errors() {
+    let schema = test_schema();
+    assert_eq!(
+        from_str(&schema, r#"{"name":"x","hash":"0g","level":{"none":{}}}"#),
+        Err(JsonError::Mismatch(String::from(
+            "\"0g\" is not a hex string"
+        )))
+    );
+    assert_eq!(
+        from_str(&schema, r#"{"name":"x","hash":"","level":{"all":1}}"#),
+        Err(JsonError::UnknownVariant(String::from("all")))
+    );
+    assert_eq!(
+        from_str(&schema, r#"["x","",{"none":[]}]"#).map(|row| row.elements.len()),
+        Ok(3)
+    );
+    assert_eq!(from_str(&schema, "{"), Err(JsonError::Syntax(1)));
+
+    let string = |text| parse(text).map(|json| decode(&AlgebraicType::String, &json));
+    let smiley = AlgebraicValue::String(String::from("\u{1f600}"));
+    assert_eq!(string(r#""\ud83d\ude00""#), Ok(Ok(smiley)));
+    assert_eq!(string(r#""\ud83d""#), Err(JsonError::Syntax(7)));
+    assert_eq!(string(r#""\u+0041""#), Err(JsonError::Syntax(3)));
+    let nested = "[".repeat(MAX_DEPTH + 1);
+    assert_eq!(parse(&nested), Err(JsonError::TooDeep(MAX_DEPTH)));
+    let nested = alloc::format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+    assert!(parse(&nested).is_ok());
+}
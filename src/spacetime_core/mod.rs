@@ -0,0 +1,2080 @@
+pub mod abi;
+pub mod atomic;
+pub mod audit;
+pub mod binary;
+pub mod buffer_pool;
+pub mod call;
+pub mod csv;
+pub mod def;
+pub mod foreign_key;
+pub mod history;
+pub mod join;
+pub mod json;
+pub mod page;
+pub mod query;
+pub mod replay;
+pub mod rtree;
+pub mod subscription;
+pub mod system;
+pub mod table;
+pub mod text;
+pub mod transaction;
+pub mod value;
+pub mod view;
+pub mod wasm_host;
+
+#[cfg(test)]
+mod test_util;
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    string::String,
+    vec::Vec,
+};
+use core::cell::{Ref, RefCell};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::allocator::account::{self, Account};
+use crate::block::BlockDevice;
+use crate::interrupts;
+use crate::println;
+use crate::task::Task;
+use crate::task::executor::{Executor, Spawner};
+use crate::task::scheduler::{FairQueue, Priority, Tenant};
+use crate::wasm;
+use crate::watchdog::{self, TimedOut};
+use atomic::{AtomicCall, AtomicError};
+use audit::{AuditEntry, AuditLog};
+use binary::DecodeError;
+use buffer_pool::{BufferPool, StorageError};
+use call::ModuleCall;
+use csv::CsvError;
+use def::{Lifecycle, ModuleDef, ModuleDefError, OnDelete, ReducerDef, TableDef};
+use history::{Commit, CommitLog, HistoryError, RestoreError, RestorePoint, RowWrite, Snapshot};
+use json::JsonError;
+use query::{Query, QueryError, QueryResult};
+use subscription::{Deltas, Subscription, SubscriptionUpdate};
+use table::{RowId, Table, TableError};
+use transaction::{Transaction, Write};
+use value::{AlgebraicValue, ProductType, ProductValue, SumValue};
+use view::{View, ViewDef};
+use wasm_host::WasmModuleError;
+
+pub struct SpacetimeCore {
+    users: BTreeMap<u64, User>,
+    modules: BTreeMap<u64, Module>,
+    /// Reducer calls queued by [`SpacetimeCore::queue_reducer`].
+    queue: FairQueue<QueuedCall>,
+    executor: Executor,
+    spawner: Spawner,
+}
+
+impl SpacetimeCore {
+    pub fn new() -> SpacetimeCore {
+        let executor = Executor::new();
+        SpacetimeCore {
+            users: BTreeMap::new(),
+            modules: BTreeMap::new(),
+            queue: FairQueue::new(),
+            spawner: Spawner::new(&executor),
+            executor,
+        }
+    }
+
+    pub fn set_user(&mut self, user: User) {
+        self.users.insert(user.id, user);
+    }
+
+    pub fn delete_user(&mut self, user_id: &u64) -> Option<User> {
+        self.users.remove(user_id)
+    }
+
+    pub fn delete_module(&mut self, module_id: &u64) -> Option<Module> {
+        self.modules.remove(module_id)
+    }
+
+    /// Publishes `module`, running its init reducer first as `publisher`.
+    ///
+    /// The module is not published if its init reducer fails.
+    pub fn publish_module(
+        &mut self,
+        mut module: Module,
+        publisher: u64,
+    ) -> Result<(), ReducerCallError> {
+        if let Some(init) = module.lifecycle_reducer(Lifecycle::Init) {
+            let init = String::from(init);
+            module.call_reducer(&init, publisher, ProductValue::new(Vec::new()))?;
+        }
+        self.modules.insert(module.id, module);
+        call::deliver(&mut self.modules);
+        Ok(())
+    }
+
+    /// Loads a WebAssembly module and publishes it, returning the new module's id.
+    pub fn publish_wasm_module(
+        &mut self,
+        bytes: &[u8],
+        publisher: u64,
+    ) -> Result<u64, WasmModuleError> {
+        let module = wasm_host::load(bytes)?;
+        let module_id = module.id;
+        self.publish_module(module, publisher)
+            .map_err(WasmModuleError::Init)?;
+        Ok(module_id)
+    }
+
+    /// Runs the module's client connected reducer, if it has one.
+    pub fn connect_client(
+        &mut self,
+        module_id: &u64,
+        user_id: u64,
+    ) -> Result<(), ReducerCallError> {
+        self.call_lifecycle_reducer(module_id, Lifecycle::ClientConnected, user_id)
+    }
+
+    /// Runs the module's client disconnected reducer, if it has one.
+    pub fn disconnect_client(
+        &mut self,
+        module_id: &u64,
+        user_id: u64,
+    ) -> Result<(), ReducerCallError> {
+        self.call_lifecycle_reducer(module_id, Lifecycle::ClientDisconnected, user_id)
+    }
+
+    fn call_lifecycle_reducer(
+        &mut self,
+        module_id: &u64,
+        lifecycle: Lifecycle,
+        caller: u64,
+    ) -> Result<(), ReducerCallError> {
+        let module = self
+            .modules
+            .get_mut(module_id)
+            .ok_or(ReducerCallError::NoSuchModule(*module_id))?;
+        match module.lifecycle_reducer(lifecycle) {
+            Some(reducer) => {
+                let reducer = String::from(reducer);
+                let outcome = module.call_reducer(&reducer, caller, ProductValue::new(Vec::new()));
+                call::deliver(&mut self.modules);
+                outcome
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Restores a module's tables to their state at `point`, see [`Module::restore`].
+    pub fn restore_module(
+        &mut self,
+        module_id: &u64,
+        point: RestorePoint,
+    ) -> Result<(), RestoreError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(RestoreError::NoSuchModule(*module_id))?
+            .restore(point)
+    }
+
+    /// Publishes a copy of a module named `name` with its tables in their state at `point`,
+    /// returning the new module's id.
+    ///
+    /// The copy's init reducer isn't run, its tables being restored already.
+    pub fn restore_module_as(
+        &mut self,
+        module_id: &u64,
+        point: RestorePoint,
+        name: String,
+    ) -> Result<u64, RestoreError> {
+        let module = self
+            .modules
+            .get(module_id)
+            .ok_or(RestoreError::NoSuchModule(*module_id))?
+            .restored(point, name)?;
+        let module_id = module.id;
+        self.modules.insert(module_id, module);
+        Ok(module_id)
+    }
+
+    /// Inserts a batch of encoded rows into a table as `caller`, see [`Module::bulk_insert`].
+    pub fn bulk_insert(
+        &mut self,
+        module_id: &u64,
+        table_name: &str,
+        caller: u64,
+        rows: &[u8],
+    ) -> Result<usize, BulkInsertError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(BulkInsertError::NoSuchModule(*module_id))?
+            .bulk_insert(table_name, caller, rows)
+    }
+
+    /// Encodes the rows of a table, or of a system table, as CSV.
+    pub fn export_csv(&self, module_id: &u64, table_name: &str) -> Result<String, QueryError> {
+        let result = self.query(module_id, &Query::table(table_name))?;
+        csv::rows_to_string(&result.schema, &result.rows).map_err(QueryError::Encode)
+    }
+
+    /// Inserts the records of CSV into a table as `caller`, reporting the ones rejected.
+    pub fn import_csv(
+        &mut self,
+        module_id: &u64,
+        table_name: &str,
+        caller: u64,
+        text: &str,
+    ) -> Result<CsvImport, ImportError> {
+        let module = self
+            .modules
+            .get_mut(module_id)
+            .ok_or(ImportError::NoSuchModule(*module_id))?;
+        let columns = module
+            .table(table_name)
+            .ok_or_else(|| ImportError::Table(TableError::NoSuchTable(String::from(table_name))))?
+            .columns();
+        let mut rejected = Vec::new();
+        let mut lines = Vec::new();
+        let mut rows = Vec::new();
+        for record in csv::from_str(columns, text).map_err(ImportError::Csv)? {
+            match record.row {
+                Ok(row) => {
+                    lines.push(record.line);
+                    rows.push(row);
+                }
+                Err(error) => rejected.push((record.line, ImportError::Csv(error))),
+            }
+        }
+        let inserted = rows.len();
+        let failed = module
+            .insert_rows(table_name, caller, rows)
+            .map_err(ImportError::Table)?;
+        let inserted = inserted - failed.len();
+        for (position, error) in failed {
+            rejected.push((lines[position], ImportError::Table(error)));
+        }
+        rejected.sort_by_key(|(line, _)| *line);
+        Ok(CsvImport { inserted, rejected })
+    }
+
+    pub fn module(&self, module_id: &u64) -> Option<&Module> {
+        self.modules.get(module_id)
+    }
+
+    pub fn call_reducer(
+        &mut self,
+        module_id: &u64,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+    ) -> Result<(), ReducerCallError> {
+        let outcome = self
+            .modules
+            .get_mut(module_id)
+            .ok_or(ReducerCallError::NoSuchModule(*module_id))?
+            .call_reducer(reducer_name, caller, args);
+        call::deliver(&mut self.modules);
+        outcome
+    }
+
+    /// Runs reducer calls in several modules as `caller`, committing all of them or none, see
+    /// [`atomic`].
+    pub fn call_atomic(&mut self, calls: Vec<AtomicCall>, caller: u64) -> Result<(), AtomicError> {
+        let outcome = atomic::run(&mut self.modules, calls, caller);
+        call::deliver(&mut self.modules);
+        outcome
+    }
+
+    /// Queues a reducer call, run by [`SpacetimeCore::run_queued`] in turn with the calls queued
+    /// for other modules and callers.
+    pub fn queue_reducer(
+        &mut self,
+        module_id: &u64,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+    ) -> Result<(), ReducerCallError> {
+        if !self.modules.contains_key(module_id) {
+            return Err(ReducerCallError::NoSuchModule(*module_id));
+        }
+        let call = QueuedCall {
+            module_id: *module_id,
+            reducer: String::from(reducer_name),
+            caller,
+            args,
+        };
+        self.queue.push(Tenant::new(*module_id, caller), call);
+        Ok(())
+    }
+
+    /// Reducer calls queued and not run yet.
+    pub fn queued_calls(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Runs up to `limit` queued reducer calls, in the fair order of [`FairQueue`], returning
+    /// them with their outcomes.
+    pub fn run_queued(&mut self, limit: usize) -> Vec<(QueuedCall, Result<(), ReducerCallError>)> {
+        let mut ran = Vec::new();
+        while ran.len() < limit
+            && let Some((_, call)) = self.queue.pop()
+        {
+            let outcome = self.call_reducer(
+                &call.module_id,
+                &call.reducer,
+                call.caller,
+                call.args.clone(),
+            );
+            ran.push((call, outcome));
+        }
+        ran
+    }
+
+    /// Spawns a task run on behalf of `caller` of the module, polled by [`SpacetimeCore::run`]
+    /// in turn with the tasks of other modules and callers.
+    pub fn spawn(
+        &mut self,
+        module_id: &u64,
+        caller: u64,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Result<(), ReducerCallError> {
+        if !self.modules.contains_key(module_id) {
+            return Err(ReducerCallError::NoSuchModule(*module_id));
+        }
+        let tenant = Tenant::new(*module_id, caller);
+        self.spawner.spawn(Task::for_tenant(tenant, future));
+        Ok(())
+    }
+
+    /// Sets how many calls and tasks of the module run in a row on its turn, see
+    /// [`FairQueue::set_weight`].
+    pub fn set_module_weight(&mut self, module_id: u64, weight: u32) {
+        self.queue.set_weight(module_id, weight);
+        self.executor.set_weight(module_id, weight);
+    }
+
+    /// Sets the priority of the module's calls and tasks, see [`FairQueue::set_priority`].
+    pub fn set_module_priority(&mut self, module_id: u64, priority: Priority) {
+        self.queue.set_priority(module_id, priority);
+        self.executor.set_priority(module_id, priority);
+    }
+
+    /// Lets the module `caller_module` call `reducer` of a module, see [`Module::grant`].
+    pub fn grant(
+        &mut self,
+        module_id: &u64,
+        caller_module: u64,
+        reducer: &str,
+    ) -> Result<(), ReducerCallError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(ReducerCallError::NoSuchModule(*module_id))?
+            .grant(caller_module, reducer)
+    }
+
+    /// Takes back a grant, returning whether there was one, see [`Module::revoke`].
+    pub fn revoke(&mut self, module_id: &u64, caller_module: u64, reducer: &str) -> bool {
+        self.modules
+            .get_mut(module_id)
+            .is_some_and(|module| module.revoke(caller_module, reducer))
+    }
+
+    /// Calls a reducer with its arguments given as a JSON object or array.
+    pub fn call_reducer_json(
+        &mut self,
+        module_id: &u64,
+        reducer_name: &str,
+        caller: u64,
+        args: &str,
+    ) -> Result<(), ReducerCallError> {
+        let params = self.reducer_params(module_id, reducer_name)?;
+        let args = json::from_str(params, args).map_err(ReducerCallError::Json)?;
+        self.call_reducer(module_id, reducer_name, caller, args)
+    }
+
+    /// Calls a reducer with its arguments in the binary format.
+    pub fn call_reducer_binary(
+        &mut self,
+        module_id: &u64,
+        reducer_name: &str,
+        caller: u64,
+        args: &[u8],
+    ) -> Result<(), ReducerCallError> {
+        let params = self.reducer_params(module_id, reducer_name)?;
+        let args = binary::from_bytes(params, args).map_err(ReducerCallError::Decode)?;
+        self.call_reducer(module_id, reducer_name, caller, args)
+    }
+
+    fn reducer_params(
+        &self,
+        module_id: &u64,
+        reducer_name: &str,
+    ) -> Result<&ProductType, ReducerCallError> {
+        let module = self
+            .modules
+            .get(module_id)
+            .ok_or(ReducerCallError::NoSuchModule(*module_id))?;
+        let reducer = module
+            .reducer(reducer_name)
+            .ok_or_else(|| ReducerCallError::NoSuchReducer(String::from(reducer_name)))?;
+        Ok(&reducer.def.params)
+    }
+
+    /// Runs `query` in a module, or against the core's [system tables](system) of modules and
+    /// users.
+    pub fn query(&self, module_id: &u64, query: &Query) -> Result<QueryResult, QueryError> {
+        self.query_with(module_id, query, None)
+    }
+
+    /// Runs `query` as [`SpacetimeCore::query`], reading the module's views as `caller`.
+    pub fn query_as(
+        &self,
+        module_id: &u64,
+        query: &Query,
+        caller: u64,
+    ) -> Result<QueryResult, QueryError> {
+        self.query_with(module_id, query, Some(caller))
+    }
+
+    fn query_with(
+        &self,
+        module_id: &u64,
+        query: &Query,
+        caller: Option<u64>,
+    ) -> Result<QueryResult, QueryError> {
+        if query.join.is_none()
+            && let Some(system) = system::core_table(self, &query.table)
+        {
+            return query.run(&system.schema, system.rows.into_iter());
+        }
+        let module = self
+            .modules
+            .get(module_id)
+            .ok_or(QueryError::NoSuchModule(*module_id))?;
+        match caller {
+            Some(caller) => module.query_as(query, caller),
+            None => module.query(query),
+        }
+    }
+
+    /// Subscribes to `query` in a module, see [`Module::subscribe`].
+    pub fn subscribe(
+        &mut self,
+        module_id: &u64,
+        query: &Query,
+    ) -> Result<(u64, QueryResult), QueryError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(QueryError::NoSuchModule(*module_id))?
+            .subscribe(query)
+    }
+
+    /// Subscribes to `query` in a module as `caller`, see [`Module::subscribe_as`].
+    pub fn subscribe_as(
+        &mut self,
+        module_id: &u64,
+        query: &Query,
+        caller: u64,
+    ) -> Result<(u64, QueryResult), QueryError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(QueryError::NoSuchModule(*module_id))?
+            .subscribe_as(query, caller)
+    }
+
+    /// Takes the updates of a subscription in a module, see [`Module::poll_subscription`].
+    pub fn poll_subscription(
+        &mut self,
+        module_id: &u64,
+        id: u64,
+    ) -> Result<Vec<SubscriptionUpdate>, QueryError> {
+        self.modules
+            .get_mut(module_id)
+            .ok_or(QueryError::NoSuchModule(*module_id))?
+            .poll_subscription(id)
+    }
+
+    /// Energy used by the reducer calls of `caller` in every module.
+    pub fn energy_used_by(&self, caller: u64) -> u64 {
+        self.modules
+            .values()
+            .map(|module| module.energy_used_by(caller))
+            .sum()
+    }
+
+    /// Runs the scheduled reducer calls that are due in every module.
+    pub fn run_scheduled(&mut self) -> Vec<(u64, ScheduledCall, ReducerCallError)> {
+        let now = interrupts::ticks();
+        let mut failed = Vec::new();
+        for (module_id, module) in &mut self.modules {
+            failed.extend(
+                module
+                    .run_scheduled(now)
+                    .into_iter()
+                    .map(|(call, error)| (*module_id, call, error)),
+            );
+        }
+        call::deliver(&mut self.modules);
+        failed
+    }
+
+    pub fn run(&mut self) {
+        self.executor.run();
+    }
+
+    /// Polls the tasks ready to make progress until none are left, without waiting for others.
+    pub fn run_ready_tasks(&mut self) {
+        self.executor.run_ready_tasks();
+    }
+}
+
+pub struct User {
+    id: u64,
+    name: String,
+}
+
+impl User {
+    pub fn new(name: String) -> User {
+        static NEXT_USER_ID: AtomicU64 = AtomicU64::new(0);
+        User {
+            id: NEXT_USER_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReducerCallError {
+    NoSuchModule(u64),
+    NoSuchReducer(String),
+    /// Arguments don't match the reducer's parameters.
+    InvalidArguments,
+    Json(JsonError),
+    Decode(DecodeError),
+    /// The reducer returned an error, its writes were rolled back.
+    Failed(String),
+    /// The reducer used more energy than its budget, its writes were rolled back.
+    OutOfEnergy,
+    /// The reducer ran for longer than its module's timeout, its writes were rolled back.
+    Timeout,
+    /// The reducer went past its module's memory quota, its writes were rolled back.
+    OutOfQuota,
+    /// The calling module wasn't granted the named reducer, see [`Module::grant`].
+    NotGranted(String),
+    /// The call was made by a chain of more than [`call::MAX_CALL_DEPTH`] module calls.
+    CallTooDeep,
+    /// Another call of its atomic transaction failed, its writes were rolled back.
+    Aborted,
+    /// A page of rows the reducer wrote couldn't be read back from the module's block device
+    /// to log or undo them, its writes were rolled back as far as they could be.
+    Storage(StorageError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    NoSuchModule(u64),
+    Table(TableError),
+    Csv(CsvError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkInsertError {
+    NoSuchModule(u64),
+    Decode(DecodeError),
+    Table(TableError),
+}
+
+/// Outcome of [`SpacetimeCore::import_csv`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvImport {
+    pub inserted: usize,
+    /// Records that weren't inserted, by line.
+    pub rejected: Vec<(usize, ImportError)>,
+}
+
+/// Energy a reducer call may use, unless its module sets another budget.
+pub const DEFAULT_ENERGY_BUDGET: u64 = 1_000_000;
+/// Timer ticks a reducer call may run for, unless its module sets another timeout.
+///
+/// About a second at the timer's default rate of 18.2 Hz.
+pub const DEFAULT_TIMEOUT: u64 = 18;
+/// Bytes of heap a module may use, unless it sets another quota.
+pub const DEFAULT_MEMORY_QUOTA: usize = 32 * 1024;
+/// Energy charged for each row inserted, deleted or updated.
+pub const ROW_WRITE_ENERGY: u64 = 100;
+/// Energy charged for each row a guest reads through the host ABI.
+pub const ROW_READ_ENERGY: u64 = 10;
+
+/// A reducer's code, run with the call's decoded arguments until it returns.
+///
+/// Not a future: a call's writes are committed or rolled back as soon as it returns, so nothing
+/// may touch its tables while it's suspended, and its arguments, from JSON or the binary format,
+/// must be decoded against its parameters before it runs.
+pub type ReducerCode = dyn Fn(&mut ReducerContext, ProductValue) -> Result<(), String>;
+pub type ReducerFn = Box<ReducerCode>;
+
+pub struct Reducer {
+    def: ReducerDef,
+    function: Rc<ReducerCode>,
+}
+
+impl Reducer {
+    pub fn name(&self) -> &str {
+        &self.def.name
+    }
+
+    pub fn params(&self) -> &ProductType {
+        &self.def.params
+    }
+
+    pub fn def(&self) -> &ReducerDef {
+        &self.def
+    }
+}
+
+/// Reducer call queued by [`ReducerContext::schedule`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledCall {
+    pub reducer: String,
+    pub args: ProductValue,
+    pub caller: u64,
+    /// Tick from which the call is due.
+    pub at: u64,
+}
+
+/// Reducer call queued by [`SpacetimeCore::queue_reducer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedCall {
+    pub module_id: u64,
+    pub reducer: String,
+    pub caller: u64,
+    pub args: ProductValue,
+}
+
+/// A reducer that ran in a transaction not yet committed or rolled back, see
+/// [`Module::prepare`].
+pub(super) struct PreparedCall {
+    ran: RanReducer,
+    start: u64,
+    args_hash: u64,
+}
+
+struct RanReducer {
+    reducer: String,
+    caller: u64,
+    args: ProductValue,
+    now: u64,
+    tx: Transaction,
+    /// Rows as `tx` left them, logged once it commits.
+    writes: Vec<RowWrite>,
+    deltas: Deltas,
+    scheduled: Vec<ScheduledCall>,
+    calls: Vec<ModuleCall>,
+}
+
+/// Access to the module's tables from inside a reducer call.
+pub struct ReducerContext<'a> {
+    caller: u64,
+    now: u64,
+    module_id: u64,
+    module_name: &'a str,
+    tables: &'a mut BTreeMap<u64, Table>,
+    reducers: &'a BTreeMap<u64, Reducer>,
+    tx: Transaction,
+    scheduled: Vec<ScheduledCall>,
+    /// Module whose call or reply runs the reducer, and the calls that led to it.
+    calling: Option<(u64, u32)>,
+    calls: Vec<ModuleCall>,
+    next_call_id: u64,
+    energy_budget: u64,
+    energy_used: u64,
+    out_of_energy: bool,
+}
+
+impl ReducerContext<'_> {
+    /// Id of the user who called the reducer.
+    pub fn caller(&self) -> u64 {
+        self.caller
+    }
+
+    /// Tick at which the reducer was called.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Energy the reducer can still use.
+    pub fn energy_left(&self) -> u64 {
+        self.energy_budget - self.energy_used
+    }
+
+    /// Uses `amount` energy, failing once the call's budget is exhausted.
+    ///
+    /// The call is rolled back if any charge failed, even if the reducer then succeeds.
+    pub fn charge(&mut self, amount: u64) -> Result<(), TableError> {
+        match self.energy_used.checked_add(amount) {
+            Some(used) if used <= self.energy_budget => {
+                self.energy_used = used;
+                Ok(())
+            }
+            _ => {
+                self.energy_used = self.energy_budget;
+                self.out_of_energy = true;
+                Err(TableError::OutOfEnergy)
+            }
+        }
+    }
+
+    pub fn log(&self, message: &str) {
+        println!("[{}] {}", self.module_name, message);
+    }
+
+    pub fn reducer(&self, name: &str) -> Option<&Reducer> {
+        self.reducers
+            .values()
+            .find(|reducer| reducer.name() == name)
+    }
+
+    /// Calls `reducer` as the same caller once `delay` ticks have passed.
+    ///
+    /// The call is dropped if this reducer fails.
+    pub fn schedule(
+        &mut self,
+        reducer: &str,
+        args: ProductValue,
+        delay: u64,
+    ) -> Result<(), ReducerCallError> {
+        let params = self
+            .reducer(reducer)
+            .ok_or_else(|| ReducerCallError::NoSuchReducer(String::from(reducer)))?
+            .params();
+        if !args.has_type(params) {
+            return Err(ReducerCallError::InvalidArguments);
+        }
+        let call = ScheduledCall {
+            reducer: String::from(reducer),
+            args,
+            caller: self.caller,
+            at: self.now.saturating_add(delay),
+        };
+        watchdog::critical(|| self.scheduled.push(call));
+        Ok(())
+    }
+
+    /// Id of the module whose [call](call) or reply runs the reducer, if any.
+    pub fn calling_module(&self) -> Option<u64> {
+        self.calling.map(|(module_id, _)| module_id)
+    }
+
+    /// Calls `reducer` of the module `module_id` as the same caller, once this reducer
+    /// committed, returning the call's id.
+    ///
+    /// The call is dropped if this reducer fails. Its outcome is passed to the `reply` reducer,
+    /// which takes [`call::reply_params`].
+    pub fn call_module(
+        &mut self,
+        module_id: u64,
+        reducer: &str,
+        args: ProductValue,
+        reply: Option<&str>,
+    ) -> Result<u64, ReducerCallError> {
+        if let Some(reply) = reply {
+            let params = self
+                .reducer(reply)
+                .ok_or_else(|| ReducerCallError::NoSuchReducer(String::from(reply)))?
+                .params();
+            if *params != call::reply_params() {
+                return Err(ReducerCallError::InvalidArguments);
+            }
+        }
+        let id = self.next_call_id;
+        let call = ModuleCall {
+            id,
+            from: self.module_id,
+            module: module_id,
+            reducer: String::from(reducer),
+            args,
+            caller: self.caller,
+            reply: reply.map(String::from),
+            depth: self.calling.map_or(0, |(_, depth)| depth + 1),
+        };
+        watchdog::critical(|| {
+            self.calls.push(call);
+            self.next_call_id += 1;
+        });
+        Ok(id)
+    }
+
+    pub fn table(&self, name: &str) -> Result<&Table, TableError> {
+        self.tables
+            .values()
+            .find(|table| table.name() == name)
+            .ok_or_else(|| TableError::NoSuchTable(String::from(name)))
+    }
+
+    /// Inserts a row, failing if one of its foreign keys refers to a missing row.
+    pub fn insert(&mut self, table_name: &str, row: ProductValue) -> Result<RowId, TableError> {
+        let table_id = self.table(table_name)?.id();
+        self.write(|ctx| {
+            ctx.charge(ROW_WRITE_ENERGY)?;
+            let table = ctx.tables.get_mut(&table_id).expect("table was dropped");
+            let row_id = table.insert(row)?;
+            ctx.tx.record(Write::Insert { table_id, row_id });
+            if account::exceeded() {
+                return Err(TableError::OutOfQuota);
+            }
+            let table = &ctx.tables[&table_id];
+            foreign_key::check(
+                ctx.tables,
+                table,
+                &table.get(row_id)?.expect("row was dropped"),
+            )?;
+            Ok(row_id)
+        })
+    }
+
+    /// Deletes a row, returning it, or `None` if there was no row.
+    ///
+    /// Rows referring to it by foreign key are deleted or updated as their foreign key says,
+    /// and the whole delete is undone if one of them restricts it.
+    pub fn delete(
+        &mut self,
+        table_name: &str,
+        row_id: RowId,
+    ) -> Result<Option<ProductValue>, TableError> {
+        let table_id = self.table(table_name)?.id();
+        self.write(|ctx| ctx.delete_row(table_id, row_id))
+    }
+
+    /// Replaces a row, returning the old row, or `None` if there was no row.
+    ///
+    /// Fails if a foreign key of the new row refers to a missing row, or if rows refer to the
+    /// old row by values the new row changes.
+    pub fn update(
+        &mut self,
+        table_name: &str,
+        row_id: RowId,
+        row: ProductValue,
+    ) -> Result<Option<ProductValue>, TableError> {
+        let table_id = self.table(table_name)?.id();
+        self.write(|ctx| ctx.update_row(table_id, row_id, row))
+    }
+
+    /// Runs a write, undoing what it wrote if it fails.
+    fn write<T>(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<T, TableError>,
+    ) -> Result<T, TableError> {
+        // A timeout must not abort a write before it's recorded for rollback
+        watchdog::critical(|| {
+            let len = self.tx.writes().len();
+            let result = write(self);
+            if result.is_err() {
+                self.tx.rollback_to(len, self.tables)?;
+            }
+            result
+        })
+    }
+
+    fn delete_row(
+        &mut self,
+        table_id: u64,
+        row_id: RowId,
+    ) -> Result<Option<ProductValue>, TableError> {
+        self.charge(ROW_WRITE_ENERGY)?;
+        let table = self.tables.get_mut(&table_id).expect("table was dropped");
+        let Some(row) = table.delete(row_id)? else {
+            return Ok(None);
+        };
+        self.tx.record(Write::Delete {
+            table_id,
+            row_id,
+            row: row.clone(),
+        });
+        for referencing in foreign_key::referencing(self.tables, table_id, &row) {
+            for referencing_row in referencing.row_ids {
+                match referencing.on_delete {
+                    OnDelete::Restrict => {
+                        return Err(TableError::ForeignKeyViolation(referencing.name));
+                    }
+                    OnDelete::Cascade => {
+                        self.delete_row(referencing.table_id, referencing_row)?;
+                    }
+                    OnDelete::SetNull => {
+                        let table = &self.tables[&referencing.table_id];
+                        let Some(mut row) = table.get(referencing_row)? else {
+                            continue;
+                        };
+                        for column in &referencing.columns {
+                            row.elements[*column as usize] =
+                                AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit()));
+                        }
+                        self.update_row(referencing.table_id, referencing_row, row)?;
+                    }
+                }
+            }
+        }
+        Ok(Some(row))
+    }
+
+    fn update_row(
+        &mut self,
+        table_id: u64,
+        row_id: RowId,
+        row: ProductValue,
+    ) -> Result<Option<ProductValue>, TableError> {
+        self.charge(ROW_WRITE_ENERGY)?;
+        let table = self.tables.get_mut(&table_id).expect("table was dropped");
+        let Some(old) = table.update(row_id, row)? else {
+            return Ok(None);
+        };
+        self.tx.record(Write::Update {
+            table_id,
+            row_id,
+            row: old.clone(),
+        });
+        if account::exceeded() {
+            return Err(TableError::OutOfQuota);
+        }
+        let table = &self.tables[&table_id];
+        foreign_key::check(
+            self.tables,
+            table,
+            &table.get(row_id)?.expect("row was dropped"),
+        )?;
+        // Rows referring to the old row must still find it
+        for referencing in foreign_key::referencing(self.tables, table_id, &old) {
+            let table = &self.tables[&referencing.table_id];
+            for referencing_row in referencing.row_ids {
+                if let Some(row) = table.get(referencing_row)? {
+                    foreign_key::check(self.tables, table, &row)?;
+                }
+            }
+        }
+        Ok(Some(old))
+    }
+}
+
+pub struct Module {
+    id: u64,
+    name: String,
+    tables: BTreeMap<u64, Table>,
+    reducers: BTreeMap<u64, Reducer>,
+    /// Code of the reducers of modules loaded from WebAssembly, to instantiate for copies.
+    wasm: Option<Rc<wasm::Module>>,
+    scheduled: Vec<ScheduledCall>,
+    energy_budget: u64,
+    /// Energy used by the module's reducer calls, by caller.
+    energy_used: BTreeMap<u64, u64>,
+    timeout: u64,
+    /// Heap used by the module's tables and reducer calls.
+    account: Account,
+    /// Pages of the module's tables.
+    pool: Rc<RefCell<BufferPool>>,
+    audit_log: AuditLog,
+    commit_log: CommitLog,
+    /// Snapshots to restore from, the oldest first.
+    snapshots: Vec<Snapshot>,
+    views: BTreeMap<String, Rc<View>>,
+    subscriptions: BTreeMap<u64, Subscription>,
+    /// Reducers other modules may call, by module id.
+    grants: BTreeMap<u64, BTreeSet<String>>,
+    /// Module calls made by committed reducers, not yet run.
+    calls: Vec<ModuleCall>,
+    /// Module whose call or reply is running, see [`Module::call_from`].
+    calling: Option<(u64, u32)>,
+    next_call_id: u64,
+    next_table_id: u64,
+    next_reducer_id: u64,
+    next_subscription_id: u64,
+}
+
+impl Module {
+    pub fn new(name: String) -> Module {
+        static NEXT_MODULE_ID: AtomicU64 = AtomicU64::new(0);
+        Module {
+            id: NEXT_MODULE_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            tables: BTreeMap::new(),
+            reducers: BTreeMap::new(),
+            wasm: None,
+            scheduled: Vec::new(),
+            energy_budget: DEFAULT_ENERGY_BUDGET,
+            energy_used: BTreeMap::new(),
+            timeout: DEFAULT_TIMEOUT,
+            account: Account::new(DEFAULT_MEMORY_QUOTA),
+            pool: Rc::new(RefCell::new(BufferPool::new(buffer_pool::DEFAULT_CAPACITY))),
+            audit_log: AuditLog::new(audit::DEFAULT_CAPACITY),
+            commit_log: CommitLog::new(history::DEFAULT_LOG_CAPACITY),
+            snapshots: alloc::vec![Snapshot {
+                offset: 0,
+                timestamp: interrupts::ticks(),
+                tables: Vec::new(),
+            }],
+            views: BTreeMap::new(),
+            subscriptions: BTreeMap::new(),
+            grants: BTreeMap::new(),
+            calls: Vec::new(),
+            calling: None,
+            next_call_id: 0,
+            next_reducer_id: 0,
+            next_table_id: 0,
+            next_subscription_id: 0,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Energy each reducer call may use.
+    pub fn energy_budget(&self) -> u64 {
+        self.energy_budget
+    }
+
+    pub fn set_energy_budget(&mut self, energy_budget: u64) {
+        self.energy_budget = energy_budget;
+    }
+
+    /// Energy used by all reducer calls so far.
+    pub fn energy_used(&self) -> u64 {
+        self.energy_used.values().sum()
+    }
+
+    /// Energy used by the reducer calls of `caller` so far.
+    pub fn energy_used_by(&self, caller: u64) -> u64 {
+        self.energy_used.get(&caller).copied().unwrap_or(0)
+    }
+
+    /// Timer ticks each reducer call may run for.
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+    }
+
+    /// Bytes of heap used by the module, including rows returned by queries that are still alive.
+    pub fn memory_used(&self) -> usize {
+        self.account.used()
+    }
+
+    pub fn memory_quota(&self) -> usize {
+        self.account.limit()
+    }
+
+    pub fn set_memory_quota(&mut self, quota: usize) {
+        self.account.set_limit(quota);
+    }
+
+    /// Pages of the module's tables, those past its capacity evicted to its block device.
+    pub fn buffer_pool(&self) -> Ref<'_, BufferPool> {
+        self.pool.borrow()
+    }
+
+    /// Sets how many pages of the module's tables are kept in memory once it has a block
+    /// device.
+    pub fn set_buffer_pool_capacity(&mut self, pages: usize) {
+        watchdog::critical(|| self.pool.borrow_mut().set_capacity(pages));
+    }
+
+    /// Lets pages of the module's tables be evicted to `device`, giving it back if the module
+    /// already has one or its blocks are larger than a page or don't divide it.
+    pub fn attach_block_device(
+        &mut self,
+        device: Box<dyn BlockDevice>,
+    ) -> Result<(), Box<dyn BlockDevice>> {
+        watchdog::critical(|| self.pool.borrow_mut().attach(device))
+    }
+
+    /// Lets the pages written since the last call be evicted, once the writes are committed
+    /// or rolled back.
+    fn unpin_pages(&self) {
+        watchdog::critical(|| self.pool.borrow_mut().unpin());
+    }
+
+    /// Latest reducer calls to the module, also queryable as `st_audit`.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    pub fn set_audit_capacity(&mut self, capacity: usize) {
+        self.audit_log.set_capacity(capacity);
+    }
+
+    /// Latest calls to the module, with their inputs and writes.
+    pub fn commit_log(&self) -> &CommitLog {
+        &self.commit_log
+    }
+
+    pub fn set_commit_log_capacity(&mut self, capacity: usize) {
+        self.commit_log.set_capacity(capacity);
+    }
+
+    /// Rows and counters of every table, as of the next call to be logged.
+    pub fn snapshot(&self) -> Result<Snapshot, StorageError> {
+        Ok(Snapshot {
+            offset: self.commit_log.next_offset(),
+            timestamp: interrupts::ticks(),
+            tables: self
+                .tables
+                .values()
+                .map(Table::snapshot)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Snapshots kept to restore the module from, the oldest first.
+    ///
+    /// The module starts with a snapshot of its empty tables.
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    /// Takes a snapshot and keeps it, dropping the oldest once [`history::SNAPSHOT_CAPACITY`]
+    /// are kept.
+    ///
+    /// The module can be restored to any offset from a kept snapshot to the latest call, as
+    /// long as the commit log still holds the calls in between.
+    pub fn take_snapshot(&mut self) -> Result<&Snapshot, StorageError> {
+        let snapshot = self.account.enter(|| self.snapshot())?;
+        if self.snapshots.len() >= history::SNAPSHOT_CAPACITY {
+            self.snapshots.remove(0);
+        }
+        self.snapshots.push(snapshot);
+        Ok(self.snapshots.last().unwrap())
+    }
+
+    /// Puts the tables back in the state of `snapshot`, dropping the calls logged since.
+    ///
+    /// Tables missing from the snapshot are emptied. Nothing changes if the snapshot doesn't
+    /// fit the tables.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), HistoryError> {
+        let tables = self.tables_from(snapshot, &self.account, &self.pool)?;
+        self.replace_tables(tables);
+        self.commit_log.truncate(snapshot.offset);
+        self.snapshots.retain(|kept| kept.offset <= snapshot.offset);
+        Ok(())
+    }
+
+    /// Puts the tables back in their state at `point`, dropping the calls logged since and
+    /// the pending scheduled calls.
+    ///
+    /// Nothing changes if the module can't be restored to `point`.
+    pub fn restore(&mut self, point: RestorePoint) -> Result<(), RestoreError> {
+        let offset = self.offset_of(point)?;
+        let tables = self.tables_at(offset, &self.account, &self.pool)?;
+        self.replace_tables(tables);
+        self.commit_log.truncate(offset);
+        self.snapshots.retain(|snapshot| snapshot.offset <= offset);
+        self.scheduled.clear();
+        Ok(())
+    }
+
+    /// Puts `tables` in place of the module's, updating subscriptions to the rows that changed.
+    fn replace_tables(&mut self, tables: BTreeMap<u64, Table>) {
+        self.account.enter(|| {
+            let deltas = subscription::diff(&self.tables, &tables);
+            self.tables = tables;
+            match deltas {
+                Ok(deltas) => subscription::publish(
+                    &mut self.subscriptions,
+                    &deltas,
+                    &self.tables,
+                    self.timeout,
+                ),
+                Err(error) => subscription::fail(&mut self.subscriptions, error),
+            }
+        });
+        self.unpin_pages();
+    }
+
+    /// A new module named `name`, with the reducers and settings of this one and its tables
+    /// in their state at `point`.
+    ///
+    /// Both modules share the reducers' code. WebAssembly modules get a new instance, so the
+    /// copy's reducers don't see or change the guest memory of this module's.
+    pub fn restored(&self, point: RestorePoint, name: String) -> Result<Module, RestoreError> {
+        let offset = self.offset_of(point)?;
+        let mut module = Module::new(name);
+        module.energy_budget = self.energy_budget;
+        module.timeout = self.timeout;
+        module.set_memory_quota(self.memory_quota());
+        module.set_buffer_pool_capacity(self.buffer_pool().capacity());
+        module.tables = self.tables_at(offset, &module.account, &module.pool)?;
+        module.next_table_id = self.next_table_id;
+        let bind = self.wasm.clone().map(|wasm| wasm_host::binder(wasm, None));
+        module.reducers = self
+            .reducers
+            .iter()
+            .map(|(id, reducer)| {
+                let function = match &bind {
+                    Some(bind) => Rc::from(bind(&reducer.def)),
+                    None => reducer.function.clone(),
+                };
+                let reducer = Reducer {
+                    def: reducer.def.clone(),
+                    function,
+                };
+                (*id, reducer)
+            })
+            .collect();
+        module.wasm = self.wasm.clone();
+        module.next_reducer_id = self.next_reducer_id;
+        module.views = self.views.clone();
+        module.grants = self.grants.clone();
+        let snapshot = module.account.enter(|| module.snapshot());
+        let snapshot = snapshot.map_err(|error| HistoryError::Table(error.into()));
+        module.snapshots = alloc::vec![snapshot.map_err(RestoreError::History)?];
+        module.unpin_pages();
+        Ok(module)
+    }
+
+    /// Offset of the first call after `point`.
+    fn offset_of(&self, point: RestorePoint) -> Result<u64, RestoreError> {
+        let next_offset = self.commit_log.next_offset();
+        match point {
+            RestorePoint::Offset(offset) if offset > next_offset => {
+                Err(RestoreError::NoSuchOffset(offset))
+            }
+            RestorePoint::Offset(offset) => Ok(offset),
+            RestorePoint::Timestamp(timestamp) => {
+                let commits = self.commit_log.commits();
+                match commits
+                    .iter()
+                    .position(|commit| commit.timestamp > timestamp)
+                {
+                    // Calls dropped from the log may have been after `timestamp` too
+                    Some(0) if commits[0].offset > 0 => Err(RestoreError::Unavailable),
+                    Some(position) => Ok(commits[position].offset),
+                    None => Ok(next_offset),
+                }
+            }
+        }
+    }
+
+    /// Tables in their state before the call at `offset`, built from the latest kept snapshot
+    /// and the writes logged since, charged to `account` with their pages in `pool`.
+    fn tables_at(
+        &self,
+        offset: u64,
+        account: &Account,
+        pool: &Rc<RefCell<BufferPool>>,
+    ) -> Result<BTreeMap<u64, Table>, RestoreError> {
+        let log_start = self
+            .commit_log
+            .commits()
+            .first()
+            .map_or(self.commit_log.next_offset(), |commit| commit.offset);
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| {
+                snapshot.offset <= offset
+                    && (snapshot.offset == offset || log_start <= snapshot.offset)
+            })
+            .ok_or(RestoreError::Unavailable)?;
+        let mut tables = self
+            .tables_from(snapshot, account, pool)
+            .map_err(RestoreError::History)?;
+        account
+            .enter(|| {
+                let commits = self
+                    .commit_log
+                    .commits()
+                    .iter()
+                    .filter(|commit| commit.committed)
+                    .filter(|commit| (snapshot.offset..offset).contains(&commit.offset));
+                for commit in commits {
+                    history::redo_writes(&mut tables, &commit.writes)?;
+                }
+                Ok(())
+            })
+            .map_err(RestoreError::History)?;
+        Ok(tables)
+    }
+
+    /// The module's tables holding the rows of `snapshot`, charged to `account` with their
+    /// pages in `pool`.
+    fn tables_from(
+        &self,
+        snapshot: &Snapshot,
+        account: &Account,
+        pool: &Rc<RefCell<BufferPool>>,
+    ) -> Result<BTreeMap<u64, Table>, HistoryError> {
+        if let Some(table) = snapshot
+            .tables
+            .iter()
+            .find(|table| !self.tables.contains_key(&table.table_id))
+        {
+            return Err(HistoryError::NoSuchTable(table.table_id));
+        }
+        account.enter(|| {
+            self.tables
+                .values()
+                .map(|table| {
+                    let restored = match snapshot
+                        .tables
+                        .iter()
+                        .find(|snapshot| snapshot.table_id == table.id())
+                    {
+                        Some(snapshot) => {
+                            Table::from_snapshot(table.id(), table.def().clone(), snapshot, pool)
+                                .map_err(HistoryError::Table)?
+                        }
+                        None => Table::new_in(table.id(), table.def().clone(), pool),
+                    };
+                    Ok((table.id(), restored))
+                })
+                .collect()
+        })
+    }
+
+    /// Builds a module from its definition, `bind` providing the code of each reducer.
+    pub fn from_def(
+        def: ModuleDef,
+        mut bind: impl FnMut(&ReducerDef) -> Option<ReducerFn>,
+    ) -> Result<Module, ModuleDefError> {
+        let mut module = Module::new(def.name);
+        for table in def.tables {
+            module.add_table(table)?;
+        }
+        for reducer in def.reducers {
+            let function = bind(&reducer)
+                .ok_or_else(|| ModuleDefError::UnboundReducer(reducer.name.clone()))?;
+            module.add_reducer(reducer, function)?;
+        }
+        Ok(module)
+    }
+
+    /// Definition of the module's current tables and reducers.
+    pub fn def(&self) -> ModuleDef {
+        ModuleDef {
+            name: self.name.clone(),
+            tables: self
+                .tables
+                .values()
+                .map(|table| table.def().clone())
+                .collect(),
+            reducers: self
+                .reducers
+                .values()
+                .map(|reducer| reducer.def.clone())
+                .collect(),
+        }
+    }
+
+    /// Adds an empty table, after the tables its foreign keys refer to.
+    pub fn add_table(&mut self, def: TableDef) -> Result<u64, ModuleDefError> {
+        def.validate()?;
+        if self.table(&def.name).is_some() || self.views.contains_key(&def.name) {
+            return Err(ModuleDefError::DuplicateTable(def.name));
+        }
+        foreign_key::validate(&self.tables, &def)?;
+        let table_id = self.next_table_id;
+        self.next_table_id += 1;
+        self.account.enter(|| {
+            self.tables
+                .insert(table_id, Table::new_in(table_id, def, &self.pool))
+        });
+        Ok(table_id)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.values().find(|table| table.name() == name)
+    }
+
+    /// Adds a view over the module's tables, queried as a table named after it.
+    pub fn add_view(&mut self, def: ViewDef) -> Result<(), ModuleDefError> {
+        if self.table(&def.name).is_some() {
+            return Err(ModuleDefError::DuplicateTable(def.name));
+        }
+        if self.views.contains_key(&def.name) {
+            return Err(ModuleDefError::DuplicateView(def.name));
+        }
+        let view = self.account.enter(|| View::new(def, &self.tables))?;
+        self.account
+            .enter(|| self.views.insert(String::from(view.name()), Rc::new(view)));
+        Ok(())
+    }
+
+    pub fn view(&self, name: &str) -> Option<&View> {
+        self.views.get(name).map(|view| &**view)
+    }
+
+    pub fn add_reducer(
+        &mut self,
+        def: ReducerDef,
+        function: ReducerFn,
+    ) -> Result<u64, ModuleDefError> {
+        if self.reducer(&def.name).is_some() {
+            return Err(ModuleDefError::DuplicateReducer(def.name));
+        }
+        if let Some(lifecycle) = def.lifecycle {
+            if !def.params.elements.is_empty() {
+                return Err(ModuleDefError::LifecycleParams(def.name));
+            }
+            if self.lifecycle_reducer(lifecycle).is_some() {
+                return Err(ModuleDefError::DuplicateLifecycle(lifecycle));
+            }
+        }
+        let reducer_id = self.next_reducer_id;
+        self.next_reducer_id += 1;
+        let function = Rc::from(function);
+        self.reducers.insert(reducer_id, Reducer { def, function });
+        Ok(reducer_id)
+    }
+
+    pub fn reducer(&self, name: &str) -> Option<&Reducer> {
+        self.reducers
+            .values()
+            .find(|reducer| reducer.name() == name)
+    }
+
+    /// Name of the reducer registered for `lifecycle`.
+    pub fn lifecycle_reducer(&self, lifecycle: Lifecycle) -> Option<&str> {
+        self.reducers
+            .values()
+            .find(|reducer| reducer.def.lifecycle == Some(lifecycle))
+            .map(|reducer| reducer.name())
+    }
+
+    /// Runs a reducer in its own transaction, rolled back if the reducer fails.
+    ///
+    /// The call is recorded in the module's audit log, whatever its outcome.
+    pub fn call_reducer(
+        &mut self,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+    ) -> Result<(), ReducerCallError> {
+        self.call(reducer_name, caller, args, interrupts::ticks())
+    }
+
+    /// Calls a reducer as if at tick `now`, as [`Module::call_reducer`] does with the current
+    /// tick.
+    fn call(
+        &mut self,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+        now: u64,
+    ) -> Result<(), ReducerCallError> {
+        let prepared = self.prepare_at(reducer_name, caller, args, now)?;
+        self.finish(prepared, Ok(()))
+    }
+
+    /// Runs a reducer as [`Module::call_reducer`] does, leaving its writes in the tables until
+    /// [`Module::commit`] or [`Module::abort`] is called with the prepared call.
+    ///
+    /// A reducer that fails is rolled back and recorded right away.
+    pub(super) fn prepare(
+        &mut self,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+    ) -> Result<PreparedCall, ReducerCallError> {
+        self.prepare_at(reducer_name, caller, args, interrupts::ticks())
+    }
+
+    /// Commits a call run by [`Module::prepare`].
+    pub(super) fn commit(&mut self, prepared: PreparedCall) {
+        let _ = self.finish(prepared, Ok(()));
+    }
+
+    /// Rolls back a call run by [`Module::prepare`], recording it as failed with `error`.
+    pub(super) fn abort(&mut self, prepared: PreparedCall, error: ReducerCallError) {
+        let _ = self.finish(prepared, Err(error));
+    }
+
+    fn prepare_at(
+        &mut self,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+        now: u64,
+    ) -> Result<PreparedCall, ReducerCallError> {
+        let start = interrupts::ticks();
+        let args_hash = audit::hash_args(&args);
+        match self.run_reducer(reducer_name, caller, args, now) {
+            Ok(ran) => Ok(PreparedCall {
+                ran,
+                start,
+                args_hash,
+            }),
+            Err((error, rows_touched)) => {
+                self.audit_log.record(AuditEntry {
+                    call_id: 0,
+                    reducer: String::from(reducer_name),
+                    caller,
+                    timestamp: now,
+                    args_hash,
+                    outcome: Err(error.clone()),
+                    duration: interrupts::ticks() - start,
+                    rows_touched,
+                });
+                Err(error)
+            }
+        }
+    }
+
+    /// Commits a prepared call, or rolls it back if `outcome` is an error, recording it as
+    /// `outcome`.
+    fn finish(
+        &mut self,
+        prepared: PreparedCall,
+        mut outcome: Result<(), ReducerCallError>,
+    ) -> Result<(), ReducerCallError> {
+        let PreparedCall {
+            ran,
+            start,
+            args_hash,
+        } = prepared;
+        let rows_touched = ran.tx.writes().len() as u64;
+        self.account.enter(|| {
+            let mut writes = Vec::new();
+            match outcome {
+                Ok(()) => {
+                    writes = ran.writes;
+                    subscription::publish(
+                        &mut self.subscriptions,
+                        &ran.deltas,
+                        &self.tables,
+                        self.timeout,
+                    );
+                    self.scheduled.extend(ran.scheduled);
+                    self.calls.extend(ran.calls);
+                }
+                Err(_) => {
+                    if let Err(error) = ran.tx.rollback(&mut self.tables) {
+                        outcome = Err(ReducerCallError::Storage(error));
+                    }
+                }
+            }
+            self.commit_log.append(Commit {
+                offset: 0,
+                timestamp: ran.now,
+                reducer: ran.reducer.clone(),
+                caller: ran.caller,
+                args: ran.args,
+                committed: outcome.is_ok(),
+                aborted: outcome == Err(ReducerCallError::Aborted),
+                writes,
+            });
+        });
+        self.unpin_pages();
+        self.audit_log.record(AuditEntry {
+            call_id: 0,
+            reducer: ran.reducer,
+            caller: ran.caller,
+            timestamp: ran.now,
+            args_hash,
+            outcome: outcome.clone(),
+            duration: interrupts::ticks() - start,
+            rows_touched,
+        });
+        outcome
+    }
+
+    /// Runs a reducer for [`Module::prepare_at`], returning its writes, left in the tables, or
+    /// its error and how many rows it wrote.
+    ///
+    /// Calls that fail once they ran are logged in the commit log.
+    fn run_reducer(
+        &mut self,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+        now: u64,
+    ) -> Result<RanReducer, (ReducerCallError, u64)> {
+        let Some(reducer) = self
+            .reducers
+            .values()
+            .find(|reducer| reducer.name() == reducer_name)
+        else {
+            let error = ReducerCallError::NoSuchReducer(String::from(reducer_name));
+            return Err((error, 0));
+        };
+        if !args.has_type(&reducer.def.params) {
+            return Err((ReducerCallError::InvalidArguments, 0));
+        }
+
+        self.account.enter(|| {
+            let mut ctx = ReducerContext {
+                caller,
+                now,
+                module_id: self.id,
+                module_name: &self.name,
+                tables: &mut self.tables,
+                reducers: &self.reducers,
+                tx: Transaction::new(),
+                scheduled: Vec::new(),
+                calling: self.calling,
+                calls: Vec::new(),
+                next_call_id: self.next_call_id,
+                energy_budget: self.energy_budget,
+                energy_used: 0,
+                out_of_energy: false,
+            };
+            // The reducer gets a copy made under the watchdog, reclaimed if it's aborted
+            let result = watchdog::run(self.timeout, || (reducer.function)(&mut ctx, args.clone()));
+            let aborted = result.is_err();
+            let ReducerContext {
+                tables,
+                tx,
+                scheduled,
+                calls,
+                next_call_id,
+                energy_used,
+                out_of_energy,
+                ..
+            } = ctx;
+            *self.energy_used.entry(caller).or_default() += energy_used;
+            self.next_call_id = next_call_id;
+            let rows_touched = tx.writes().len() as u64;
+            let error = match result {
+                _ if out_of_energy => ReducerCallError::OutOfEnergy,
+                _ if self.account.exceeded() => ReducerCallError::OutOfQuota,
+                Err(TimedOut) => ReducerCallError::Timeout,
+                // What the call wrote is read while it can still fail, before any commit
+                Ok(Ok(())) => match history::row_writes(&tx, tables)
+                    .and_then(|writes| Ok((writes, subscription::deltas(&tx, tables)?)))
+                {
+                    Ok((writes, deltas)) => {
+                        return Ok(RanReducer {
+                            reducer: String::from(reducer_name),
+                            caller,
+                            args,
+                            now,
+                            tx,
+                            writes,
+                            deltas,
+                            scheduled,
+                            calls,
+                        });
+                    }
+                    Err(error) => ReducerCallError::Storage(error),
+                },
+                Ok(Err(error)) => ReducerCallError::Failed(error),
+            };
+            let error = match tx.rollback(tables) {
+                Ok(()) => error,
+                Err(storage) => ReducerCallError::Storage(storage),
+            };
+            watchdog::critical(|| self.pool.borrow_mut().unpin());
+            drop((scheduled, calls));
+            if aborted {
+                watchdog::reclaim();
+            }
+            self.commit_log.append(Commit {
+                offset: 0,
+                timestamp: now,
+                reducer: String::from(reducer_name),
+                caller,
+                args,
+                committed: false,
+                aborted: false,
+                writes: Vec::new(),
+            });
+            Err((error, rows_touched))
+        })
+    }
+
+    /// Runs the scheduled calls due at tick `now`, returning the ones that failed.
+    pub fn run_scheduled(&mut self, now: u64) -> Vec<(ScheduledCall, ReducerCallError)> {
+        let (due, pending) = core::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition::<Vec<_>, _>(|call| call.at <= now);
+        self.scheduled = pending;
+        let mut failed = Vec::new();
+        for call in due {
+            if let Err(error) = self.call_reducer(&call.reducer, call.caller, call.args.clone()) {
+                failed.push((call, error));
+            }
+        }
+        failed
+    }
+
+    /// Lets the module `module_id` call `reducer` through [`ReducerContext::call_module`].
+    pub fn grant(&mut self, module_id: u64, reducer: &str) -> Result<(), ReducerCallError> {
+        if self.reducer(reducer).is_none() {
+            return Err(ReducerCallError::NoSuchReducer(String::from(reducer)));
+        }
+        self.grants
+            .entry(module_id)
+            .or_default()
+            .insert(String::from(reducer));
+        Ok(())
+    }
+
+    /// Takes back a grant, returning whether there was one.
+    pub fn revoke(&mut self, module_id: u64, reducer: &str) -> bool {
+        let Some(reducers) = self.grants.get_mut(&module_id) else {
+            return false;
+        };
+        let revoked = reducers.remove(reducer);
+        if reducers.is_empty() {
+            self.grants.remove(&module_id);
+        }
+        revoked
+    }
+
+    pub fn is_granted(&self, module_id: u64, reducer: &str) -> bool {
+        self.grants
+            .get(&module_id)
+            .is_some_and(|reducers| reducers.contains(reducer))
+    }
+
+    /// Module calls made by committed reducers that the core hasn't run yet.
+    pub fn pending_calls(&self) -> &[ModuleCall] {
+        &self.calls
+    }
+
+    pub(super) fn take_calls(&mut self) -> Vec<ModuleCall> {
+        core::mem::take(&mut self.calls)
+    }
+
+    /// Calls a reducer for the module `module_id`, `depth` calls deep, as
+    /// [`Module::call_reducer`] does.
+    pub(super) fn call_from(
+        &mut self,
+        module_id: u64,
+        depth: u32,
+        reducer_name: &str,
+        caller: u64,
+        args: ProductValue,
+    ) -> Result<(), ReducerCallError> {
+        self.calling = Some((module_id, depth));
+        let outcome = self.call_reducer(reducer_name, caller, args);
+        self.calling = None;
+        outcome
+    }
+
+    /// Inserts rows into a table outside of any reducer, returning the rows rejected by their
+    /// position in `rows`.
+    ///
+    /// The rows inserted are logged as one commit without a reducer, made by `caller`. A row
+    /// rejected with [`TableError::Storage`] couldn't be taken out again once found invalid,
+    /// and is kept and logged with them.
+    pub fn insert_rows(
+        &mut self,
+        table_name: &str,
+        caller: u64,
+        rows: impl IntoIterator<Item = ProductValue>,
+    ) -> Result<Vec<(usize, TableError)>, TableError> {
+        let table_id = self
+            .table(table_name)
+            .ok_or_else(|| TableError::NoSuchTable(String::from(table_name)))?
+            .id();
+        let mut tx = Transaction::new();
+        let mut rejected = Vec::new();
+        self.account.enter(|| {
+            for (position, row) in rows.into_iter().enumerate() {
+                if self.account.exceeded() {
+                    rejected.push((position, TableError::OutOfQuota));
+                    continue;
+                }
+                let table = self.tables.get_mut(&table_id).expect("table was dropped");
+                let row_id = match table.insert(row) {
+                    Ok(row_id) => row_id,
+                    Err(error) => {
+                        rejected.push((position, error));
+                        continue;
+                    }
+                };
+                let table = &self.tables[&table_id];
+                let checked = match self.account.exceeded() {
+                    true => Err(TableError::OutOfQuota),
+                    false => table.get(row_id).map_err(TableError::from).and_then(|row| {
+                        foreign_key::check(&self.tables, table, &row.expect("row was dropped"))
+                    }),
+                };
+                let Err(error) = checked else {
+                    tx.record(Write::Insert { table_id, row_id });
+                    continue;
+                };
+                let table = self.tables.get_mut(&table_id).expect("table was dropped");
+                match table.delete(row_id) {
+                    Ok(_) => rejected.push((position, error)),
+                    Err(error) => {
+                        tx.record(Write::Insert { table_id, row_id });
+                        rejected.push((position, TableError::Storage(error)));
+                    }
+                }
+            }
+        });
+        self.commit_writes(tx, caller)?;
+        Ok(rejected)
+    }
+
+    /// Logs the writes of `tx`, made outside of any reducer by `caller`, as one commit, or
+    /// rolls them back if the rows they wrote can't be read back.
+    fn commit_writes(&mut self, tx: Transaction, caller: u64) -> Result<(), StorageError> {
+        let committed = self.account.enter(|| {
+            let read = history::row_writes(&tx, &self.tables)
+                .and_then(|writes| Ok((writes, subscription::deltas(&tx, &self.tables)?)));
+            let (writes, deltas) = match read {
+                Ok(read) => read,
+                Err(error) => return tx.rollback(&mut self.tables).and(Err(error)),
+            };
+            self.commit_log.append(Commit {
+                offset: 0,
+                timestamp: interrupts::ticks(),
+                reducer: String::new(),
+                caller,
+                args: ProductValue::new(Vec::new()),
+                committed: true,
+                aborted: false,
+                writes,
+            });
+            subscription::publish(&mut self.subscriptions, &deltas, &self.tables, self.timeout);
+            Ok(())
+        });
+        self.unpin_pages();
+        committed
+    }
+
+    /// Inserts a batch of rows into a table outside of any reducer, all or none of them, see
+    /// [`Table::bulk_insert`].
+    ///
+    /// `rows` are encoded as [`binary::rows_from_bytes`] expects. They're logged as one commit
+    /// without a reducer, made by `caller`. Failing with [`TableError::Storage`], the rows that
+    /// couldn't be taken out again are kept and logged.
+    pub fn bulk_insert(
+        &mut self,
+        table_name: &str,
+        caller: u64,
+        rows: &[u8],
+    ) -> Result<usize, BulkInsertError> {
+        let table_id = self
+            .table(table_name)
+            .ok_or_else(|| TableError::NoSuchTable(String::from(table_name)))
+            .map_err(BulkInsertError::Table)?
+            .id();
+        let (row_ids, failed) = self.account.enter(|| {
+            let table = self.tables.get_mut(&table_id).expect("table was dropped");
+            let rows =
+                binary::rows_from_bytes(table.columns(), rows).map_err(BulkInsertError::Decode)?;
+            let row_ids = table.bulk_insert(rows).map_err(BulkInsertError::Table)?;
+            // Checked once every row is in, so rows of the batch can refer to each other
+            let table = &self.tables[&table_id];
+            let checked = match self.account.exceeded() {
+                true => Err(TableError::OutOfQuota),
+                false => row_ids.iter().try_for_each(|row_id| {
+                    foreign_key::check(
+                        &self.tables,
+                        table,
+                        &table.get(*row_id)?.expect("row was dropped"),
+                    )
+                }),
+            };
+            let Err(mut error) = checked else {
+                return Ok((row_ids, None));
+            };
+            let table = self.tables.get_mut(&table_id).expect("table was dropped");
+            let mut kept = Vec::new();
+            for row_id in row_ids {
+                if let Err(storage) = table.delete(row_id) {
+                    kept.push(row_id);
+                    error = TableError::Storage(storage);
+                }
+            }
+            Ok((kept, Some(error)))
+        })?;
+        let inserted = row_ids.len();
+        if !row_ids.is_empty() {
+            let mut tx = Transaction::new();
+            for row_id in row_ids {
+                tx.record(Write::Insert { table_id, row_id });
+            }
+            self.commit_writes(tx, caller)
+                .map_err(|error| BulkInsertError::Table(error.into()))?;
+        }
+        match failed {
+            Some(error) => Err(BulkInsertError::Table(error)),
+            None => Ok(inserted),
+        }
+    }
+
+    /// Applies the writes of a commit without a reducer and logs it, for replays.
+    fn redo(&mut self, commit: &Commit) -> Result<(), HistoryError> {
+        let redone = self.account.enter(|| {
+            let old = commit
+                .writes
+                .iter()
+                .map(|write| match self.tables.get(&write.table_id) {
+                    Some(table) => table.get(write.row_id),
+                    None => Ok(None),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|error| HistoryError::Table(error.into()))?;
+            history::redo_writes(&mut self.tables, &commit.writes)?;
+            self.commit_log.append(commit.clone());
+            let mut deltas = subscription::Deltas::new();
+            for (write, old) in commit.writes.iter().zip(old) {
+                subscription::push(&mut deltas, write.table_id, old, write.row.clone());
+            }
+            subscription::publish(&mut self.subscriptions, &deltas, &self.tables, self.timeout);
+            Ok(())
+        });
+        self.unpin_pages();
+        redone
+    }
+
+    /// Runs `query` against one of the module's tables, views or [system tables](system).
+    ///
+    /// Views showing rows by caller can only be read with [`Module::query_as`].
+    pub fn query(&self, query: &Query) -> Result<QueryResult, QueryError> {
+        self.read(query, None)
+    }
+
+    /// Runs `query` as [`Module::query`], reading views as `caller`.
+    pub fn query_as(&self, query: &Query, caller: u64) -> Result<QueryResult, QueryError> {
+        self.read(query, Some(caller))
+    }
+
+    fn read(&self, query: &Query, caller: Option<u64>) -> Result<QueryResult, QueryError> {
+        if query.join.is_none()
+            && let Some(view) = self.views.get(&query.table)
+        {
+            let rows = self
+                .account
+                .enter(|| view.rows(caller, &self.tables, self.timeout))?;
+            return query.run(view.columns(), rows.into_iter());
+        }
+        if query.join.is_some() || self.table(&query.table).is_some() {
+            return query::read(query, &self.tables);
+        }
+        let system = system::module_table(self, &query.table)
+            .ok_or_else(|| QueryError::NoSuchTable(query.table.clone()))?;
+        query.run(&system.schema, system.rows.into_iter())
+    }
+
+    /// Subscribes to `query` over the module's tables, returning the subscription's id and the
+    /// query's current results.
+    ///
+    /// The changes commits make to the results are queued for
+    /// [`Module::poll_subscription`]. They're found from the rows each commit changes: for
+    /// joins, by looking up the rows they join with, through an index starting with the joined
+    /// column if the table has one. Views are read again after each commit.
+    ///
+    /// Views showing rows by caller can only be subscribed to with [`Module::subscribe_as`].
+    pub fn subscribe(&mut self, query: &Query) -> Result<(u64, QueryResult), QueryError> {
+        self.subscribe_with(query, None)
+    }
+
+    /// Subscribes to `query` as [`Module::subscribe`], reading views as `caller`.
+    pub fn subscribe_as(
+        &mut self,
+        query: &Query,
+        caller: u64,
+    ) -> Result<(u64, QueryResult), QueryError> {
+        self.subscribe_with(query, Some(caller))
+    }
+
+    fn subscribe_with(
+        &mut self,
+        query: &Query,
+        caller: Option<u64>,
+    ) -> Result<(u64, QueryResult), QueryError> {
+        if self.table(&query.table).is_none() && system::module_table(self, &query.table).is_some()
+        {
+            return Err(QueryError::NotSubscribable(query.table.clone()));
+        }
+        let view = match query.join {
+            None => self.views.get(&query.table).cloned(),
+            Some(_) => None,
+        };
+        let (subscription, result) = self.account.enter(|| match view {
+            Some(view) => Subscription::of_view(query, view, caller, &self.tables, self.timeout),
+            None => Subscription::new(query, &self.tables),
+        })?;
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.account
+            .enter(|| self.subscriptions.insert(id, subscription));
+        Ok((id, result))
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) -> Result<(), QueryError> {
+        self.subscriptions
+            .remove(&id)
+            .map(drop)
+            .ok_or(QueryError::NoSuchSubscription(id))
+    }
+
+    /// Takes the updates to the results of a subscription queued since its last poll.
+    pub fn poll_subscription(&mut self, id: u64) -> Result<Vec<SubscriptionUpdate>, QueryError> {
+        self.subscriptions
+            .get_mut(&id)
+            .ok_or(QueryError::NoSuchSubscription(id))?
+            .poll()
+    }
+}
+
+// Tests
+
+/// A core with modules `noisy` and `quiet`, each with a reducer `ping` doing nothing.
+#[cfg(test)]
+fn ping_modules() -> (SpacetimeCore, u64, u64) {
+    let mut core = SpacetimeCore::new();
+    let mut module_ids = Vec::new();
+    for name in ["noisy", "quiet"] {
+        let mut module = test_util::module(name, []);
+        test_util::add_reducer(&mut module, "ping", test_util::columns([]), |_, _| Ok(()));
+        module_ids.push(module.id());
+        core.publish_module(module, 0).unwrap();
+    }
+    (core, module_ids[0], module_ids[1])
+}
+
+#[test_case]
+fn test_queued_calls_take_turns() {
+    let (mut core, noisy, quiet) = ping_modules();
+    let ping = |core: &mut SpacetimeCore, module_id, caller| {
+        let args = test_util::row([]);
+        core.queue_reducer(&module_id, "ping", caller, args)
+            .unwrap();
+    };
+    for _ in 0..3 {
+        ping(&mut core, noisy, 1);
+    }
+    ping(&mut core, quiet, 2);
+
+    let ran = core.run_queued(2);
+    let order: Vec<_> = ran.iter().map(|(call, _)| call.module_id).collect();
+    assert_eq!(order, [noisy, quiet]);
+    assert!(ran.iter().all(|(_, outcome)| outcome.is_ok()));
+    assert_eq!(core.queued_calls(), 2);
+}
+
+#[test_case]
+fn test_module_tasks_take_turns() {
+    let (mut core, noisy, quiet) = ping_modules();
+    let polled = Rc::new(RefCell::new(Vec::new()));
+    for (module_id, tasks) in [(noisy, 3), (quiet, 1)] {
+        for _ in 0..tasks {
+            let polled = polled.clone();
+            let task = async move { polled.borrow_mut().push(module_id) };
+            core.spawn(&module_id, 1, task).unwrap();
+        }
+    }
+    core.run_ready_tasks();
+    assert_eq!(*polled.borrow(), [noisy, quiet, noisy, noisy]);
+    assert_eq!(
+        core.spawn(&u64::MAX, 1, async {}),
+        Err(ReducerCallError::NoSuchModule(u64::MAX))
+    );
+}
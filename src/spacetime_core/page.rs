@@ -0,0 +1,582 @@
+//! Row storage in fixed-size pages, so a table's rows take a few large allocations rather than
+//! a few per row.
+//!
+//! A page starts with a header, followed by its fixed-length section: one slot per row, all of
+//! the same size in a table. A slot holds the row's fixed-size columns, such as integers or
+//! products of them, in the binary format. Other columns are encoded in the page's
+//! variable-length heap, which grows down from the end of the page, the slot holding their
+//! offset and length. Values too large to share a page are kept apart, as blobs.
+//!
+//! A page's state is all in its bytes, so it can be written out and read back as is.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    vec::Vec,
+};
+use core::cell::RefCell;
+
+use super::binary;
+use super::buffer_pool::{self, BufferPool, PageId, StorageError};
+use super::value::{AlgebraicType, ProductType, ProductValue};
+use crate::watchdog;
+
+pub const PAGE_SIZE: usize = 1024;
+/// Largest encoded value kept in a page's heap, larger ones are blobs.
+pub const MAX_INLINE: usize = 256;
+
+/// Slot count, heap start, live rows and garbage heap bytes, as `u16`s.
+const HEADER: usize = 8;
+/// Largest slot, leaving a page room for one row with inline values.
+pub const MAX_SLOT_SIZE: usize = PAGE_SIZE - HEADER - MAX_INLINE;
+/// Set in the length of a value kept as a blob, its offset being the blob's id.
+const BLOB: u32 = 1 << 31;
+/// Size in a slot of the offset and length of a variable-length value.
+const VAR_REF: usize = 8;
+
+/// Page and slot of a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowPointer {
+    pub page: u32,
+    pub slot: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Column {
+    Fixed { offset: usize, size: usize },
+    Var { offset: usize },
+}
+
+/// Size of a slot for rows of type `ty`, including the byte marking it in use.
+pub fn slot_size(ty: &ProductType) -> usize {
+    1 + ty
+        .elements
+        .iter()
+        .map(|column| fixed_size(&column.algebraic_type).unwrap_or(VAR_REF))
+        .sum::<usize>()
+}
+
+/// Size of the values of type `ty` if they all encode to the same number of bytes.
+fn fixed_size(ty: &AlgebraicType) -> Option<usize> {
+    match ty {
+        AlgebraicType::Bool | AlgebraicType::I8 | AlgebraicType::U8 => Some(1),
+        AlgebraicType::I16 | AlgebraicType::U16 => Some(2),
+        AlgebraicType::I32 | AlgebraicType::U32 | AlgebraicType::F32 => Some(4),
+        AlgebraicType::I64 | AlgebraicType::U64 | AlgebraicType::F64 => Some(8),
+        AlgebraicType::Product(product) => product
+            .elements
+            .iter()
+            .map(|element| fixed_size(&element.algebraic_type))
+            .sum(),
+        AlgebraicType::String
+        | AlgebraicType::Bytes
+        | AlgebraicType::Array(_)
+        | AlgebraicType::Sum(_) => None,
+    }
+}
+
+/// A page of rows of one table, see the [module docs](self).
+pub struct Page {
+    data: Vec<u8>,
+}
+
+impl Page {
+    pub(super) fn new() -> Page {
+        let mut page = Page {
+            data: alloc::vec![0; PAGE_SIZE],
+        };
+        page.clear();
+        page
+    }
+
+    /// A page from the bytes of [`Page::as_bytes`], or `None` if they're not a page.
+    ///
+    /// Only the header is checked: the slots are, against the layout of their table, by
+    /// [`PageStore::is_valid`].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Page> {
+        if bytes.len() != PAGE_SIZE {
+            return None;
+        }
+        let page = Page {
+            data: bytes.to_vec(),
+        };
+        let valid = (HEADER..=PAGE_SIZE).contains(&page.heap_start())
+            && HEADER + page.slot_count() <= page.heap_start()
+            && page.len() <= page.slot_count()
+            && page.garbage() <= PAGE_SIZE - page.heap_start();
+        valid.then_some(page)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Rows in the page.
+    pub fn len(&self) -> usize {
+        self.header(2) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn header(&self, field: usize) -> u16 {
+        u16::from_le_bytes([self.data[field * 2], self.data[field * 2 + 1]])
+    }
+
+    fn set_header(&mut self, field: usize, value: u16) {
+        self.data[field * 2..field * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn slot_count(&self) -> usize {
+        self.header(0) as usize
+    }
+
+    fn heap_start(&self) -> usize {
+        self.header(1) as usize
+    }
+
+    fn garbage(&self) -> usize {
+        self.header(3) as usize
+    }
+
+    fn clear(&mut self) {
+        self.set_header(0, 0);
+        self.set_header(1, PAGE_SIZE as u16);
+        self.set_header(2, 0);
+        self.set_header(3, 0);
+    }
+
+    fn slot(&self, slot_size: usize, slot: usize) -> &[u8] {
+        let start = HEADER + slot * slot_size;
+        &self.data[start..start + slot_size]
+    }
+
+    fn slot_mut(&mut self, slot_size: usize, slot: usize) -> &mut [u8] {
+        let start = HEADER + slot * slot_size;
+        &mut self.data[start..start + slot_size]
+    }
+
+    fn free_slot(&self, slot_size: usize) -> Option<usize> {
+        (0..self.slot_count()).find(|slot| self.slot(slot_size, *slot)[0] == 0)
+    }
+
+    /// Bytes needed for a row with `heap_len` heap bytes, and the bytes free for it, as
+    /// `(needed, free)`.
+    fn space(&self, slot_size: usize, heap_len: usize) -> (usize, usize) {
+        let needed = match self.free_slot(slot_size) {
+            Some(_) => heap_len,
+            None => heap_len + slot_size,
+        };
+        let free = self.heap_start() - (HEADER + self.slot_count() * slot_size);
+        (needed, free)
+    }
+}
+
+/// The pages and blobs holding the rows of a table.
+///
+/// Pages are kept in a module's [`BufferPool`], which may write them out to a block device,
+/// while blobs stay in memory.
+pub struct PageStore {
+    columns: ProductType,
+    layout: Vec<Column>,
+    slot_size: usize,
+    pool: Rc<RefCell<BufferPool>>,
+    pages: Vec<PageId>,
+    blobs: BTreeMap<u32, Vec<u8>>,
+    next_blob: u32,
+    /// Pages that had rows deleted, tried first for new rows.
+    reusable: BTreeSet<u32>,
+}
+
+impl PageStore {
+    /// Storage for rows of type `columns`, whose slots must fit in a page, in a pool of its own.
+    pub fn new(columns: &ProductType) -> PageStore {
+        let pool = BufferPool::new(buffer_pool::DEFAULT_CAPACITY);
+        PageStore::new_in(columns, &Rc::new(RefCell::new(pool)))
+    }
+
+    /// Storage for rows of type `columns` with its pages in `pool`.
+    pub fn new_in(columns: &ProductType, pool: &Rc<RefCell<BufferPool>>) -> PageStore {
+        let mut offset = 1;
+        let layout = columns
+            .elements
+            .iter()
+            .map(|column| {
+                let layout = match fixed_size(&column.algebraic_type) {
+                    Some(size) => Column::Fixed { offset, size },
+                    None => Column::Var { offset },
+                };
+                offset += fixed_size(&column.algebraic_type).unwrap_or(VAR_REF);
+                layout
+            })
+            .collect();
+        PageStore {
+            columns: columns.clone(),
+            layout,
+            slot_size: offset,
+            pool: pool.clone(),
+            pages: Vec::new(),
+            blobs: BTreeMap::new(),
+            next_blob: 0,
+            reusable: BTreeSet::new(),
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Runs `f` on a page, reading it back from the pool's device if it was evicted.
+    pub fn read_page<T>(
+        &self,
+        page_id: u32,
+        f: impl FnOnce(&Page) -> T,
+    ) -> Result<T, StorageError> {
+        let check = |page: &Page| self.is_valid(page);
+        with_pool(&self.pool, |pool| {
+            pool.read(self.pages[page_id as usize], check, f)
+        })
+    }
+
+    fn write_page<T>(
+        &self,
+        page_id: u32,
+        f: impl FnOnce(&mut Page) -> T,
+    ) -> Result<T, StorageError> {
+        let check = |page: &Page| self.is_valid(page);
+        with_pool(&self.pool, |pool| {
+            pool.write(self.pages[page_id as usize], check, f)
+        })
+    }
+
+    /// Whether the slots of `page` fit before its heap, and hold rows whose heap values are
+    /// all within the page, as many as its header says.
+    pub fn is_valid(&self, page: &Page) -> bool {
+        if HEADER + page.slot_count() * self.slot_size > page.heap_start() {
+            return false;
+        }
+        let mut rows = 0;
+        for slot_id in 0..page.slot_count() {
+            let slot = page.slot(self.slot_size, slot_id);
+            match slot[0] {
+                0 => continue,
+                1 => rows += 1,
+                _ => return false,
+            }
+            for layout in &self.layout {
+                let Column::Var { offset } = *layout else {
+                    continue;
+                };
+                let (start, len) = read_var_ref(slot, offset);
+                let in_page = (start as usize) >= page.heap_start()
+                    && start as usize + len as usize <= PAGE_SIZE;
+                if len & BLOB == 0 && !in_page {
+                    return false;
+                }
+            }
+        }
+        rows == page.len()
+    }
+
+    /// Bytes taken by the pages and blobs, in memory or not.
+    pub fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE + self.blobs.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Stores `row`, which must have the store's type.
+    pub fn insert(&mut self, row: &ProductValue) -> Result<RowPointer, StorageError> {
+        let mut slot = alloc::vec![0; self.slot_size];
+        slot[0] = 1;
+        let mut heap = Vec::new();
+        for (layout, value) in self.layout.iter().zip(&row.elements) {
+            match *layout {
+                Column::Fixed { offset, size } => {
+                    let mut buf = Vec::with_capacity(size);
+                    binary::encode(value, &mut buf);
+                    slot[offset..offset + size].copy_from_slice(&buf);
+                }
+                Column::Var { offset } => {
+                    let mut buf = Vec::new();
+                    binary::encode(value, &mut buf);
+                    heap.push((offset, buf));
+                }
+            }
+        }
+        // Large values, and the largest ones of a row too large for an empty page, are blobs
+        heap.sort_by_key(|(_, buf)| buf.len());
+        let mut heap_len: usize = heap.iter().map(|(_, buf)| buf.len()).sum();
+        let mut blobs = Vec::new();
+        while let Some((offset, buf)) = heap.pop_if(|(_, buf)| {
+            buf.len() > MAX_INLINE || HEADER + self.slot_size + heap_len > PAGE_SIZE
+        }) {
+            heap_len -= buf.len();
+            let blob = self.next_blob;
+            self.next_blob += 1;
+            write_var_ref(&mut slot, offset, blob, buf.len() as u32 | BLOB);
+            blobs.push((blob, buf));
+        }
+
+        let page_id = self.page_for(heap_len)?;
+        let slot_size = self.slot_size;
+        let slot_id = self.write_page(page_id, |page| {
+            let slot_id = match page.free_slot(slot_size) {
+                Some(slot_id) => slot_id,
+                None => {
+                    page.set_header(0, page.slot_count() as u16 + 1);
+                    page.slot_count() - 1
+                }
+            };
+            for (offset, buf) in heap {
+                let start = page.heap_start() - buf.len();
+                page.data[start..start + buf.len()].copy_from_slice(&buf);
+                page.set_header(1, start as u16);
+                write_var_ref(&mut slot, offset, start as u32, buf.len() as u32);
+            }
+            page.slot_mut(slot_size, slot_id).copy_from_slice(&slot);
+            page.set_header(2, page.len() as u16 + 1);
+            slot_id
+        })?;
+        self.blobs.extend(blobs);
+        Ok(RowPointer {
+            page: page_id,
+            slot: slot_id as u16,
+        })
+    }
+
+    /// The row at `pointer`, which must hold one.
+    ///
+    /// Fails if its page can't be read back, or holds something else than a row there.
+    pub fn get(&self, pointer: RowPointer) -> Result<ProductValue, StorageError> {
+        self.read_page(pointer.page, |page| {
+            let slot = page.slot(self.slot_size, pointer.slot as usize);
+            let elements = self
+                .layout
+                .iter()
+                .zip(&self.columns.elements)
+                .map(|(layout, column)| {
+                    let mut bytes = match *layout {
+                        Column::Fixed { offset, size } => &slot[offset..offset + size],
+                        Column::Var { offset } => match read_var_ref(slot, offset) {
+                            (blob, len) if len & BLOB != 0 => &self.blobs.get(&blob)?[..],
+                            (start, len) => {
+                                let start = start as usize;
+                                page.data.get(start..start + len as usize)?
+                            }
+                        },
+                    };
+                    binary::decode(&column.algebraic_type, &mut bytes).ok()
+                })
+                .collect::<Option<_>>()?;
+            Some(ProductValue::new(elements))
+        })?
+        .ok_or(StorageError::BadPage)
+    }
+
+    /// Frees the slot at `pointer`, which must hold a row.
+    pub fn delete(&mut self, pointer: RowPointer) -> Result<(), StorageError> {
+        let slot_size = self.slot_size;
+        let blobs = self.write_page(pointer.page, |page| {
+            let slot = page.slot(slot_size, pointer.slot as usize);
+            let mut garbage = page.garbage();
+            let mut blobs = Vec::new();
+            for layout in &self.layout {
+                if let Column::Var { offset } = *layout {
+                    match read_var_ref(slot, offset) {
+                        (blob, len) if len & BLOB != 0 => blobs.push(blob),
+                        (_, len) => garbage += len as usize,
+                    }
+                }
+            }
+            page.slot_mut(slot_size, pointer.slot as usize)[0] = 0;
+            page.set_header(2, page.len() as u16 - 1);
+            page.set_header(3, garbage as u16);
+            if page.is_empty() {
+                page.clear();
+            }
+            blobs
+        })?;
+        for blob in blobs {
+            self.blobs.remove(&blob);
+        }
+        self.reusable.insert(pointer.page);
+        Ok(())
+    }
+
+    /// Page with room for a new row with `heap_len` heap bytes, compacting or adding one if
+    /// needed.
+    fn page_for(&mut self, heap_len: usize) -> Result<u32, StorageError> {
+        let last = self.pages.len().checked_sub(1).map(|page| page as u32);
+        let candidates: Vec<u32> = self.reusable.iter().copied().chain(last).collect();
+        for page_id in candidates {
+            // A page that can't be read back is skipped, rather than failing the insert
+            let Ok((needed, free, garbage)) = self.read_page(page_id, |page| {
+                let (needed, free) = page.space(self.slot_size, heap_len);
+                (needed, free, page.garbage())
+            }) else {
+                continue;
+            };
+            if needed <= free {
+                return Ok(page_id);
+            }
+            if needed <= free + garbage {
+                self.compact(page_id)?;
+                return Ok(page_id);
+            }
+            self.reusable.remove(&page_id);
+        }
+        let page = with_pool(&self.pool, BufferPool::allocate);
+        self.pages.push(page);
+        Ok(self.pages.len() as u32 - 1)
+    }
+
+    /// Moves the heap values of the rows of a page together, freeing their garbage.
+    fn compact(&self, page_id: u32) -> Result<(), StorageError> {
+        self.write_page(page_id, |page| {
+            let old = page.data.clone();
+            let mut heap_start = PAGE_SIZE;
+            for slot_id in 0..page.slot_count() {
+                let slot = HEADER + slot_id * self.slot_size;
+                if old[slot] == 0 {
+                    continue;
+                }
+                for layout in &self.layout {
+                    let Column::Var { offset } = *layout else {
+                        continue;
+                    };
+                    let (start, len) = read_var_ref(&old[slot..], offset);
+                    if len & BLOB != 0 {
+                        continue;
+                    }
+                    heap_start -= len as usize;
+                    write_var_ref(&mut page.data[slot..], offset, heap_start as u32, len);
+                    page.data[heap_start..heap_start + len as usize]
+                        .copy_from_slice(&old[start as usize..(start + len) as usize]);
+                }
+            }
+            page.set_header(1, heap_start as u16);
+            page.set_header(3, 0);
+        })
+    }
+}
+
+impl Drop for PageStore {
+    fn drop(&mut self) {
+        with_pool(&self.pool, |pool| {
+            for page in &self.pages {
+                pool.release(*page);
+            }
+        });
+    }
+}
+
+/// Runs `f` on `pool` in a critical section, as an abort would leave it borrowed.
+fn with_pool<T>(pool: &RefCell<BufferPool>, f: impl FnOnce(&mut BufferPool) -> T) -> T {
+    watchdog::critical(|| f(&mut pool.borrow_mut()))
+}
+
+fn read_var_ref(slot: &[u8], offset: usize) -> (u32, u32) {
+    let word = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+    (word(offset), word(offset + 4))
+}
+
+fn write_var_ref(slot: &mut [u8], offset: usize, start: u32, len: u32) {
+    slot[offset..offset + 4].copy_from_slice(&start.to_le_bytes());
+    slot[offset + 4..offset + 8].copy_from_slice(&len.to_le_bytes());
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_columns() -> ProductType {
+    super::test_util::columns([("id", AlgebraicType::U64), ("name", AlgebraicType::String)])
+}
+
+#[cfg(test)]
+fn test_row(id: u64, name: &str) -> ProductValue {
+    use super::value::AlgebraicValue;
+
+    super::test_util::row([AlgebraicValue::U64(id), AlgebraicValue::String(name.into())])
+}
+
+/// Store of 100 rows named `row <id>`, and where they are.
+#[cfg(test)]
+fn test_store() -> (PageStore, Vec<RowPointer>) {
+    let mut store = PageStore::new(&test_columns());
+    let pointers = (0..100)
+        .map(|id| {
+            store
+                .insert(&test_row(id, &alloc::format!("row {}", id)))
+                .unwrap()
+        })
+        .collect();
+    (store, pointers)
+}
+
+#[test_case]
+fn test_page_store() {
+    assert_eq!(slot_size(&test_columns()), 1 + 8 + VAR_REF);
+    let (store, pointers) = test_store();
+    let pages = store.page_count();
+    assert!(pages > 1 && pages < 10);
+    assert_eq!(store.get(pointers[42]), Ok(test_row(42, "row 42")));
+}
+
+/// Freed slots and heap bytes are reused before adding pages.
+#[test_case]
+fn test_page_store_reuse() {
+    let (mut store, mut pointers) = test_store();
+    let pages = store.page_count();
+    for pointer in pointers.drain(..50) {
+        store.delete(pointer).unwrap();
+    }
+    for id in 0..50 {
+        let row = test_row(id, &alloc::format!("new row {}", id));
+        pointers.push(store.insert(&row).unwrap());
+    }
+    assert_eq!(store.page_count(), pages);
+    assert_eq!(store.get(pointers[0]), Ok(test_row(50, "row 50")));
+    assert_eq!(store.get(pointers[99]), Ok(test_row(49, "new row 49")));
+}
+
+#[test_case]
+fn test_page_store_large_row() {
+    let (mut store, _) = test_store();
+    let large = test_row(100, &"x".repeat(PAGE_SIZE * 2));
+    let pointer = store.insert(&large).unwrap();
+    assert_eq!(store.get(pointer), Ok(large));
+    assert!(store.size() > store.page_count() * PAGE_SIZE);
+    store.delete(pointer).unwrap();
+    assert_eq!(store.size(), store.page_count() * PAGE_SIZE);
+}
+
+#[test_case]
+fn test_page_bytes() {
+    let (store, _) = test_store();
+    store
+        .read_page(0, |page| {
+            let copy = Page::from_bytes(page.as_bytes()).unwrap();
+            assert_eq!(copy.as_bytes(), page.as_bytes());
+            assert_eq!(copy.len(), page.len());
+        })
+        .unwrap();
+    assert!(Page::from_bytes(&[0; 16]).is_none());
+}
+
+/// Pages read back with headers or slots out of bounds are rejected, not indexed.
+#[test_case]
+fn test_page_corrupt() {
+    let (store, _) = test_store();
+    let bytes = store.read_page(0, |page| page.as_bytes().to_vec()).unwrap();
+    let corrupt = |at: usize, value: &[u8]| {
+        let mut bytes = bytes.clone();
+        bytes[at..at + value.len()].copy_from_slice(value);
+        Page::from_bytes(&bytes).is_some_and(|page| store.is_valid(&page))
+    };
+    assert!(corrupt(0, &[]));
+    assert!(!corrupt(0, &u16::MAX.to_le_bytes()));
+    assert!(!corrupt(4, &u16::MAX.to_le_bytes()));
+    let name = HEADER + 1 + 8;
+    assert!(!corrupt(name, &(PAGE_SIZE as u32 - 2).to_le_bytes()));
+    assert!(!corrupt(name + 4, &(PAGE_SIZE as u32).to_le_bytes()));
+}
@@ -0,0 +1,862 @@
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+
+use super::binary;
+use super::buffer_pool::StorageError;
+use super::join::{Join, JoinPlan};
+use super::json::{self, JsonError};
+use super::rtree;
+use super::table::{RowId, Table};
+use super::text;
+use super::value::{
+    AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue, SumValue,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    NoSuchModule(u64),
+    NoSuchTable(String),
+    NoSuchColumn(String),
+    /// The column can't be summed or averaged, not being numeric.
+    InvalidAggregate(String),
+    /// The table's rows change without commits, so it can't be subscribed to.
+    NotSubscribable(String),
+    NoSuchSubscription(u64),
+    /// The joined columns have different types.
+    InvalidJoin(String),
+    /// The box's columns aren't numeric, or its corners don't have a coordinate per column.
+    InvalidBounds(String),
+    /// The searched column isn't a string column.
+    InvalidSearch(String),
+    /// The named view shows rows by caller, and was read without one.
+    CallerRequired(String),
+    /// The function of a view failed, ran past its module's timeout or quota, or returned rows
+    /// that don't match its columns.
+    ViewFailed {
+        view: String,
+        error: String,
+    },
+    /// A page of a table couldn't be read back from the module's block device.
+    Storage(StorageError),
+    /// The result's rows couldn't be encoded, not matching its schema.
+    Encode(JsonError),
+}
+
+impl From<StorageError> for QueryError {
+    fn from(error: StorageError) -> QueryError {
+        QueryError::Storage(error)
+    }
+}
+
+/// Read of the rows of one table, optionally restricted to rows with given column values, to
+/// rows whose coordinates are in a box and to rows matching a text search, ranked best first.
+///
+/// With aggregates or grouping columns, the query reads one row per group of rows with the
+/// same values in the grouping columns: those values followed by the aggregates over the
+/// group. Without grouping columns, all the rows are one group, even when there are none.
+///
+/// A query with a join reads joined rows, its columns named as [`join`](super::join)
+/// describes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub table: String,
+    pub join: Option<Join>,
+    pub filter: Vec<(String, AlgebraicValue)>,
+    pub within: Option<BoundingBox>,
+    /// Column searched and the search, see [`Query::filter_matches`].
+    pub search: Option<(String, String)>,
+    pub group_by: Vec<String>,
+    pub aggregates: Vec<Aggregate>,
+}
+
+/// Box between two corners, inclusive, of the coordinates given by numeric columns, see
+/// [`Query::filter_within`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub columns: Vec<String>,
+    pub min: Vec<f64>,
+    pub max: Vec<f64>,
+}
+
+/// Value computed over the rows of a group, named in results after the function and column,
+/// such as `count` or `max_score`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregate {
+    /// Rows in the group, as a `u64`.
+    Count,
+    /// Sum of a numeric column: an `i64`, `u64` or `f64` as the column is signed, unsigned or
+    /// a float, saturating at its bounds.
+    Sum(String),
+    /// Smallest value of a column, as an option that is `none` without rows.
+    Min(String),
+    /// Largest value of a column, as an option that is `none` without rows.
+    Max(String),
+    /// Mean of a numeric column, as an option of an `f64` that is `none` without rows.
+    Avg(String),
+}
+
+impl Aggregate {
+    fn column(&self) -> Option<&str> {
+        match self {
+            Aggregate::Count => None,
+            Aggregate::Sum(column)
+            | Aggregate::Min(column)
+            | Aggregate::Max(column)
+            | Aggregate::Avg(column) => Some(column),
+        }
+    }
+
+    fn name(&self) -> String {
+        let function = match self {
+            Aggregate::Count => return String::from("count"),
+            Aggregate::Sum(_) => "sum",
+            Aggregate::Min(_) => "min",
+            Aggregate::Max(_) => "max",
+            Aggregate::Avg(_) => "avg",
+        };
+        format!("{}_{}", function, self.column().unwrap_or_default())
+    }
+}
+
+impl Query {
+    pub fn table(name: &str) -> Query {
+        Query {
+            table: String::from(name),
+            join: None,
+            filter: Vec::new(),
+            within: None,
+            search: None,
+            group_by: Vec::new(),
+            aggregates: Vec::new(),
+        }
+    }
+
+    pub fn filter_eq(mut self, column: &str, value: AlgebraicValue) -> Query {
+        self.filter.push((String::from(column), value));
+        self
+    }
+
+    /// Keeps the rows whose `columns` are between `min` and `max`, read through a spatial
+    /// index on exactly those columns if the table has one.
+    pub fn filter_within(mut self, columns: &[&str], min: &[f64], max: &[f64]) -> Query {
+        self.within = Some(BoundingBox {
+            columns: columns.iter().map(|column| String::from(*column)).collect(),
+            min: min.to_vec(),
+            max: max.to_vec(),
+        });
+        self
+    }
+
+    /// Keeps the rows whose `column` matches `search`, as [`text`](super::text) describes,
+    /// best matches first. The rows are read through a text index on the column if the table
+    /// has one.
+    pub fn filter_matches(mut self, column: &str, search: &str) -> Query {
+        self.search = Some((String::from(column), String::from(search)));
+        self
+    }
+
+    /// Joins the rows of `table` whose `right` column equals the `left` column of a row.
+    pub fn join(mut self, table: &str, left: &str, right: &str) -> Query {
+        self.join = Some(Join {
+            table: String::from(table),
+            left: String::from(left),
+            right: String::from(right),
+        });
+        self
+    }
+
+    pub fn group_by(mut self, column: &str) -> Query {
+        self.group_by.push(String::from(column));
+        self
+    }
+
+    pub fn aggregate(mut self, aggregate: Aggregate) -> Query {
+        self.aggregates.push(aggregate);
+        self
+    }
+
+    /// Rows among `rows`, of type `schema`, that pass the filter, or their groups.
+    ///
+    /// `rows` are the joined rows for a query with a join.
+    pub fn run(
+        &self,
+        schema: &ProductType,
+        rows: impl Iterator<Item = ProductValue>,
+    ) -> Result<QueryResult, QueryError> {
+        let plan = Plan::new(self, schema)?;
+        let (_, rows) = evaluate(&plan, rows);
+        Ok(QueryResult {
+            schema: plan.schema,
+            rows,
+        })
+    }
+}
+
+/// Results of `query` over `tables`, joined if it has a join.
+pub(super) fn read(
+    query: &Query,
+    tables: &BTreeMap<u64, Table>,
+) -> Result<QueryResult, QueryError> {
+    if let Some(join) = JoinPlan::new(query, tables)? {
+        return query.run(&join.schema(tables), join.rows(tables)?.into_iter());
+    }
+    let table = tables
+        .values()
+        .find(|table| table.name() == query.table)
+        .ok_or_else(|| QueryError::NoSuchTable(query.table.clone()))?;
+    let rows = Plan::new(query, table.columns())?.scan(table)?;
+    query.run(table.columns(), rows.into_iter())
+}
+
+/// A query checked against the type of its table's rows, with the columns it uses resolved.
+pub(super) struct Plan {
+    columns: ProductType,
+    filter: Vec<(usize, AlgebraicValue)>,
+    /// Columns of the box and its corners.
+    within: Option<(Vec<usize>, Vec<f64>, Vec<f64>)>,
+    /// Column searched, the search and its words.
+    search: Option<(usize, String, Vec<String>)>,
+    group_by: Vec<usize>,
+    aggregates: Vec<(Aggregate, usize)>,
+    /// Type of the query's results.
+    schema: ProductType,
+}
+
+impl Plan {
+    pub(super) fn new(query: &Query, columns: &ProductType) -> Result<Plan, QueryError> {
+        let index_of = |column: &str| {
+            columns
+                .index_of(column)
+                .ok_or_else(|| QueryError::NoSuchColumn(String::from(column)))
+        };
+        let filter = query
+            .filter
+            .iter()
+            .map(|(column, value)| Ok((index_of(column)?, value.clone())))
+            .collect::<Result<Vec<_>, QueryError>>()?;
+        let within = match &query.within {
+            Some(bounds) => {
+                let mut within = Vec::new();
+                for column in &bounds.columns {
+                    let index = index_of(column)?;
+                    if numeric(&columns.elements[index].algebraic_type).is_none() {
+                        return Err(QueryError::InvalidBounds(column.clone()));
+                    }
+                    within.push(index);
+                }
+                let dims = within.len();
+                if bounds.min.len() != dims || bounds.max.len() != dims {
+                    return Err(QueryError::InvalidBounds(bounds.columns.join(", ")));
+                }
+                Some((within, bounds.min.clone(), bounds.max.clone()))
+            }
+            None => None,
+        };
+        let search = match &query.search {
+            Some((column, search)) => {
+                let index = index_of(column)?;
+                if columns.elements[index].algebraic_type != AlgebraicType::String {
+                    return Err(QueryError::InvalidSearch(column.clone()));
+                }
+                Some((index, search.clone(), text::words(search)))
+            }
+            None => None,
+        };
+        let group_by = query
+            .group_by
+            .iter()
+            .map(|column| index_of(column))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut aggregates = Vec::new();
+        let mut schema: Vec<_> = group_by
+            .iter()
+            .map(|column| columns.elements[*column].clone())
+            .collect();
+        for aggregate in &query.aggregates {
+            let column = aggregate.column().map(index_of).transpose()?.unwrap_or(0);
+            let ty = aggregate
+                .column()
+                .map(|_| &columns.elements[column].algebraic_type);
+            let ty = match (aggregate, ty) {
+                (Aggregate::Count, _) => AlgebraicType::U64,
+                (Aggregate::Sum(name), Some(ty)) => match numeric(ty) {
+                    Some(Numeric::Signed) => AlgebraicType::I64,
+                    Some(Numeric::Unsigned) => AlgebraicType::U64,
+                    Some(Numeric::Float) => AlgebraicType::F64,
+                    None => return Err(QueryError::InvalidAggregate(name.clone())),
+                },
+                (Aggregate::Avg(name), Some(ty)) => match numeric(ty) {
+                    Some(_) => AlgebraicType::option(AlgebraicType::F64),
+                    None => return Err(QueryError::InvalidAggregate(name.clone())),
+                },
+                (_, ty) => AlgebraicType::option(ty.expect("aggregate has a column").clone()),
+            };
+            schema.push(ProductTypeElement::new(&aggregate.name(), ty));
+            aggregates.push((aggregate.clone(), column));
+        }
+        let schema = match aggregates.is_empty() && group_by.is_empty() {
+            true => columns.clone(),
+            false => ProductType::new(schema),
+        };
+        Ok(Plan {
+            columns: columns.clone(),
+            filter,
+            within,
+            search,
+            group_by,
+            aggregates,
+            schema,
+        })
+    }
+
+    pub(super) fn schema(&self) -> &ProductType {
+        &self.schema
+    }
+
+    /// Whether the query reads groups rather than rows.
+    pub(super) fn is_aggregate(&self) -> bool {
+        !self.aggregates.is_empty() || !self.group_by.is_empty()
+    }
+
+    /// Whether `row` passes the filter.
+    pub(super) fn matches(&self, row: &ProductValue) -> bool {
+        let within = self.within.iter().all(|(columns, min, max)| {
+            columns.iter().enumerate().all(|(axis, column)| {
+                rtree::coordinate(&row.elements[*column])
+                    .is_some_and(|coordinate| min[axis] <= coordinate && coordinate <= max[axis])
+            })
+        });
+        within
+            && self.rank(row).is_some()
+            && self
+                .filter
+                .iter()
+                .all(|(index, value)| row.elements[*index] == *value)
+    }
+
+    /// Rank of `row` for the text search, or `None` if it doesn't match. Every row has the
+    /// same rank without a search.
+    fn rank(&self, row: &ProductValue) -> Option<f64> {
+        let Some((column, _, words)) = &self.search else {
+            return Some(0.0);
+        };
+        match &row.elements[*column] {
+            AlgebraicValue::String(text) => text::score(words, text),
+            _ => None,
+        }
+    }
+
+    /// Rows of `table` that may pass the filter: those matching the search or in the box, if
+    /// a text or spatial index covers their columns, or all of them.
+    pub(super) fn scan(&self, table: &Table) -> Result<Vec<ProductValue>, StorageError> {
+        let rows = |row_ids: Vec<RowId>| {
+            row_ids
+                .into_iter()
+                .filter_map(|row_id| table.get(row_id).transpose())
+                .collect()
+        };
+        if let Some((column, search, _)) = &self.search
+            && let Some(index) = table.text_index_on(*column)
+            && let Ok(row_ids) = table.search(index, search, usize::MAX)
+        {
+            return rows(row_ids);
+        }
+        if let Some((columns, min, max)) = &self.within
+            && let Some(index) = table.spatial_index_on(columns)
+            && let Ok(row_ids) = table.within(index, min, max)
+        {
+            return rows(row_ids);
+        }
+        table.iter().map(|row| row.map(|(_, row)| row)).collect()
+    }
+
+    /// Key of the group of `row`.
+    pub(super) fn key(&self, row: &ProductValue) -> ProductValue {
+        ProductValue::new(
+            self.group_by
+                .iter()
+                .map(|column| row.elements[*column].clone())
+                .collect(),
+        )
+    }
+}
+
+/// Results of `plan` over `rows`, with the state of their groups for aggregate queries.
+pub(super) fn evaluate(
+    plan: &Plan,
+    rows: impl Iterator<Item = ProductValue>,
+) -> (Option<Groups>, Vec<ProductValue>) {
+    let rows = rows.filter(|row| plan.matches(row));
+    match plan.is_aggregate() {
+        true => {
+            let mut groups = Groups::new();
+            for row in rows {
+                groups.add(plan, &row);
+            }
+            let rows = groups.rows(plan);
+            (Some(groups), rows)
+        }
+        false if plan.search.is_some() => {
+            let mut ranked: Vec<_> = rows
+                .map(|row| (plan.rank(&row).unwrap_or_default(), row))
+                .collect();
+            ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+            (None, ranked.into_iter().map(|(_, row)| row).collect())
+        }
+        false => (None, rows.collect()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Numeric {
+    Signed,
+    Unsigned,
+    Float,
+}
+
+fn numeric(ty: &AlgebraicType) -> Option<Numeric> {
+    match ty {
+        AlgebraicType::I8 | AlgebraicType::I16 | AlgebraicType::I32 | AlgebraicType::I64 => {
+            Some(Numeric::Signed)
+        }
+        AlgebraicType::U8 | AlgebraicType::U16 | AlgebraicType::U32 | AlgebraicType::U64 => {
+            Some(Numeric::Unsigned)
+        }
+        AlgebraicType::F32 | AlgebraicType::F64 => Some(Numeric::Float),
+        _ => None,
+    }
+}
+
+/// Sum of integers, exact, or of floats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Total {
+    Int(i128),
+    Float(FloatTotal),
+}
+
+impl Total {
+    fn add(&mut self, value: &AlgebraicValue, sign: i8) {
+        match self {
+            Total::Int(total) => {
+                let v = match *value {
+                    AlgebraicValue::I8(v) => v as i128,
+                    AlgebraicValue::U8(v) => v as i128,
+                    AlgebraicValue::I16(v) => v as i128,
+                    AlgebraicValue::U16(v) => v as i128,
+                    AlgebraicValue::I32(v) => v as i128,
+                    AlgebraicValue::U32(v) => v as i128,
+                    AlgebraicValue::I64(v) => v as i128,
+                    AlgebraicValue::U64(v) => v as i128,
+                    _ => return,
+                };
+                *total += v * sign as i128;
+            }
+            Total::Float(total) => match *value {
+                AlgebraicValue::F32(v) => total.add(v as f64, sign),
+                AlgebraicValue::F64(v) => total.add(v, sign),
+                _ => {}
+            },
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Total::Int(total) => *total as f64,
+            Total::Float(total) => total.sum(),
+        }
+    }
+}
+
+/// Sum of floats, with the NaNs and infinities counted apart: once added to the sum, they
+/// couldn't be taken out of it again.
+///
+/// The finite sum is compensated (Neumaier's summation), so taking out a large value leaves
+/// the small ones added next to it rather than the rounding error they were lost in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct FloatTotal {
+    finite: f64,
+    /// Low-order part of the finite sum that `finite` couldn't hold.
+    compensation: f64,
+    nans: i64,
+    infinities: i64,
+    negative_infinities: i64,
+}
+
+impl FloatTotal {
+    fn add(&mut self, value: f64, sign: i8) {
+        let count = if value.is_nan() {
+            &mut self.nans
+        } else if value == f64::INFINITY {
+            &mut self.infinities
+        } else if value == f64::NEG_INFINITY {
+            &mut self.negative_infinities
+        } else {
+            let value = value * sign as f64;
+            let sum = self.finite + value;
+            self.compensation += if self.finite.abs() >= value.abs() {
+                (self.finite - sum) + value
+            } else {
+                (value - sum) + self.finite
+            };
+            self.finite = sum;
+            return;
+        };
+        *count += sign as i64;
+    }
+
+    fn sum(&self) -> f64 {
+        if self.nans > 0 || (self.infinities > 0 && self.negative_infinities > 0) {
+            f64::NAN
+        } else if self.infinities > 0 {
+            f64::INFINITY
+        } else if self.negative_infinities > 0 {
+            f64::NEG_INFINITY
+        } else {
+            self.finite + self.compensation
+        }
+    }
+}
+
+enum Accumulator {
+    Count,
+    Total(Total),
+    /// How many rows have each value.
+    Values(BTreeMap<AlgebraicValue, u64>),
+}
+
+impl Accumulator {
+    fn new(aggregate: &Aggregate, ty: &AlgebraicType) -> Accumulator {
+        match aggregate {
+            Aggregate::Count => Accumulator::Count,
+            Aggregate::Sum(_) | Aggregate::Avg(_) => match numeric(ty) {
+                Some(Numeric::Float) => Accumulator::Total(Total::Float(FloatTotal::default())),
+                _ => Accumulator::Total(Total::Int(0)),
+            },
+            Aggregate::Min(_) | Aggregate::Max(_) => Accumulator::Values(BTreeMap::new()),
+        }
+    }
+
+    fn add(&mut self, value: &AlgebraicValue) {
+        match self {
+            Accumulator::Count => {}
+            Accumulator::Total(total) => total.add(value, 1),
+            Accumulator::Values(values) => *values.entry(value.clone()).or_default() += 1,
+        }
+    }
+
+    fn remove(&mut self, value: &AlgebraicValue) {
+        match self {
+            Accumulator::Count => {}
+            Accumulator::Total(total) => total.add(value, -1),
+            Accumulator::Values(values) => {
+                if let Some(count) = values.get_mut(value) {
+                    *count -= 1;
+                    if *count == 0 {
+                        values.remove(value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Group {
+    rows: u64,
+    accumulators: Vec<Accumulator>,
+}
+
+impl Group {
+    fn new(plan: &Plan) -> Group {
+        Group {
+            rows: 0,
+            accumulators: plan
+                .aggregates
+                .iter()
+                .map(|(aggregate, column)| {
+                    let ty = plan
+                        .columns
+                        .elements
+                        .get(*column)
+                        .map_or(&AlgebraicType::U64, |column| &column.algebraic_type);
+                    Accumulator::new(aggregate, ty)
+                })
+                .collect(),
+        }
+    }
+
+    fn row(&self, plan: &Plan, key: &ProductValue) -> ProductValue {
+        let some = |value| AlgebraicValue::Sum(SumValue::new(0, value));
+        let none = || AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit()));
+        let mut elements = key.elements.clone();
+        for ((aggregate, _), (accumulator, ty)) in plan.aggregates.iter().zip(
+            self.accumulators
+                .iter()
+                .zip(&plan.schema.elements[key.elements.len()..]),
+        ) {
+            let value = match (aggregate, accumulator) {
+                (Aggregate::Count, _) => AlgebraicValue::U64(self.rows),
+                (Aggregate::Sum(_), Accumulator::Total(total)) => {
+                    match (*total, &ty.algebraic_type) {
+                        (Total::Int(total), AlgebraicType::I64) => AlgebraicValue::I64(
+                            total.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+                        ),
+                        (Total::Int(total), _) => {
+                            AlgebraicValue::U64(total.clamp(0, u64::MAX as i128) as u64)
+                        }
+                        (Total::Float(total), _) => AlgebraicValue::F64(total.sum()),
+                    }
+                }
+                (Aggregate::Avg(_), Accumulator::Total(total)) => match self.rows {
+                    0 => none(),
+                    rows => some(AlgebraicValue::F64(total.as_f64() / rows as f64)),
+                },
+                (Aggregate::Min(_), Accumulator::Values(values)) => {
+                    values.keys().next().cloned().map_or_else(none, some)
+                }
+                (Aggregate::Max(_), Accumulator::Values(values)) => {
+                    values.keys().next_back().cloned().map_or_else(none, some)
+                }
+                _ => unreachable!("accumulator doesn't match its aggregate"),
+            };
+            elements.push(value);
+        }
+        ProductValue::new(elements)
+    }
+}
+
+/// State of the groups of an aggregate query, updated as rows are added and removed.
+pub(super) struct Groups {
+    groups: BTreeMap<ProductValue, Group>,
+}
+
+impl Groups {
+    pub(super) fn new() -> Groups {
+        Groups {
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Counts `row`, which must pass the filter, in its group.
+    pub(super) fn add(&mut self, plan: &Plan, row: &ProductValue) {
+        let group = self
+            .groups
+            .entry(plan.key(row))
+            .or_insert_with(|| Group::new(plan));
+        group.rows += 1;
+        for ((_, column), accumulator) in plan.aggregates.iter().zip(&mut group.accumulators) {
+            if let Some(value) = row.elements.get(*column) {
+                accumulator.add(value);
+            }
+        }
+    }
+
+    /// Stops counting `row`, which must have been added, dropping its group once empty.
+    pub(super) fn remove(&mut self, plan: &Plan, row: &ProductValue) {
+        let key = plan.key(row);
+        let Some(group) = self.groups.get_mut(&key) else {
+            return;
+        };
+        group.rows -= 1;
+        for ((_, column), accumulator) in plan.aggregates.iter().zip(&mut group.accumulators) {
+            if let Some(value) = row.elements.get(*column) {
+                accumulator.remove(value);
+            }
+        }
+        if group.rows == 0 {
+            self.groups.remove(&key);
+        }
+    }
+
+    /// Result row of the group with `key`, if the query reads one for it.
+    pub(super) fn row(&self, plan: &Plan, key: &ProductValue) -> Option<ProductValue> {
+        match self.groups.get(key) {
+            Some(group) => Some(group.row(plan, key)),
+            None if plan.group_by.is_empty() => Some(Group::new(plan).row(plan, key)),
+            None => None,
+        }
+    }
+
+    pub(super) fn rows(&self, plan: &Plan) -> Vec<ProductValue> {
+        match plan.group_by.is_empty() {
+            true => self
+                .row(plan, &ProductValue::new(Vec::new()))
+                .into_iter()
+                .collect(),
+            false => self
+                .groups
+                .iter()
+                .map(|(key, group)| group.row(plan, key))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub schema: ProductType,
+    pub rows: Vec<ProductValue>,
+}
+
+impl QueryResult {
+    pub fn to_json(&self) -> Result<String, JsonError> {
+        json::rows_to_string(&self.schema, &self.rows)
+    }
+
+    /// Row count as a `u32` followed by each encoded row.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.rows.len() as u32).to_le_bytes());
+        for row in &self.rows {
+            binary::encode_product(row, &mut buf);
+        }
+        buf
+    }
+}
+
+// Tests
+
+/// Columns of a player's name and one of their scores.
+#[cfg(test)]
+fn score_schema() -> ProductType {
+    super::test_util::columns([
+        ("player", AlgebraicType::String),
+        ("score", AlgebraicType::I32),
+    ])
+}
+
+/// Scores of ann and bob.
+#[cfg(test)]
+fn scores() -> impl Iterator<Item = ProductValue> {
+    let score = |player: &str, score| {
+        super::test_util::row([
+            AlgebraicValue::String(String::from(player)),
+            AlgebraicValue::I32(score),
+        ])
+    };
+    [
+        score("ann", 3),
+        score("bob", 5),
+        score("ann", -1),
+        score("ann", 7),
+    ]
+    .into_iter()
+}
+
+#[cfg(test)]
+fn some(value: AlgebraicValue) -> AlgebraicValue {
+    AlgebraicValue::Sum(SumValue::new(0, value))
+}
+
+#[test_case]
+fn test_aggregates() {
+    let leaderboard = Query::table("score")
+        .group_by("player")
+        .aggregate(Aggregate::Count)
+        .aggregate(Aggregate::Sum(String::from("score")))
+        .aggregate(Aggregate::Max(String::from("score")))
+        .aggregate(Aggregate::Avg(String::from("score")));
+    let result = leaderboard.run(&score_schema(), scores()).unwrap();
+    let names: Vec<_> = result
+        .schema
+        .elements
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        ["player", "count", "sum_score", "max_score", "avg_score"]
+    );
+    assert_eq!(
+        result.rows[0].elements[1..],
+        [
+            AlgebraicValue::U64(3),
+            AlgebraicValue::I64(9),
+            some(AlgebraicValue::I32(7)),
+            some(AlgebraicValue::F64(3.0)),
+        ]
+    );
+    assert_eq!(result.rows.len(), 2);
+}
+
+/// Without grouping columns, no rows are still a group.
+#[test_case]
+fn test_aggregates_ungrouped() {
+    let min = Query::table("score")
+        .filter_eq("player", AlgebraicValue::String(String::from("eve")))
+        .aggregate(Aggregate::Count)
+        .aggregate(Aggregate::Min(String::from("score")));
+    let result = min.run(&score_schema(), scores()).unwrap();
+    let none = AlgebraicValue::Sum(SumValue::new(1, AlgebraicValue::unit()));
+    assert_eq!(
+        result.rows,
+        [ProductValue::new(alloc::vec![AlgebraicValue::U64(0), none])]
+    );
+}
+
+#[test_case]
+fn test_aggregate_invalid() {
+    let sum = Query::table("score").aggregate(Aggregate::Sum(String::from("player")));
+    assert_eq!(
+        sum.run(&score_schema(), scores()),
+        Err(QueryError::InvalidAggregate(String::from("player")))
+    );
+}
+
+/// Plan of the sum and average of temperature readings.
+#[cfg(test)]
+fn reading_plan() -> Plan {
+    let schema = super::test_util::columns([("temperature", AlgebraicType::F64)]);
+    let average = Query::table("reading")
+        .aggregate(Aggregate::Sum(String::from("temperature")))
+        .aggregate(Aggregate::Avg(String::from("temperature")));
+    Plan::new(&average, &schema).unwrap()
+}
+
+#[cfg(test)]
+fn reading(value: f64) -> ProductValue {
+    super::test_util::row([AlgebraicValue::F64(value)])
+}
+
+/// Sum of the readings in `groups`.
+#[cfg(test)]
+fn reading_sum(plan: &Plan, groups: &Groups) -> f64 {
+    let row = groups.rows(plan).remove(0);
+    let AlgebraicValue::F64(sum) = row.elements[0] else {
+        unreachable!()
+    };
+    sum
+}
+
+/// NaNs and infinities leave float totals as they were once removed.
+#[test_case]
+fn test_float_totals_not_finite() {
+    let plan = reading_plan();
+    let mut groups = Groups::new();
+    groups.add(&plan, &reading(1.5));
+    groups.add(&plan, &reading(f64::INFINITY));
+    assert_eq!(reading_sum(&plan, &groups), f64::INFINITY);
+    groups.add(&plan, &reading(f64::NAN));
+    groups.add(&plan, &reading(f64::NEG_INFINITY));
+    assert!(reading_sum(&plan, &groups).is_nan());
+    for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        groups.remove(&plan, &reading(value));
+    }
+    assert_eq!(
+        groups.rows(&plan),
+        [ProductValue::new(alloc::vec![
+            AlgebraicValue::F64(1.5),
+            some(AlgebraicValue::F64(1.5)),
+        ])]
+    );
+}
+
+/// Large values taken out leave the small ones added next to them.
+#[test_case]
+fn test_float_totals_compensated() {
+    let plan = reading_plan();
+    let mut groups = Groups::new();
+    groups.add(&plan, &reading(1.5));
+    groups.add(&plan, &reading(1e20));
+    groups.add(&plan, &reading(0.25));
+    groups.remove(&plan, &reading(1e20));
+    assert_eq!(reading_sum(&plan, &groups), 1.75);
+}
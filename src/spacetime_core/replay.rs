@@ -0,0 +1,253 @@
+//! Re-running a module's logged calls on top of a snapshot, checking they write the same rows.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::history::{Commit, CommitLog, HistoryError, Snapshot};
+use super::{Module, ReducerCallError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    History(HistoryError),
+    /// The log doesn't have the call at this offset, right after the snapshot.
+    MissingCommit(u64),
+}
+
+/// A logged call that didn't do the same when replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub expected: Commit,
+    /// The call as logged by the replay, or `None` if the reducer couldn't be called.
+    pub actual: Option<Commit>,
+    pub outcome: Result<(), ReducerCallError>,
+}
+
+/// Logged calls replayed one at a time against a module.
+pub struct Replay {
+    module: Module,
+    commits: Vec<Commit>,
+    next: usize,
+}
+
+impl Replay {
+    /// Restores `snapshot` into `module` to replay the calls of `log` that follow it.
+    ///
+    /// `module` must have the definition and reducers of the module the history was taken from.
+    pub fn new(
+        mut module: Module,
+        snapshot: &Snapshot,
+        log: &CommitLog,
+    ) -> Result<Replay, ReplayError> {
+        module
+            .restore_snapshot(snapshot)
+            .map_err(ReplayError::History)?;
+        let commits: Vec<Commit> = log
+            .commits()
+            .iter()
+            .filter(|commit| commit.offset >= snapshot.offset)
+            .cloned()
+            .collect();
+        let starts_at_snapshot = match commits.first() {
+            Some(first) => first.offset == snapshot.offset,
+            None => log.next_offset() <= snapshot.offset,
+        };
+        if !starts_at_snapshot {
+            return Err(ReplayError::MissingCommit(snapshot.offset));
+        }
+        Ok(Replay {
+            module,
+            commits,
+            next: 0,
+        })
+    }
+
+    /// The module, in the state after the calls replayed so far.
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn into_module(self) -> Module {
+        self.module
+    }
+
+    /// The call [`Replay::step`] replays next, if any are left.
+    pub fn next_commit(&self) -> Option<&Commit> {
+        self.commits.get(self.next)
+    }
+
+    /// Replays the next call with the caller, arguments and timestamp it was logged with.
+    ///
+    /// Writes logged without a reducer are applied as they were logged, and calls aborted with
+    /// an atomic transaction are rolled back once they ran, as they were then.
+    ///
+    /// Returns `None` once every call was replayed, and the call if it committed or failed
+    /// just as logged, with the same writes.
+    pub fn step(&mut self) -> Option<Result<&Commit, Divergence>> {
+        let expected = self.commits.get(self.next)?;
+        self.next += 1;
+        let outcome = match expected.reducer.as_str() {
+            "" => self
+                .module
+                .redo(expected)
+                .map_err(|error| ReducerCallError::Failed(alloc::format!("{:?}", error))),
+            reducer if expected.aborted => self
+                .module
+                .prepare_at(
+                    reducer,
+                    expected.caller,
+                    expected.args.clone(),
+                    expected.timestamp,
+                )
+                .and_then(|prepared| {
+                    self.module.abort(prepared, ReducerCallError::Aborted);
+                    Err(ReducerCallError::Aborted)
+                }),
+            reducer => self.module.call(
+                reducer,
+                expected.caller,
+                expected.args.clone(),
+                expected.timestamp,
+            ),
+        };
+        let actual = self
+            .module
+            .commit_log
+            .commits()
+            .last()
+            .filter(|actual| actual.offset == expected.offset);
+        match actual {
+            Some(actual)
+                if actual.committed == expected.committed
+                    && actual.aborted == expected.aborted
+                    && actual.writes == expected.writes =>
+            {
+                Some(Ok(expected))
+            }
+            actual => Some(Err(Divergence {
+                expected: expected.clone(),
+                actual: actual.cloned(),
+                outcome,
+            })),
+        }
+    }
+
+    /// Replays every call left, stopping at the first that diverges.
+    pub fn run(&mut self) -> Result<(), Box<Divergence>> {
+        while let Some(result) = self.step() {
+            result.map_err(Box::new)?;
+        }
+        Ok(())
+    }
+}
+
+// Tests
+
+/// Module whose reducer `add(n)` inserts `n + step` into its counter, failing if `n` is 0.
+#[cfg(test)]
+fn counter_module(step: u64) -> Module {
+    use super::def::TableDef;
+    use super::test_util::{add_reducer, columns, failed, row};
+    use super::value::{AlgebraicType, AlgebraicValue};
+    use alloc::string::String;
+
+    let counter = columns([("id", AlgebraicType::U64), ("n", AlgebraicType::U64)]);
+    let mut module = Module::new(String::from("counter"));
+    module
+        .add_table(TableDef::new("counter", counter).with_sequence("counter_id", 0))
+        .unwrap();
+    let params = columns([("n", AlgebraicType::U64)]);
+    add_reducer(&mut module, "add", params, move |ctx, args| {
+        let AlgebraicValue::U64(n) = args.elements[0] else {
+            unreachable!()
+        };
+        let counter = row([AlgebraicValue::U64(0), AlgebraicValue::U64(n + step)]);
+        ctx.insert("counter", counter).map_err(failed)?;
+        if n == 0 {
+            return Err(String::from("zero"));
+        }
+        Ok(())
+    });
+    module
+}
+
+#[cfg(test)]
+fn add(n: u64) -> super::value::ProductValue {
+    super::test_util::row([super::value::AlgebraicValue::U64(n)])
+}
+
+/// A counter module after adding 5, then 0 and 7, with its snapshot in between and its log,
+/// both read back from bytes.
+#[cfg(test)]
+fn test_history() -> (Module, Snapshot, CommitLog) {
+    let mut module = counter_module(0);
+    module.call_reducer("add", 1, add(5)).unwrap();
+    let snapshot = module.snapshot().unwrap();
+    for n in [0, 7] {
+        let _ = module.call_reducer("add", 2, add(n));
+    }
+    let snapshot = Snapshot::from_bytes(&module, &snapshot.to_bytes()).unwrap();
+    let log = CommitLog::from_bytes(&module, &module.commit_log().to_bytes()).unwrap();
+    (module, snapshot, log)
+}
+
+#[test_case]
+fn test_replay() {
+    let (module, snapshot, log) = test_history();
+    assert_eq!(log.commits(), module.commit_log().commits());
+    assert_eq!(log.commits().len(), 3);
+    assert!(!log.commits()[1].committed);
+
+    let mut replay = Replay::new(counter_module(0), &snapshot, &log).unwrap();
+    assert_eq!(replay.next_commit().map(|commit| commit.offset), Some(1));
+    assert_eq!(replay.run(), Ok(()));
+    let rows = |module: &Module| {
+        let table = module.table("counter").unwrap();
+        table.iter().map(|row| row.unwrap().1).collect::<Vec<_>>()
+    };
+    assert_eq!(rows(replay.module()), rows(&module));
+}
+
+#[test_case]
+fn test_replay_divergence() {
+    let (_, snapshot, log) = test_history();
+    let mut replay = Replay::new(counter_module(1), &snapshot, &log).unwrap();
+    assert!(replay.step().unwrap().is_ok());
+    let divergence = replay.step().unwrap().unwrap_err();
+    assert_eq!(divergence.expected.offset, 2);
+    assert_eq!(divergence.actual.unwrap().writes.len(), 1);
+}
+
+#[test_case]
+fn test_replay_missing_commit() {
+    let (mut module, snapshot, _) = test_history();
+    module.set_commit_log_capacity(1);
+    assert!(matches!(
+        Replay::new(counter_module(0), &snapshot, module.commit_log()),
+        Err(ReplayError::MissingCommit(1))
+    ));
+}
+
+#[test_case]
+fn test_replay_aborted() {
+    use super::atomic::{self, AtomicCall};
+    use alloc::collections::BTreeMap;
+
+    let counter = counter_module(0);
+    let counter_id = counter.id();
+    let snapshot = counter.snapshot().unwrap();
+    let failing = counter_module(0);
+    let failing_id = failing.id();
+    let mut modules = BTreeMap::from([(counter_id, counter), (failing_id, failing)]);
+    let calls = alloc::vec![
+        AtomicCall::new(counter_id, "add", add(5)),
+        AtomicCall::new(failing_id, "add", add(0)),
+    ];
+    assert!(atomic::run(&mut modules, calls, 1).is_err());
+    let counter = &modules[&counter_id];
+    let log = CommitLog::from_bytes(counter, &counter.commit_log().to_bytes()).unwrap();
+    assert!(log.commits()[0].aborted);
+
+    // The call succeeds again, and is rolled back as it was with its transaction
+    let mut replay = Replay::new(counter_module(0), &snapshot, &log).unwrap();
+    assert_eq!(replay.run(), Ok(()));
+    assert!(replay.module().table("counter").unwrap().is_empty());
+}
@@ -0,0 +1,409 @@
+//! R-trees: spatial indexes of points with two or three coordinates, finding the points in a
+//! bounding box or nearest to a point without reading the others.
+//!
+//! Nodes hold up to [`MAX_ENTRIES`] entries, each under the bounding box of its points.
+//! Inserts go down the child whose box grows the least, splitting full nodes in two along
+//! their widest axis. Deletes reinsert the points of nodes left with fewer than
+//! [`MIN_ENTRIES`] entries.
+
+use alloc::{collections::BinaryHeap, vec::Vec};
+use core::cmp::Ordering;
+
+use super::table::RowId;
+use super::value::AlgebraicValue;
+
+pub const MAX_ENTRIES: usize = 8;
+pub const MIN_ENTRIES: usize = 3;
+
+/// Coordinates of a point, those past the tree's dimensions being `0`.
+pub type Point = [f64; 3];
+
+/// Coordinate given by a numeric value, or `None` for other values and NaN.
+pub fn coordinate(value: &AlgebraicValue) -> Option<f64> {
+    let coordinate = match *value {
+        AlgebraicValue::I8(v) => v as f64,
+        AlgebraicValue::U8(v) => v as f64,
+        AlgebraicValue::I16(v) => v as f64,
+        AlgebraicValue::U16(v) => v as f64,
+        AlgebraicValue::I32(v) => v as f64,
+        AlgebraicValue::U32(v) => v as f64,
+        AlgebraicValue::I64(v) => v as f64,
+        AlgebraicValue::U64(v) => v as f64,
+        AlgebraicValue::F32(v) => v as f64,
+        AlgebraicValue::F64(v) => v,
+        _ => return None,
+    };
+    (!coordinate.is_nan()).then_some(coordinate)
+}
+
+/// Box between two corners, inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rect {
+    min: Point,
+    max: Point,
+}
+
+impl Rect {
+    fn point(point: Point) -> Rect {
+        Rect {
+            min: point,
+            max: point,
+        }
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        let mut union = *self;
+        for axis in 0..3 {
+            union.min[axis] = union.min[axis].min(other.min[axis]);
+            union.max[axis] = union.max[axis].max(other.max[axis]);
+        }
+        union
+    }
+
+    /// Area or volume, over the first `dims` axes.
+    fn size(&self, dims: usize) -> f64 {
+        (0..dims)
+            .map(|axis| self.max[axis] - self.min[axis])
+            .product()
+    }
+
+    fn intersects(&self, other: &Rect) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && other.min[axis] <= self.max[axis])
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.intersects(&Rect::point(*point))
+    }
+
+    /// Squared distance from `point` to the closest point of the box.
+    fn distance(&self, point: &Point) -> f64 {
+        (0..3)
+            .map(|axis| {
+                let gap = (self.min[axis] - point[axis])
+                    .max(point[axis] - self.max[axis])
+                    .max(0.0);
+                gap * gap
+            })
+            .sum()
+    }
+
+    fn center(&self, axis: usize) -> f64 {
+        (self.min[axis] + self.max[axis]) / 2.0
+    }
+}
+
+enum Node {
+    Leaf(Vec<(Point, RowId)>),
+    Inner(Vec<(Rect, Node)>),
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => entries.len(),
+            Node::Inner(children) => children.len(),
+        }
+    }
+
+    /// Box around the node's entries, which mustn't be empty.
+    fn bounds(&self) -> Rect {
+        match self {
+            Node::Leaf(entries) => entries
+                .iter()
+                .map(|(point, _)| Rect::point(*point))
+                .reduce(|a, b| a.union(&b)),
+            Node::Inner(children) => children
+                .iter()
+                .map(|(rect, _)| *rect)
+                .reduce(|a, b| a.union(&b)),
+        }
+        .expect("node is empty")
+    }
+
+    /// Inserts a point, returning the new sibling of the node if it had to be split.
+    fn insert(&mut self, point: Point, row_id: RowId, dims: usize) -> Option<Node> {
+        match self {
+            Node::Leaf(entries) => {
+                entries.push((point, row_id));
+                (entries.len() > MAX_ENTRIES)
+                    .then(|| Node::Leaf(split(entries, |(point, _)| Rect::point(*point))))
+            }
+            Node::Inner(children) => {
+                let rect = Rect::point(point);
+                let growth = |child: &Rect| {
+                    let size = child.size(dims);
+                    (child.union(&rect).size(dims) - size, size)
+                };
+                let (_, (child_rect, child)) = children
+                    .iter_mut()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| {
+                        let (a, b) = (growth(&a.0), growth(&b.0));
+                        a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+                    })
+                    .expect("inner node is empty");
+                let sibling = child.insert(point, row_id, dims);
+                *child_rect = child.bounds();
+                if let Some(sibling) = sibling {
+                    children.push((sibling.bounds(), sibling));
+                }
+                (children.len() > MAX_ENTRIES)
+                    .then(|| Node::Inner(split(children, |(rect, _)| *rect)))
+            }
+        }
+    }
+
+    /// Removes a point, adding the points of the nodes it leaves too small to `orphans`.
+    fn remove(&mut self, point: &Point, row_id: RowId, orphans: &mut Vec<(Point, RowId)>) -> bool {
+        match self {
+            Node::Leaf(entries) => {
+                let found = entries
+                    .iter()
+                    .position(|entry| entry.0 == *point && entry.1 == row_id);
+                found
+                    .map(|position| entries.swap_remove(position))
+                    .is_some()
+            }
+            Node::Inner(children) => {
+                for i in 0..children.len() {
+                    let (rect, child) = &mut children[i];
+                    if !rect.contains(point) || !child.remove(point, row_id, orphans) {
+                        continue;
+                    }
+                    if child.len() < MIN_ENTRIES {
+                        let (_, child) = children.swap_remove(i);
+                        child.points(orphans);
+                    } else {
+                        *rect = child.bounds();
+                    }
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    fn points(self, points: &mut Vec<(Point, RowId)>) {
+        match self {
+            Node::Leaf(entries) => points.extend(entries),
+            Node::Inner(children) => {
+                for (_, child) in children {
+                    child.points(points);
+                }
+            }
+        }
+    }
+
+    fn search(&self, rect: &Rect, found: &mut Vec<RowId>) {
+        match self {
+            Node::Leaf(entries) => found.extend(
+                entries
+                    .iter()
+                    .filter(|(point, _)| rect.contains(point))
+                    .map(|(_, row_id)| *row_id),
+            ),
+            Node::Inner(children) => {
+                for (child_rect, child) in children {
+                    if rect.intersects(child_rect) {
+                        child.search(rect, found);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Moves half of `entries`, those further along the axis their centers spread the most on,
+/// to a new vector.
+fn split<T>(entries: &mut Vec<T>, rect: impl Fn(&T) -> Rect) -> Vec<T> {
+    let spread = |axis: usize| {
+        let centers = entries.iter().map(|entry| rect(entry).center(axis));
+        let max = centers.clone().fold(f64::MIN, f64::max);
+        max - centers.fold(f64::MAX, f64::min)
+    };
+    let axis = (0..3)
+        .max_by(|a, b| spread(*a).total_cmp(&spread(*b)))
+        .unwrap_or(0);
+    entries.sort_by(|a, b| rect(a).center(axis).total_cmp(&rect(b).center(axis)));
+    entries.split_off(entries.len() / 2)
+}
+
+pub struct RTree {
+    dims: usize,
+    root: Node,
+    len: usize,
+}
+
+impl RTree {
+    /// An empty tree of points with `dims` coordinates, two or three.
+    pub fn new(dims: usize) -> RTree {
+        RTree {
+            dims,
+            root: Node::Leaf(Vec::new()),
+            len: 0,
+        }
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, point: Point, row_id: RowId) {
+        self.len += 1;
+        if let Some(sibling) = self.root.insert(point, row_id, self.dims) {
+            let root = core::mem::replace(&mut self.root, Node::Leaf(Vec::new()));
+            self.root = Node::Inner(alloc::vec![
+                (root.bounds(), root),
+                (sibling.bounds(), sibling),
+            ]);
+        }
+    }
+
+    /// Removes the point of `row_id` at `point`, returning whether it was there.
+    pub fn remove(&mut self, point: &Point, row_id: RowId) -> bool {
+        let mut orphans = Vec::new();
+        if !self.root.remove(point, row_id, &mut orphans) {
+            return false;
+        }
+        self.len -= 1;
+        loop {
+            match &mut self.root {
+                Node::Inner(children) if children.len() == 1 => {
+                    let (_, child) = children.pop().expect("root has a child");
+                    self.root = child;
+                }
+                Node::Inner(children) if children.is_empty() => self.root = Node::Leaf(Vec::new()),
+                _ => break,
+            }
+        }
+        for (point, row_id) in orphans {
+            self.len -= 1;
+            self.insert(point, row_id);
+        }
+        true
+    }
+
+    /// Rows of the points between `min` and `max`, inclusive.
+    pub fn within(&self, min: Point, max: Point) -> Vec<RowId> {
+        let mut found = Vec::new();
+        self.root.search(&Rect { min, max }, &mut found);
+        found
+    }
+
+    /// Rows of the `k` points nearest to `point`, nearest first.
+    pub fn nearest(&self, point: &Point, k: usize) -> Vec<RowId> {
+        let mut found = Vec::new();
+        let mut candidates = BinaryHeap::new();
+        candidates.push(Candidate {
+            distance: 0.0,
+            item: Item::Node(&self.root),
+        });
+        while found.len() < k
+            && let Some(Candidate { item, .. }) = candidates.pop()
+        {
+            match item {
+                Item::Row(row_id) => found.push(row_id),
+                Item::Node(Node::Leaf(entries)) => {
+                    candidates.extend(entries.iter().map(|(entry, row_id)| Candidate {
+                        distance: Rect::point(*entry).distance(point),
+                        item: Item::Row(*row_id),
+                    }))
+                }
+                Item::Node(Node::Inner(children)) => {
+                    candidates.extend(children.iter().map(|(rect, child)| Candidate {
+                        distance: rect.distance(point),
+                        item: Item::Node(child),
+                    }))
+                }
+            }
+        }
+        found
+    }
+}
+
+enum Item<'a> {
+    Node(&'a Node),
+    Row(RowId),
+}
+
+/// A node or point to visit, the heap popping the nearest first.
+struct Candidate<'a> {
+    distance: f64,
+    item: Item<'a>,
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+fn point(x: u64, y: u64) -> [f64; 3] {
+    [x as f64, y as f64, 0.0]
+}
+
+/// Tree of a 20 by 20 grid of points, the point at `(x, y)` that of row `x * 20 + y`.
+#[cfg(test)]
+fn grid_tree() -> RTree {
+    let mut tree = RTree::new(2);
+    for x in 0..20 {
+        for y in 0..20 {
+            tree.insert(point(x, y), RowId(x * 20 + y));
+        }
+    }
+    tree
+}
+
+#[test_case]
+fn test_rtree() {
+    let tree = grid_tree();
+    assert_eq!(tree.len(), 400);
+    let mut found = tree.within(point(2, 3), point(4, 4));
+    found.sort();
+    let ids = [43, 44, 63, 64, 83, 84].map(RowId);
+    assert_eq!(found, ids);
+    assert_eq!(tree.nearest(&[10.2, 9.9, 0.0], 1), [RowId(210)]);
+    assert_eq!(tree.nearest(&point(0, 0), 3)[0], RowId(0));
+}
+
+#[test_case]
+fn test_rtree_remove() {
+    let mut tree = grid_tree();
+    for x in 0..20 {
+        for y in 0..19 {
+            assert!(tree.remove(&point(x, y), RowId(x * 20 + y)));
+        }
+    }
+    assert!(!tree.remove(&point(0, 0), RowId(0)));
+    assert_eq!(tree.len(), 20);
+    let mut found = tree.within(point(0, 0), point(19, 19));
+    found.sort();
+    let ids: Vec<_> = (0..20).map(|x| RowId(x * 20 + 19)).collect();
+    assert_eq!(found, ids);
+    assert_eq!(tree.nearest(&point(5, 0), 1), [RowId(119)]);
+}
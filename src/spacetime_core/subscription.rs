@@ -0,0 +1,387 @@
+//! Subscriptions: queries whose results are kept up to date from the rows each commit changes,
+//! the changes queued for the subscriber to poll.
+//!
+//! A subscription whose changes can't be found, a page of a table failing to be read back,
+//! fails from then on: subscribing again reads its results afresh.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    rc::Rc,
+    vec::Vec,
+};
+
+use super::buffer_pool::StorageError;
+use super::join::JoinPlan;
+use super::query::{self, Groups, Plan, Query, QueryError, QueryResult};
+use super::table::Table;
+use super::transaction::{Transaction, Write};
+use super::value::ProductValue;
+use super::view::{self, View};
+
+/// Rows leaving and entering the results of a subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionUpdate {
+    pub deletes: Vec<ProductValue>,
+    pub inserts: Vec<ProductValue>,
+}
+
+/// Rows of one table removed and added by a commit, an update being both.
+#[derive(Debug, Default)]
+pub(super) struct TableDelta {
+    pub deletes: Vec<ProductValue>,
+    pub inserts: Vec<ProductValue>,
+}
+
+/// Changes of each table written by a commit, by table id.
+pub(super) type Deltas = BTreeMap<u64, TableDelta>;
+
+/// Records a row of the table `table_id` going from `old` to `new`.
+pub(super) fn push(
+    deltas: &mut Deltas,
+    table_id: u64,
+    old: Option<ProductValue>,
+    new: Option<ProductValue>,
+) {
+    if old != new {
+        let delta = deltas.entry(table_id).or_default();
+        delta.deletes.extend(old);
+        delta.inserts.extend(new);
+    }
+}
+
+/// Changes made by `tx`, whose writes are applied to `tables`.
+pub(super) fn deltas(
+    tx: &Transaction,
+    tables: &BTreeMap<u64, Table>,
+) -> Result<Deltas, StorageError> {
+    let mut seen = BTreeSet::new();
+    let mut deltas = Deltas::new();
+    for write in tx.writes() {
+        let (table_id, row_id) = write.row();
+        if !seen.insert((table_id, row_id)) {
+            continue;
+        }
+        let old = match write {
+            Write::Insert { .. } => None,
+            Write::Delete { row, .. } | Write::Update { row, .. } => Some(row.clone()),
+        };
+        let new = match tables.get(&table_id) {
+            Some(table) => table.get(row_id)?,
+            None => None,
+        };
+        push(&mut deltas, table_id, old, new);
+    }
+    Ok(deltas)
+}
+
+/// Changes from the rows of `old` to those of `new`, tables with the same ids.
+pub(super) fn diff(
+    old: &BTreeMap<u64, Table>,
+    new: &BTreeMap<u64, Table>,
+) -> Result<Deltas, StorageError> {
+    let mut deltas = Deltas::new();
+    for (table_id, table) in new {
+        let mut old_rows: BTreeMap<_, _> = match old.get(table_id) {
+            Some(table) => table.iter().collect::<Result<_, _>>()?,
+            None => BTreeMap::new(),
+        };
+        for row in table.iter() {
+            let (row_id, row) = row?;
+            push(&mut deltas, *table_id, old_rows.remove(&row_id), Some(row));
+        }
+        for row in old_rows.into_values() {
+            push(&mut deltas, *table_id, Some(row), None);
+        }
+    }
+    Ok(deltas)
+}
+
+/// Queues the changes `deltas` made to `tables` make to the results of each of
+/// `subscriptions`, reading views for `timeout` ticks at most.
+pub(super) fn publish(
+    subscriptions: &mut BTreeMap<u64, Subscription>,
+    deltas: &Deltas,
+    tables: &BTreeMap<u64, Table>,
+    timeout: u64,
+) {
+    if deltas.is_empty() {
+        return;
+    }
+    for subscription in subscriptions.values_mut() {
+        subscription.apply(deltas, tables, timeout);
+    }
+}
+
+/// Fails every one of `subscriptions` with `error`, their changes being unknown.
+pub(super) fn fail(subscriptions: &mut BTreeMap<u64, Subscription>, error: StorageError) {
+    for subscription in subscriptions.values_mut() {
+        subscription.fail(error.clone());
+    }
+}
+
+/// Rows a subscription's query reads.
+enum Source {
+    Table(u64),
+    Join(JoinPlan),
+    /// A view read as `caller`, with its rows as last read.
+    View {
+        view: Rc<View>,
+        caller: Option<u64>,
+        rows: Vec<ProductValue>,
+    },
+}
+
+pub(super) struct Subscription {
+    source: Source,
+    plan: Plan,
+    /// State of the groups, for aggregate queries.
+    groups: Option<Groups>,
+    pending: Vec<SubscriptionUpdate>,
+    /// Why the changes to the results are no longer known, if they aren't.
+    failed: Option<StorageError>,
+}
+
+impl Subscription {
+    /// Subscribes to `query` over one of `tables`, also returning its current results.
+    pub(super) fn new(
+        query: &Query,
+        tables: &BTreeMap<u64, Table>,
+    ) -> Result<(Subscription, QueryResult), QueryError> {
+        let (source, plan, rows) = match JoinPlan::new(query, tables)? {
+            Some(join) => {
+                let plan = Plan::new(query, &join.schema(tables))?;
+                let rows = join.rows(tables)?;
+                (Source::Join(join), plan, rows)
+            }
+            None => {
+                let table = tables
+                    .values()
+                    .find(|table| table.name() == query.table)
+                    .ok_or_else(|| QueryError::NoSuchTable(query.table.clone()))?;
+                let plan = Plan::new(query, table.columns())?;
+                let rows = plan.scan(table)?;
+                (Source::Table(table.id()), plan, rows)
+            }
+        };
+        Ok(Subscription::start(source, plan, rows))
+    }
+
+    /// Subscribes to `query` over `view`, read as `caller` for `timeout` ticks at most, also
+    /// returning its current results.
+    pub(super) fn of_view(
+        query: &Query,
+        view: Rc<View>,
+        caller: Option<u64>,
+        tables: &BTreeMap<u64, Table>,
+        timeout: u64,
+    ) -> Result<(Subscription, QueryResult), QueryError> {
+        let plan = Plan::new(query, view.columns())?;
+        let rows = view.rows(caller, tables, timeout)?;
+        let source = Source::View {
+            view,
+            caller,
+            rows: rows.clone(),
+        };
+        Ok(Subscription::start(source, plan, rows))
+    }
+
+    fn start(source: Source, plan: Plan, rows: Vec<ProductValue>) -> (Subscription, QueryResult) {
+        let (groups, rows) = query::evaluate(&plan, rows.into_iter());
+        let result = QueryResult {
+            schema: plan.schema().clone(),
+            rows,
+        };
+        let subscription = Subscription {
+            source,
+            plan,
+            groups,
+            pending: Vec::new(),
+            failed: None,
+        };
+        (subscription, result)
+    }
+
+    /// Queues the changes `deltas` made to `tables` make to the results, if any.
+    pub(super) fn apply(&mut self, deltas: &Deltas, tables: &BTreeMap<u64, Table>, timeout: u64) {
+        if self.failed.is_some() {
+            return;
+        }
+        let computed;
+        let delta = match &mut self.source {
+            Source::Table(table_id) => match deltas.get(table_id) {
+                Some(delta) => delta,
+                None => return,
+            },
+            Source::Join(join) if join.is_changed(deltas) => match join.delta(deltas, tables) {
+                Ok(delta) => {
+                    computed = delta;
+                    &computed
+                }
+                Err(error) => return self.fail(error),
+            },
+            Source::Join(_) => return,
+            // The view's function may read any table, and a failed read leaves its rows as
+            // they were
+            Source::View { view, caller, rows } => match view.rows(*caller, tables, timeout) {
+                Ok(new) => {
+                    computed = view::diff(rows, &new);
+                    *rows = new;
+                    &computed
+                }
+                Err(_) => return,
+            },
+        };
+        let deletes = delta.deletes.iter().filter(|row| self.plan.matches(row));
+        let inserts = delta.inserts.iter().filter(|row| self.plan.matches(row));
+        let update = match &mut self.groups {
+            None => SubscriptionUpdate {
+                deletes: deletes.cloned().collect(),
+                inserts: inserts.cloned().collect(),
+            },
+            Some(groups) => {
+                // Results of the groups the rows are in, before they change
+                let mut touched = BTreeMap::new();
+                for row in deletes.clone().chain(inserts.clone()) {
+                    let key = self.plan.key(row);
+                    if let Entry::Vacant(entry) = touched.entry(key) {
+                        let old = groups.row(&self.plan, entry.key());
+                        entry.insert(old);
+                    }
+                }
+                for row in deletes {
+                    groups.remove(&self.plan, row);
+                }
+                for row in inserts {
+                    groups.add(&self.plan, row);
+                }
+                let mut update = SubscriptionUpdate {
+                    deletes: Vec::new(),
+                    inserts: Vec::new(),
+                };
+                for (key, old) in touched {
+                    let new = groups.row(&self.plan, &key);
+                    if old != new {
+                        update.deletes.extend(old);
+                        update.inserts.extend(new);
+                    }
+                }
+                update
+            }
+        };
+        if !update.deletes.is_empty() || !update.inserts.is_empty() {
+            self.pending.push(update);
+        }
+    }
+
+    fn fail(&mut self, error: StorageError) {
+        self.pending.clear();
+        self.failed = Some(error);
+    }
+
+    /// Takes the updates queued since the last poll, oldest first, or fails if the
+    /// subscription did.
+    pub(super) fn poll(&mut self) -> Result<Vec<SubscriptionUpdate>, QueryError> {
+        if let Some(error) = &self.failed {
+            return Err(QueryError::Storage(error.clone()));
+        }
+        Ok(core::mem::take(&mut self.pending))
+    }
+}
+
+// Tests
+
+/// Module with a score of 10 for player 1, subscribed to the total score of each player.
+#[cfg(test)]
+fn scores_module() -> (super::Module, u64) {
+    use super::query::Aggregate;
+    use super::test_util::{columns, module};
+    use super::value::AlgebraicType;
+    use alloc::string::String;
+
+    let columns = columns([
+        ("player", AlgebraicType::U64),
+        ("score", AlgebraicType::U32),
+    ]);
+    let mut module = module("scores", [("score", columns)]);
+    module.insert_rows("score", 0, [score(1, 10)]).unwrap();
+    let leaderboard = Query::table("score")
+        .group_by("player")
+        .aggregate(Aggregate::Sum(String::from("score")));
+    let (totals, result) = module.subscribe(&leaderboard).unwrap();
+    assert_eq!(result.rows, [total(1, 10)]);
+    (module, totals)
+}
+
+#[cfg(test)]
+fn score(player: u64, score: u32) -> ProductValue {
+    use super::value::AlgebraicValue;
+
+    super::test_util::row([AlgebraicValue::U64(player), AlgebraicValue::U32(score)])
+}
+
+#[cfg(test)]
+fn total(player: u64, score: u64) -> ProductValue {
+    use super::value::AlgebraicValue;
+
+    super::test_util::row([AlgebraicValue::U64(player), AlgebraicValue::U64(score)])
+}
+
+#[cfg(test)]
+fn player_2() -> Query {
+    Query::table("score").filter_eq("player", super::value::AlgebraicValue::U64(2))
+}
+
+#[test_case]
+fn test_subscriptions() {
+    let (mut module, totals) = scores_module();
+    let (player_2, _) = module.subscribe(&player_2()).unwrap();
+    module
+        .insert_rows("score", 0, [score(1, 5), score(2, 7)])
+        .unwrap();
+    let update = SubscriptionUpdate {
+        deletes: alloc::vec![total(1, 10)],
+        inserts: alloc::vec![total(1, 15), total(2, 7)],
+    };
+    assert_eq!(module.poll_subscription(totals), Ok(alloc::vec![update]));
+    assert_eq!(module.poll_subscription(totals), Ok(Vec::new()));
+    let update = SubscriptionUpdate {
+        deletes: Vec::new(),
+        inserts: alloc::vec![score(2, 7)],
+    };
+    assert_eq!(module.poll_subscription(player_2), Ok(alloc::vec![update]));
+}
+
+/// Restoring puts the results back too.
+#[test_case]
+fn test_subscriptions_restore() {
+    let (mut module, totals) = scores_module();
+    module
+        .insert_rows("score", 0, [score(1, 5), score(2, 7)])
+        .unwrap();
+    module.poll_subscription(totals).unwrap();
+    module
+        .restore(super::history::RestorePoint::Offset(1))
+        .unwrap();
+    let update = SubscriptionUpdate {
+        deletes: alloc::vec![total(1, 15), total(2, 7)],
+        inserts: alloc::vec![total(1, 10)],
+    };
+    assert_eq!(module.poll_subscription(totals), Ok(alloc::vec![update]));
+}
+
+#[test_case]
+fn test_unsubscribe() {
+    use alloc::string::String;
+
+    let (mut module, _) = scores_module();
+    assert_eq!(
+        module.subscribe(&Query::table("st_table")).map(|_| ()),
+        Err(QueryError::NotSubscribable(String::from("st_table")))
+    );
+    let (player_2, _) = module.subscribe(&player_2()).unwrap();
+    assert_eq!(module.unsubscribe(player_2), Ok(()));
+    assert_eq!(
+        module.poll_subscription(player_2),
+        Err(QueryError::NoSuchSubscription(player_2))
+    );
+}
@@ -0,0 +1,365 @@
+//! System tables: the catalog of a module, and of the core, as rows.
+//!
+//! They're built from the catalog when queried, so they're always current and can't be written
+//! to. Table names starting with [`PREFIX`] are reserved for them.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use super::audit;
+use super::binary;
+use super::def::{ConstraintDef, IndexKind, Lifecycle};
+use super::value::{
+    AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue, SumValue,
+};
+use super::view::ViewSource;
+use super::{Module, SpacetimeCore};
+
+pub const PREFIX: &str = "st_";
+
+pub const ST_TABLE: &str = "st_table";
+pub const ST_COLUMN: &str = "st_column";
+pub const ST_INDEX: &str = "st_index";
+pub const ST_CONSTRAINT: &str = "st_constraint";
+pub const ST_SEQUENCE: &str = "st_sequence";
+pub const ST_REDUCER: &str = "st_reducer";
+pub const ST_VIEW: &str = "st_view";
+pub const ST_AUDIT: &str = "st_audit";
+pub const ST_MODULE: &str = "st_module";
+pub const ST_USER: &str = "st_user";
+
+/// Rows of a system table.
+pub struct SystemTable {
+    pub schema: ProductType,
+    pub rows: Vec<ProductValue>,
+}
+
+impl SystemTable {
+    fn new(columns: &[(&str, AlgebraicType)]) -> SystemTable {
+        SystemTable {
+            schema: ProductType::new(
+                columns
+                    .iter()
+                    .map(|(name, ty)| ProductTypeElement::new(name, ty.clone()))
+                    .collect(),
+            ),
+            rows: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, row: Vec<AlgebraicValue>) {
+        self.rows.push(ProductValue::new(row));
+    }
+}
+
+pub fn is_system_table(name: &str) -> bool {
+    name.starts_with(PREFIX)
+}
+
+/// The system table of `module` named `name`.
+pub fn module_table(module: &Module, name: &str) -> Option<SystemTable> {
+    let columns = || AlgebraicType::Array(Box::new(AlgebraicType::U16));
+    let mut table = match name {
+        ST_TABLE => SystemTable::new(&[
+            ("table_id", AlgebraicType::U64),
+            ("table_name", AlgebraicType::String),
+            ("row_count", AlgebraicType::U64),
+        ]),
+        ST_COLUMN => SystemTable::new(&[
+            ("table_id", AlgebraicType::U64),
+            ("col_pos", AlgebraicType::U16),
+            ("col_name", AlgebraicType::String),
+            // In the binary format, as in module definitions
+            ("col_type", AlgebraicType::Bytes),
+        ]),
+        ST_INDEX => SystemTable::new(&[
+            ("table_id", AlgebraicType::U64),
+            ("index_name", AlgebraicType::String),
+            ("kind", AlgebraicType::String),
+            ("columns", columns()),
+        ]),
+        ST_CONSTRAINT => SystemTable::new(&[
+            ("table_id", AlgebraicType::U64),
+            ("constraint_name", AlgebraicType::String),
+            ("kind", AlgebraicType::String),
+            ("columns", columns()),
+        ]),
+        ST_SEQUENCE => SystemTable::new(&[
+            ("table_id", AlgebraicType::U64),
+            ("sequence_name", AlgebraicType::String),
+            ("col_pos", AlgebraicType::U16),
+            ("next", AlgebraicType::U64),
+        ]),
+        ST_REDUCER => SystemTable::new(&[
+            ("reducer_id", AlgebraicType::U64),
+            ("reducer_name", AlgebraicType::String),
+            ("params", AlgebraicType::Bytes),
+            ("lifecycle", AlgebraicType::option(AlgebraicType::String)),
+        ]),
+        ST_VIEW => SystemTable::new(&[
+            ("view_name", AlgebraicType::String),
+            ("kind", AlgebraicType::String),
+            ("columns", AlgebraicType::Bytes),
+            (
+                "caller_column",
+                AlgebraicType::option(AlgebraicType::String),
+            ),
+        ]),
+        ST_AUDIT => SystemTable::new(&[
+            ("call_id", AlgebraicType::U64),
+            ("module_id", AlgebraicType::U64),
+            ("reducer_name", AlgebraicType::String),
+            ("caller", AlgebraicType::U64),
+            ("timestamp", AlgebraicType::U64),
+            ("args_hash", AlgebraicType::U64),
+            ("outcome", AlgebraicType::String),
+            ("duration", AlgebraicType::U64),
+            ("rows_touched", AlgebraicType::U64),
+        ]),
+        _ => return None,
+    };
+    for (id, user_table) in &module.tables {
+        let def = user_table.def();
+        match name {
+            ST_TABLE => table.push(alloc::vec![
+                AlgebraicValue::U64(*id),
+                AlgebraicValue::String(def.name.clone()),
+                AlgebraicValue::U64(user_table.len() as u64),
+            ]),
+            ST_COLUMN => {
+                for (pos, column) in def.columns.elements.iter().enumerate() {
+                    let mut ty = Vec::new();
+                    binary::encode_type(&column.algebraic_type, &mut ty);
+                    table.push(alloc::vec![
+                        AlgebraicValue::U64(*id),
+                        AlgebraicValue::U16(pos as u16),
+                        AlgebraicValue::String(column.name.clone()),
+                        AlgebraicValue::Bytes(ty),
+                    ]);
+                }
+            }
+            ST_INDEX => {
+                for index in &def.indexes {
+                    table.push(alloc::vec![
+                        AlgebraicValue::U64(*id),
+                        AlgebraicValue::String(index.name.clone()),
+                        AlgebraicValue::String(String::from(match index.kind {
+                            IndexKind::BTree => "btree",
+                            IndexKind::Spatial => "spatial",
+                            IndexKind::Text => "text",
+                        })),
+                        column_list(&index.columns),
+                    ]);
+                }
+            }
+            ST_CONSTRAINT => {
+                for constraint in &def.constraints {
+                    let kind = match constraint {
+                        ConstraintDef::Unique { .. } => "unique",
+                        ConstraintDef::PrimaryKey { .. } => "primary_key",
+                        ConstraintDef::ForeignKey { .. } => "foreign_key",
+                        ConstraintDef::Check { .. } => "check",
+                    };
+                    table.push(alloc::vec![
+                        AlgebraicValue::U64(*id),
+                        AlgebraicValue::String(String::from(constraint.name())),
+                        AlgebraicValue::String(String::from(kind)),
+                        column_list(constraint.columns()),
+                    ]);
+                }
+            }
+            ST_SEQUENCE => {
+                for sequence in &def.sequences {
+                    let next = user_table.sequence_next(&sequence.name).unwrap_or(1);
+                    table.push(alloc::vec![
+                        AlgebraicValue::U64(*id),
+                        AlgebraicValue::String(sequence.name.clone()),
+                        AlgebraicValue::U16(sequence.column),
+                        AlgebraicValue::U64(next),
+                    ]);
+                }
+            }
+            _ => {}
+        }
+    }
+    if name == ST_REDUCER {
+        for (id, reducer) in &module.reducers {
+            let mut params = Vec::new();
+            binary::encode_product_type(reducer.params(), &mut params);
+            let lifecycle = match reducer.def.lifecycle {
+                Some(lifecycle) => SumValue::new(
+                    0,
+                    AlgebraicValue::String(String::from(lifecycle_name(lifecycle))),
+                ),
+                None => SumValue::new(1, AlgebraicValue::unit()),
+            };
+            table.push(alloc::vec![
+                AlgebraicValue::U64(*id),
+                AlgebraicValue::String(String::from(reducer.name())),
+                AlgebraicValue::Bytes(params),
+                AlgebraicValue::Sum(lifecycle),
+            ]);
+        }
+    }
+    if name == ST_VIEW {
+        for view in module.views.values() {
+            let mut columns = Vec::new();
+            binary::encode_product_type(view.columns(), &mut columns);
+            let kind = match view.def().source {
+                ViewSource::Query(_) => "query",
+                ViewSource::Function { .. } => "function",
+            };
+            let caller_column = match &view.def().caller_column {
+                Some(column) => SumValue::new(0, AlgebraicValue::String(column.clone())),
+                None => SumValue::new(1, AlgebraicValue::unit()),
+            };
+            table.push(alloc::vec![
+                AlgebraicValue::String(String::from(view.name())),
+                AlgebraicValue::String(String::from(kind)),
+                AlgebraicValue::Bytes(columns),
+                AlgebraicValue::Sum(caller_column),
+            ]);
+        }
+    }
+    if name == ST_AUDIT {
+        for entry in module.audit_log.iter() {
+            table.push(alloc::vec![
+                AlgebraicValue::U64(entry.call_id),
+                AlgebraicValue::U64(module.id),
+                AlgebraicValue::String(entry.reducer.clone()),
+                AlgebraicValue::U64(entry.caller),
+                AlgebraicValue::U64(entry.timestamp),
+                AlgebraicValue::U64(entry.args_hash),
+                AlgebraicValue::String(audit::describe(&entry.outcome)),
+                AlgebraicValue::U64(entry.duration),
+                AlgebraicValue::U64(entry.rows_touched),
+            ]);
+        }
+    }
+    Some(table)
+}
+
+/// The system table of `core` named `name`, describing its modules or users.
+pub fn core_table(core: &SpacetimeCore, name: &str) -> Option<SystemTable> {
+    match name {
+        ST_MODULE => {
+            let mut table = SystemTable::new(&[
+                ("module_id", AlgebraicType::U64),
+                ("module_name", AlgebraicType::String),
+                ("memory_used", AlgebraicType::U64),
+                ("memory_quota", AlgebraicType::U64),
+                ("energy_used", AlgebraicType::U64),
+            ]);
+            for module in core.modules.values() {
+                table.push(alloc::vec![
+                    AlgebraicValue::U64(module.id),
+                    AlgebraicValue::String(module.name.clone()),
+                    AlgebraicValue::U64(module.memory_used() as u64),
+                    AlgebraicValue::U64(module.memory_quota() as u64),
+                    AlgebraicValue::U64(module.energy_used()),
+                ]);
+            }
+            Some(table)
+        }
+        ST_USER => {
+            let mut table = SystemTable::new(&[
+                ("user_id", AlgebraicType::U64),
+                ("user_name", AlgebraicType::String),
+            ]);
+            for user in core.users.values() {
+                table.push(alloc::vec![
+                    AlgebraicValue::U64(user.id),
+                    AlgebraicValue::String(user.name.clone()),
+                ]);
+            }
+            Some(table)
+        }
+        _ => None,
+    }
+}
+
+fn column_list(columns: &[u16]) -> AlgebraicValue {
+    AlgebraicValue::Array(columns.iter().map(|c| AlgebraicValue::U16(*c)).collect())
+}
+
+fn lifecycle_name(lifecycle: Lifecycle) -> &'static str {
+    match lifecycle {
+        Lifecycle::Init => "init",
+        Lifecycle::ClientConnected => "client_connected",
+        Lifecycle::ClientDisconnected => "client_disconnected",
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_catalog() -> (Module, u64) {
+    use super::def::{ReducerDef, TableDef};
+    use super::test_util::columns;
+
+    let mut module = Module::new(String::from("catalog"));
+    let columns = columns([("id", AlgebraicType::U64), ("name", AlgebraicType::String)]);
+    let item = TableDef::new("item", columns)
+        .with_index("item_name", &[1])
+        .with_constraint(ConstraintDef::PrimaryKey {
+            name: String::from("item_id"),
+            column: 0,
+        })
+        .with_sequence("item_id_seq", 0);
+    let table_id = module.add_table(item).unwrap();
+    module
+        .add_reducer(
+            ReducerDef::lifecycle("init", Lifecycle::Init),
+            Box::new(|_, _| Ok(())),
+        )
+        .unwrap();
+    (module, table_id)
+}
+
+#[test_case]
+fn test_system_tables() {
+    use super::query::Query;
+    use super::test_util::{row, rows};
+
+    let (module, table_id) = test_catalog();
+    assert_eq!(
+        rows(&module, ST_TABLE),
+        [row([
+            AlgebraicValue::U64(table_id),
+            AlgebraicValue::String(String::from("item")),
+            AlgebraicValue::U64(0),
+        ])]
+    );
+    let name = module
+        .query(&Query::table(ST_COLUMN).filter_eq("col_pos", AlgebraicValue::U16(1)))
+        .unwrap();
+    assert_eq!(
+        name.rows[0].elements[2],
+        AlgebraicValue::String(String::from("name"))
+    );
+    assert_eq!(
+        rows(&module, ST_SEQUENCE)[0].elements[3],
+        AlgebraicValue::U64(1)
+    );
+    assert_eq!(
+        rows(&module, ST_REDUCER)[0].elements[3],
+        AlgebraicValue::Sum(SumValue::new(
+            0,
+            AlgebraicValue::String(String::from("init"))
+        ))
+    );
+    assert_eq!(rows(&module, ST_INDEX).len(), 1);
+    assert_eq!(rows(&module, ST_CONSTRAINT).len(), 1);
+}
+
+#[test_case]
+fn test_system_table_names_reserved() {
+    use super::def::{ModuleDefError, TableDef};
+    use super::test_util::columns;
+
+    let (mut module, _) = test_catalog();
+    let columns = columns([("id", AlgebraicType::U64)]);
+    assert_eq!(
+        module.add_table(TableDef::new("st_item", columns)),
+        Err(ModuleDefError::ReservedName(String::from("st_item")))
+    );
+}
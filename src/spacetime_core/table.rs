@@ -0,0 +1,986 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    string::String,
+    vec::Vec,
+};
+use core::cell::RefCell;
+
+use super::buffer_pool::{BufferPool, StorageError};
+use super::def::{Check, CompareOp, ConstraintDef, IndexKind, TableDef};
+use super::history::TableSnapshot;
+use super::page::{PageStore, RowPointer};
+use super::rtree::{self, Point, RTree};
+use super::text::TextIndex;
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RowId(pub u64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableError {
+    NoSuchTable(String),
+    NoSuchIndex(String),
+    /// The row doesn't match the table's columns.
+    InvalidRow,
+    /// Inserting the row would break the named unique constraint.
+    UniqueViolation(String),
+    /// The write would leave the named foreign key referring to a missing row.
+    ForeignKeyViolation(String),
+    /// The row fails the named check constraint.
+    CheckViolation(String),
+    /// The named sequence has no values left for its column type.
+    SequenceExhausted(String),
+    /// The reducer used up its energy budget, see [`ReducerContext::charge`].
+    ///
+    /// [`ReducerContext::charge`]: super::ReducerContext::charge
+    OutOfEnergy,
+    /// The module went past its memory quota, the reducer will be rolled back.
+    OutOfQuota,
+    /// A point or box doesn't have as many coordinates as the named spatial index.
+    InvalidCoordinates(String),
+    /// A page of the table couldn't be read back from the module's block device.
+    Storage(StorageError),
+}
+
+impl From<StorageError> for TableError {
+    fn from(error: StorageError) -> TableError {
+        TableError::Storage(error)
+    }
+}
+
+struct Index {
+    name: String,
+    columns: Vec<usize>,
+    unique: bool,
+    entries: BTreeMap<ProductValue, BTreeSet<RowId>>,
+}
+
+impl Index {
+    fn key(&self, row: &ProductValue) -> ProductValue {
+        ProductValue::new(
+            self.columns
+                .iter()
+                .map(|column| row.elements[*column].clone())
+                .collect(),
+        )
+    }
+}
+
+/// Index of the rows as points, see [`IndexKind::Spatial`].
+///
+/// Rows with a NaN coordinate are left out.
+struct SpatialIndex {
+    name: String,
+    columns: Vec<usize>,
+    tree: RTree,
+}
+
+impl SpatialIndex {
+    fn point(&self, row: &ProductValue) -> Option<Point> {
+        let mut point = [0.0; 3];
+        for (coordinate, column) in point.iter_mut().zip(&self.columns) {
+            *coordinate = rtree::coordinate(&row.elements[*column])?;
+        }
+        Some(point)
+    }
+
+    /// `coordinates` as a point, if there are as many as the index's columns.
+    fn to_point(&self, coordinates: &[f64]) -> Result<Point, TableError> {
+        if coordinates.len() != self.columns.len() {
+            return Err(TableError::InvalidCoordinates(self.name.clone()));
+        }
+        let mut point = [0.0; 3];
+        point[..coordinates.len()].copy_from_slice(coordinates);
+        Ok(point)
+    }
+}
+
+/// Index of the words of a string column, see [`IndexKind::Text`].
+struct TextColumnIndex {
+    name: String,
+    column: usize,
+    index: TextIndex,
+}
+
+impl TextColumnIndex {
+    fn text<'a>(&self, row: &'a ProductValue) -> &'a str {
+        match &row.elements[self.column] {
+            AlgebraicValue::String(text) => text,
+            _ => "",
+        }
+    }
+}
+
+struct Sequence {
+    name: String,
+    column: usize,
+    next: u64,
+}
+
+pub struct Table {
+    id: u64,
+    def: TableDef,
+    rows: BTreeMap<RowId, RowPointer>,
+    store: PageStore,
+    indexes: Vec<Index>,
+    spatial_indexes: Vec<SpatialIndex>,
+    text_indexes: Vec<TextColumnIndex>,
+    sequences: Vec<Sequence>,
+    next_row_id: u64,
+}
+
+impl Table {
+    /// Creates an empty table, `def` must have been validated against its columns.
+    pub fn new(id: u64, def: TableDef) -> Table {
+        let store = PageStore::new(&def.columns);
+        Table::with_store(id, def, store)
+    }
+
+    /// Creates an empty table, as [`Table::new`], with its pages in `pool`.
+    pub fn new_in(id: u64, def: TableDef, pool: &Rc<RefCell<BufferPool>>) -> Table {
+        let store = PageStore::new_in(&def.columns, pool);
+        Table::with_store(id, def, store)
+    }
+
+    fn with_store(id: u64, def: TableDef, store: PageStore) -> Table {
+        let to_columns = |columns: &[u16]| columns.iter().map(|c| *c as usize).collect();
+        let mut indexes: Vec<Index> = def
+            .indexes
+            .iter()
+            .filter(|index| index.kind == IndexKind::BTree)
+            .map(|index| Index {
+                name: index.name.clone(),
+                columns: to_columns(&index.columns),
+                unique: false,
+                entries: BTreeMap::new(),
+            })
+            .collect();
+        // Foreign keys are indexed to find the rows referring to a deleted row
+        indexes.extend(
+            def.constraints
+                .iter()
+                .filter(|constraint| !matches!(constraint, ConstraintDef::Check { .. }))
+                .map(|constraint| Index {
+                    name: String::from(constraint.name()),
+                    columns: to_columns(constraint.columns()),
+                    unique: !matches!(constraint, ConstraintDef::ForeignKey { .. }),
+                    entries: BTreeMap::new(),
+                }),
+        );
+        let spatial_indexes = def
+            .indexes
+            .iter()
+            .filter(|index| index.kind == IndexKind::Spatial)
+            .map(|index| SpatialIndex {
+                name: index.name.clone(),
+                columns: to_columns(&index.columns),
+                tree: RTree::new(index.columns.len()),
+            })
+            .collect();
+        let text_indexes = def
+            .indexes
+            .iter()
+            .filter(|index| index.kind == IndexKind::Text)
+            .map(|index| TextColumnIndex {
+                name: index.name.clone(),
+                column: index.columns[0] as usize,
+                index: TextIndex::new(),
+            })
+            .collect();
+        let sequences = def
+            .sequences
+            .iter()
+            .map(|sequence| Sequence {
+                name: sequence.name.clone(),
+                column: sequence.column as usize,
+                next: 1,
+            })
+            .collect();
+        Table {
+            id,
+            store,
+            def,
+            rows: BTreeMap::new(),
+            indexes,
+            spatial_indexes,
+            text_indexes,
+            sequences,
+            next_row_id: 0,
+        }
+    }
+
+    /// Creates a table holding the rows of `snapshot`, with its row id and sequence counters,
+    /// and its pages in `pool`.
+    pub fn from_snapshot(
+        id: u64,
+        def: TableDef,
+        snapshot: &TableSnapshot,
+        pool: &Rc<RefCell<BufferPool>>,
+    ) -> Result<Table, TableError> {
+        let mut table = Table::new_in(id, def, pool);
+        for (row_id, row) in &snapshot.rows {
+            table.restore(*row_id, row.clone())?;
+        }
+        table.next_row_id = snapshot.next_row_id;
+        for (sequence, next) in table.sequences.iter_mut().zip(&snapshot.sequences) {
+            sequence.next = *next;
+        }
+        Ok(table)
+    }
+
+    pub fn snapshot(&self) -> Result<TableSnapshot, StorageError> {
+        Ok(TableSnapshot {
+            table_id: self.id,
+            next_row_id: self.next_row_id,
+            sequences: self
+                .sequences
+                .iter()
+                .map(|sequence| sequence.next)
+                .collect(),
+            rows: self.iter().collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.def.name
+    }
+
+    pub fn columns(&self) -> &ProductType {
+        &self.def.columns
+    }
+
+    pub fn def(&self) -> &TableDef {
+        &self.def
+    }
+
+    /// Column holding the primary key, if the table has one.
+    pub fn primary_key(&self) -> Option<usize> {
+        self.def
+            .constraints
+            .iter()
+            .find_map(|constraint| match constraint {
+                ConstraintDef::PrimaryKey { column, .. } => Some(*column as usize),
+                _ => None,
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The row at `row_id`, failing if its page can't be read back.
+    pub fn get(&self, row_id: RowId) -> Result<Option<ProductValue>, StorageError> {
+        let Some(pointer) = self.rows.get(&row_id) else {
+            return Ok(None);
+        };
+        Ok(Some(self.store.get(*pointer)?))
+    }
+
+    /// Ids of the rows, in order, without reading the rows.
+    pub fn row_ids(&self) -> impl Iterator<Item = RowId> {
+        self.rows.keys().copied()
+    }
+
+    /// Rows in row id order, decoded from their pages one at a time, each failing if its page
+    /// can't be read back.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(RowId, ProductValue), StorageError>> {
+        self.rows
+            .iter()
+            .map(|(row_id, pointer)| Ok((*row_id, self.store.get(*pointer)?)))
+    }
+
+    /// Storage of the rows.
+    pub fn store(&self) -> &PageStore {
+        &self.store
+    }
+
+    /// Value the named sequence will give the next row inserted with `0` in its column.
+    pub fn sequence_next(&self, name: &str) -> Option<u64> {
+        self.sequences
+            .iter()
+            .find(|sequence| sequence.name == name)
+            .map(|sequence| sequence.next)
+    }
+
+    /// Rows whose indexed columns equal `key`, in the index or constraint named `index`.
+    pub fn seek(&self, index: &str, key: &ProductValue) -> Result<Vec<RowId>, TableError> {
+        Ok(self
+            .index(index)?
+            .entries
+            .get(key)
+            .map(|row_ids| row_ids.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    /// Rows with `value` in `column`, found through an index starting with the column if there
+    /// is one, or else by reading every row.
+    pub fn find(
+        &self,
+        column: usize,
+        value: &AlgebraicValue,
+    ) -> Result<Vec<ProductValue>, StorageError> {
+        let Some(index) = self
+            .indexes
+            .iter()
+            .find(|index| index.columns.first() == Some(&column))
+        else {
+            return self
+                .iter()
+                .map(|row| row.map(|(_, row)| row))
+                .filter(|row| !row.as_ref().is_ok_and(|row| row.elements[column] != *value))
+                .collect();
+        };
+        // Keys with `value` first sort after `value` alone and before any other
+        let start = ProductValue::new(alloc::vec![value.clone()]);
+        index
+            .entries
+            .range(start..)
+            .take_while(|(key, _)| key.elements[0] == *value)
+            .flat_map(|(_, row_ids)| row_ids)
+            .filter_map(|row_id| self.get(*row_id).transpose())
+            .collect()
+    }
+
+    /// Rows whose point in the spatial index `index` is between the corners `min` and `max`,
+    /// inclusive.
+    pub fn within(&self, index: &str, min: &[f64], max: &[f64]) -> Result<Vec<RowId>, TableError> {
+        let index = self.spatial_index(index)?;
+        Ok(index
+            .tree
+            .within(index.to_point(min)?, index.to_point(max)?))
+    }
+
+    /// The `k` rows whose point in the spatial index `index` is nearest to `point`, nearest
+    /// first.
+    pub fn nearest(&self, index: &str, point: &[f64], k: usize) -> Result<Vec<RowId>, TableError> {
+        let index = self.spatial_index(index)?;
+        Ok(index.tree.nearest(&index.to_point(point)?, k))
+    }
+
+    /// Coordinates of the points of the spatial index `index`.
+    pub fn spatial_dims(&self, index: &str) -> Result<usize, TableError> {
+        Ok(self.spatial_index(index)?.columns.len())
+    }
+
+    /// Name of a spatial index on exactly `columns`, in that order.
+    pub fn spatial_index_on(&self, columns: &[usize]) -> Option<&str> {
+        self.spatial_indexes
+            .iter()
+            .find(|index| index.columns == columns)
+            .map(|index| index.name.as_str())
+    }
+
+    fn spatial_index(&self, name: &str) -> Result<&SpatialIndex, TableError> {
+        self.spatial_indexes
+            .iter()
+            .find(|index| index.name == name)
+            .ok_or_else(|| TableError::NoSuchIndex(String::from(name)))
+    }
+
+    /// The `limit` rows best matching `search` in the text index `index`, best first, as
+    /// [`text`](super::text) describes.
+    pub fn search(
+        &self,
+        index: &str,
+        search: &str,
+        limit: usize,
+    ) -> Result<Vec<RowId>, TableError> {
+        let index = self
+            .text_indexes
+            .iter()
+            .find(|text_index| text_index.name == index)
+            .ok_or_else(|| TableError::NoSuchIndex(String::from(index)))?;
+        Ok(index.index.search(search, limit))
+    }
+
+    /// Name of a text index on `column`.
+    pub fn text_index_on(&self, column: usize) -> Option<&str> {
+        self.text_indexes
+            .iter()
+            .find(|index| index.column == column)
+            .map(|index| index.name.as_str())
+    }
+
+    /// Type of the keys accepted by [`Table::seek`] for `index`.
+    pub fn index_key_type(&self, index: &str) -> Result<ProductType, TableError> {
+        let columns = &self.index(index)?.columns;
+        Ok(ProductType::new(
+            columns
+                .iter()
+                .map(|column| self.def.columns.elements[*column].clone())
+                .collect(),
+        ))
+    }
+
+    fn index(&self, name: &str) -> Result<&Index, TableError> {
+        self.indexes
+            .iter()
+            .find(|index| index.name == name)
+            .ok_or_else(|| TableError::NoSuchIndex(String::from(name)))
+    }
+
+    pub fn insert(&mut self, mut row: ProductValue) -> Result<RowId, TableError> {
+        if !row.has_type(&self.def.columns) {
+            return Err(TableError::InvalidRow);
+        }
+        let mut used_sequences = Vec::new();
+        for (i, sequence) in self.sequences.iter().enumerate() {
+            let value = &mut row.elements[sequence.column];
+            if is_zero(value) {
+                let ty = &self.def.columns.elements[sequence.column].algebraic_type;
+                *value = integer_value(ty, sequence.next)
+                    .ok_or_else(|| TableError::SequenceExhausted(sequence.name.clone()))?;
+                used_sequences.push(i);
+            }
+        }
+        self.check(&row)?;
+
+        let row_id = RowId(self.next_row_id);
+        self.insert_at(row_id, row)?;
+        self.next_row_id += 1;
+        for i in used_sequences {
+            self.sequences[i].next += 1;
+        }
+        Ok(row_id)
+    }
+
+    /// Inserts `rows` at once, or none of them if one is rejected.
+    ///
+    /// Faster than inserting them one at a time for large batches: unique constraints are
+    /// checked for the whole batch by sorting its keys, and indexes are built once every row is
+    /// in.
+    pub fn bulk_insert(&mut self, mut rows: Vec<ProductValue>) -> Result<Vec<RowId>, TableError> {
+        if rows.iter().any(|row| !row.has_type(&self.def.columns)) {
+            return Err(TableError::InvalidRow);
+        }
+        let mut next: Vec<u64> = self
+            .sequences
+            .iter()
+            .map(|sequence| sequence.next)
+            .collect();
+        for row in &mut rows {
+            for (sequence, next) in self.sequences.iter().zip(&mut next) {
+                let value = &mut row.elements[sequence.column];
+                if is_zero(value) {
+                    let ty = &self.def.columns.elements[sequence.column].algebraic_type;
+                    *value = integer_value(ty, *next)
+                        .ok_or_else(|| TableError::SequenceExhausted(sequence.name.clone()))?;
+                    *next += 1;
+                }
+            }
+            self.check(row)?;
+        }
+        for index in self.indexes.iter().filter(|index| index.unique) {
+            let mut keys: Vec<ProductValue> = rows.iter().map(|row| index.key(row)).collect();
+            keys.sort_unstable();
+            if keys.windows(2).any(|pair| pair[0] == pair[1])
+                || keys.iter().any(|key| index.entries.contains_key(key))
+            {
+                return Err(TableError::UniqueViolation(index.name.clone()));
+            }
+        }
+
+        let mut pointers = Vec::with_capacity(rows.len());
+        for row in &rows {
+            match self.store.insert(row) {
+                Ok(pointer) => pointers.push(pointer),
+                Err(error) => {
+                    // A slot that can't be freed stays taken, its row not being in the table
+                    for pointer in pointers {
+                        let _ = self.store.delete(pointer);
+                    }
+                    return Err(error.into());
+                }
+            }
+        }
+
+        let first = self.next_row_id;
+        let row_ids: Vec<RowId> = (first..first + rows.len() as u64).map(RowId).collect();
+        for index in &mut self.indexes {
+            let mut entries: Vec<(ProductValue, RowId)> = rows
+                .iter()
+                .zip(&row_ids)
+                .map(|(row, row_id)| (index.key(row), *row_id))
+                .collect();
+            entries.sort_unstable();
+            for (key, row_id) in entries {
+                index.entries.entry(key).or_default().insert(row_id);
+            }
+        }
+        for index in &mut self.spatial_indexes {
+            for (row_id, row) in row_ids.iter().zip(&rows) {
+                if let Some(point) = index.point(row) {
+                    index.tree.insert(point, *row_id);
+                }
+            }
+        }
+        for index in &mut self.text_indexes {
+            for (row_id, row) in row_ids.iter().zip(&rows) {
+                let text = index.text(row);
+                index.index.insert(*row_id, text);
+            }
+        }
+        self.rows.extend(row_ids.iter().copied().zip(pointers));
+        self.next_row_id += row_ids.len() as u64;
+        for (sequence, next) in self.sequences.iter_mut().zip(next) {
+            sequence.next = next;
+        }
+        Ok(row_ids)
+    }
+
+    /// Inserts `row` under a known id, used to undo a delete.
+    pub(crate) fn restore(&mut self, row_id: RowId, row: ProductValue) -> Result<(), TableError> {
+        if !row.has_type(&self.def.columns) {
+            return Err(TableError::InvalidRow);
+        }
+        self.insert_at(row_id, row)
+    }
+
+    /// Fails with the first check constraint `row` doesn't pass.
+    fn check(&self, row: &ProductValue) -> Result<(), TableError> {
+        for constraint in &self.def.constraints {
+            if let ConstraintDef::Check { name, check } = constraint
+                && !passes(check, row)
+            {
+                return Err(TableError::CheckViolation(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_at(&mut self, row_id: RowId, row: ProductValue) -> Result<(), TableError> {
+        for index in self.indexes.iter().filter(|index| index.unique) {
+            if index.entries.contains_key(&index.key(&row)) {
+                return Err(TableError::UniqueViolation(index.name.clone()));
+            }
+        }
+        let pointer = self.store.insert(&row)?;
+        for index in &mut self.indexes {
+            let key = index.key(&row);
+            index.entries.entry(key).or_default().insert(row_id);
+        }
+        for index in &mut self.spatial_indexes {
+            if let Some(point) = index.point(&row) {
+                index.tree.insert(point, row_id);
+            }
+        }
+        for index in &mut self.text_indexes {
+            let text = index.text(&row);
+            index.index.insert(row_id, text);
+        }
+        self.rows.insert(row_id, pointer);
+        Ok(())
+    }
+
+    /// Replaces the row at `row_id`, returning the old row, or `None` if there was no row.
+    ///
+    /// Sequences are not applied, and the old row is kept if the new one is rejected, unless
+    /// putting it back fails with [`TableError::Storage`].
+    pub fn update(
+        &mut self,
+        row_id: RowId,
+        row: ProductValue,
+    ) -> Result<Option<ProductValue>, TableError> {
+        if !row.has_type(&self.def.columns) {
+            return Err(TableError::InvalidRow);
+        }
+        if !self.rows.contains_key(&row_id) {
+            return Ok(None);
+        }
+        self.check(&row)?;
+        let Some(old) = self.delete(row_id)? else {
+            return Ok(None);
+        };
+        match self.insert_at(row_id, row) {
+            Ok(()) => Ok(Some(old)),
+            Err(error) => {
+                self.insert_at(row_id, old)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Sets the row at `row_id` as a commit left it, `None` deleting it, moving the row id
+    /// and sequence counters past it.
+    pub fn redo(&mut self, row_id: RowId, row: Option<ProductValue>) -> Result<(), TableError> {
+        self.delete(row_id)?;
+        self.next_row_id = self.next_row_id.max(row_id.0 + 1);
+        let Some(row) = row else {
+            return Ok(());
+        };
+        self.restore(row_id, row.clone())?;
+        for sequence in &mut self.sequences {
+            if let Some(value) = integer_of(&row.elements[sequence.column]) {
+                sequence.next = sequence.next.max(value.saturating_add(1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the row at `row_id`, returning it, or `None` if there was no row.
+    pub fn delete(&mut self, row_id: RowId) -> Result<Option<ProductValue>, StorageError> {
+        let Some(pointer) = self.rows.get(&row_id).copied() else {
+            return Ok(None);
+        };
+        let row = self.store.get(pointer)?;
+        self.store.delete(pointer)?;
+        self.rows.remove(&row_id);
+        for index in &mut self.indexes {
+            let key = index.key(&row);
+            if let Some(row_ids) = index.entries.get_mut(&key) {
+                row_ids.remove(&row_id);
+                if row_ids.is_empty() {
+                    index.entries.remove(&key);
+                }
+            }
+        }
+        for index in &mut self.spatial_indexes {
+            if let Some(point) = index.point(&row) {
+                index.tree.remove(&point, row_id);
+            }
+        }
+        for index in &mut self.text_indexes {
+            let text = index.text(&row);
+            index.index.remove(row_id, text);
+        }
+        Ok(Some(row))
+    }
+}
+
+fn passes(check: &Check, row: &ProductValue) -> bool {
+    let value = |column: &u16| &row.elements[*column as usize];
+    match check {
+        Check::Range { column, min, max } => {
+            min.as_ref().is_none_or(|min| value(column) >= min)
+                && max.as_ref().is_none_or(|max| value(column) <= max)
+        }
+        Check::NonEmpty { column } => match value(column) {
+            AlgebraicValue::String(value) => !value.is_empty(),
+            AlgebraicValue::Bytes(value) => !value.is_empty(),
+            AlgebraicValue::Array(value) => !value.is_empty(),
+            _ => true,
+        },
+        Check::Compare { columns, op } => {
+            let ordering = value(&columns[0]).cmp(value(&columns[1]));
+            match op {
+                CompareOp::Eq => ordering.is_eq(),
+                CompareOp::Ne => ordering.is_ne(),
+                CompareOp::Lt => ordering.is_lt(),
+                CompareOp::Le => ordering.is_le(),
+                CompareOp::Gt => ordering.is_gt(),
+                CompareOp::Ge => ordering.is_ge(),
+            }
+        }
+    }
+}
+
+fn is_zero(value: &AlgebraicValue) -> bool {
+    matches!(
+        value,
+        AlgebraicValue::I8(0)
+            | AlgebraicValue::U8(0)
+            | AlgebraicValue::I16(0)
+            | AlgebraicValue::U16(0)
+            | AlgebraicValue::I32(0)
+            | AlgebraicValue::U32(0)
+            | AlgebraicValue::I64(0)
+            | AlgebraicValue::U64(0)
+    )
+}
+
+/// Value of a non-negative integer.
+fn integer_of(value: &AlgebraicValue) -> Option<u64> {
+    match *value {
+        AlgebraicValue::I8(value) => value.try_into().ok(),
+        AlgebraicValue::U8(value) => Some(value.into()),
+        AlgebraicValue::I16(value) => value.try_into().ok(),
+        AlgebraicValue::U16(value) => Some(value.into()),
+        AlgebraicValue::I32(value) => value.try_into().ok(),
+        AlgebraicValue::U32(value) => Some(value.into()),
+        AlgebraicValue::I64(value) => value.try_into().ok(),
+        AlgebraicValue::U64(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// `value` as an integer of type `ty`, if it fits.
+pub(crate) fn integer_value(ty: &AlgebraicType, value: u64) -> Option<AlgebraicValue> {
+    Some(match ty {
+        AlgebraicType::I8 => AlgebraicValue::I8(value.try_into().ok()?),
+        AlgebraicType::U8 => AlgebraicValue::U8(value.try_into().ok()?),
+        AlgebraicType::I16 => AlgebraicValue::I16(value.try_into().ok()?),
+        AlgebraicType::U16 => AlgebraicValue::U16(value.try_into().ok()?),
+        AlgebraicType::I32 => AlgebraicValue::I32(value.try_into().ok()?),
+        AlgebraicType::U32 => AlgebraicValue::U32(value.try_into().ok()?),
+        AlgebraicType::I64 => AlgebraicValue::I64(value.try_into().ok()?),
+        AlgebraicType::U64 => AlgebraicValue::U64(value),
+        _ => return None,
+    })
+}
+
+// Tests
+
+/// Items with a sequenced id and a unique, indexed name.
+#[cfg(test)]
+fn item_def() -> TableDef {
+    let columns =
+        super::test_util::columns([("id", AlgebraicType::U64), ("name", AlgebraicType::String)]);
+    TableDef::new("item", columns)
+        .with_index("item_name", &[1])
+        .with_constraint(ConstraintDef::Unique {
+            name: String::from("item_name_unique"),
+            columns: alloc::vec![1],
+        })
+        .with_sequence("item_id", 0)
+}
+
+#[cfg(test)]
+fn item(id: u64, name: &str) -> ProductValue {
+    super::test_util::row([
+        AlgebraicValue::U64(id),
+        AlgebraicValue::String(String::from(name)),
+    ])
+}
+
+#[test_case]
+fn test_bulk_insert() {
+    let mut table = Table::new(1, item_def());
+    table.insert(item(0, "a")).unwrap();
+    let rows: Vec<_> = (0..100)
+        .map(|n| item(0, &alloc::format!("b{}", n)))
+        .collect();
+    let row_ids = table.bulk_insert(rows).unwrap();
+    assert_eq!(row_ids.len(), 100);
+    assert_eq!(table.len(), 101);
+    assert_eq!(table.get(row_ids[99]), Ok(Some(item(101, "b99"))));
+    assert_eq!(
+        table.seek("item_name", &super::test_util::named("b42")),
+        Ok(alloc::vec![row_ids[42]])
+    );
+    assert_eq!(table.sequence_next("item_id"), Some(102));
+}
+
+#[test_case]
+fn test_bulk_insert_all_or_nothing() {
+    let mut table = Table::new(1, item_def());
+    table.insert(item(0, "a")).unwrap();
+    for rows in [
+        alloc::vec![item(0, "c"), item(0, "c")],
+        alloc::vec![item(0, "c"), item(0, "a")],
+    ] {
+        assert_eq!(
+            table.bulk_insert(rows),
+            Err(TableError::UniqueViolation(String::from(
+                "item_name_unique"
+            )))
+        );
+    }
+    assert_eq!(table.len(), 1);
+    assert_eq!(table.sequence_next("item_id"), Some(2));
+}
+
+#[test_case]
+fn test_module_bulk_insert() {
+    use super::binary::DecodeError;
+    use super::{BulkInsertError, Module, query::QueryResult};
+
+    let mut module = Module::new(String::from("bulk"));
+    module.add_table(item_def()).unwrap();
+    let rows = QueryResult {
+        schema: item_def().columns,
+        rows: alloc::vec![item(0, "x"), item(0, "y")],
+    };
+    assert_eq!(module.bulk_insert("item", 7, &rows.to_bytes()), Ok(2));
+    let commit = module.commit_log().commits().last().unwrap();
+    assert_eq!((commit.caller, commit.writes.len()), (7, 2));
+    assert_eq!(
+        module.bulk_insert("item", 7, &[1, 0]),
+        Err(BulkInsertError::Decode(DecodeError::UnexpectedEof))
+    );
+}
+
+/// Heroes with a name, whose level is at least 1 and at most their max level.
+#[cfg(test)]
+fn hero_def() -> TableDef {
+    let columns = super::test_util::columns([
+        ("name", AlgebraicType::String),
+        ("level", AlgebraicType::U8),
+        ("max_level", AlgebraicType::U8),
+    ]);
+    let check = |name: &str, check| ConstraintDef::Check {
+        name: String::from(name),
+        check,
+    };
+    TableDef::new("hero", columns)
+        .with_constraint(check("name_set", Check::NonEmpty { column: 0 }))
+        .with_constraint(check(
+            "level_range",
+            Check::Range {
+                column: 1,
+                min: Some(AlgebraicValue::U8(1)),
+                max: None,
+            },
+        ))
+        .with_constraint(check(
+            "level_capped",
+            Check::Compare {
+                columns: [1, 2],
+                op: CompareOp::Le,
+            },
+        ))
+}
+
+#[cfg(test)]
+fn hero(name: &str, level: u8, max_level: u8) -> ProductValue {
+    super::test_util::row([
+        AlgebraicValue::String(String::from(name)),
+        AlgebraicValue::U8(level),
+        AlgebraicValue::U8(max_level),
+    ])
+}
+
+#[test_case]
+fn test_check_constraint_def() {
+    use super::def::ModuleDef;
+
+    let def = hero_def();
+    assert_eq!(def.validate(), Ok(()));
+    let mut module_def = ModuleDef::new("heroes");
+    module_def.tables.push(def);
+    assert_eq!(
+        ModuleDef::from_bytes(&module_def.to_bytes()),
+        Ok(module_def)
+    );
+}
+
+#[test_case]
+fn test_check_constraints() {
+    let mut table = Table::new(0, hero_def());
+    let row_id = table.insert(hero("ada", 1, 10)).unwrap();
+    let violation = |name: &str| TableError::CheckViolation(String::from(name));
+    assert_eq!(table.insert(hero("", 1, 10)), Err(violation("name_set")));
+    assert_eq!(
+        table.insert(hero("bob", 0, 10)),
+        Err(violation("level_range"))
+    );
+    assert_eq!(
+        table.update(row_id, hero("ada", 11, 10)),
+        Err(violation("level_capped"))
+    );
+    assert_eq!(
+        table.bulk_insert(alloc::vec![hero("eve", 0, 1)]),
+        Err(violation("level_range"))
+    );
+    assert_eq!(table.get(row_id), Ok(Some(hero("ada", 1, 10))));
+    assert_eq!(table.len(), 1);
+}
+
+/// Units with an id and a position indexed by `unit_position`.
+#[cfg(test)]
+fn unit_def() -> TableDef {
+    TableDef::new("unit", unit_columns()).with_spatial_index("unit_position", &[1, 2])
+}
+
+#[cfg(test)]
+fn unit_columns() -> ProductType {
+    super::test_util::columns([
+        ("id", AlgebraicType::U32),
+        ("x", AlgebraicType::F64),
+        ("y", AlgebraicType::I32),
+    ])
+}
+
+#[cfg(test)]
+fn unit(id: u32, x: f64, y: i32) -> ProductValue {
+    super::test_util::row([
+        AlgebraicValue::U32(id),
+        AlgebraicValue::F64(x),
+        AlgebraicValue::I32(y),
+    ])
+}
+
+#[test_case]
+fn test_spatial_index_def() {
+    use super::def::{ModuleDef, ModuleDefError};
+
+    let mut module_def = ModuleDef::new("map");
+    module_def.tables.push(unit_def());
+    assert_eq!(
+        ModuleDef::from_bytes(&module_def.to_bytes()),
+        Ok(module_def)
+    );
+    let invalid = TableDef::new("unit", unit_columns()).with_spatial_index("unit_id", &[0]);
+    assert_eq!(
+        invalid.validate(),
+        Err(ModuleDefError::InvalidSpatialIndex(String::from("unit_id")))
+    );
+}
+
+#[test_case]
+fn test_spatial_index() {
+    let mut table = Table::new(0, unit_def());
+    let a = table.insert(unit(1, 0.5, 0)).unwrap();
+    let b = table.insert(unit(2, 3.0, 4)).unwrap();
+    let c = table.insert(unit(3, -2.0, 1)).unwrap();
+    let mut found = table
+        .within("unit_position", &[0.0, 0.0], &[5.0, 5.0])
+        .unwrap();
+    found.sort();
+    assert_eq!(found, [a, b]);
+    assert_eq!(
+        table.nearest("unit_position", &[-1.0, 1.0], 2),
+        Ok(alloc::vec![c, a])
+    );
+    table.update(b, unit(2, -1.5, 1)).unwrap();
+    table.delete(c).unwrap();
+    assert_eq!(
+        table.nearest("unit_position", &[-1.0, 1.0], 1),
+        Ok(alloc::vec![b])
+    );
+    assert_eq!(
+        table.within("unit_position", &[0.0], &[1.0]),
+        Err(TableError::InvalidCoordinates(String::from(
+            "unit_position"
+        )))
+    );
+}
+
+/// Queries and subscriptions filter by box, reading through the index.
+#[test_case]
+fn test_spatial_query() {
+    use super::query::{Query, QueryError};
+    use super::subscription::SubscriptionUpdate;
+    use super::test_util::rows;
+
+    let mut module = super::Module::new(String::from("map"));
+    module.add_table(unit_def()).unwrap();
+    module
+        .insert_rows("unit", 0, [unit(1, 0.5, 0), unit(2, 3.0, 4)])
+        .unwrap();
+    let nearby = Query::table("unit").filter_within(&["x", "y"], &[0.0, 0.0], &[1.0, 1.0]);
+    assert_eq!(module.query(&nearby).unwrap().rows, [unit(1, 0.5, 0)]);
+    let (id, _) = module.subscribe(&nearby).unwrap();
+    module
+        .insert_rows("unit", 0, [unit(3, 1.0, 1), unit(4, 1.5, 1)])
+        .unwrap();
+    let update = SubscriptionUpdate {
+        deletes: Vec::new(),
+        inserts: alloc::vec![unit(3, 1.0, 1)],
+    };
+    assert_eq!(module.poll_subscription(id), Ok(alloc::vec![update]));
+    assert_eq!(rows(&module, "unit").len(), 4);
+    let invalid = Query::table("unit").filter_within(&["x"], &[0.0, 0.0], &[1.0, 1.0]);
+    assert_eq!(
+        module.query(&invalid),
+        Err(QueryError::InvalidBounds(String::from("x")))
+    );
+}
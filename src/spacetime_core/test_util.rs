@@ -0,0 +1,65 @@
+//! Fixtures shared by the tests of the core: modules with tables and reducers, and their rows.
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::fmt::Debug;
+
+use super::def::{ReducerDef, TableDef};
+use super::query::Query;
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue};
+use super::{Module, ReducerContext};
+
+/// Columns with the given names and types.
+pub fn columns<'a>(columns: impl IntoIterator<Item = (&'a str, AlgebraicType)>) -> ProductType {
+    ProductType::new(
+        columns
+            .into_iter()
+            .map(|(name, ty)| ProductTypeElement::new(name, ty))
+            .collect(),
+    )
+}
+
+pub fn row(values: impl IntoIterator<Item = AlgebraicValue>) -> ProductValue {
+    ProductValue::new(values.into_iter().collect())
+}
+
+/// Row of a single string, e.g. the name of an item.
+pub fn named(name: &str) -> ProductValue {
+    row([AlgebraicValue::String(String::from(name))])
+}
+
+/// A module named `name` with the given empty tables.
+pub fn module<'a>(name: &str, tables: impl IntoIterator<Item = (&'a str, ProductType)>) -> Module {
+    let mut module = Module::new(String::from(name));
+    for (table, columns) in tables {
+        module.add_table(TableDef::new(table, columns)).unwrap();
+    }
+    module
+}
+
+pub fn add_reducer(
+    module: &mut Module,
+    name: &str,
+    params: ProductType,
+    function: impl Fn(&mut ReducerContext, ProductValue) -> Result<(), String> + 'static,
+) {
+    module
+        .add_reducer(ReducerDef::new(name, params), Box::new(function))
+        .unwrap();
+}
+
+/// Reducer inserting its arguments as a row of `table`.
+pub fn inserting(
+    table: &'static str,
+) -> impl Fn(&mut ReducerContext, ProductValue) -> Result<(), String> {
+    move |ctx, args| ctx.insert(table, args).map(|_| ()).map_err(failed)
+}
+
+/// What a reducer fails with when an operation fails with `error`.
+pub fn failed(error: impl Debug) -> String {
+    format!("{:?}", error)
+}
+
+/// Rows of a table, system table or view of `module`, as an anonymous caller sees them.
+pub fn rows(module: &Module, table: &str) -> Vec<ProductValue> {
+    module.query(&Query::table(table)).unwrap().rows
+}
@@ -0,0 +1,254 @@
+//! Full-text indexes: for each word of a string column, the rows it appears in, finding the
+//! rows matching a search without reading the others.
+//!
+//! Texts are split into words at every character that isn't alphanumeric, and lowercased. A
+//! search matches the rows having, for each of its words, a word starting with it, and a
+//! search without words matches none. Rows are ranked by how often the searched words appear
+//! in them, words matched by a shorter prefix counting less, relative to the square root of the
+//! row's word count.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::f64::math as f64_math;
+use core::ops::Bound;
+
+use super::table::RowId;
+
+/// Words of `text`, lowercased, in order.
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Rank of `text` for the search `words`, as ranked by [`TextIndex::search`], or `None` if it
+/// doesn't match.
+pub fn score(words: &[String], text: &str) -> Option<f64> {
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    let mut len = 0;
+    for word in self::words(text) {
+        *counts.entry(word).or_default() += 1;
+        len += 1;
+    }
+    let mut total = 0.0;
+    for word in words {
+        let weight: f64 = prefixed(&counts, word)
+            .map(|(found, count)| weight(word, found, *count))
+            .sum();
+        if weight == 0.0 {
+            return None;
+        }
+        total += weight;
+    }
+    (!words.is_empty()).then(|| total / f64_math::sqrt(len as f64))
+}
+
+/// Entries of `map` whose key starts with `prefix`.
+fn prefixed<'a, V>(
+    map: &'a BTreeMap<String, V>,
+    prefix: &'a str,
+) -> impl Iterator<Item = (&'a String, &'a V)> {
+    map.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+        .take_while(move |(word, _)| word.starts_with(prefix))
+}
+
+/// Weight of `count` occurrences of `found` matching the searched `word`.
+fn weight(word: &str, found: &str, count: u32) -> f64 {
+    count as f64 * word.len() as f64 / found.len() as f64
+}
+
+#[derive(Default)]
+pub struct TextIndex {
+    /// Rows each word appears in, with how many times.
+    postings: BTreeMap<String, BTreeMap<RowId, u32>>,
+    /// Words in each indexed row.
+    lengths: BTreeMap<RowId, u32>,
+}
+
+impl TextIndex {
+    pub fn new() -> TextIndex {
+        TextIndex {
+            postings: BTreeMap::new(),
+            lengths: BTreeMap::new(),
+        }
+    }
+
+    /// Rows indexed.
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    pub fn insert(&mut self, row_id: RowId, text: &str) {
+        let words = words(text);
+        self.lengths.insert(row_id, words.len() as u32);
+        for word in words {
+            *self
+                .postings
+                .entry(word)
+                .or_default()
+                .entry(row_id)
+                .or_default() += 1;
+        }
+    }
+
+    /// Removes the row `row_id`, whose text was `text`.
+    pub fn remove(&mut self, row_id: RowId, text: &str) {
+        self.lengths.remove(&row_id);
+        for word in words(text) {
+            if let Some(rows) = self.postings.get_mut(&word) {
+                rows.remove(&row_id);
+                if rows.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// The `limit` highest ranked rows matching `search`, highest first.
+    pub fn search(&self, search: &str, limit: usize) -> Vec<RowId> {
+        let words = words(search);
+        let mut scores: Option<BTreeMap<RowId, f64>> = None;
+        for word in &words {
+            let mut weights: BTreeMap<RowId, f64> = BTreeMap::new();
+            for (found, rows) in prefixed(&self.postings, word) {
+                for (row_id, count) in rows {
+                    *weights.entry(*row_id).or_default() += weight(word, found, *count);
+                }
+            }
+            scores = Some(match scores {
+                None => weights,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(row_id, score)| Some((row_id, score + weights.get(&row_id)?)))
+                    .collect(),
+            });
+        }
+        let mut ranked: Vec<(RowId, f64)> = scores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(row_id, score)| (row_id, score / f64_math::sqrt(self.lengths[&row_id] as f64)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(row_id, _)| row_id)
+            .collect()
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+const TEXTS: [&str; 4] = [
+    "Iron Sword",
+    "sword of the north, a sword",
+    "Swordfish",
+    "iron shield",
+];
+
+/// Index of [`TEXTS`], each that of the row of its position.
+#[cfg(test)]
+fn test_index() -> TextIndex {
+    let mut index = TextIndex::new();
+    for (row_id, text) in TEXTS.iter().enumerate() {
+        index.insert(RowId(row_id as u64), text);
+    }
+    index
+}
+
+#[test_case]
+fn test_words() {
+    assert_eq!(words("Hello, wörld!"), ["hello", "wörld"]);
+    let search = words("iron s");
+    assert_eq!(score(&search, TEXTS[2]), None);
+    assert!(score(&search, TEXTS[0]) > score(&search, TEXTS[3]));
+}
+
+#[test_case]
+fn test_text_index() {
+    let index = test_index();
+    assert_eq!(index.search("sword", 10), [RowId(1), RowId(0), RowId(2)]);
+    assert_eq!(index.search("IRON", 1), [RowId(0)]);
+    assert_eq!(index.search("iron s", 10), [RowId(0), RowId(3)]);
+    assert_eq!(index.search("", 10), []);
+}
+
+#[test_case]
+fn test_text_index_remove() {
+    let mut index = test_index();
+    index.remove(RowId(0), TEXTS[0]);
+    assert_eq!(index.search("iron", 10), [RowId(3)]);
+    assert_eq!(index.len(), 3);
+}
+
+/// Module whose items are named [`TEXTS`], their ids their positions, and indexed by name.
+#[cfg(test)]
+fn shop_module() -> super::Module {
+    use super::def::TableDef;
+    use super::value::AlgebraicType;
+
+    let columns =
+        super::test_util::columns([("id", AlgebraicType::U32), ("name", AlgebraicType::String)]);
+    let def = TableDef::new("item", columns).with_text_index("item_name", 1);
+    let mut module = super::Module::new(String::from("shop"));
+    module.add_table(def).unwrap();
+    let items = TEXTS
+        .iter()
+        .enumerate()
+        .map(|(id, name)| item(id as u32, name));
+    module.insert_rows("item", 0, items).unwrap();
+    module
+}
+
+#[cfg(test)]
+fn item(id: u32, name: &str) -> super::ProductValue {
+    use super::value::AlgebraicValue;
+
+    super::test_util::row([
+        AlgebraicValue::U32(id),
+        AlgebraicValue::String(String::from(name)),
+    ])
+}
+
+/// Tables keep their text indexes up to date, and queries search them.
+#[test_case]
+fn test_text_query() {
+    use super::query::{Query, QueryError};
+
+    let module = shop_module();
+    let table = module.table("item").unwrap();
+    assert_eq!(table.search("item_name", "iron s", 10).unwrap().len(), 2);
+    let swords = Query::table("item").filter_matches("name", "sword");
+    assert_eq!(
+        module.query(&swords).unwrap().rows,
+        [item(1, TEXTS[1]), item(0, TEXTS[0]), item(2, TEXTS[2])]
+    );
+    let invalid = Query::table("item").filter_matches("id", "1");
+    assert_eq!(
+        module.query(&invalid),
+        Err(QueryError::InvalidSearch(String::from("id")))
+    );
+}
+
+#[test_case]
+fn test_text_subscription() {
+    use super::query::Query;
+    use super::subscription::SubscriptionUpdate;
+
+    let mut module = shop_module();
+    let swords = Query::table("item").filter_matches("name", "sword");
+    let (id, _) = module.subscribe(&swords).unwrap();
+    module
+        .insert_rows("item", 0, [item(4, "Short sword"), item(5, "Bow")])
+        .unwrap();
+    let update = SubscriptionUpdate {
+        deletes: Vec::new(),
+        inserts: alloc::vec![item(4, "Short sword")],
+    };
+    assert_eq!(module.poll_subscription(id), Ok(alloc::vec![update]));
+}
@@ -0,0 +1,106 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::buffer_pool::StorageError;
+use super::table::{RowId, Table, TableError};
+use super::value::ProductValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Write {
+    Insert {
+        table_id: u64,
+        row_id: RowId,
+    },
+    Delete {
+        table_id: u64,
+        row_id: RowId,
+        row: ProductValue,
+    },
+    /// `row` is the row before the update.
+    Update {
+        table_id: u64,
+        row_id: RowId,
+        row: ProductValue,
+    },
+}
+
+impl Write {
+    /// Table id and row id of the written row.
+    pub fn row(&self) -> (u64, RowId) {
+        match self {
+            Write::Insert { table_id, row_id }
+            | Write::Delete {
+                table_id, row_id, ..
+            }
+            | Write::Update {
+                table_id, row_id, ..
+            } => (*table_id, *row_id),
+        }
+    }
+}
+
+/// Writes applied to a module's tables by a reducer, kept so they can be undone.
+///
+/// Undoing them reads the pages they wrote, which stay in memory until the module's
+/// [`BufferPool`](super::buffer_pool::BufferPool) is unpinned.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    writes: Vec<Write>,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction { writes: Vec::new() }
+    }
+
+    pub fn record(&mut self, write: Write) {
+        self.writes.push(write);
+    }
+
+    pub fn writes(&self) -> &[Write] {
+        &self.writes
+    }
+
+    /// Undoes every write, most recent first.
+    ///
+    /// Fails with the first page that couldn't be read back to undo a write, once the others
+    /// are undone.
+    pub fn rollback(mut self, tables: &mut BTreeMap<u64, Table>) -> Result<(), StorageError> {
+        self.rollback_to(0, tables)
+    }
+
+    /// Undoes the writes recorded after the first `len`, most recent first, as
+    /// [`Transaction::rollback`] does.
+    pub fn rollback_to(
+        &mut self,
+        len: usize,
+        tables: &mut BTreeMap<u64, Table>,
+    ) -> Result<(), StorageError> {
+        let mut result = Ok(());
+        for write in self.writes.drain(len..).rev() {
+            let (table_id, row_id) = write.row();
+            let Some(table) = tables.get_mut(&table_id) else {
+                continue;
+            };
+            let undone = match write {
+                Write::Insert { .. } => table.delete(row_id).map(drop),
+                Write::Delete { row, .. } => table.restore(row_id, row).map_err(storage_error),
+                Write::Update { row, .. } => {
+                    table.update(row_id, row).map(drop).map_err(storage_error)
+                }
+            };
+            if result.is_ok() {
+                result = undone;
+            }
+        }
+        result
+    }
+}
+
+/// The storage error undoing a write failed with, the row it put back fitting its table
+/// before.
+fn storage_error(error: TableError) -> StorageError {
+    match error {
+        TableError::Storage(error) => error,
+        error => panic!("undone row no longer fits its table: {:?}", error),
+    }
+}
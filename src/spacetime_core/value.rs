@@ -0,0 +1,262 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::cmp::Ordering;
+
+/// Type of a value stored in a table or passed to a reducer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlgebraicType {
+    Bool,
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    String,
+    Bytes,
+    Array(Box<AlgebraicType>),
+    Product(ProductType),
+    Sum(SumType),
+}
+
+impl AlgebraicType {
+    pub fn unit() -> AlgebraicType {
+        AlgebraicType::Product(ProductType::new(Vec::new()))
+    }
+
+    /// `Option<ty>`, encoded as the sum `some(ty) | none`.
+    pub fn option(ty: AlgebraicType) -> AlgebraicType {
+        AlgebraicType::Sum(SumType {
+            variants: alloc::vec![
+                SumTypeVariant::new("some", ty),
+                SumTypeVariant::new("none", AlgebraicType::unit()),
+            ],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductTypeElement {
+    pub name: String,
+    pub algebraic_type: AlgebraicType,
+}
+
+impl ProductTypeElement {
+    pub fn new(name: &str, algebraic_type: AlgebraicType) -> ProductTypeElement {
+        ProductTypeElement {
+            name: String::from(name),
+            algebraic_type,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductType {
+    pub elements: Vec<ProductTypeElement>,
+}
+
+impl ProductType {
+    pub fn new(elements: Vec<ProductTypeElement>) -> ProductType {
+        ProductType { elements }
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.elements
+            .iter()
+            .position(|element| element.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SumTypeVariant {
+    pub name: String,
+    pub algebraic_type: AlgebraicType,
+}
+
+impl SumTypeVariant {
+    pub fn new(name: &str, algebraic_type: AlgebraicType) -> SumTypeVariant {
+        SumTypeVariant {
+            name: String::from(name),
+            algebraic_type,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SumType {
+    pub variants: Vec<SumTypeVariant>,
+}
+
+impl SumType {
+    pub fn tag_of(&self, name: &str) -> Option<u8> {
+        self.variants
+            .iter()
+            .position(|variant| variant.name == name)
+            .map(|tag| tag as u8)
+    }
+
+    /// Whether this is the sum built by [`AlgebraicType::option`].
+    pub fn is_option(&self) -> bool {
+        matches!(
+            &self.variants[..],
+            [some, none] if some.name == "some" && none.name == "none"
+                && none.algebraic_type == AlgebraicType::unit()
+        )
+    }
+}
+
+/// A value of some [`AlgebraicType`].
+///
+/// Values are totally ordered (floats through `total_cmp`) so they can be used as index keys.
+#[derive(Debug, Clone)]
+pub enum AlgebraicValue {
+    Bool(bool),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Array(Vec<AlgebraicValue>),
+    Product(ProductValue),
+    Sum(SumValue),
+}
+
+impl AlgebraicValue {
+    pub fn unit() -> AlgebraicValue {
+        AlgebraicValue::Product(ProductValue::new(Vec::new()))
+    }
+
+    pub fn has_type(&self, ty: &AlgebraicType) -> bool {
+        match (self, ty) {
+            (AlgebraicValue::Bool(_), AlgebraicType::Bool)
+            | (AlgebraicValue::I8(_), AlgebraicType::I8)
+            | (AlgebraicValue::U8(_), AlgebraicType::U8)
+            | (AlgebraicValue::I16(_), AlgebraicType::I16)
+            | (AlgebraicValue::U16(_), AlgebraicType::U16)
+            | (AlgebraicValue::I32(_), AlgebraicType::I32)
+            | (AlgebraicValue::U32(_), AlgebraicType::U32)
+            | (AlgebraicValue::I64(_), AlgebraicType::I64)
+            | (AlgebraicValue::U64(_), AlgebraicType::U64)
+            | (AlgebraicValue::F32(_), AlgebraicType::F32)
+            | (AlgebraicValue::F64(_), AlgebraicType::F64)
+            | (AlgebraicValue::String(_), AlgebraicType::String)
+            | (AlgebraicValue::Bytes(_), AlgebraicType::Bytes) => true,
+            (AlgebraicValue::Array(values), AlgebraicType::Array(element_type)) => {
+                values.iter().all(|value| value.has_type(element_type))
+            }
+            (AlgebraicValue::Product(value), AlgebraicType::Product(ty)) => value.has_type(ty),
+            (AlgebraicValue::Sum(value), AlgebraicType::Sum(ty)) => ty
+                .variants
+                .get(value.tag as usize)
+                .is_some_and(|variant| value.value.has_type(&variant.algebraic_type)),
+            _ => false,
+        }
+    }
+
+    /// Position of the variant in the enum, used to order values of different variants.
+    fn rank(&self) -> u8 {
+        match self {
+            AlgebraicValue::Bool(_) => 0,
+            AlgebraicValue::I8(_) => 1,
+            AlgebraicValue::U8(_) => 2,
+            AlgebraicValue::I16(_) => 3,
+            AlgebraicValue::U16(_) => 4,
+            AlgebraicValue::I32(_) => 5,
+            AlgebraicValue::U32(_) => 6,
+            AlgebraicValue::I64(_) => 7,
+            AlgebraicValue::U64(_) => 8,
+            AlgebraicValue::F32(_) => 9,
+            AlgebraicValue::F64(_) => 10,
+            AlgebraicValue::String(_) => 11,
+            AlgebraicValue::Bytes(_) => 12,
+            AlgebraicValue::Array(_) => 13,
+            AlgebraicValue::Product(_) => 14,
+            AlgebraicValue::Sum(_) => 15,
+        }
+    }
+}
+
+impl PartialEq for AlgebraicValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for AlgebraicValue {}
+
+impl PartialOrd for AlgebraicValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AlgebraicValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use AlgebraicValue::*;
+        match (self, other) {
+            (Bool(a), Bool(b)) => a.cmp(b),
+            (I8(a), I8(b)) => a.cmp(b),
+            (U8(a), U8(b)) => a.cmp(b),
+            (I16(a), I16(b)) => a.cmp(b),
+            (U16(a), U16(b)) => a.cmp(b),
+            (I32(a), I32(b)) => a.cmp(b),
+            (U32(a), U32(b)) => a.cmp(b),
+            (I64(a), I64(b)) => a.cmp(b),
+            (U64(a), U64(b)) => a.cmp(b),
+            (F32(a), F32(b)) => a.total_cmp(b),
+            (F64(a), F64(b)) => a.total_cmp(b),
+            (String(a), String(b)) => a.cmp(b),
+            (Bytes(a), Bytes(b)) => a.cmp(b),
+            (Array(a), Array(b)) => a.cmp(b),
+            (Product(a), Product(b)) => a.cmp(b),
+            (Sum(a), Sum(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+/// A row of a table, or the arguments of a reducer.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProductValue {
+    pub elements: Vec<AlgebraicValue>,
+}
+
+impl ProductValue {
+    pub fn new(elements: Vec<AlgebraicValue>) -> ProductValue {
+        ProductValue { elements }
+    }
+
+    pub fn has_type(&self, ty: &ProductType) -> bool {
+        self.elements.len() == ty.elements.len()
+            && self
+                .elements
+                .iter()
+                .zip(&ty.elements)
+                .all(|(value, element)| value.has_type(&element.algebraic_type))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SumValue {
+    pub tag: u8,
+    pub value: Box<AlgebraicValue>,
+}
+
+impl SumValue {
+    pub fn new(tag: u8, value: AlgebraicValue) -> SumValue {
+        SumValue {
+            tag,
+            value: Box::new(value),
+        }
+    }
+}
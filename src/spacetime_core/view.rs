@@ -0,0 +1,325 @@
+//! Views: named, read-only rows a module computes from its tables, queried and subscribed to
+//! as its tables are.
+//!
+//! A view is a stored query, or a function reading the tables. It can show each caller only
+//! the rows whose given column holds their identity, keeping the rules of what a client sees
+//! inside the module. Subscriptions to a view compute its rows again after each commit, and
+//! queue the rows that changed.
+//!
+//! View functions run under the module's timeout, charging what they allocate to the module;
+//! one running past either fails the read with [`QueryError::ViewFailed`].
+//!
+//! Views aren't part of a [`ModuleDef`](super::def::ModuleDef): they're added with
+//! [`Module::add_view`](super::Module::add_view) once the module is built, so modules
+//! published as WASM can't declare any yet, and [`Module::def`](super::Module::def) leaves them
+//! out.
+
+use alloc::{boxed::Box, collections::BTreeMap, rc::Rc, string::String, vec::Vec};
+
+use super::def::ModuleDefError;
+use super::query::{self, Query, QueryError};
+use super::subscription::TableDelta;
+use super::system;
+use super::table::Table;
+use super::value::{AlgebraicType, AlgebraicValue, ProductType, ProductValue};
+use crate::allocator::account;
+use crate::watchdog;
+
+pub type ViewCode = dyn Fn(&ViewContext) -> Result<Vec<ProductValue>, String>;
+pub type ViewFn = Box<ViewCode>;
+
+/// Read-only access to the tables for a view function.
+pub struct ViewContext<'a> {
+    caller: Option<u64>,
+    tables: &'a BTreeMap<u64, Table>,
+}
+
+impl<'a> ViewContext<'a> {
+    /// Identity of the caller reading the view, if it's read as one.
+    pub fn caller(&self) -> Option<u64> {
+        self.caller
+    }
+
+    pub fn table(&self, name: &str) -> Option<&'a Table> {
+        self.tables.values().find(|table| table.name() == name)
+    }
+}
+
+/// How a view computes its rows.
+#[derive(Clone)]
+pub enum ViewSource {
+    Query(Box<Query>),
+    /// A function returning rows of `columns`.
+    Function {
+        columns: ProductType,
+        function: Rc<ViewCode>,
+    },
+}
+
+#[derive(Clone)]
+pub struct ViewDef {
+    pub name: String,
+    pub source: ViewSource,
+    /// Column holding the identity of the caller each row is shown to, a `u64`.
+    pub caller_column: Option<String>,
+}
+
+impl ViewDef {
+    pub fn query(name: &str, query: Query) -> ViewDef {
+        ViewDef {
+            name: String::from(name),
+            source: ViewSource::Query(Box::new(query)),
+            caller_column: None,
+        }
+    }
+
+    pub fn function(name: &str, columns: ProductType, function: ViewFn) -> ViewDef {
+        ViewDef {
+            name: String::from(name),
+            source: ViewSource::Function {
+                columns,
+                function: Rc::from(function),
+            },
+            caller_column: None,
+        }
+    }
+
+    /// Shows each caller only the rows whose `column` holds their identity.
+    pub fn by_caller(mut self, column: &str) -> ViewDef {
+        self.caller_column = Some(String::from(column));
+        self
+    }
+}
+
+/// A view checked against the tables it reads.
+pub struct View {
+    def: ViewDef,
+    columns: ProductType,
+    caller_column: Option<usize>,
+}
+
+impl View {
+    pub(super) fn new(def: ViewDef, tables: &BTreeMap<u64, Table>) -> Result<View, ModuleDefError> {
+        if system::is_system_table(&def.name) {
+            return Err(ModuleDefError::ReservedName(def.name));
+        }
+        let invalid = || ModuleDefError::InvalidView(def.name.clone());
+        let columns = match &def.source {
+            ViewSource::Query(query) => query::read(query, tables).map_err(|_| invalid())?.schema,
+            ViewSource::Function { columns, .. } => columns.clone(),
+        };
+        let caller_column = match &def.caller_column {
+            Some(column) => {
+                let index = columns.index_of(column).ok_or_else(invalid)?;
+                if columns.elements[index].algebraic_type != AlgebraicType::U64 {
+                    return Err(invalid());
+                }
+                Some(index)
+            }
+            None => None,
+        };
+        Ok(View {
+            def,
+            columns,
+            caller_column,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.def.name
+    }
+
+    pub fn def(&self) -> &ViewDef {
+        &self.def
+    }
+
+    /// Type of the view's rows.
+    pub fn columns(&self) -> &ProductType {
+        &self.columns
+    }
+
+    /// Rows of the view over `tables` shown to `caller`, its function running for `timeout`
+    /// ticks at most under the module's account.
+    pub(super) fn rows(
+        &self,
+        caller: Option<u64>,
+        tables: &BTreeMap<u64, Table>,
+        timeout: u64,
+    ) -> Result<Vec<ProductValue>, QueryError> {
+        if self.caller_column.is_some() && caller.is_none() {
+            return Err(QueryError::CallerRequired(self.def.name.clone()));
+        }
+        let failed = |error: String| QueryError::ViewFailed {
+            view: self.def.name.clone(),
+            error,
+        };
+        let mut rows = match &self.def.source {
+            ViewSource::Query(query) => query::read(query, tables)?.rows,
+            ViewSource::Function { function, .. } => {
+                let ctx = ViewContext { caller, tables };
+                let rows = match watchdog::run(timeout, || function(&ctx)) {
+                    Ok(rows) => rows.map_err(failed)?,
+                    Err(_) => {
+                        watchdog::reclaim();
+                        let error = match account::exceeded() {
+                            true => "out of memory quota",
+                            false => "timed out",
+                        };
+                        return Err(failed(String::from(error)));
+                    }
+                };
+                if let Some(row) = rows.iter().find(|row| !row.has_type(&self.columns)) {
+                    return Err(failed(alloc::format!(
+                        "{:?} doesn't match its columns",
+                        row
+                    )));
+                }
+                rows
+            }
+        };
+        if let (Some(column), Some(caller)) = (self.caller_column, caller) {
+            rows.retain(|row| row.elements[column] == AlgebraicValue::U64(caller));
+        }
+        Ok(rows)
+    }
+}
+
+/// Changes from the rows `old` to the rows `new`, in any order.
+pub(super) fn diff(old: &[ProductValue], new: &[ProductValue]) -> TableDelta {
+    let mut counts: BTreeMap<&ProductValue, i64> = BTreeMap::new();
+    for row in old {
+        *counts.entry(row).or_default() -= 1;
+    }
+    for row in new {
+        *counts.entry(row).or_default() += 1;
+    }
+    let mut delta = TableDelta::default();
+    for (row, count) in counts {
+        let rows = match count < 0 {
+            true => &mut delta.deletes,
+            false => &mut delta.inserts,
+        };
+        for _ in 0..count.unsigned_abs() {
+            rows.push(row.clone());
+        }
+    }
+    delta
+}
+
+// Tests
+
+#[cfg(test)]
+fn item(owner: u64, name: &str, hidden: bool) -> ProductValue {
+    super::test_util::row([
+        AlgebraicValue::U64(owner),
+        AlgebraicValue::String(String::from(name)),
+        AlgebraicValue::Bool(hidden),
+    ])
+}
+
+#[cfg(test)]
+fn count(owner: u64, items: u64) -> ProductValue {
+    super::test_util::row([AlgebraicValue::U64(owner), AlgebraicValue::U64(items)])
+}
+
+/// Module of the items of players 1 and 2, with views `my_items` of the caller's items that
+/// aren't hidden, and `item_counts` of how many items each player has.
+#[cfg(test)]
+fn inventory_module() -> super::Module {
+    use super::test_util::{columns, failed, module};
+
+    let item_columns = columns([
+        ("owner", AlgebraicType::U64),
+        ("item", AlgebraicType::String),
+        ("hidden", AlgebraicType::Bool),
+    ]);
+    let mut module = module("inventory", [("item", item_columns)]);
+    let items = [
+        item(1, "sword", false),
+        item(1, "key", true),
+        item(2, "bow", false),
+    ];
+    module.insert_rows("item", 0, items).unwrap();
+    let visible = Query::table("item").filter_eq("hidden", AlgebraicValue::Bool(false));
+    let mine = ViewDef::query("my_items", visible).by_caller("owner");
+    module.add_view(mine).unwrap();
+    let counts =
+        super::test_util::columns([("owner", AlgebraicType::U64), ("items", AlgebraicType::U64)]);
+    let function = Box::new(|ctx: &ViewContext| {
+        let mut counts = BTreeMap::new();
+        for row in ctx.table("item").ok_or("no items")?.iter() {
+            let (_, row) = row.map_err(failed)?;
+            *counts.entry(row.elements[0].clone()).or_insert(0) += 1;
+        }
+        Ok(counts
+            .into_iter()
+            .map(|(owner, items)| ProductValue::new(alloc::vec![owner, AlgebraicValue::U64(items)]))
+            .collect())
+    });
+    module
+        .add_view(ViewDef::function("item_counts", counts, function))
+        .unwrap();
+    module
+}
+
+/// Players see their own items, but not hidden ones.
+#[test_case]
+fn test_view_by_caller() {
+    let module = inventory_module();
+    let my_items = Query::table("my_items");
+    assert_eq!(
+        module.query_as(&my_items, 1).unwrap().rows,
+        [item(1, "sword", false)]
+    );
+    assert_eq!(
+        module.query(&my_items),
+        Err(QueryError::CallerRequired(String::from("my_items")))
+    );
+}
+
+/// Functions compute rows the way the module wants.
+#[test_case]
+fn test_view_function() {
+    let module = inventory_module();
+    let result = module.query(&Query::table("item_counts")).unwrap();
+    assert_eq!(result.rows, [count(1, 2), count(2, 1)]);
+}
+
+#[test_case]
+fn test_view_subscriptions() {
+    use super::subscription::SubscriptionUpdate;
+
+    let mut module = inventory_module();
+    let (counts, _) = module.subscribe(&Query::table("item_counts")).unwrap();
+    let (mine, _) = module.subscribe_as(&Query::table("my_items"), 2).unwrap();
+    module
+        .insert_rows("item", 0, [item(2, "arrow", false)])
+        .unwrap();
+    let update = SubscriptionUpdate {
+        deletes: alloc::vec![count(2, 1)],
+        inserts: alloc::vec![count(2, 2)],
+    };
+    assert_eq!(module.poll_subscription(counts), Ok(alloc::vec![update]));
+    let update = SubscriptionUpdate {
+        deletes: Vec::new(),
+        inserts: alloc::vec![item(2, "arrow", false)],
+    };
+    assert_eq!(module.poll_subscription(mine), Ok(alloc::vec![update]));
+}
+
+#[test_case]
+fn test_view_defs() {
+    let mut module = inventory_module();
+    let clash = ViewDef::query("item", Query::table("item"));
+    assert_eq!(
+        module.add_view(clash),
+        Err(ModuleDefError::DuplicateTable(String::from("item")))
+    );
+    let invalid = ViewDef::query("owners", Query::table("item")).by_caller("item");
+    assert_eq!(
+        module.add_view(invalid),
+        Err(ModuleDefError::InvalidView(String::from("owners")))
+    );
+    let views = module.query(&Query::table(system::ST_VIEW)).unwrap();
+    assert_eq!(views.rows.len(), 2);
+}
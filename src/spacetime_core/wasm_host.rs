@@ -0,0 +1,329 @@
+//! Modules whose reducers are WebAssembly functions.
+//!
+//! The guest exports its linear `memory` and:
+//! - `__describe_module__() -> i64`, the address (high 32 bits) and length (low 32 bits) of
+//!   its [`ModuleDef`] in the binary format,
+//! - `__alloc__(len: i32) -> i32`, reserving guest memory for reducer arguments,
+//! - `__reducer__<name>(args: i32, args_len: i32) -> i32` for each reducer, returning `0` on
+//!   success and an error code otherwise.
+//!
+//! Reducers access the module's tables through the host functions in [`abi`](super::abi).
+
+use alloc::{boxed::Box, format, rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+
+use super::abi::{self, AbiHost};
+use super::binary::{self, DecodeError};
+use super::def::{ModuleDef, ModuleDefError, ReducerDef};
+use super::value::ProductValue;
+use super::{Module, ReducerCallError, ReducerContext, ReducerFn};
+use crate::allocator::account;
+use crate::wasm::parser::ExportKind;
+use crate::wasm::{self, Host, Instance, InstantiateError, Memory, ParseError, Trap, Value};
+use crate::watchdog;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WasmModuleError {
+    Parse(ParseError),
+    Instantiate(InstantiateError),
+    /// The guest trapped while describing itself.
+    Trap(Trap),
+    /// A required export is missing or has the wrong signature.
+    MissingExport(String),
+    Describe(DecodeError),
+    Def(ModuleDefError),
+    /// The module's init reducer failed.
+    Init(ReducerCallError),
+}
+
+const DESCRIBE_MODULE: &str = "__describe_module__";
+const ALLOC: &str = "__alloc__";
+const REDUCER_PREFIX: &str = "__reducer__";
+
+/// Host while the guest describes itself, outside of any reducer.
+struct NoContext;
+
+impl Host for NoContext {
+    fn call(
+        &mut self,
+        _function: u32,
+        _memory: &mut Memory,
+        _args: &[Value],
+    ) -> Result<Option<Value>, Trap> {
+        Err(Trap::Host(String::from(
+            "host functions can only be called by reducers",
+        )))
+    }
+}
+
+/// Loads a WebAssembly module, building a [`Module`] from the definition it exports.
+pub fn load(bytes: &[u8]) -> Result<Module, WasmModuleError> {
+    let wasm = Rc::new(wasm::Module::parse(bytes).map_err(WasmModuleError::Parse)?);
+    let mut host = NoContext;
+    let mut instance = Instance::new(wasm::Module::clone(&wasm), abi::resolve, &mut host)
+        .map_err(WasmModuleError::Instantiate)?;
+
+    let described =
+        instance
+            .invoke(DESCRIBE_MODULE, &[], &mut host)
+            .map_err(|trap| match trap {
+                Trap::NoSuchFunction(name) => WasmModuleError::MissingExport(name),
+                trap => WasmModuleError::Trap(trap),
+            })?;
+    let [Value::I64(described)] = described[..] else {
+        return Err(WasmModuleError::MissingExport(String::from(
+            DESCRIBE_MODULE,
+        )));
+    };
+    let (address, len) = ((described >> 32) as u32, described as u32);
+    let def_bytes = instance
+        .memory()
+        .read(address, len)
+        .map_err(WasmModuleError::Trap)?;
+    let def = ModuleDef::from_bytes(def_bytes).map_err(WasmModuleError::Describe)?;
+
+    for reducer in &def.reducers {
+        let export = format!("{}{}", REDUCER_PREFIX, reducer.name);
+        if instance
+            .module()
+            .export(&export, ExportKind::Func)
+            .is_none()
+        {
+            return Err(WasmModuleError::MissingExport(export));
+        }
+    }
+
+    let bind = binder(wasm.clone(), Some(instance));
+    let mut module =
+        Module::from_def(def, |reducer| Some(bind(reducer))).map_err(WasmModuleError::Def)?;
+    module.wasm = Some(wasm);
+    Ok(module)
+}
+
+/// A guest's code and the instance its reducers run in.
+struct Guest {
+    code: Rc<wasm::Module>,
+    instance: Option<Instance>,
+}
+
+/// Binds reducers to the exports of `instance`, shared by the reducers it binds, or of a new
+/// instance of `code` made on their first call.
+pub(super) fn binder(
+    code: Rc<wasm::Module>,
+    instance: Option<Instance>,
+) -> impl Fn(&ReducerDef) -> ReducerFn {
+    let guest = Rc::new(RefCell::new(Guest { code, instance }));
+    move |reducer| {
+        let guest = guest.clone();
+        let export = format!("{}{}", REDUCER_PREFIX, reducer.name);
+        // Aborting the interpreter would leave the instance borrowed, it stops itself once
+        // `AbiHost::interrupted` sees the deadline passed
+        Box::new(move |ctx, args| watchdog::critical(|| call_reducer(&guest, &export, ctx, args)))
+    }
+}
+
+fn call_reducer(
+    guest: &RefCell<Guest>,
+    export: &str,
+    ctx: &mut ReducerContext,
+    args: ProductValue,
+) -> Result<(), String> {
+    let mut guest = guest
+        .try_borrow_mut()
+        .map_err(|_| String::from("reducer called while the module is already running"))?;
+    let Guest {
+        code,
+        instance: slot,
+    } = &mut *guest;
+    let instance = match slot {
+        Some(instance) => instance,
+        // Charged to the kernel like the instance the module was loaded with, a page of guest
+        // memory being larger than most quotas
+        None => slot.insert(
+            account::kernel(|| {
+                Instance::new(wasm::Module::clone(code), abi::resolve, &mut NoContext)
+            })
+            .map_err(|error| format!("instantiating the module failed: {:?}", error))?,
+        ),
+    };
+
+    match invoke_reducer(instance, &mut AbiHost::new(ctx), export, args) {
+        Ok(result) => result,
+        Err(trap) => {
+            // The guest stopped part way through and may have left its memory in any state,
+            // the next call gets it as instantiated
+            if account::kernel(|| instance.reset(&mut NoContext)).is_err() {
+                *slot = None;
+            }
+            Err(format!("{} trapped: {:?}", export, trap))
+        }
+    }
+}
+
+/// Copies `args` into the guest and calls `export` with them, failing with the trap if the
+/// guest didn't return.
+fn invoke_reducer(
+    instance: &mut Instance,
+    host: &mut AbiHost,
+    export: &str,
+    args: ProductValue,
+) -> Result<Result<(), String>, Trap> {
+    let args = binary::to_bytes(&args);
+    let address = match invoke_metered(instance, host, ALLOC, &[Value::I32(args.len() as i32)])?[..]
+    {
+        [Value::I32(address)] => address as u32,
+        _ => return Ok(Err(format!("{} has the wrong signature", ALLOC))),
+    };
+    instance.memory_mut().write(address, &args)?;
+
+    let params = [Value::I32(address as i32), Value::I32(args.len() as i32)];
+    Ok(match invoke_metered(instance, host, export, &params)?[..] {
+        [Value::I32(0)] => Ok(()),
+        [Value::I32(code)] => Err(format!("{} returned error code {}", export, code)),
+        _ => Err(format!("{} has the wrong signature", export)),
+    })
+}
+
+/// Invokes `export` with the reducer's remaining energy as fuel, charging the fuel it used.
+fn invoke_metered(
+    instance: &mut Instance,
+    host: &mut AbiHost,
+    export: &str,
+    args: &[Value],
+) -> Result<Vec<Value>, Trap> {
+    let fuel = host.ctx().energy_left();
+    instance.set_fuel(fuel);
+    let result = instance.invoke(export, args, host);
+    let used = match result {
+        Err(Trap::OutOfFuel) => u64::MAX,
+        _ => fuel - instance.fuel(),
+    };
+    host.ctx().charge(used).map_err(|_| Trap::OutOfFuel)?;
+    result
+}
+
+// Tests
+
+/// Guest describing a module `guest` with a reducer `check(n: u32)`, failing unless `n == 7`.
+#[cfg(test)]
+#[rustfmt::skip]
+const CHECK_GUEST: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x10, 0x03, 0x60,
+    0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f,
+    0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x01, 0x02, 0x05, 0x03, 0x01, 0x00,
+    0x01, 0x07, 0x3f, 0x04, 0x13, 0x5f, 0x5f, 0x64, 0x65, 0x73, 0x63, 0x72,
+    0x69, 0x62, 0x65, 0x5f, 0x6d, 0x6f, 0x64, 0x75, 0x6c, 0x65, 0x5f, 0x5f,
+    0x00, 0x00, 0x09, 0x5f, 0x5f, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x5f, 0x5f,
+    0x00, 0x01, 0x10, 0x5f, 0x5f, 0x72, 0x65, 0x64, 0x75, 0x63, 0x65, 0x72,
+    0x5f, 0x5f, 0x63, 0x68, 0x65, 0x63, 0x6b, 0x00, 0x02, 0x06, 0x6d, 0x65,
+    0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x0a, 0x1c, 0x03, 0x0a, 0x00, 0x42,
+    0xa5, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01, 0x0b, 0x04, 0x00, 0x41, 0x10,
+    0x0b, 0x0a, 0x00, 0x20, 0x00, 0x28, 0x02, 0x00, 0x41, 0x07, 0x47, 0x0b,
+    0x0b, 0x2c, 0x01, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x25, 0x05, 0x00, 0x00,
+    0x00, 0x67, 0x75, 0x65, 0x73, 0x74, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+    0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x63, 0x68, 0x65, 0x63, 0x6b, 0x01,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x6e, 0x06, 0x00,
+];
+
+#[test_case]
+fn test_wasm_reducer() {
+    use super::value::AlgebraicValue;
+    use alloc::vec;
+
+    let mut module = load(CHECK_GUEST).expect("valid guest");
+    assert_eq!(module.name(), "guest");
+    let args = |n| ProductValue::new(vec![AlgebraicValue::U32(n)]);
+    assert_eq!(module.call_reducer("check", 1, args(7)), Ok(()));
+    assert!(matches!(
+        module.call_reducer("check", 1, args(3)),
+        Err(ReducerCallError::Failed(_))
+    ));
+
+    // A restored copy runs its reducers in an instance of its own
+    let point = super::history::RestorePoint::Offset(0);
+    let mut copy = module.restored(point, String::from("copy")).unwrap();
+    let function = |module: &Module| module.reducers.values().next().unwrap().function.clone();
+    assert!(!Rc::ptr_eq(&function(&module), &function(&copy)));
+    // Two instances wouldn't fit in the kernel heap
+    drop(module);
+    assert_eq!(copy.call_reducer("check", 1, args(7)), Ok(()));
+
+    assert!(matches!(
+        load(&CHECK_GUEST[..CHECK_GUEST.len() - 1]),
+        Err(WasmModuleError::Parse(_))
+    ));
+}
+
+/// Guest describing a module `spinner` with a reducer `spin()` that loops forever.
+#[cfg(test)]
+#[rustfmt::skip]
+const SPIN_GUEST: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x10, 0x03, 0x60,
+    0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f,
+    0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x01, 0x02, 0x05, 0x03, 0x01, 0x00,
+    0x01, 0x07, 0x3e, 0x04, 0x13, 0x5f, 0x5f, 0x64, 0x65, 0x73, 0x63, 0x72,
+    0x69, 0x62, 0x65, 0x5f, 0x6d, 0x6f, 0x64, 0x75, 0x6c, 0x65, 0x5f, 0x5f,
+    0x00, 0x00, 0x09, 0x5f, 0x5f, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x5f, 0x5f,
+    0x00, 0x01, 0x0f, 0x5f, 0x5f, 0x72, 0x65, 0x64, 0x75, 0x63, 0x65, 0x72,
+    0x5f, 0x5f, 0x73, 0x70, 0x69, 0x6e, 0x00, 0x02, 0x06, 0x6d, 0x65, 0x6d,
+    0x6f, 0x72, 0x79, 0x02, 0x00, 0x0a, 0x1b, 0x03, 0x0a, 0x00, 0x42, 0xa0,
+    0x80, 0x80, 0x80, 0x80, 0x80, 0x01, 0x0b, 0x04, 0x00, 0x41, 0x10, 0x0b,
+    0x09, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x41, 0x00, 0x0b, 0x0b, 0x27,
+    0x01, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x20, 0x07, 0x00, 0x00, 0x00, 0x73,
+    0x70, 0x69, 0x6e, 0x6e, 0x65, 0x72, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+    0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x73, 0x70, 0x69, 0x6e, 0x00, 0x00,
+    0x00, 0x00, 0x00,
+];
+
+#[test_case]
+fn test_wasm_out_of_fuel() {
+    let mut module = load(SPIN_GUEST).expect("valid guest");
+    module.set_energy_budget(10_000);
+    assert_eq!(
+        module.call_reducer("spin", 3, ProductValue::new(alloc::vec![])),
+        Err(ReducerCallError::OutOfEnergy)
+    );
+    assert_eq!(module.energy_used_by(3), 10_000);
+    assert_eq!(module.energy_used(), 10_000);
+}
+
+/// Guest describing a module `locker` with a reducer `lock(n: u32)` holding a lock in a global
+/// while it runs, looping forever if `n == 0` and failing if the lock is already held.
+#[cfg(test)]
+#[rustfmt::skip]
+const LOCK_GUEST: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x10, 0x03, 0x60,
+    0x00, 0x01, 0x7e, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f,
+    0x01, 0x7f, 0x03, 0x04, 0x03, 0x00, 0x01, 0x02, 0x05, 0x03, 0x01, 0x00,
+    0x01, 0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b, 0x07, 0x3e, 0x04,
+    0x13, 0x5f, 0x5f, 0x64, 0x65, 0x73, 0x63, 0x72, 0x69, 0x62, 0x65, 0x5f,
+    0x6d, 0x6f, 0x64, 0x75, 0x6c, 0x65, 0x5f, 0x5f, 0x00, 0x00, 0x09, 0x5f,
+    0x5f, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x5f, 0x5f, 0x00, 0x01, 0x0f, 0x5f,
+    0x5f, 0x72, 0x65, 0x64, 0x75, 0x63, 0x65, 0x72, 0x5f, 0x5f, 0x6c, 0x6f,
+    0x63, 0x6b, 0x00, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02,
+    0x00, 0x0a, 0x34, 0x03, 0x0a, 0x00, 0x42, 0xa5, 0x80, 0x80, 0x80, 0x80,
+    0x80, 0x01, 0x0b, 0x04, 0x00, 0x41, 0x10, 0x0b, 0x22, 0x00, 0x23, 0x00,
+    0x04, 0x40, 0x41, 0x01, 0x0f, 0x0b, 0x41, 0x01, 0x24, 0x00, 0x20, 0x00,
+    0x28, 0x02, 0x00, 0x45, 0x04, 0x40, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b,
+    0x41, 0x00, 0x24, 0x00, 0x41, 0x00, 0x0b, 0x0b, 0x2c, 0x01, 0x00, 0x41,
+    0x80, 0x08, 0x0b, 0x25, 0x06, 0x00, 0x00, 0x00, 0x6c, 0x6f, 0x63, 0x6b,
+    0x65, 0x72, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x04, 0x00,
+    0x00, 0x00, 0x6c, 0x6f, 0x63, 0x6b, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00,
+    0x00, 0x00, 0x6e, 0x06, 0x00,
+];
+
+#[test_case]
+fn test_wasm_trap_resets_instance() {
+    use super::value::AlgebraicValue;
+
+    let mut module = load(LOCK_GUEST).expect("valid guest");
+    module.set_energy_budget(10_000);
+    let args = |n| ProductValue::new(alloc::vec![AlgebraicValue::U32(n)]);
+    assert_eq!(module.call_reducer("lock", 1, args(1)), Ok(()));
+    // The guest ran out of fuel holding the lock, the next call finds it as instantiated
+    assert_eq!(
+        module.call_reducer("lock", 1, args(0)),
+        Err(ReducerCallError::OutOfEnergy)
+    );
+    assert_eq!(module.call_reducer("lock", 1, args(1)), Ok(()));
+}
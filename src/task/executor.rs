@@ -1,3 +1,4 @@
+use super::scheduler::{FairQueue, Priority, Tenant};
 use super::{Task, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
@@ -9,7 +10,9 @@ const MAX_NUMBER_TASKS: usize = 100;
 
 pub struct Executor {
     tasks: Arc<Mutex<BTreeMap<TaskId, Task>>>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    // Woken tasks, pushed without locking as wakers may run in interrupt handlers
+    task_queue: Arc<ArrayQueue<(Tenant, TaskId)>>,
+    run_queue: FairQueue<TaskId>,
     waker_cache: BTreeMap<TaskId, Waker>,
 }
 
@@ -18,10 +21,21 @@ impl Executor {
         Executor {
             tasks: Arc::new(Mutex::new(BTreeMap::new())),
             task_queue: Arc::new(ArrayQueue::new(MAX_NUMBER_TASKS)),
+            run_queue: FairQueue::new(),
             waker_cache: BTreeMap::new(),
         }
     }
 
+    /// Sets how many tasks of the module run in a row on its turn, see [`FairQueue`].
+    pub fn set_weight(&mut self, module: u64, weight: u32) {
+        self.run_queue.set_weight(module, weight);
+    }
+
+    /// Sets the priority of the module's tasks, see [`FairQueue`].
+    pub fn set_priority(&mut self, module: u64, priority: Priority) {
+        self.run_queue.set_priority(module, priority);
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
@@ -29,15 +43,23 @@ impl Executor {
         }
     }
 
-    fn run_ready_tasks(&mut self) {
+    /// Polls the woken tasks, in the fair order of [`FairQueue`], until none are left.
+    pub fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
+            run_queue,
             waker_cache,
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
+        loop {
+            while let Some((tenant, task_id)) = task_queue.pop() {
+                run_queue.push(tenant, task_id);
+            }
+            let Some((tenant, task_id)) = run_queue.pop() else {
+                break;
+            };
             let mut tasks_guard = tasks.lock();
             let mut task = match tasks_guard.remove(&task_id) {
                 Some(task) => task,
@@ -51,7 +73,7 @@ impl Executor {
 
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(tenant, task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
@@ -82,20 +104,28 @@ impl Executor {
 }
 
 struct TaskWaker {
+    tenant: Tenant,
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ArrayQueue<(Tenant, TaskId)>>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(
+        tenant: Tenant,
+        task_id: TaskId,
+        task_queue: Arc<ArrayQueue<(Tenant, TaskId)>>,
+    ) -> Waker {
         Waker::from(Arc::new(TaskWaker {
+            tenant,
             task_id,
             task_queue,
         }))
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue
+            .push((self.tenant, self.task_id))
+            .expect("task_queue full");
     }
 }
 
@@ -112,7 +142,7 @@ impl Wake for TaskWaker {
 #[derive(Clone)]
 pub struct Spawner {
     tasks: Arc<Mutex<BTreeMap<TaskId, Task>>>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<ArrayQueue<(Tenant, TaskId)>>,
 }
 
 impl Spawner {
@@ -123,10 +153,12 @@ impl Spawner {
         }
     }
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
+        let (tenant, task_id) = (task.tenant, task.id);
         if self.tasks.lock().insert(task_id, task).is_some() {
             panic!("Task with same id already exists");
         }
-        self.task_queue.push(task_id).expect("task queue is full");
+        self.task_queue
+            .push((tenant, task_id))
+            .expect("task queue is full");
     }
 }
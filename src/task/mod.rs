@@ -1,10 +1,12 @@
 pub mod executor;
 pub mod keyboard;
+pub mod scheduler;
 
 use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
+use scheduler::Tenant;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -18,17 +20,28 @@ impl TaskId {
 
 pub struct Task {
     id: TaskId,
+    tenant: Tenant,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::for_tenant(Tenant::KERNEL, future)
+    }
+
+    /// A task run on behalf of `tenant`, scheduled fairly with the tasks of other tenants.
+    pub fn for_tenant(tenant: Tenant, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            tenant,
             future: Box::pin(future),
         }
     }
 
+    pub fn tenant(&self) -> Tenant {
+        self.tenant
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
@@ -0,0 +1,273 @@
+//! Fair ordering of work queued by several modules and callers.
+//!
+//! Each module gets a queue per caller. Modules of the highest priority with work queued take
+//! turns, each taking as many items in a row as its weight; within a module, callers take one
+//! item each in turn. A module or caller queueing a lot of work only delays its own, and a
+//! lower priority with work queued still gets an item once higher ones took [`MAX_WAIT`].
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// Items taken from higher priorities while a lower one has work queued, before it gets one.
+pub const MAX_WAIT: u32 = 8;
+
+/// Who queued an item: a module, and the user calling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tenant {
+    pub module: u64,
+    pub caller: u64,
+}
+
+impl Tenant {
+    /// Tenant of the kernel's own tasks.
+    pub const KERNEL: Tenant = Tenant {
+        module: u64::MAX,
+        caller: 0,
+    };
+
+    pub fn new(module: u64, caller: u64) -> Tenant {
+        Tenant { module, caller }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+struct ModuleQueue<T> {
+    callers: BTreeMap<u64, VecDeque<T>>,
+    /// Callers with items queued, in the order of their turns.
+    turns: VecDeque<u64>,
+    /// Items the module may still take in its current turn.
+    credit: u32,
+}
+
+impl<T> ModuleQueue<T> {
+    fn new() -> ModuleQueue<T> {
+        ModuleQueue {
+            callers: BTreeMap::new(),
+            turns: VecDeque::new(),
+            credit: 0,
+        }
+    }
+
+    fn push(&mut self, caller: u64, item: T) {
+        let items = self.callers.entry(caller).or_default();
+        if items.is_empty() {
+            self.turns.push_back(caller);
+        }
+        items.push_back(item);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let caller = self.turns.pop_front()?;
+        let items = self.callers.get_mut(&caller).expect("caller has a turn");
+        let item = items.pop_front();
+        if items.is_empty() {
+            self.callers.remove(&caller);
+        } else {
+            self.turns.push_back(caller);
+        }
+        item
+    }
+
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+/// Modules of one priority with items queued.
+#[derive(Default)]
+struct Level {
+    /// Modules in the order of their turns.
+    turns: VecDeque<u64>,
+    /// Items taken from higher priorities since this one last got one.
+    waited: u32,
+}
+
+/// Items queued by tenants, taken in weighted round-robin order.
+pub struct FairQueue<T> {
+    modules: BTreeMap<u64, ModuleQueue<T>>,
+    levels: BTreeMap<Priority, Level>,
+    weights: BTreeMap<u64, u32>,
+    priorities: BTreeMap<u64, Priority>,
+    len: usize,
+}
+
+impl<T> FairQueue<T> {
+    pub fn new() -> FairQueue<T> {
+        FairQueue {
+            modules: BTreeMap::new(),
+            levels: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            priorities: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Items the module takes in a row on its turn, 1 unless set.
+    pub fn weight(&self, module: u64) -> u32 {
+        self.weights.get(&module).copied().unwrap_or(1)
+    }
+
+    /// Sets the module's weight, at least 1.
+    pub fn set_weight(&mut self, module: u64, weight: u32) {
+        self.weights.insert(module, weight.max(1));
+    }
+
+    pub fn priority(&self, module: u64) -> Priority {
+        self.priorities.get(&module).copied().unwrap_or_default()
+    }
+
+    /// Sets the module's priority, its items being taken before those of lower priorities
+    /// except every [`MAX_WAIT`] items.
+    pub fn set_priority(&mut self, module: u64, priority: Priority) {
+        let old = self.priority(module);
+        self.priorities.insert(module, priority);
+        if old != priority && self.modules.contains_key(&module) {
+            let level = self.levels.get_mut(&old).expect("queued module has a turn");
+            level.turns.retain(|queued| *queued != module);
+            if level.turns.is_empty() {
+                self.levels.remove(&old);
+            }
+            let level = self.levels.entry(priority).or_default();
+            level.turns.push_back(module);
+        }
+    }
+
+    pub fn push(&mut self, tenant: Tenant, item: T) {
+        let queue = self.modules.entry(tenant.module).or_insert_with(|| {
+            let priority = self.priorities.get(&tenant.module).copied();
+            let level = self.levels.entry(priority.unwrap_or_default()).or_default();
+            level.turns.push_back(tenant.module);
+            ModuleQueue::new()
+        });
+        queue.push(tenant.caller, item);
+        self.len += 1;
+    }
+
+    /// Takes the next item, and the tenant who queued it.
+    pub fn pop(&mut self) -> Option<(Tenant, T)> {
+        // The lowest priority that waited long enough, or else the highest
+        let priority = self
+            .levels
+            .iter()
+            .find(|(_, level)| level.waited >= MAX_WAIT)
+            .or_else(|| self.levels.last_key_value())
+            .map(|(priority, _)| *priority)?;
+        for (_, level) in self.levels.range_mut(..priority) {
+            level.waited += 1;
+        }
+        let level = self
+            .levels
+            .get_mut(&priority)
+            .expect("priority has modules");
+        level.waited = 0;
+        let turns = &mut level.turns;
+        let module = *turns.front().expect("priorities without turns are removed");
+        let queue = self.modules.get_mut(&module).expect("module has a turn");
+        if queue.credit == 0 {
+            queue.credit = self.weights.get(&module).copied().unwrap_or(1);
+        }
+        let caller = *queue.turns.front().expect("queued module has callers");
+        let item = queue.pop().expect("caller has a turn");
+        queue.credit -= 1;
+        if queue.is_empty() {
+            self.modules.remove(&module);
+            turns.pop_front();
+            if turns.is_empty() {
+                self.levels.remove(&priority);
+            }
+        } else if queue.credit == 0 {
+            turns.rotate_left(1);
+        }
+        self.len -= 1;
+        Some((Tenant::new(module, caller), item))
+    }
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> FairQueue<T> {
+        FairQueue::new()
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+const NOISY: u64 = 1;
+#[cfg(test)]
+const QUIET: u64 = 2;
+
+/// Items of `queue` in the order they're popped.
+#[cfg(test)]
+fn drain(queue: &mut FairQueue<i32>) -> alloc::vec::Vec<i32> {
+    core::iter::from_fn(|| queue.pop().map(|(_, item)| item)).collect()
+}
+
+/// Modules take turns, and so do the callers of each module.
+#[test_case]
+fn test_fair_queue() {
+    let mut queue = FairQueue::new();
+    for item in 0..4 {
+        queue.push(Tenant::new(NOISY, 10), item);
+    }
+    queue.push(Tenant::new(NOISY, 11), 4);
+    queue.push(Tenant::new(QUIET, 10), 5);
+    queue.push(Tenant::new(QUIET, 10), 6);
+    assert_eq!(queue.len(), 7);
+    assert_eq!(drain(&mut queue), [0, 5, 4, 6, 1, 2, 3]);
+    assert!(queue.is_empty());
+}
+
+/// Weights let a module take more items on its turn.
+#[test_case]
+fn test_fair_queue_weights() {
+    let mut queue = FairQueue::new();
+    queue.set_weight(NOISY, 2);
+    for item in 0..4 {
+        queue.push(Tenant::new(NOISY, 10), item);
+    }
+    queue.push(Tenant::new(QUIET, 10), 4);
+    queue.push(Tenant::new(QUIET, 10), 5);
+    assert_eq!(drain(&mut queue), [0, 1, 4, 2, 3, 5]);
+}
+
+/// Higher priorities go before lower ones.
+#[test_case]
+fn test_fair_queue_priorities() {
+    let admin = 3;
+    let mut queue = FairQueue::new();
+    queue.push(Tenant::new(NOISY, 10), 0);
+    queue.push(Tenant::new(admin, 0), 1);
+    queue.set_priority(admin, Priority::High);
+    queue.push(Tenant::new(QUIET, 10), 2);
+    queue.set_priority(QUIET, Priority::Low);
+    assert_eq!(drain(&mut queue), [1, 0, 2]);
+}
+
+/// Lower priorities still get an item once higher ones took enough.
+#[test_case]
+fn test_fair_queue_aging() {
+    let mut queue = FairQueue::new();
+    queue.set_priority(QUIET, Priority::Low);
+    let busy = MAX_WAIT as i32;
+    for item in 0..=busy {
+        queue.push(Tenant::new(NOISY, 10), item);
+    }
+    queue.push(Tenant::new(QUIET, 10), -1);
+    let mut expected: alloc::vec::Vec<_> = (0..busy).collect();
+    expected.extend([-1, busy]);
+    assert_eq!(drain(&mut queue), expected);
+}
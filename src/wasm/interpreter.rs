@@ -0,0 +1,923 @@
+use alloc::{string::String, vec::Vec};
+use core::f32::math as f32_math;
+use core::f64::math as f64_math;
+
+use super::parser::{BlockType, ConstExpr, ExportKind, Import, Instr, Module};
+use super::{FuncType, Trap, Value};
+
+pub const PAGE_SIZE: usize = 64 * 1024;
+
+/// Memories never grow past this many pages, whatever the module asks for.
+const MAX_PAGES: u32 = 16;
+/// Tables never start with more elements than this, whatever the module asks for.
+const MAX_TABLE_ELEMENTS: u32 = 4096;
+const MAX_FRAMES: usize = 512;
+const MAX_STACK: usize = 16 * 1024;
+/// Instructions run between two calls to [`Host::interrupted`].
+const INTERRUPT_POLL_INTERVAL: u64 = 1024;
+
+/// Functions imported by a guest module.
+pub trait Host {
+    /// Calls the host function that `resolve` returned for the import, see [`Instance::new`].
+    fn call(
+        &mut self,
+        function: u32,
+        memory: &mut Memory,
+        args: &[Value],
+    ) -> Result<Option<Value>, Trap>;
+
+    /// Polled while the guest runs, stopping it with [`Trap::Interrupted`] once it returns true.
+    fn interrupted(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstantiateError {
+    UnresolvedImport {
+        module: String,
+        name: String,
+    },
+    /// A global initializer or segment offset is not a valid constant.
+    InvalidConstant,
+    /// The memory the module asks for is larger than we allow.
+    MemoryTooLarge,
+    /// The table the module asks for is larger than we allow.
+    TableTooLarge,
+    /// A data or element segment doesn't fit, or the start function trapped.
+    Trap(Trap),
+}
+
+/// Linear memory of an instance.
+pub struct Memory {
+    bytes: Vec<u8>,
+    max_pages: u32,
+}
+
+impl Memory {
+    /// Size in pages.
+    pub fn size(&self) -> u32 {
+        (self.bytes.len() / PAGE_SIZE) as u32
+    }
+
+    /// Grows the memory by `delta` pages, returning the previous size.
+    pub fn grow(&mut self, delta: u32) -> Option<u32> {
+        let size = self.size();
+        let new_size = size.checked_add(delta)?;
+        if new_size > self.max_pages {
+            return None;
+        }
+        let new_len = new_size as usize * PAGE_SIZE;
+        self.bytes
+            .try_reserve_exact(new_len - self.bytes.len())
+            .ok()?;
+        self.bytes.resize(new_len, 0);
+        Some(size)
+    }
+
+    pub fn read(&self, address: u32, len: u32) -> Result<&[u8], Trap> {
+        let start = address as usize;
+        let end = start + len as usize;
+        self.bytes.get(start..end).ok_or(Trap::MemoryOutOfBounds)
+    }
+
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Trap> {
+        let start = address as usize;
+        let end = start + data.len();
+        self.bytes
+            .get_mut(start..end)
+            .ok_or(Trap::MemoryOutOfBounds)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn effective_address(address: i32, offset: u32) -> u32 {
+        // Wasm addresses are unsigned, and offsets past 4 GiB are out of bounds anyway
+        (address as u32).saturating_add(offset)
+    }
+
+    fn load<const N: usize>(&self, address: i32, offset: u32) -> Result<[u8; N], Trap> {
+        let address = Memory::effective_address(address, offset);
+        Ok(self.read(address, N as u32)?.try_into().unwrap())
+    }
+
+    fn store(&mut self, address: i32, offset: u32, data: &[u8]) -> Result<(), Trap> {
+        self.write(Memory::effective_address(address, offset), data)
+    }
+}
+
+struct Frame {
+    /// Index of the function in `Module::functions`.
+    function: usize,
+    pc: usize,
+    locals: Vec<Value>,
+    label_base: usize,
+    stack_base: usize,
+    arity: usize,
+}
+
+struct Label {
+    /// Stack height when the block was entered, below its parameters.
+    height: usize,
+    /// Number of values carried by a branch to this label.
+    arity: usize,
+    /// Where a branch to this label continues.
+    target: usize,
+    is_loop: bool,
+}
+
+/// An instantiated module, ready to run its exported functions.
+pub struct Instance {
+    module: Module,
+    host_functions: Vec<u32>,
+    memory: Memory,
+    globals: Vec<Value>,
+    table: Vec<Option<u32>>,
+    fuel: u64,
+}
+
+impl Instance {
+    /// Instantiates `module`, asking `resolve` for the host function behind each import.
+    pub fn new(
+        module: Module,
+        resolve: impl Fn(&Import, &FuncType) -> Option<u32>,
+        host: &mut dyn Host,
+    ) -> Result<Instance, InstantiateError> {
+        let mut host_functions = Vec::new();
+        for import in &module.imports {
+            let function = module
+                .types
+                .get(import.type_index as usize)
+                .and_then(|ty| resolve(import, ty))
+                .ok_or_else(|| InstantiateError::UnresolvedImport {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                })?;
+            host_functions.push(function);
+        }
+
+        let max_pages = module
+            .memory
+            .map_or(0, |limits| limits.max.unwrap_or(MAX_PAGES).min(MAX_PAGES));
+        let table_len = module.table.map_or(0, |limits| limits.min);
+        if table_len > MAX_TABLE_ELEMENTS {
+            return Err(InstantiateError::TableTooLarge);
+        }
+        let mut table = Vec::new();
+        table
+            .try_reserve_exact(table_len as usize)
+            .map_err(|_| InstantiateError::TableTooLarge)?;
+        table.resize(table_len as usize, None);
+
+        let mut instance = Instance {
+            module,
+            host_functions,
+            memory: Memory {
+                bytes: Vec::new(),
+                max_pages,
+            },
+            globals: Vec::new(),
+            table,
+            fuel: u64::MAX,
+        };
+        instance.reset(host)?;
+        Ok(instance)
+    }
+
+    /// Puts the memory, globals and table back as instantiating left them, running the start
+    /// function again, so a call that trapped half way leaves nothing behind.
+    ///
+    /// Reuses the memory the instance already has, only growing it if the start function does.
+    pub fn reset(&mut self, host: &mut dyn Host) -> Result<(), InstantiateError> {
+        let min_pages = self.module.memory.map_or(0, |limits| limits.min);
+        if min_pages > self.memory.max_pages {
+            return Err(InstantiateError::MemoryTooLarge);
+        }
+        self.memory.bytes.clear();
+        self.memory
+            .grow(min_pages)
+            .ok_or(InstantiateError::MemoryTooLarge)?;
+
+        self.globals.clear();
+        for global in &self.module.globals {
+            let value = eval_const(&global.init, &self.globals)?;
+            if value.ty() != global.ty {
+                return Err(InstantiateError::InvalidConstant);
+            }
+            self.globals.push(value);
+        }
+
+        self.table.fill(None);
+        for segment in &self.module.elements {
+            let Value::I32(offset) = eval_const(&segment.offset, &self.globals)? else {
+                return Err(InstantiateError::InvalidConstant);
+            };
+            let start = offset as u32 as usize;
+            let slots = self
+                .table
+                .get_mut(start..start + segment.functions.len())
+                .ok_or(InstantiateError::Trap(Trap::UndefinedElement))?;
+            for (slot, function) in slots.iter_mut().zip(&segment.functions) {
+                *slot = Some(*function);
+            }
+        }
+        for segment in &self.module.data {
+            let Some(offset) = &segment.offset else {
+                continue;
+            };
+            let Value::I32(offset) = eval_const(offset, &self.globals)? else {
+                return Err(InstantiateError::InvalidConstant);
+            };
+            self.memory
+                .write(offset as u32, &segment.bytes)
+                .map_err(InstantiateError::Trap)?;
+        }
+
+        self.fuel = u64::MAX;
+        if let Some(start) = self.module.start {
+            self.invoke_index(start, &[], host)
+                .map_err(InstantiateError::Trap)?;
+        }
+        Ok(())
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Instructions left to run before calls trap with [`Trap::OutOfFuel`].
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    /// Calls the exported function `name`.
+    pub fn invoke(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        host: &mut dyn Host,
+    ) -> Result<Vec<Value>, Trap> {
+        let index = self
+            .module
+            .export(name, ExportKind::Func)
+            .ok_or_else(|| Trap::NoSuchFunction(String::from(name)))?;
+        self.invoke_index(index, args, host)
+    }
+
+    pub fn invoke_index(
+        &mut self,
+        function: u32,
+        args: &[Value],
+        host: &mut dyn Host,
+    ) -> Result<Vec<Value>, Trap> {
+        let ty = self
+            .module
+            .function_type(function)
+            .ok_or(Trap::UndefinedElement)?;
+        if args.len() != ty.params.len()
+            || args.iter().zip(&ty.params).any(|(arg, ty)| arg.ty() != *ty)
+        {
+            return Err(Trap::TypeMismatch);
+        }
+        let results = ty.results.len();
+        let mut machine = Machine {
+            module: &self.module,
+            host_functions: &self.host_functions,
+            memory: &mut self.memory,
+            globals: &mut self.globals,
+            table: &self.table,
+            host,
+            fuel: &mut self.fuel,
+            stack: args.to_vec(),
+            frames: Vec::new(),
+            labels: Vec::new(),
+        };
+        machine.call(function)?;
+        machine.run()?;
+        let stack = machine.stack;
+        Ok(stack[stack.len() - results..].to_vec())
+    }
+}
+
+fn eval_const(expr: &ConstExpr, globals: &[Value]) -> Result<Value, InstantiateError> {
+    Ok(match expr {
+        ConstExpr::I32(value) => Value::I32(*value),
+        ConstExpr::I64(value) => Value::I64(*value),
+        ConstExpr::F32(value) => Value::F32(*value),
+        ConstExpr::F64(value) => Value::F64(*value),
+        ConstExpr::GlobalGet(index) => *globals
+            .get(*index as usize)
+            .ok_or(InstantiateError::InvalidConstant)?,
+    })
+}
+
+/// State of one call into the instance.
+struct Machine<'a> {
+    module: &'a Module,
+    host_functions: &'a [u32],
+    memory: &'a mut Memory,
+    globals: &'a mut Vec<Value>,
+    table: &'a [Option<u32>],
+    host: &'a mut dyn Host,
+    fuel: &'a mut u64,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    labels: Vec<Label>,
+}
+
+macro_rules! pop {
+    ($machine:expr, $variant:ident) => {
+        match $machine.stack.pop() {
+            Some(Value::$variant(value)) => value,
+            _ => return Err(Trap::TypeMismatch),
+        }
+    };
+}
+
+macro_rules! unary {
+    ($machine:expr, $in:ident => $out:ident, $f:expr) => {{
+        let a = pop!($machine, $in);
+        #[allow(clippy::redundant_closure_call)]
+        $machine.stack.push(Value::$out($f(a)));
+    }};
+}
+
+macro_rules! binary {
+    ($machine:expr, $in:ident => $out:ident, $f:expr) => {{
+        let b = pop!($machine, $in);
+        let a = pop!($machine, $in);
+        #[allow(clippy::redundant_closure_call)]
+        $machine.stack.push(Value::$out($f(a, b)));
+    }};
+}
+
+macro_rules! binary_checked {
+    ($machine:expr, $in:ident => $out:ident, $f:expr) => {{
+        let b = pop!($machine, $in);
+        let a = pop!($machine, $in);
+        #[allow(clippy::redundant_closure_call)]
+        $machine.stack.push(Value::$out($f(a, b)?));
+    }};
+}
+
+impl Machine<'_> {
+    fn block_arity(&self, ty: BlockType) -> Result<(usize, usize), Trap> {
+        Ok(match ty {
+            BlockType::Empty => (0, 0),
+            BlockType::Value(_) => (0, 1),
+            BlockType::Type(index) => {
+                let ty = self
+                    .module
+                    .types
+                    .get(index as usize)
+                    .ok_or(Trap::TypeMismatch)?;
+                (ty.params.len(), ty.results.len())
+            }
+        })
+    }
+
+    fn pop_values(&mut self, count: usize) -> Result<Vec<Value>, Trap> {
+        if self.stack.len() < count {
+            return Err(Trap::TypeMismatch);
+        }
+        Ok(self.stack.split_off(self.stack.len() - count))
+    }
+
+    /// Calls a function, either running a host import or pushing a new frame.
+    fn call(&mut self, function: u32) -> Result<(), Trap> {
+        let ty = self
+            .module
+            .function_type(function)
+            .ok_or(Trap::UndefinedElement)?;
+        let args = self.pop_values(ty.params.len())?;
+        let imports = self.module.imports.len();
+        if (function as usize) < imports {
+            let host_function = self.host_functions[function as usize];
+            let result = self.host.call(host_function, self.memory, &args)?;
+            if result.map(|value| value.ty()) != ty.results.first().copied() {
+                return Err(Trap::TypeMismatch);
+            }
+            self.stack.extend(result);
+            return Ok(());
+        }
+
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(Trap::StackExhausted);
+        }
+        let index = function as usize - imports;
+        let mut locals = args;
+        locals.extend(
+            self.module.functions[index]
+                .locals
+                .iter()
+                .map(|ty| Value::default_of(*ty)),
+        );
+        self.frames.push(Frame {
+            function: index,
+            pc: 0,
+            locals,
+            label_base: self.labels.len(),
+            stack_base: self.stack.len(),
+            arity: ty.results.len(),
+        });
+        Ok(())
+    }
+
+    fn return_from_frame(&mut self) -> Result<(), Trap> {
+        let frame = self.frames.pop().expect("return without a frame");
+        let results = self.pop_values(frame.arity)?;
+        self.stack.truncate(frame.stack_base);
+        self.stack.extend(results);
+        self.labels.truncate(frame.label_base);
+        Ok(())
+    }
+
+    /// Branches to the label `depth` levels up, or returns for the function's own label.
+    fn branch(&mut self, depth: u32) -> Result<(), Trap> {
+        let frame = self.frames.last().unwrap();
+        let open = self.labels.len() - frame.label_base;
+        let depth = depth as usize;
+        if depth == open {
+            return self.return_from_frame();
+        }
+        if depth > open {
+            return Err(Trap::TypeMismatch);
+        }
+        let index = self.labels.len() - 1 - depth;
+        let label = &self.labels[index];
+        let (height, arity, target, is_loop) =
+            (label.height, label.arity, label.target, label.is_loop);
+        let values = self.pop_values(arity)?;
+        self.stack.truncate(height);
+        self.stack.extend(values);
+        self.labels
+            .truncate(if is_loop { index + 1 } else { index });
+        self.frames.last_mut().unwrap().pc = target;
+        Ok(())
+    }
+
+    fn push_label(&mut self, ty: BlockType, target: usize, is_loop: bool) -> Result<(), Trap> {
+        let (params, results) = self.block_arity(ty)?;
+        if self.stack.len() < params {
+            return Err(Trap::TypeMismatch);
+        }
+        self.labels.push(Label {
+            height: self.stack.len() - params,
+            arity: if is_loop { params } else { results },
+            target,
+            is_loop,
+        });
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), Trap> {
+        let module = self.module;
+        while let Some(frame) = self.frames.last_mut() {
+            let code = &module.functions[frame.function].body;
+            let pc = frame.pc;
+            let label_base = frame.label_base;
+            frame.pc += 1;
+            if self.stack.len() > MAX_STACK {
+                return Err(Trap::StackExhausted);
+            }
+            if *self.fuel == 0 {
+                return Err(Trap::OutOfFuel);
+            }
+            *self.fuel -= 1;
+            if self.fuel.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.host.interrupted() {
+                return Err(Trap::Interrupted);
+            }
+            match &code[pc] {
+                Instr::Unreachable => return Err(Trap::Unreachable),
+                Instr::Nop => {}
+                Instr::Block { ty, end } => self.push_label(*ty, end + 1, false)?,
+                Instr::Loop { ty } => self.push_label(*ty, pc + 1, true)?,
+                Instr::If { ty, else_, end } => {
+                    let condition = pop!(self, I32);
+                    self.push_label(*ty, end + 1, false)?;
+                    if condition == 0 {
+                        // Jump to the else branch, or to the `end` which pops the label
+                        self.frames.last_mut().unwrap().pc = else_.map_or(*end, |else_| else_ + 1);
+                    }
+                }
+                Instr::Else { end } => self.frames.last_mut().unwrap().pc = *end,
+                Instr::End => {
+                    if self.labels.len() > label_base {
+                        self.labels.pop();
+                    } else {
+                        self.return_from_frame()?;
+                    }
+                }
+                Instr::Br(depth) => self.branch(*depth)?,
+                Instr::BrIf(depth) => {
+                    if pop!(self, I32) != 0 {
+                        self.branch(*depth)?;
+                    }
+                }
+                Instr::BrTable(targets, default) => {
+                    let index = pop!(self, I32) as u32 as usize;
+                    self.branch(*targets.get(index).unwrap_or(default))?;
+                }
+                Instr::Return => self.return_from_frame()?,
+                Instr::Call(function) => self.call(*function)?,
+                Instr::CallIndirect(type_index) => {
+                    let index = pop!(self, I32) as u32 as usize;
+                    let function = self
+                        .table
+                        .get(index)
+                        .copied()
+                        .flatten()
+                        .ok_or(Trap::UndefinedElement)?;
+                    let expected = self.module.types.get(*type_index as usize);
+                    if expected.is_none() || self.module.function_type(function) != expected {
+                        return Err(Trap::IndirectCallTypeMismatch);
+                    }
+                    self.call(function)?;
+                }
+                Instr::Drop => {
+                    self.stack.pop().ok_or(Trap::TypeMismatch)?;
+                }
+                Instr::Select => {
+                    let condition = pop!(self, I32);
+                    let b = self.stack.pop().ok_or(Trap::TypeMismatch)?;
+                    let a = self.stack.pop().ok_or(Trap::TypeMismatch)?;
+                    self.stack.push(if condition != 0 { a } else { b });
+                }
+                Instr::LocalGet(index) => {
+                    let frame = self.frames.last().unwrap();
+                    let value = *frame
+                        .locals
+                        .get(*index as usize)
+                        .ok_or(Trap::TypeMismatch)?;
+                    self.stack.push(value);
+                }
+                Instr::LocalSet(index) | Instr::LocalTee(index) => {
+                    let value = *self.stack.last().ok_or(Trap::TypeMismatch)?;
+                    if matches!(code[pc], Instr::LocalSet(_)) {
+                        self.stack.pop();
+                    }
+                    let frame = self.frames.last_mut().unwrap();
+                    *frame
+                        .locals
+                        .get_mut(*index as usize)
+                        .ok_or(Trap::TypeMismatch)? = value;
+                }
+                Instr::GlobalGet(index) => {
+                    let value = *self
+                        .globals
+                        .get(*index as usize)
+                        .ok_or(Trap::TypeMismatch)?;
+                    self.stack.push(value);
+                }
+                Instr::GlobalSet(index) => {
+                    let value = self.stack.pop().ok_or(Trap::TypeMismatch)?;
+                    *self
+                        .globals
+                        .get_mut(*index as usize)
+                        .ok_or(Trap::TypeMismatch)? = value;
+                }
+                Instr::Load(opcode, offset) => self.load(*opcode, *offset)?,
+                Instr::Store(opcode, offset) => self.store(*opcode, *offset)?,
+                Instr::MemorySize => self.stack.push(Value::I32(self.memory.size() as i32)),
+                Instr::MemoryGrow => {
+                    let delta = pop!(self, I32) as u32;
+                    let previous = self.memory.grow(delta).map_or(-1, |size| size as i32);
+                    self.stack.push(Value::I32(previous));
+                }
+                Instr::MemoryCopy => {
+                    let len = pop!(self, I32) as u32;
+                    let source = pop!(self, I32) as u32;
+                    let destination = pop!(self, I32) as u32;
+                    self.memory.read(source, len)?;
+                    self.memory.read(destination, len)?;
+                    let source = source as usize;
+                    self.memory
+                        .bytes
+                        .copy_within(source..source + len as usize, destination as usize);
+                }
+                Instr::MemoryFill => {
+                    let len = pop!(self, I32) as u32;
+                    let value = pop!(self, I32) as u8;
+                    let destination = pop!(self, I32) as u32;
+                    self.memory.read(destination, len)?;
+                    let destination = destination as usize;
+                    self.memory.bytes[destination..destination + len as usize].fill(value);
+                }
+                Instr::I32Const(value) => self.stack.push(Value::I32(*value)),
+                Instr::I64Const(value) => self.stack.push(Value::I64(*value)),
+                Instr::F32Const(value) => self.stack.push(Value::F32(*value)),
+                Instr::F64Const(value) => self.stack.push(Value::F64(*value)),
+                Instr::Numeric(opcode) => self.numeric(*opcode)?,
+                Instr::TruncSat(sub) => self.trunc_sat(*sub)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, opcode: u8, offset: u32) -> Result<(), Trap> {
+        let address = pop!(self, I32);
+        let memory = &*self.memory;
+        let value = match opcode {
+            0x28 => Value::I32(i32::from_le_bytes(memory.load(address, offset)?)),
+            0x29 => Value::I64(i64::from_le_bytes(memory.load(address, offset)?)),
+            0x2a => Value::F32(f32::from_le_bytes(memory.load(address, offset)?)),
+            0x2b => Value::F64(f64::from_le_bytes(memory.load(address, offset)?)),
+            0x2c => Value::I32(i8::from_le_bytes(memory.load(address, offset)?) as i32),
+            0x2d => Value::I32(u8::from_le_bytes(memory.load(address, offset)?) as i32),
+            0x2e => Value::I32(i16::from_le_bytes(memory.load(address, offset)?) as i32),
+            0x2f => Value::I32(u16::from_le_bytes(memory.load(address, offset)?) as i32),
+            0x30 => Value::I64(i8::from_le_bytes(memory.load(address, offset)?) as i64),
+            0x31 => Value::I64(u8::from_le_bytes(memory.load(address, offset)?) as i64),
+            0x32 => Value::I64(i16::from_le_bytes(memory.load(address, offset)?) as i64),
+            0x33 => Value::I64(u16::from_le_bytes(memory.load(address, offset)?) as i64),
+            0x34 => Value::I64(i32::from_le_bytes(memory.load(address, offset)?) as i64),
+            0x35 => Value::I64(u32::from_le_bytes(memory.load(address, offset)?) as i64),
+            _ => unreachable!("not a load opcode"),
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn store(&mut self, opcode: u8, offset: u32) -> Result<(), Trap> {
+        let value = self.stack.pop().ok_or(Trap::TypeMismatch)?;
+        let address = pop!(self, I32);
+        let memory = &mut *self.memory;
+        match (opcode, value) {
+            (0x36, Value::I32(value)) => memory.store(address, offset, &value.to_le_bytes()),
+            (0x37, Value::I64(value)) => memory.store(address, offset, &value.to_le_bytes()),
+            (0x38, Value::F32(value)) => memory.store(address, offset, &value.to_le_bytes()),
+            (0x39, Value::F64(value)) => memory.store(address, offset, &value.to_le_bytes()),
+            (0x3a, Value::I32(value)) => {
+                memory.store(address, offset, &(value as u8).to_le_bytes())
+            }
+            (0x3b, Value::I32(value)) => {
+                memory.store(address, offset, &(value as u16).to_le_bytes())
+            }
+            (0x3c, Value::I64(value)) => {
+                memory.store(address, offset, &(value as u8).to_le_bytes())
+            }
+            (0x3d, Value::I64(value)) => {
+                memory.store(address, offset, &(value as u16).to_le_bytes())
+            }
+            (0x3e, Value::I64(value)) => {
+                memory.store(address, offset, &(value as u32).to_le_bytes())
+            }
+            _ => Err(Trap::TypeMismatch),
+        }
+    }
+
+    fn numeric(&mut self, opcode: u8) -> Result<(), Trap> {
+        match opcode {
+            0x45 => unary!(self, I32 => I32, |a: i32| (a == 0) as i32),
+            0x46 => binary!(self, I32 => I32, |a, b| (a == b) as i32),
+            0x47 => binary!(self, I32 => I32, |a, b| (a != b) as i32),
+            0x48 => binary!(self, I32 => I32, |a, b| (a < b) as i32),
+            0x49 => binary!(self, I32 => I32, |a, b| ((a as u32) < (b as u32)) as i32),
+            0x4a => binary!(self, I32 => I32, |a, b| (a > b) as i32),
+            0x4b => binary!(self, I32 => I32, |a, b| ((a as u32) > (b as u32)) as i32),
+            0x4c => binary!(self, I32 => I32, |a, b| (a <= b) as i32),
+            0x4d => binary!(self, I32 => I32, |a, b| ((a as u32) <= (b as u32)) as i32),
+            0x4e => binary!(self, I32 => I32, |a, b| (a >= b) as i32),
+            0x4f => binary!(self, I32 => I32, |a, b| ((a as u32) >= (b as u32)) as i32),
+
+            0x50 => unary!(self, I64 => I32, |a: i64| (a == 0) as i32),
+            0x51 => binary!(self, I64 => I32, |a, b| (a == b) as i32),
+            0x52 => binary!(self, I64 => I32, |a, b| (a != b) as i32),
+            0x53 => binary!(self, I64 => I32, |a, b| (a < b) as i32),
+            0x54 => binary!(self, I64 => I32, |a, b| ((a as u64) < (b as u64)) as i32),
+            0x55 => binary!(self, I64 => I32, |a, b| (a > b) as i32),
+            0x56 => binary!(self, I64 => I32, |a, b| ((a as u64) > (b as u64)) as i32),
+            0x57 => binary!(self, I64 => I32, |a, b| (a <= b) as i32),
+            0x58 => binary!(self, I64 => I32, |a, b| ((a as u64) <= (b as u64)) as i32),
+            0x59 => binary!(self, I64 => I32, |a, b| (a >= b) as i32),
+            0x5a => binary!(self, I64 => I32, |a, b| ((a as u64) >= (b as u64)) as i32),
+
+            0x5b => binary!(self, F32 => I32, |a, b| (a == b) as i32),
+            0x5c => binary!(self, F32 => I32, |a, b| (a != b) as i32),
+            0x5d => binary!(self, F32 => I32, |a, b| (a < b) as i32),
+            0x5e => binary!(self, F32 => I32, |a, b| (a > b) as i32),
+            0x5f => binary!(self, F32 => I32, |a, b| (a <= b) as i32),
+            0x60 => binary!(self, F32 => I32, |a, b| (a >= b) as i32),
+
+            0x61 => binary!(self, F64 => I32, |a, b| (a == b) as i32),
+            0x62 => binary!(self, F64 => I32, |a, b| (a != b) as i32),
+            0x63 => binary!(self, F64 => I32, |a, b| (a < b) as i32),
+            0x64 => binary!(self, F64 => I32, |a, b| (a > b) as i32),
+            0x65 => binary!(self, F64 => I32, |a, b| (a <= b) as i32),
+            0x66 => binary!(self, F64 => I32, |a, b| (a >= b) as i32),
+
+            0x67 => unary!(self, I32 => I32, |a: i32| a.leading_zeros() as i32),
+            0x68 => unary!(self, I32 => I32, |a: i32| a.trailing_zeros() as i32),
+            0x69 => unary!(self, I32 => I32, |a: i32| a.count_ones() as i32),
+            0x6a => binary!(self, I32 => I32, |a: i32, b| a.wrapping_add(b)),
+            0x6b => binary!(self, I32 => I32, |a: i32, b| a.wrapping_sub(b)),
+            0x6c => binary!(self, I32 => I32, |a: i32, b| a.wrapping_mul(b)),
+            0x6d => binary_checked!(self, I32 => I32, |a: i32, b: i32| match b {
+                0 => Err(Trap::DivisionByZero),
+                -1 if a == i32::MIN => Err(Trap::IntegerOverflow),
+                _ => Ok(a / b),
+            }),
+            0x6e => binary_checked!(self, I32 => I32, |a: i32, b: i32| {
+                (a as u32)
+                    .checked_div(b as u32)
+                    .map(|r| r as i32)
+                    .ok_or(Trap::DivisionByZero)
+            }),
+            0x6f => binary_checked!(self, I32 => I32, |a: i32, b: i32| match b {
+                0 => Err(Trap::DivisionByZero),
+                _ => Ok(a.wrapping_rem(b)),
+            }),
+            0x70 => binary_checked!(self, I32 => I32, |a: i32, b: i32| {
+                (a as u32)
+                    .checked_rem(b as u32)
+                    .map(|r| r as i32)
+                    .ok_or(Trap::DivisionByZero)
+            }),
+            0x71 => binary!(self, I32 => I32, |a, b| a & b),
+            0x72 => binary!(self, I32 => I32, |a, b| a | b),
+            0x73 => binary!(self, I32 => I32, |a, b| a ^ b),
+            0x74 => binary!(self, I32 => I32, |a: i32, b| a.wrapping_shl(b as u32)),
+            0x75 => binary!(self, I32 => I32, |a: i32, b| a.wrapping_shr(b as u32)),
+            0x76 => binary!(self, I32 => I32, |a: i32, b| {
+                (a as u32).wrapping_shr(b as u32) as i32
+            }),
+            0x77 => binary!(self, I32 => I32, |a: i32, b| a.rotate_left(b as u32)),
+            0x78 => binary!(self, I32 => I32, |a: i32, b| a.rotate_right(b as u32)),
+
+            0x79 => unary!(self, I64 => I64, |a: i64| a.leading_zeros() as i64),
+            0x7a => unary!(self, I64 => I64, |a: i64| a.trailing_zeros() as i64),
+            0x7b => unary!(self, I64 => I64, |a: i64| a.count_ones() as i64),
+            0x7c => binary!(self, I64 => I64, |a: i64, b| a.wrapping_add(b)),
+            0x7d => binary!(self, I64 => I64, |a: i64, b| a.wrapping_sub(b)),
+            0x7e => binary!(self, I64 => I64, |a: i64, b| a.wrapping_mul(b)),
+            0x7f => binary_checked!(self, I64 => I64, |a: i64, b: i64| match b {
+                0 => Err(Trap::DivisionByZero),
+                -1 if a == i64::MIN => Err(Trap::IntegerOverflow),
+                _ => Ok(a / b),
+            }),
+            0x80 => binary_checked!(self, I64 => I64, |a: i64, b: i64| {
+                (a as u64)
+                    .checked_div(b as u64)
+                    .map(|r| r as i64)
+                    .ok_or(Trap::DivisionByZero)
+            }),
+            0x81 => binary_checked!(self, I64 => I64, |a: i64, b: i64| match b {
+                0 => Err(Trap::DivisionByZero),
+                _ => Ok(a.wrapping_rem(b)),
+            }),
+            0x82 => binary_checked!(self, I64 => I64, |a: i64, b: i64| {
+                (a as u64)
+                    .checked_rem(b as u64)
+                    .map(|r| r as i64)
+                    .ok_or(Trap::DivisionByZero)
+            }),
+            0x83 => binary!(self, I64 => I64, |a, b| a & b),
+            0x84 => binary!(self, I64 => I64, |a, b| a | b),
+            0x85 => binary!(self, I64 => I64, |a, b| a ^ b),
+            0x86 => binary!(self, I64 => I64, |a: i64, b| a.wrapping_shl(b as u32)),
+            0x87 => binary!(self, I64 => I64, |a: i64, b| a.wrapping_shr(b as u32)),
+            0x88 => binary!(self, I64 => I64, |a: i64, b| {
+                (a as u64).wrapping_shr(b as u32) as i64
+            }),
+            0x89 => binary!(self, I64 => I64, |a: i64, b| a.rotate_left(b as u32)),
+            0x8a => binary!(self, I64 => I64, |a: i64, b| a.rotate_right(b as u32)),
+
+            0x8b => unary!(self, F32 => F32, |a: f32| a.abs()),
+            0x8c => unary!(self, F32 => F32, |a: f32| -a),
+            0x8d => unary!(self, F32 => F32, f32_math::ceil),
+            0x8e => unary!(self, F32 => F32, f32_math::floor),
+            0x8f => unary!(self, F32 => F32, f32_math::trunc),
+            0x90 => unary!(self, F32 => F32, f32_math::round_ties_even),
+            0x91 => unary!(self, F32 => F32, f32_math::sqrt),
+            0x92 => binary!(self, F32 => F32, |a, b| a + b),
+            0x93 => binary!(self, F32 => F32, |a, b| a - b),
+            0x94 => binary!(self, F32 => F32, |a, b| a * b),
+            0x95 => binary!(self, F32 => F32, |a, b| a / b),
+            0x96 => binary!(self, F32 => F32, |a: f32, b: f32| wasm_min(a as f64, b as f64) as f32),
+            0x97 => binary!(self, F32 => F32, |a: f32, b: f32| wasm_max(a as f64, b as f64) as f32),
+            0x98 => binary!(self, F32 => F32, |a: f32, b| a.copysign(b)),
+
+            0x99 => unary!(self, F64 => F64, |a: f64| a.abs()),
+            0x9a => unary!(self, F64 => F64, |a: f64| -a),
+            0x9b => unary!(self, F64 => F64, f64_math::ceil),
+            0x9c => unary!(self, F64 => F64, f64_math::floor),
+            0x9d => unary!(self, F64 => F64, f64_math::trunc),
+            0x9e => unary!(self, F64 => F64, f64_math::round_ties_even),
+            0x9f => unary!(self, F64 => F64, f64_math::sqrt),
+            0xa0 => binary!(self, F64 => F64, |a, b| a + b),
+            0xa1 => binary!(self, F64 => F64, |a, b| a - b),
+            0xa2 => binary!(self, F64 => F64, |a, b| a * b),
+            0xa3 => binary!(self, F64 => F64, |a, b| a / b),
+            0xa4 => binary!(self, F64 => F64, wasm_min),
+            0xa5 => binary!(self, F64 => F64, wasm_max),
+            0xa6 => binary!(self, F64 => F64, |a: f64, b| a.copysign(b)),
+
+            0xa7 => unary!(self, I64 => I32, |a: i64| a as i32),
+            0xa8 => self.trunc(I32_S, |a| Value::I32(a as i32))?,
+            0xa9 => self.trunc(I32_U, |a| Value::I32(a as u32 as i32))?,
+            0xaa => self.trunc(I32_S, |a| Value::I32(a as i32))?,
+            0xab => self.trunc(I32_U, |a| Value::I32(a as u32 as i32))?,
+            0xac => unary!(self, I32 => I64, |a: i32| a as i64),
+            0xad => unary!(self, I32 => I64, |a: i32| a as u32 as i64),
+            0xae => self.trunc(I64_S, |a| Value::I64(a as i64))?,
+            0xaf => self.trunc(I64_U, |a| Value::I64(a as u64 as i64))?,
+            0xb0 => self.trunc(I64_S, |a| Value::I64(a as i64))?,
+            0xb1 => self.trunc(I64_U, |a| Value::I64(a as u64 as i64))?,
+            0xb2 => unary!(self, I32 => F32, |a: i32| a as f32),
+            0xb3 => unary!(self, I32 => F32, |a: i32| a as u32 as f32),
+            0xb4 => unary!(self, I64 => F32, |a: i64| a as f32),
+            0xb5 => unary!(self, I64 => F32, |a: i64| a as u64 as f32),
+            0xb6 => unary!(self, F64 => F32, |a: f64| a as f32),
+            0xb7 => unary!(self, I32 => F64, |a: i32| a as f64),
+            0xb8 => unary!(self, I32 => F64, |a: i32| a as u32 as f64),
+            0xb9 => unary!(self, I64 => F64, |a: i64| a as f64),
+            0xba => unary!(self, I64 => F64, |a: i64| a as u64 as f64),
+            0xbb => unary!(self, F32 => F64, |a: f32| a as f64),
+            0xbc => unary!(self, F32 => I32, |a: f32| a.to_bits() as i32),
+            0xbd => unary!(self, F64 => I64, |a: f64| a.to_bits() as i64),
+            0xbe => unary!(self, I32 => F32, |a: i32| f32::from_bits(a as u32)),
+            0xbf => unary!(self, I64 => F64, |a: i64| f64::from_bits(a as u64)),
+
+            0xc0 => unary!(self, I32 => I32, |a: i32| a as i8 as i32),
+            0xc1 => unary!(self, I32 => I32, |a: i32| a as i16 as i32),
+            0xc2 => unary!(self, I64 => I64, |a: i64| a as i8 as i64),
+            0xc3 => unary!(self, I64 => I64, |a: i64| a as i16 as i64),
+            0xc4 => unary!(self, I64 => I64, |a: i64| a as i32 as i64),
+            _ => unreachable!("not a numeric opcode"),
+        }
+        Ok(())
+    }
+
+    /// Pops a float of either width, widened to `f64`, which represents every `f32` exactly.
+    fn pop_float(&mut self) -> Result<f64, Trap> {
+        match self.stack.pop() {
+            Some(Value::F32(value)) => Ok(value as f64),
+            Some(Value::F64(value)) => Ok(value),
+            _ => Err(Trap::TypeMismatch),
+        }
+    }
+
+    /// Trapping float to integer truncation, `range` holding the exclusive bounds.
+    fn trunc(&mut self, range: (f64, f64), convert: impl Fn(f64) -> Value) -> Result<(), Trap> {
+        let value = self.pop_float()?;
+        if value.is_nan() {
+            return Err(Trap::InvalidConversion);
+        }
+        if !(value > range.0 && value < range.1) {
+            return Err(Trap::IntegerOverflow);
+        }
+        self.stack.push(convert(value));
+        Ok(())
+    }
+
+    fn trunc_sat(&mut self, sub: u8) -> Result<(), Trap> {
+        // `as` casts from floats already saturate and map NaN to zero
+        let value = self.pop_float()?;
+        self.stack.push(match sub {
+            0 | 2 => Value::I32(value as i32),
+            1 | 3 => Value::I32(value as u32 as i32),
+            4 | 6 => Value::I64(value as i64),
+            5 | 7 => Value::I64(value as u64 as i64),
+            _ => unreachable!("not a saturating truncation"),
+        });
+        Ok(())
+    }
+}
+
+const I32_S: (f64, f64) = (-2147483649.0, 2147483648.0);
+const I32_U: (f64, f64) = (-1.0, 4294967296.0);
+const I64_S: (f64, f64) = (-9223372036854777856.0, 9223372036854775808.0);
+const I64_U: (f64, f64) = (-1.0, 18446744073709551616.0);
+
+fn wasm_min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        // Only differ for zeros, where -0 is the smaller
+        if a.is_sign_negative() { a } else { b }
+    } else {
+        a.min(b)
+    }
+}
+
+fn wasm_max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_positive() { a } else { b }
+    } else {
+        a.max(b)
+    }
+}
@@ -0,0 +1,77 @@
+//! A small WebAssembly interpreter, used to run guest modules inside the kernel.
+//!
+//! Supports the MVP instruction set plus sign extension, saturating truncation and the bulk
+//! memory `copy`/`fill` instructions. Imports are limited to functions, provided by a [`Host`].
+
+pub mod interpreter;
+pub mod parser;
+
+use alloc::{string::String, vec::Vec};
+
+pub use interpreter::{Host, Instance, InstantiateError, Memory, PAGE_SIZE};
+pub use parser::{Module, ParseError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    pub fn default_of(ty: ValType) -> Value {
+        match ty {
+            ValType::I32 => Value::I32(0),
+            ValType::I64 => Value::I64(0),
+            ValType::F32 => Value::F32(0.0),
+            ValType::F64 => Value::F64(0.0),
+        }
+    }
+
+    pub fn ty(&self) -> ValType {
+        match self {
+            Value::I32(_) => ValType::I32,
+            Value::I64(_) => ValType::I64,
+            Value::F32(_) => ValType::F32,
+            Value::F64(_) => ValType::F64,
+        }
+    }
+}
+
+/// Error aborting the execution of a guest function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    Unreachable,
+    MemoryOutOfBounds,
+    DivisionByZero,
+    IntegerOverflow,
+    InvalidConversion,
+    /// Too many nested calls, or too many values on the stack.
+    StackExhausted,
+    /// The instance ran every instruction its fuel allowed.
+    OutOfFuel,
+    /// The host asked to stop the guest, see [`Host::interrupted`].
+    Interrupted,
+    /// An operand had the wrong type, the module was not valid.
+    TypeMismatch,
+    UndefinedElement,
+    IndirectCallTypeMismatch,
+    NoSuchFunction(String),
+    /// Error reported by a host function.
+    Host(String),
+}
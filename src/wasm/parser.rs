@@ -0,0 +1,602 @@
+//! Decoding of the WebAssembly binary format.
+//!
+//! Function bodies are decoded once into [`Instr`]s with their branch targets resolved, so
+//! the interpreter never has to scan for the end of a block.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use super::{FuncType, ValType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    UnexpectedEof,
+    MalformedLeb,
+    InvalidUtf8,
+    InvalidValType(u8),
+    InvalidOpcode(u8),
+    InvalidSection(u8),
+    /// A valid construct that this runtime does not implement.
+    Unsupported(&'static str),
+    /// The function body's blocks are not properly nested.
+    UnbalancedBlocks,
+    FunctionCountMismatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockType {
+    Empty,
+    Value(ValType),
+    /// Index of a function type giving the block's parameters and results.
+    Type(u32),
+}
+
+/// A decoded instruction. Numeric instructions without immediates keep their opcode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Unreachable,
+    Nop,
+    Block {
+        ty: BlockType,
+        end: usize,
+    },
+    Loop {
+        ty: BlockType,
+    },
+    If {
+        ty: BlockType,
+        else_: Option<usize>,
+        end: usize,
+    },
+    Else {
+        end: usize,
+    },
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Box<[u32]>, u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    /// Memory access, the opcode picks the width and type.
+    Load(u8, u32),
+    Store(u8, u32),
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    I32Const(i32),
+    I64Const(i64),
+    F32Const(f32),
+    F64Const(f64),
+    Numeric(u8),
+    /// `0xfc` prefixed saturating truncation, by sub-opcode.
+    TruncSat(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub type_index: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+/// Constant expression used to initialize globals and place segments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstExpr {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    GlobalGet(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub ty: ValType,
+    pub mutable: bool,
+    pub init: ConstExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub type_index: u32,
+    pub locals: Vec<ValType>,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElementSegment {
+    pub offset: ConstExpr,
+    pub functions: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataSegment {
+    /// `None` for passive segments, which are never copied into memory.
+    pub offset: Option<ConstExpr>,
+    pub bytes: Vec<u8>,
+}
+
+/// A decoded, not yet instantiated, WebAssembly module.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    /// Functions defined by the module, indexed after the imports.
+    pub functions: Vec<Function>,
+    pub table: Option<Limits>,
+    pub memory: Option<Limits>,
+    pub globals: Vec<Global>,
+    pub exports: Vec<Export>,
+    pub start: Option<u32>,
+    pub elements: Vec<ElementSegment>,
+    pub data: Vec<DataSegment>,
+}
+
+impl Module {
+    pub fn parse(bytes: &[u8]) -> Result<Module, ParseError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != b"\0asm" {
+            return Err(ParseError::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        if version != 1 {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+
+        let mut module = Module::default();
+        let mut function_types = Vec::new();
+        while !reader.bytes.is_empty() {
+            let id = reader.u8()?;
+            let len = reader.u32()? as usize;
+            let mut section = Reader {
+                bytes: reader.take(len)?,
+            };
+            match id {
+                0 => {} // custom sections carry nothing we need
+                1 => {
+                    module.types = section.vec(|r| {
+                        if r.u8()? != 0x60 {
+                            return Err(ParseError::Unsupported("non-function type"));
+                        }
+                        Ok(FuncType {
+                            params: r.vec(Reader::val_type)?,
+                            results: r.vec(Reader::val_type)?,
+                        })
+                    })?
+                }
+                2 => {
+                    module.imports = section.vec(|r| {
+                        let module = r.name()?;
+                        let name = r.name()?;
+                        match r.u8()? {
+                            0x00 => Ok(Import {
+                                module,
+                                name,
+                                type_index: r.u32()?,
+                            }),
+                            _ => Err(ParseError::Unsupported("non-function import")),
+                        }
+                    })?
+                }
+                3 => function_types = section.vec(Reader::u32)?,
+                4 => {
+                    let tables = section.vec(|r| {
+                        if r.u8()? != 0x70 {
+                            return Err(ParseError::Unsupported("non-funcref table"));
+                        }
+                        r.limits()
+                    })?;
+                    if tables.len() > 1 {
+                        return Err(ParseError::Unsupported("multiple tables"));
+                    }
+                    module.table = tables.first().copied();
+                }
+                5 => {
+                    let memories = section.vec(Reader::limits)?;
+                    if memories.len() > 1 {
+                        return Err(ParseError::Unsupported("multiple memories"));
+                    }
+                    module.memory = memories.first().copied();
+                }
+                6 => {
+                    module.globals = section.vec(|r| {
+                        let ty = r.val_type()?;
+                        let mutable = r.u8()? == 1;
+                        Ok(Global {
+                            ty,
+                            mutable,
+                            init: r.const_expr()?,
+                        })
+                    })?
+                }
+                7 => {
+                    module.exports = section.vec(|r| {
+                        let name = r.name()?;
+                        let kind = match r.u8()? {
+                            0 => ExportKind::Func,
+                            1 => ExportKind::Table,
+                            2 => ExportKind::Memory,
+                            3 => ExportKind::Global,
+                            kind => return Err(ParseError::InvalidSection(kind)),
+                        };
+                        Ok(Export {
+                            name,
+                            kind,
+                            index: r.u32()?,
+                        })
+                    })?
+                }
+                8 => module.start = Some(section.u32()?),
+                9 => {
+                    module.elements = section.vec(|r| match r.u32()? {
+                        0 => Ok(ElementSegment {
+                            offset: r.const_expr()?,
+                            functions: r.vec(Reader::u32)?,
+                        }),
+                        _ => Err(ParseError::Unsupported("non-active element segment")),
+                    })?
+                }
+                10 => {
+                    let bodies = section.vec(|r| {
+                        let len = r.u32()? as usize;
+                        let mut body = Reader {
+                            bytes: r.take(len)?,
+                        };
+                        let mut locals = Vec::new();
+                        for _ in 0..body.u32()? {
+                            let count = body.u32()?;
+                            let ty = body.val_type()?;
+                            if locals.len() + count as usize > 50_000 {
+                                return Err(ParseError::Unsupported("too many locals"));
+                            }
+                            locals.extend((0..count).map(|_| ty));
+                        }
+                        Ok((locals, body.code()?))
+                    })?;
+                    if bodies.len() != function_types.len() {
+                        return Err(ParseError::FunctionCountMismatch);
+                    }
+                    module.functions = function_types
+                        .iter()
+                        .zip(bodies)
+                        .map(|(type_index, (locals, body))| Function {
+                            type_index: *type_index,
+                            locals,
+                            body,
+                        })
+                        .collect();
+                }
+                11 => {
+                    module.data = section.vec(|r| {
+                        let offset = match r.u32()? {
+                            0 => Some(r.const_expr()?),
+                            1 => None,
+                            _ => return Err(ParseError::Unsupported("multiple memories")),
+                        };
+                        let len = r.u32()? as usize;
+                        Ok(DataSegment {
+                            offset,
+                            bytes: r.take(len)?.to_vec(),
+                        })
+                    })?
+                }
+                12 => {} // data count, only needed by validators
+                id => return Err(ParseError::InvalidSection(id)),
+            }
+        }
+        if module.functions.len() != function_types.len() {
+            return Err(ParseError::FunctionCountMismatch);
+        }
+        Ok(module)
+    }
+
+    /// Type of the function at `index`, counting imports first.
+    pub fn function_type(&self, index: u32) -> Option<&FuncType> {
+        let index = index as usize;
+        let type_index = match self.imports.get(index) {
+            Some(import) => import.type_index,
+            None => self.functions.get(index - self.imports.len())?.type_index,
+        };
+        self.types.get(type_index as usize)
+    }
+
+    pub fn export(&self, name: &str, kind: ExportKind) -> Option<u32> {
+        self.exports
+            .iter()
+            .find(|export| export.name == name && export.kind == kind)
+            .map(|export| export.index)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.bytes.len() < len {
+            return Err(ParseError::UnexpectedEof);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uleb(&mut self, bits: u32) -> Result<u64, ParseError> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= bits {
+                return Err(ParseError::MalformedLeb);
+            }
+            result |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+    }
+
+    fn sleb(&mut self, bits: u32) -> Result<i64, ParseError> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= bits {
+                return Err(ParseError::MalformedLeb);
+            }
+            result |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        Ok(self.uleb(32)? as u32)
+    }
+
+    fn vec<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let len = self.u32()? as usize;
+        // Every item takes at least one byte, don't trust larger counts
+        let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ParseError::InvalidUtf8)
+    }
+
+    fn val_type(&mut self) -> Result<ValType, ParseError> {
+        match self.u8()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            0x7d => Ok(ValType::F32),
+            0x7c => Ok(ValType::F64),
+            other => Err(ParseError::InvalidValType(other)),
+        }
+    }
+
+    fn limits(&mut self) -> Result<Limits, ParseError> {
+        match self.u8()? {
+            0 => Ok(Limits {
+                min: self.u32()?,
+                max: None,
+            }),
+            1 => Ok(Limits {
+                min: self.u32()?,
+                max: Some(self.u32()?),
+            }),
+            _ => Err(ParseError::Unsupported("shared or 64-bit limits")),
+        }
+    }
+
+    fn const_expr(&mut self) -> Result<ConstExpr, ParseError> {
+        let expr = match self.u8()? {
+            0x41 => ConstExpr::I32(self.sleb(32)? as i32),
+            0x42 => ConstExpr::I64(self.sleb(64)?),
+            0x43 => ConstExpr::F32(f32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            0x44 => ConstExpr::F64(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            0x23 => ConstExpr::GlobalGet(self.u32()?),
+            _ => return Err(ParseError::Unsupported("constant expression")),
+        };
+        if self.u8()? != 0x0b {
+            return Err(ParseError::Unsupported("constant expression"));
+        }
+        Ok(expr)
+    }
+
+    fn block_type(&mut self) -> Result<BlockType, ParseError> {
+        match self.bytes.first() {
+            Some(0x40) => {
+                self.u8()?;
+                Ok(BlockType::Empty)
+            }
+            Some(0x7c..=0x7f) => Ok(BlockType::Value(self.val_type()?)),
+            _ => {
+                let index = self.sleb(33)?;
+                u32::try_from(index)
+                    .map(BlockType::Type)
+                    .map_err(|_| ParseError::MalformedLeb)
+            }
+        }
+    }
+
+    fn mem_arg(&mut self) -> Result<u32, ParseError> {
+        let _align = self.u32()?;
+        self.u32()
+    }
+
+    /// Decodes a function body, patching block ends as they are found.
+    fn code(&mut self) -> Result<Vec<Instr>, ParseError> {
+        let mut code = Vec::new();
+        // Positions of the enclosing `block`, `loop` and `if` instructions
+        let mut open_blocks: Vec<usize> = Vec::new();
+        loop {
+            let opcode = self.u8()?;
+            let instr = match opcode {
+                0x00 => Instr::Unreachable,
+                0x01 => Instr::Nop,
+                0x02 => Instr::Block {
+                    ty: self.block_type()?,
+                    end: 0,
+                },
+                0x03 => Instr::Loop {
+                    ty: self.block_type()?,
+                },
+                0x04 => Instr::If {
+                    ty: self.block_type()?,
+                    else_: None,
+                    end: 0,
+                },
+                0x05 => {
+                    let open = *open_blocks.last().ok_or(ParseError::UnbalancedBlocks)?;
+                    let position = code.len();
+                    match &mut code[open] {
+                        Instr::If { else_, .. } if else_.is_none() => *else_ = Some(position),
+                        _ => return Err(ParseError::UnbalancedBlocks),
+                    }
+                    Instr::Else { end: 0 }
+                }
+                0x0b => {
+                    let end = code.len();
+                    match open_blocks.pop() {
+                        Some(open) => {
+                            match &mut code[open] {
+                                Instr::Block { end: block_end, .. } => *block_end = end,
+                                Instr::If {
+                                    end: if_end, else_, ..
+                                } => {
+                                    *if_end = end;
+                                    if let Some(else_) = *else_ {
+                                        code[else_] = Instr::Else { end };
+                                    }
+                                }
+                                _ => {}
+                            }
+                            Instr::End
+                        }
+                        None => {
+                            // End of the function body
+                            code.push(Instr::End);
+                            if !self.bytes.is_empty() {
+                                return Err(ParseError::UnbalancedBlocks);
+                            }
+                            return Ok(code);
+                        }
+                    }
+                }
+                0x0c => Instr::Br(self.u32()?),
+                0x0d => Instr::BrIf(self.u32()?),
+                0x0e => {
+                    let targets = self.vec(Reader::u32)?;
+                    Instr::BrTable(targets.into_boxed_slice(), self.u32()?)
+                }
+                0x0f => Instr::Return,
+                0x10 => Instr::Call(self.u32()?),
+                0x11 => {
+                    let type_index = self.u32()?;
+                    if self.u8()? != 0 {
+                        return Err(ParseError::Unsupported("multiple tables"));
+                    }
+                    Instr::CallIndirect(type_index)
+                }
+                0x1a => Instr::Drop,
+                0x1b => Instr::Select,
+                0x1c => {
+                    // Typed select, the type is only needed for validation
+                    self.vec(Reader::val_type)?;
+                    Instr::Select
+                }
+                0x20 => Instr::LocalGet(self.u32()?),
+                0x21 => Instr::LocalSet(self.u32()?),
+                0x22 => Instr::LocalTee(self.u32()?),
+                0x23 => Instr::GlobalGet(self.u32()?),
+                0x24 => Instr::GlobalSet(self.u32()?),
+                0x28..=0x35 => Instr::Load(opcode, self.mem_arg()?),
+                0x36..=0x3e => Instr::Store(opcode, self.mem_arg()?),
+                0x3f => {
+                    self.u8()?;
+                    Instr::MemorySize
+                }
+                0x40 => {
+                    self.u8()?;
+                    Instr::MemoryGrow
+                }
+                0x41 => Instr::I32Const(self.sleb(32)? as i32),
+                0x42 => Instr::I64Const(self.sleb(64)?),
+                0x43 => Instr::F32Const(f32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+                0x44 => Instr::F64Const(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                0x45..=0xc4 => Instr::Numeric(opcode),
+                0xfc => match self.u32()? {
+                    sub @ 0..=7 => Instr::TruncSat(sub as u8),
+                    10 => {
+                        self.take(2)?;
+                        Instr::MemoryCopy
+                    }
+                    11 => {
+                        self.u8()?;
+                        Instr::MemoryFill
+                    }
+                    _ => return Err(ParseError::Unsupported("0xfc instruction")),
+                },
+                other => return Err(ParseError::InvalidOpcode(other)),
+            };
+            if matches!(
+                instr,
+                Instr::Block { .. } | Instr::Loop { .. } | Instr::If { .. }
+            ) {
+                open_blocks.push(code.len());
+            }
+            code.push(instr);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Aborts code running past its deadline, see [`run`].
+//!
+//! The timer interrupt checks the deadline. Once it has passed, the interrupt returns into
+//! [`run`] instead of the interrupted code, discarding its stack. Destructors of the aborted
+//! code don't run: locks it held would stay locked, so code updating shared state must do so
+//! in a [`critical`] section. What it allocated outside of critical sections is tracked, and
+//! freed by [`reclaim`] once its caller dropped what it kept of the aborted work.
+
+use core::arch::naked_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::allocator::account;
+use crate::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Whether code runs under the watchdog, set and cleared around the call by `enter`.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Tick from which the running code is aborted.
+static DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Stack pointer in `enter` to return to when aborting.
+static RESUME_STACK: AtomicU64 = AtomicU64::new(0);
+static CRITICAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `f`, aborting it once `ticks` timer interrupts have passed.
+///
+/// Fails if `f` was aborted, by the timer or by [`abort`], or returned after its deadline.
+/// Nested calls run under the deadline of the outermost one. Once `f` was aborted, call
+/// [`reclaim`] after dropping what refers to its work, or what it allocated leaks.
+pub fn run<R>(ticks: u64, f: impl FnOnce() -> R) -> Result<R, TimedOut> {
+    if ACTIVE.load(Ordering::SeqCst) {
+        return Ok(f());
+    }
+    let mut f = Some(f);
+    let mut result = None;
+    let mut call = || result = Some((f.take().unwrap())());
+    DEADLINE.store(interrupts::ticks().saturating_add(ticks), Ordering::SeqCst);
+    account::track(true);
+    let aborted = unsafe { enter(&mut call as *mut _ as *mut u8, trampoline(&call)) };
+    account::track(false);
+    if aborted == 0 {
+        account::forget();
+    }
+    let expired = expired();
+    DEADLINE.store(u64::MAX, Ordering::SeqCst);
+    match result {
+        Some(result) if aborted == 0 && !expired => Ok(result),
+        _ => Err(TimedOut),
+    }
+}
+
+/// Whether the code running under [`run`] is past its deadline.
+///
+/// Code running in a [`critical`] section for long can poll this to stop early.
+pub fn expired() -> bool {
+    interrupts::ticks() >= DEADLINE.load(Ordering::SeqCst)
+}
+
+/// Frees what the code last aborted by [`run`] allocated and still held.
+///
+/// Its caller must have dropped whatever refers to it first, e.g. rolled back its writes.
+pub fn reclaim() {
+    account::reclaim();
+}
+
+/// Whether code runs in a [`critical`] section.
+pub fn is_critical() -> bool {
+    CRITICAL.load(Ordering::SeqCst) != 0
+}
+
+/// Runs `f` without letting the watchdog abort it.
+pub fn critical<R>(f: impl FnOnce() -> R) -> R {
+    CRITICAL.fetch_add(1, Ordering::SeqCst);
+    let result = f();
+    CRITICAL.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Aborts the code running under [`run`] right away, unless it's in a [`critical`] section.
+pub fn abort() {
+    if ACTIVE.load(Ordering::SeqCst) && CRITICAL.load(Ordering::SeqCst) == 0 {
+        ACTIVE.store(false, Ordering::SeqCst);
+        unsafe { resume() }
+    }
+}
+
+/// Aborts the code running under [`run`] if it's past its deadline, called by the timer
+/// interrupt handler after the end of interrupt.
+pub fn check(stack_frame: &mut InterruptStackFrame) {
+    if !ACTIVE.load(Ordering::SeqCst) || !expired() || CRITICAL.load(Ordering::SeqCst) != 0 {
+        return;
+    }
+    ACTIVE.store(false, Ordering::SeqCst);
+    let resume_stack = RESUME_STACK.load(Ordering::SeqCst);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(resume as *const () as u64);
+            frame.stack_pointer = VirtAddr::new(resume_stack);
+        });
+    }
+}
+
+/// Function calling a closure of type `F` through a pointer, for [`enter`].
+fn trampoline<F: FnMut()>(_: &F) -> extern "sysv64" fn(*mut u8) {
+    extern "sysv64" fn call<F: FnMut()>(closure: *mut u8) {
+        unsafe { (*(closure as *mut F))() }
+    }
+    call::<F>
+}
+
+/// Calls `call(closure)`, returning 0, or 1 when [`check`] aborts it through [`resume`].
+#[unsafe(naked)]
+unsafe extern "sysv64" fn enter(closure: *mut u8, call: extern "sysv64" fn(*mut u8)) -> u64 {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // Keep the stack 16-byte aligned for the call
+        "sub rsp, 8",
+        "mov [rip + {resume_stack}], rsp",
+        "mov byte ptr [rip + {active}], 1",
+        "call rsi",
+        "mov byte ptr [rip + {active}], 0",
+        "xor eax, eax",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        resume_stack = sym RESUME_STACK,
+        active = sym ACTIVE,
+    )
+}
+
+/// Returns 1 from [`enter`], restoring the registers it saved.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn resume() -> ! {
+    naked_asm!(
+        "mov rsp, [rip + {resume_stack}]",
+        "mov eax, 1",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        resume_stack = sym RESUME_STACK,
+    )
+}
@@ -64,3 +64,19 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+use spacetime_os::allocator::account::Account;
+
+#[test_case]
+fn account_usage() {
+    let account = Account::new(1024);
+    let boxed = account.enter(|| Box::new([0u8; 100]));
+    assert!(account.used() >= 100);
+    assert!(!account.exceeded());
+
+    let large = account.enter(|| Vec::<u8>::with_capacity(2048));
+    assert!(account.exceeded());
+    drop(boxed);
+    drop(large);
+    assert_eq!(account.used(), 0);
+}
@@ -0,0 +1,179 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(spacetime_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use spacetime_os::allocator;
+    use spacetime_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    spacetime_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    spacetime_os::test_panic_handler(info)
+}
+
+// Tests
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use spacetime_os::spacetime_core::def::{ReducerDef, TableDef};
+use spacetime_os::spacetime_core::query::{Query, QueryError};
+use spacetime_os::spacetime_core::value::{
+    AlgebraicType, AlgebraicValue, ProductType, ProductTypeElement, ProductValue,
+};
+use spacetime_os::spacetime_core::view::ViewDef;
+use spacetime_os::spacetime_core::{Module, ReducerCallError};
+use spacetime_os::watchdog::{self, TimedOut};
+
+#[test_case]
+fn loop_is_aborted() {
+    let result = watchdog::run(2, || {
+        loop {
+            core::hint::spin_loop();
+        }
+    });
+    assert_eq!(result, Err::<(), _>(TimedOut));
+    assert_eq!(watchdog::run(100, || 42), Ok(42));
+}
+
+#[test_case]
+fn looping_reducer_is_rolled_back() {
+    let columns = ProductType::new(alloc::vec![ProductTypeElement::new(
+        "n",
+        AlgebraicType::U64
+    )]);
+    let mut module = Module::new(String::from("spinner"));
+    module.add_table(TableDef::new("counter", columns)).unwrap();
+    module
+        .add_reducer(
+            ReducerDef::new("spin", ProductType::new(Vec::new())),
+            Box::new(|ctx, _| {
+                let row = ProductValue::new(alloc::vec![AlgebraicValue::U64(1)]);
+                ctx.insert("counter", row)
+                    .map_err(|error| format!("{:?}", error))?;
+                loop {
+                    core::hint::spin_loop();
+                }
+            }),
+        )
+        .unwrap();
+    module.set_timeout(2);
+
+    let args = ProductValue::new(Vec::new());
+    assert_eq!(
+        module.call_reducer("spin", 0, args),
+        Err(ReducerCallError::Timeout)
+    );
+    assert!(module.table("counter").unwrap().is_empty());
+}
+
+#[test_case]
+fn aborted_reducer_allocations_are_reclaimed() {
+    let mut module = Module::new(String::from("hoarder"));
+    module
+        .add_reducer(
+            ReducerDef::new("hoard", ProductType::new(Vec::new())),
+            Box::new(|_, _| {
+                let hoard = alloc::vec![0u8; 16 * 1024];
+                loop {
+                    core::hint::black_box(&hoard);
+                }
+            }),
+        )
+        .unwrap();
+    module.set_timeout(2);
+
+    // Leaking what each call allocated would exhaust the heap within a few calls
+    let hoard =
+        |module: &mut Module| module.call_reducer("hoard", 0, ProductValue::new(Vec::new()));
+    assert_eq!(hoard(&mut module), Err(ReducerCallError::Timeout));
+    let used = module.memory_used();
+    for _ in 0..20 {
+        assert_eq!(hoard(&mut module), Err(ReducerCallError::Timeout));
+    }
+    assert!(module.memory_used() < used + 16 * 1024);
+}
+
+#[test_case]
+fn quota_aborts_are_credited_back() {
+    let mut module = Module::new(String::from("glutton"));
+    module
+        .add_reducer(
+            ReducerDef::new("hog", ProductType::new(Vec::new())),
+            Box::new(|_, _| {
+                let mut hoard = Vec::new();
+                loop {
+                    hoard.push(alloc::vec![0u8; 1024]);
+                }
+            }),
+        )
+        .unwrap();
+    module
+        .add_reducer(
+            ReducerDef::new("nibble", ProductType::new(Vec::new())),
+            Box::new(|_, _| {
+                core::hint::black_box(alloc::vec![0u8; 1024]);
+                Ok(())
+            }),
+        )
+        .unwrap();
+    module.set_memory_quota(module.memory_used() + 8 * 1024);
+
+    // What the aborted calls held is credited back to the module, leaving room for others
+    let call = |module: &mut Module, reducer| {
+        module.call_reducer(reducer, 0, ProductValue::new(Vec::new()))
+    };
+    for _ in 0..20 {
+        assert_eq!(call(&mut module, "hog"), Err(ReducerCallError::OutOfQuota));
+    }
+    assert_eq!(call(&mut module, "nibble"), Ok(()));
+}
+
+#[test_case]
+fn looping_view_fails() {
+    let columns = ProductType::new(alloc::vec![ProductTypeElement::new(
+        "n",
+        AlgebraicType::U64
+    )]);
+    let mut module = Module::new(String::from("spinner"));
+    let spin = ViewDef::function(
+        "spin",
+        columns,
+        Box::new(|_| {
+            loop {
+                core::hint::spin_loop();
+            }
+        }),
+    );
+    module.add_view(spin).unwrap();
+    module.set_timeout(2);
+
+    // Reading the view fails rather than hanging the kernel, on query and on subscribe
+    let failed = Err(QueryError::ViewFailed {
+        view: String::from("spin"),
+        error: String::from("timed out"),
+    });
+    assert_eq!(module.query(&Query::table("spin")), failed);
+    assert_eq!(
+        module.subscribe(&Query::table("spin")).map(drop),
+        failed.map(drop)
+    );
+}